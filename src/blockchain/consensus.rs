@@ -1,10 +1,11 @@
 #![allow(dead_code, unused_imports)]
 
+use crate::codec::{Decode, DecodeError, Encode};
 use crate::hash;
 
 type Hash = u64;
 
-pub const THRESHOLD: u64 = u64::max_value()/100;
+pub const THRESHOLD: u64 = u64::MAX/100;
 
 const FORK_HEIGHT: u64 = 2;
 
@@ -20,15 +21,13 @@ pub struct Header {
 
 impl Header {
     fn genesis() -> Self {
-        let genesis = Header {
+        Header {
             parent: 0,
             height: 0,
             extrinsic: 0,
             state: 0,
             consensus_digest: 0
-           };
-    
-           genesis
+        }
     }
     fn child(&self, extrinsic: u64) -> Self {
         let mut header = Header {
@@ -49,8 +48,8 @@ impl Header {
     
         for block_header in chain {
             if block_header.parent != hash(last_header)
-                || block_header.height != last_header.height + 1
-                || block_header.state != last_header.state + block_header.extrinsic
+                || Some(block_header.height) != last_header.height.checked_add(1)
+                || Some(block_header.state) != last_header.state.checked_add(block_header.extrinsic)
                 || hash(block_header) > THRESHOLD
             {
                 return false;
@@ -66,9 +65,9 @@ impl Header {
 
         for block_header in chain {
             if block_header.parent != hash(last_header)
-                || block_header.height != last_header.height + 1
+                || Some(block_header.height) != last_header.height.checked_add(1)
                 || block_header.height > 2 && block_header.state % 2 != 0
-                || block_header.state != last_header.state + block_header.extrinsic 
+                || Some(block_header.state) != last_header.state.checked_add(block_header.extrinsic) 
                 || hash(block_header) > THRESHOLD
             {
                 return false;
@@ -86,9 +85,9 @@ impl Header {
 
         for block_header in chain {
             if block_header.parent != hash(last_header)
-                || block_header.height != last_header.height + 1
+                || Some(block_header.height) != last_header.height.checked_add(1)
                 || block_header.height > 2 && block_header.state % 2 != 1
-                || block_header.state != last_header.state + block_header.extrinsic 
+                || Some(block_header.state) != last_header.state.checked_add(block_header.extrinsic) 
                 || hash(block_header) > THRESHOLD
             {
                 return false;
@@ -113,6 +112,50 @@ fn build_contentious_forked_chain() -> (Vec<Header>, Vec<Header>, Vec<Header>) {
     todo!()
 }
 
+/// Verifies `chain` from its first header on under the original, even and odd rules. This is
+/// what the `headers` fuzz target runs, so the verifiers themselves can stay private.
+pub(crate) fn verify_under_every_rule(chain: &[Header]) -> [bool; 3] {
+    match chain.split_first() {
+        Some((first, rest)) => {
+            [first.verify_sub_chain(rest), first.verify_sub_chain_even(rest), first.verify_sub_chain_odd(rest)]
+        }
+        None => [false; 3],
+    }
+}
+
+/// Genesis followed by a child for each of `extrinsics`, e.g. to seed the fuzz corpus.
+#[cfg(test)]
+pub(crate) fn mined_chain(extrinsics: &[u64]) -> Vec<Header> {
+    let mut chain = vec![Header::genesis()];
+    for extrinsic in extrinsics {
+        let child = chain[chain.len() - 1].child(*extrinsic);
+        chain.push(child);
+    }
+    chain
+}
+
+impl Encode for Header {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.parent.encode_to(out);
+        self.height.encode_to(out);
+        self.extrinsic.encode_to(out);
+        self.state.encode_to(out);
+        self.consensus_digest.encode_to(out);
+    }
+}
+
+impl Decode for Header {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Header {
+            parent: Hash::decode_from(input)?,
+            height: u64::decode_from(input)?,
+            extrinsic: u64::decode_from(input)?,
+            state: u64::decode_from(input)?,
+            consensus_digest: u64::decode_from(input)?,
+        })
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
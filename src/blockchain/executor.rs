@@ -0,0 +1,660 @@
+#![allow(dead_code)]
+
+//! Runs blocks whose bodies are transitions of a state machine. The headers of the exercises
+//! in `hearder_chain`, `extrinsic_state` and `consensus` are fixed by their tests, so the
+//! executor has a `Header` of its own, with what running a machine needs on top: the author
+//! the block hooks pay and their signature, the runtime version and the receipts root.
+
+use super::receipts::{receipts_root, Receipt};
+use crate::codec::{Decode, DecodeError, Encode};
+use crate::hash;
+use crate::state_machine::events::Event;
+use crate::state_machine::journal::{Journal, Overlay};
+use crate::state_machine::script::{self, public_key, SecretKey};
+use crate::state_machine::{BlockContext, BlockHooks, Revertible, RuntimeVersion, UndoEmitter, User, Versioned};
+use std::hash::Hash as StdHash;
+
+type Hash = u64;
+
+/// The state a block led to and its receipts, see `apply_block_with_receipts`.
+pub type Executed<S> = (S, Vec<Receipt>);
+
+/// A block fresh from `build_block` and the receipts its header was sealed with.
+pub type Sealed<T> = (Block<T>, Vec<Receipt>);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Header {
+    parent: Hash,
+    height: u64,
+    /// Collects the fees and the block reward. Genesis has no author.
+    author: Option<User>,
+    /// The rules the block was executed under. Children inherit it unless they are built on an
+    /// upgrade, see `Block::with_runtime_version`.
+    runtime_version: RuntimeVersion,
+    extrinsics_root: Hash,
+    /// Commits to the events the block emitted, see `receipts::receipts_root`. It is only known
+    /// once the block is executed, so `build_block` fills it in and `Block::child` leaves it at 0.
+    receipts_root: Hash,
+    /// The author's signature of the rest of the header, see `Header::sign`.
+    signature: Option<u64>,
+}
+
+impl Header {
+    pub fn genesis() -> Self {
+        Header {
+            parent: 0,
+            height: 0,
+            author: None,
+            runtime_version: 0,
+            extrinsics_root: 0,
+            receipts_root: receipts_root(&[]),
+            signature: None,
+        }
+    }
+
+    pub fn child(&self, author: User, extrinsics_root: Hash) -> Self {
+        Header {
+            parent: hash(self),
+            height: self.height + 1,
+            author: Some(author),
+            runtime_version: self.runtime_version,
+            extrinsics_root,
+            receipts_root: 0,
+            signature: None,
+        }
+    }
+
+    pub fn author(&self) -> Option<User> {
+        self.author
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn runtime_version(&self) -> RuntimeVersion {
+        self.runtime_version
+    }
+
+    pub fn receipts_root(&self) -> Hash {
+        self.receipts_root
+    }
+
+    /// What the author signs: the hash of the header without its signature.
+    pub fn signing_hash(&self) -> Hash {
+        hash(&Header { signature: None, ..self.clone() })
+    }
+
+    /// Signs the header with `key`. Changing the header afterwards voids the signature.
+    pub fn sign(&mut self, key: &SecretKey) {
+        self.signature = Some(script::sign(key, self.signing_hash()));
+    }
+
+    /// Whether the header carries its author's signature.
+    pub fn is_signed_by_author(&self) -> bool {
+        match (self.author, self.signature) {
+            (Some(author), Some(signature)) => script::verify_signature(public_key(author), self.signing_hash(), signature),
+            _ => false,
+        }
+    }
+
+    /// What the state machine gets to know about the block.
+    pub fn context(&self) -> BlockContext {
+        BlockContext { height: self.height, author: self.author }
+    }
+}
+
+/// A block whose body holds transitions of some state machine.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Block<T> {
+    pub(crate) header: Header,
+    pub(crate) body: Vec<T>,
+}
+
+impl<T: StdHash> Block<T> {
+    pub fn genesis() -> Self {
+        Block { header: Header::genesis(), body: Vec::new() }
+    }
+
+    pub fn child(&self, author: User, body: Vec<T>) -> Self {
+        Block { header: self.header.child(author, hash(&body)), body }
+    }
+
+    pub fn signed(mut self, key: &SecretKey) -> Self {
+        self.header.sign(key);
+        self
+    }
+
+    /// Claims the block runs under `version`, e.g. the first one after an upgrade.
+    pub fn with_runtime_version(mut self, version: RuntimeVersion) -> Self {
+        self.header.runtime_version = version;
+        self
+    }
+
+    pub fn verify_child(&self, child: &Block<T>) -> bool {
+        child.header.parent == hash(&self.header)
+            && Some(child.header.height) == self.header.height.checked_add(1)
+            && child.header.author.is_some()
+            && child.header.extrinsics_root == hash(&child.body)
+    }
+}
+
+impl Encode for Header {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.parent.encode_to(out);
+        self.height.encode_to(out);
+        self.author.encode_to(out);
+        self.runtime_version.encode_to(out);
+        self.extrinsics_root.encode_to(out);
+        self.receipts_root.encode_to(out);
+        self.signature.encode_to(out);
+    }
+}
+
+impl Decode for Header {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Header {
+            parent: Hash::decode_from(input)?,
+            height: u64::decode_from(input)?,
+            author: Option::decode_from(input)?,
+            runtime_version: RuntimeVersion::decode_from(input)?,
+            extrinsics_root: Hash::decode_from(input)?,
+            receipts_root: Hash::decode_from(input)?,
+            signature: Option::decode_from(input)?,
+        })
+    }
+}
+
+impl<T: Encode> Encode for Block<T> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.header.encode_to(out);
+        self.body.encode_to(out);
+    }
+}
+
+impl<T: Decode> Decode for Block<T> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Block { header: Header::decode_from(input)?, body: Vec::decode_from(input)? })
+    }
+}
+
+/// Executes `block` on top of `state`. A single rejected transition rejects the whole block.
+pub fn execute_block<M>(state: &M::State, block: &Block<M::Transition>) -> Result<M::State, M::Error>
+where
+    M: BlockHooks,
+    M::State: Clone,
+{
+    let context = block.header.context();
+    let mut state = state.clone();
+
+    M::on_initialize(&mut state, &context);
+    for transition in &block.body {
+        M::apply(&mut state, transition)?;
+    }
+    M::on_finalize(&mut state, &context);
+
+    Ok(state)
+}
+
+/// Like `execute_block`, also returning the receipts of the block, see `apply_block_with_receipts`.
+pub fn execute_block_with_receipts<M>(
+    state: &M::State,
+    block: &Block<M::Transition>,
+) -> Result<Executed<M::State>, M::Error>
+where
+    M: BlockHooks + UndoEmitter<Event = Event>,
+    M::State: Clone,
+{
+    let mut state = state.clone();
+    let receipts = apply_block_with_receipts::<M>(&mut state, block)?;
+    Ok((state, receipts))
+}
+
+/// Executes `block` on `state` in place, returning a receipt for every transaction and a last
+/// one for what the block hooks did. A rejected block leaves `state` untouched.
+pub fn apply_block_with_receipts<M>(state: &mut M::State, block: &Block<M::Transition>) -> Result<Vec<Receipt>, M::Error>
+where
+    M: BlockHooks + UndoEmitter<Event = Event>,
+    M::State: Clone,
+{
+    let context = block.header.context();
+    let mut overlay = Overlay::<M>::new(state);
+    let mut receipts = Vec::with_capacity(block.body.len() + 1);
+
+    let mut hooks = overlay.on_initialize_with_events(&context);
+    for transition in &block.body {
+        receipts.push(Receipt { events: overlay.apply_with_events(transition)? });
+    }
+    hooks.extend(overlay.on_finalize_with_events(&context));
+    receipts.push(Receipt { events: hooks });
+
+    overlay.commit();
+    Ok(receipts)
+}
+
+/// Authors the child of `parent` holding `body` and executes it on `state` in place, sealing
+/// the receipts root into its header and signing it with the author's development key, the
+/// only key a `User` has, see `script::public_key`. A rejected block leaves `state` untouched.
+pub fn build_block<M>(
+    parent: &Block<M::Transition>,
+    state: &mut M::State,
+    author: User,
+    body: Vec<M::Transition>,
+) -> Result<Sealed<M::Transition>, M::Error>
+where
+    M: BlockHooks + UndoEmitter<Event = Event>,
+    M::State: Clone,
+    M::Transition: StdHash,
+{
+    let mut block = parent.child(author, body);
+    let receipts = apply_block_with_receipts::<M>(state, &block)?;
+    block.header.receipts_root = receipts_root(&receipts);
+    block.header.sign(&SecretKey::dev(author));
+
+    Ok((block, receipts))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportError<E> {
+    /// The header does not carry its author's signature.
+    Unsigned,
+    /// A transition of the block was rejected.
+    Rejected(E),
+    /// Executing the block emitted other events than its header commits to.
+    ReceiptsRootMismatch,
+    /// The header claims other rules than the chain runs at the block's height.
+    WrongRuntimeVersion { expected: RuntimeVersion, found: RuntimeVersion },
+}
+
+/// Executes a block built by someone else, checking its signature and its receipts against
+/// its header.
+pub fn import_block<M>(
+    state: &M::State,
+    block: &Block<M::Transition>,
+) -> Result<Executed<M::State>, ImportError<M::Error>>
+where
+    M: BlockHooks + UndoEmitter<Event = Event>,
+    M::State: Clone,
+{
+    if !block.header.is_signed_by_author() {
+        return Err(ImportError::Unsigned)
+    }
+    let (state, receipts) = execute_block_with_receipts::<M>(state, block).map_err(ImportError::Rejected)?;
+
+    if receipts_root(&receipts) != block.header.receipts_root {
+        return Err(ImportError::ReceiptsRootMismatch)
+    }
+    Ok((state, receipts))
+}
+
+/// Executes a block of a machine whose rules change over time, which has to run under the
+/// version its header claims.
+pub fn execute_versioned_block<M>(
+    state: &M::State,
+    block: &Block<M::Transition>,
+) -> Result<M::State, ImportError<M::Error>>
+where
+    M: BlockHooks + Versioned,
+    M::State: Clone,
+{
+    let expected = M::runtime_version(state, block.header.height);
+    if block.header.runtime_version != expected {
+        return Err(ImportError::WrongRuntimeVersion { expected, found: block.header.runtime_version })
+    }
+    execute_block::<M>(state, block).map_err(ImportError::Rejected)
+}
+
+/// Executes `block` on `state` in place, returning how to undo it. A rejected block leaves
+/// `state` untouched.
+pub fn apply_block<M>(state: &mut M::State, block: &Block<M::Transition>) -> Result<Vec<M::Undo>, M::Error>
+where
+    M: BlockHooks + Revertible,
+    M::State: Clone,
+{
+    let context = block.header.context();
+    let mut overlay = Overlay::<M>::new(state);
+
+    overlay.on_initialize(&context);
+    for transition in &block.body {
+        overlay.apply(transition)?;
+    }
+    overlay.on_finalize(&context);
+
+    Ok(overlay.commit())
+}
+
+/// Like `apply_block`, recording how to undo the block in `journal` once it is accepted.
+pub fn execute_block_journaled<M>(
+    state: &mut M::State,
+    block: &Block<M::Transition>,
+    journal: &mut Journal<M>,
+) -> Result<(), M::Error>
+where
+    M: BlockHooks + Revertible,
+    M::State: Clone,
+{
+    journal.push_block(apply_block::<M>(state, block)?);
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::state_machine::accounted_currency::{
+        AccountedCurrency, AccountingError, AccountingTransaction, ExistenceRequirement, FeeConfig, State,
+        NATIVE_ASSET,
+    };
+    use crate::blockchain::receipts::{EventFilter, EventLog};
+    use crate::state_machine::accounted_currency::{self, Htlc};
+    use crate::state_machine::events::EventKind;
+    use crate::state_machine::script::{hash_of, SecretKey};
+    use crate::state_machine::utxo_currency::{self, CashTransaction, DigitalCashSystem, Input, OutPoint, Output};
+    use crate::state_machine::Checked;
+    use std::fmt::Debug;
+
+    fn transfer(amount: u64, nonce: u64) -> AccountingTransaction {
+        AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            asset: NATIVE_ASSET,
+            amount,
+            existence: ExistenceRequirement::AllowDeath,
+            nonce,
+        }
+    }
+
+    fn genesis_state() -> State {
+        State::from([(User::Alice, 100)]).with_fees(FeeConfig { base_fee: 1, weight_fee: 1, block_reward: 10 })
+    }
+
+    #[test]
+    fn child_block_is_valid() {
+        let genesis = Block::genesis();
+        let b1 = genesis.child(User::Charlie, vec![transfer(10, 0)]);
+
+        assert_eq!(b1.header.author(), Some(User::Charlie));
+        assert!(genesis.verify_child(&b1));
+    }
+
+    #[test]
+    fn tampered_body_is_invalid() {
+        let genesis = Block::genesis();
+        let mut b1 = genesis.child(User::Charlie, vec![transfer(10, 0)]);
+        b1.body.push(transfer(10, 1));
+
+        assert!(!genesis.verify_child(&b1));
+    }
+
+    #[test]
+    fn author_is_paid_fees_and_reward() {
+        let b1 = Block::genesis().child(User::Charlie, vec![transfer(10, 0), transfer(10, 1)]);
+        let state = execute_block::<Checked<AccountedCurrency>>(&genesis_state(), &b1).unwrap();
+
+        assert_eq!(state.block_height(), 1);
+        assert_eq!(state.balance(NATIVE_ASSET, &User::Alice), 74);
+        assert_eq!(state.balance(NATIVE_ASSET, &User::Bob), 20);
+        assert_eq!(state.balance(NATIVE_ASSET, &User::Charlie), 16);
+        assert_eq!(state.total_issuance(), 110);
+    }
+
+    #[test]
+    fn invalid_transition_rejects_block() {
+        let b1 = Block::genesis().child(User::Charlie, vec![transfer(10, 0), transfer(10, 0)]);
+
+        assert_eq!(
+            execute_block::<AccountedCurrency>(&genesis_state(), &b1),
+            Err(AccountingError::StaleNonce)
+        );
+    }
+
+    #[test]
+    fn reorg_reverts_to_common_ancestor() {
+        let genesis = Block::genesis();
+        let mut journal = Journal::<AccountedCurrency>::new();
+        let mut state = genesis_state();
+
+        let b1 = genesis.child(User::Charlie, vec![transfer(10, 0)]);
+        execute_block_journaled(&mut state, &b1, &mut journal).unwrap();
+        let ancestor = state.clone();
+        let b2 = b1.child(User::Charlie, vec![transfer(20, 1)]);
+        execute_block_journaled(&mut state, &b2, &mut journal).unwrap();
+        let b3 = b2.child(User::Charlie, vec![transfer(30, 2)]);
+        execute_block_journaled(&mut state, &b3, &mut journal).unwrap();
+
+        // A rejected block is not recorded.
+        let invalid = b3.child(User::Charlie, vec![transfer(10, 0)]);
+        assert!(execute_block_journaled(&mut state, &invalid, &mut journal).is_err());
+        assert_eq!(journal.depth(), 3);
+
+        journal.revert_to(1, &mut state);
+        assert_eq!(state, ancestor);
+
+        // The fork is applied on the ancestor without replaying from genesis.
+        let fork = b1.child(User::Bob, vec![transfer(5, 1)]);
+        execute_block_journaled(&mut state, &fork, &mut journal).unwrap();
+        assert_eq!(state.balance(NATIVE_ASSET, &User::Alice), 79);
+        assert_eq!(journal.depth(), 2);
+    }
+
+    #[test]
+    fn rejected_block_leaves_state_untouched() {
+        let mut state = genesis_state();
+        let b1 = Block::genesis().child(User::Charlie, vec![transfer(10, 0), transfer(10, 5)]);
+
+        assert_eq!(apply_block::<AccountedCurrency>(&mut state, &b1), Err(AccountingError::FutureNonce));
+        assert_eq!(state, genesis_state());
+        let built = build_block::<AccountedCurrency>(&Block::genesis(), &mut state, User::Charlie, b1.body);
+        assert_eq!(built, Err(AccountingError::FutureNonce));
+        assert_eq!(state, genesis_state());
+    }
+
+    #[test]
+    fn applied_block_matches_executed_block() {
+        let b1 = Block::genesis().child(User::Charlie, vec![transfer(10, 0), transfer(10, 1)]);
+        let executed = execute_block::<Checked<AccountedCurrency>>(&genesis_state(), &b1).unwrap();
+        let mut state = genesis_state();
+        apply_block::<AccountedCurrency>(&mut state, &b1).unwrap();
+
+        assert_eq!(state, executed);
+    }
+
+    #[test]
+    fn overlay_is_committed_or_discarded() {
+        let mut state = genesis_state();
+
+        let mut overlay = Overlay::<AccountedCurrency>::new(&mut state);
+        overlay.apply(&transfer(10, 0)).unwrap();
+        assert_eq!(overlay.state().balance(NATIVE_ASSET, &User::Bob), 10);
+        overlay.discard();
+        assert_eq!(state, genesis_state());
+
+        let mut overlay = Overlay::<AccountedCurrency>::new(&mut state);
+        overlay.apply(&transfer(10, 0)).unwrap();
+        assert_eq!(overlay.apply(&transfer(10, 0)), Err(AccountingError::StaleNonce));
+        assert_eq!(overlay.commit().len(), 1);
+        assert_eq!(state.balance(NATIVE_ASSET, &User::Bob), 10);
+    }
+
+    #[test]
+    fn built_block_commits_to_its_receipts() {
+        let mut built = genesis_state();
+        let (b1, receipts) =
+            build_block::<Checked<AccountedCurrency>>(&Block::genesis(), &mut built, User::Charlie, vec![transfer(10, 0)]).unwrap();
+
+        assert!(Block::genesis().verify_child(&b1));
+        assert_eq!(b1.header.receipts_root(), receipts_root(&receipts));
+        assert_eq!(receipts[0].events, vec![Event::Transferred { asset: NATIVE_ASSET, from: User::Alice, to: User::Bob, amount: 10 }]);
+        // The block hooks paid Charlie the reward and the fee of the transfer.
+        assert_eq!(receipts[1].events, vec![
+            Event::Minted { asset: NATIVE_ASSET, to: User::Charlie, amount: 10 },
+            Event::FeesPaid { to: User::Charlie, amount: 3 },
+        ]);
+        assert_eq!(import_block::<Checked<AccountedCurrency>>(&genesis_state(), &b1), Ok((built, receipts)));
+    }
+
+    #[test]
+    fn unsealed_or_invalid_block_is_not_imported() {
+        let charlie = SecretKey::dev(User::Charlie);
+        let unsealed = Block::genesis().child(User::Charlie, vec![transfer(10, 0)]).signed(&charlie);
+        let invalid = Block::genesis().child(User::Charlie, vec![transfer(10, 1)]).signed(&charlie);
+
+        assert_eq!(import_block::<AccountedCurrency>(&genesis_state(), &unsealed), Err(ImportError::ReceiptsRootMismatch));
+        assert_eq!(
+            import_block::<AccountedCurrency>(&genesis_state(), &invalid),
+            Err(ImportError::Rejected(AccountingError::FutureNonce))
+        );
+    }
+
+    #[test]
+    fn only_blocks_signed_by_their_author_are_imported() {
+        let (built, _) = build_block::<AccountedCurrency>(&Block::genesis(), &mut genesis_state(), User::Charlie, vec![]).unwrap();
+        let unsigned = Block { header: Header { signature: None, ..built.header.clone() }, body: Vec::new() };
+        let forged = built.clone().signed(&SecretKey::dev(User::Bob));
+        let tampered = Block { header: Header { receipts_root: 0, ..built.header.clone() }, body: Vec::new() };
+
+        assert!(built.header.is_signed_by_author());
+        for block in [unsigned, forged, tampered] {
+            assert_eq!(import_block::<AccountedCurrency>(&genesis_state(), &block), Err(ImportError::Unsigned));
+        }
+    }
+
+    #[test]
+    fn imported_events_are_queried_by_block_range() {
+        let mut log = EventLog::new();
+        let mut state = genesis_state();
+        let mut built = genesis_state();
+        let mut head = Block::genesis();
+
+        for nonce in 0..3 {
+            let (block, _) = build_block::<AccountedCurrency>(&head, &mut built, User::Charlie, vec![transfer(10, nonce)]).unwrap();
+            let (imported, receipts) = import_block::<AccountedCurrency>(&state, &block).unwrap();
+            assert_eq!(imported, built);
+            log.insert(block.header.height(), receipts);
+            (state, head) = (imported, block);
+        }
+        let to_bob = EventFilter::new().kind(EventKind::Transferred).account(User::Bob).blocks(2..=3);
+
+        let heights: Vec<u64> = log.query(&to_bob).iter().map(|record| record.height).collect();
+        assert_eq!(heights, vec![2, 3]);
+        let rewards = log.query(&EventFilter::new().kind(EventKind::Minted).account(User::Charlie));
+        let rewarded: Vec<(u64, usize)> = rewards.iter().map(|record| (record.height, record.transaction)).collect();
+        assert_eq!(rewarded, vec![(1, 1), (2, 1), (3, 1)]);
+    }
+
+    /// A chain of `M` whose blocks are all authored by Charlie.
+    struct Chain<M: BlockHooks> {
+        state: M::State,
+        head: Block<M::Transition>,
+    }
+
+    impl<M> Chain<M>
+    where
+        M: BlockHooks,
+        M::State: Clone,
+        M::Transition: StdHash,
+        M::Error: Debug,
+    {
+        fn new(state: M::State) -> Self {
+            Chain { state, head: Block::genesis() }
+        }
+
+        fn produce(&mut self, body: Vec<M::Transition>) {
+            let block = self.head.child(User::Charlie, body);
+            self.state = execute_block::<M>(&self.state, &block).unwrap();
+            self.head = block;
+        }
+
+        fn produce_until(&mut self, height: u64) {
+            while self.head.header.height + 1 < height {
+                self.produce(Vec::new());
+            }
+        }
+    }
+
+    const SECRET: u64 = 1234;
+    const SWAP_EXPIRY_A: u64 = 20;
+    const SWAP_EXPIRY_B: u64 = 10;
+
+    /// Alice swaps 30 on the account chain for Bob's 50 on the cash chain. She knows the secret
+    /// and locks first with the later expiry, so Bob can still claim after she reveals it.
+    fn locked_swap() -> (Chain<Checked<AccountedCurrency>>, Chain<Checked<DigitalCashSystem>>) {
+        let mut chain_a: Chain<Checked<AccountedCurrency>> = Chain::new(accounted_currency::State::from([(User::Alice, 100), (User::Bob, 10)]));
+        let mut chain_b: Chain<Checked<DigitalCashSystem>> = Chain::new(utxo_currency::State::from([Output::owned_by(User::Bob, 50)]));
+
+        chain_a.produce(vec![AccountingTransaction::HtlcLock {
+            sender: User::Alice,
+            receiver: User::Bob,
+            asset: NATIVE_ASSET,
+            amount: 30,
+            hash_lock: hash_of(SECRET),
+            expiry: SWAP_EXPIRY_A,
+            nonce: 0,
+        }]);
+
+        // Bob only locks once he sees Alice's contract, under the same hash.
+        let htlc = chain_a.state.htlc(Htlc::id(User::Alice, 0)).unwrap();
+        let mut lock = CashTransaction::Transfer {
+            inputs: vec![Input::unsigned(OutPoint { tx_hash: utxo_currency::GENESIS_TX, index: 0 })],
+            outputs: vec![Output::htlc(50, htlc.hash_lock, User::Alice, User::Bob, SWAP_EXPIRY_B)],
+        };
+        lock.sign(0, &SecretKey::dev(User::Bob)).unwrap();
+        chain_b.produce(vec![lock]);
+
+        (chain_a, chain_b)
+    }
+
+    fn htlc_output(chain: &Chain<Checked<DigitalCashSystem>>) -> OutPoint {
+        let lock = &chain.head.body[0];
+        assert!(chain.state.utxo(&lock.outpoint(0)).is_some());
+        lock.outpoint(0)
+    }
+
+    #[test]
+    fn atomic_swap_between_chains() {
+        let (mut chain_a, mut chain_b) = locked_swap();
+
+        let mut claim = CashTransaction::Transfer {
+            inputs: vec![Input::unsigned(htlc_output(&chain_b))],
+            outputs: vec![Output::owned_by(User::Alice, 50)],
+        };
+        claim.claim_htlc(0, &SecretKey::dev(User::Alice), SECRET).unwrap();
+        chain_b.produce(vec![claim]);
+
+        // Claiming revealed the secret to Bob, who uses it on the other chain.
+        let preimage = chain_b.head.body[0].revealed_preimages()[0];
+        chain_a.produce(vec![AccountingTransaction::HtlcClaim {
+            claimer: User::Bob,
+            id: Htlc::id(User::Alice, 0),
+            preimage,
+            nonce: 0,
+        }]);
+
+        assert_eq!(chain_a.state.balance(NATIVE_ASSET, &User::Alice), 70);
+        assert_eq!(chain_a.state.balance(NATIVE_ASSET, &User::Bob), 40);
+        assert!(chain_b.state.outputs().values().any(|output| *output == Output::owned_by(User::Alice, 50)));
+    }
+
+    #[test]
+    fn expired_swap_is_refunded_on_both_chains() {
+        let (mut chain_a, mut chain_b) = locked_swap();
+        let outpoint = htlc_output(&chain_b);
+
+        chain_b.produce_until(SWAP_EXPIRY_B);
+        let mut refund = CashTransaction::Transfer {
+            inputs: vec![Input::unsigned(outpoint)],
+            outputs: vec![Output::owned_by(User::Bob, 50)],
+        };
+        refund.refund_htlc(0, &SecretKey::dev(User::Bob)).unwrap();
+        chain_b.produce(vec![refund]);
+
+        chain_a.produce_until(SWAP_EXPIRY_A);
+        chain_a.produce(vec![AccountingTransaction::HtlcRefund {
+            sender: User::Alice,
+            id: Htlc::id(User::Alice, 0),
+            nonce: 1,
+        }]);
+
+        assert_eq!(chain_a.state.balance(NATIVE_ASSET, &User::Alice), 100);
+        assert!(chain_b.state.outputs().values().any(|output| *output == Output::owned_by(User::Bob, 50)));
+    }
+}
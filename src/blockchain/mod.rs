@@ -1,4 +1,6 @@
 mod hearder_chain;
 mod extrinsic_state;
-mod consensus;
-mod batched_extrinsics;
\ No newline at end of file
+pub(crate) mod consensus;
+mod batched_extrinsics;
+pub(crate) mod executor;
+pub(crate) mod receipts;
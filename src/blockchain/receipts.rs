@@ -0,0 +1,185 @@
+#![allow(dead_code)]
+
+//! Receipts record the events each transaction of a block emitted, and after them what the
+//! block hooks did, e.g. minting the block reward. Headers commit to them through the
+//! receipts root, and an `EventLog` keeps them around to be searched.
+
+use crate::hash;
+use crate::state_machine::events::{Event, EventKind};
+use crate::state_machine::User;
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+
+/// What one transaction of a block, or the block hooks, did.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Receipt {
+    pub events: Vec<Event>,
+}
+
+/// What a header commits to for the receipts of its block, one per transaction and one for
+/// the block hooks.
+pub fn receipts_root(receipts: &[Receipt]) -> u64 {
+    hash(&receipts)
+}
+
+/// An event found by a query, with where it was emitted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventRecord<'a> {
+    pub height: u64,
+    /// Index of the transaction in its block. Events of the block hooks come after the last one.
+    pub transaction: usize,
+    pub event: &'a Event,
+}
+
+/// Which events a query returns. An empty filter matches every event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventFilter {
+    kinds: Vec<EventKind>,
+    account: Option<User>,
+    blocks: (Bound<u64>, Bound<u64>),
+}
+
+impl Default for EventFilter {
+    fn default() -> Self {
+        EventFilter { kinds: Vec::new(), account: None, blocks: (Bound::Unbounded, Bound::Unbounded) }
+    }
+}
+
+impl EventFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also matches events of `kind`. Without any kind, events of every kind match.
+    pub fn kind(mut self, kind: EventKind) -> Self {
+        self.kinds.push(kind);
+        self
+    }
+
+    /// Only matches events that involve `account`.
+    pub fn account(mut self, account: User) -> Self {
+        self.account = Some(account);
+        self
+    }
+
+    /// Only matches events emitted in blocks whose height is in `heights`.
+    pub fn blocks(mut self, heights: impl RangeBounds<u64>) -> Self {
+        self.blocks = (heights.start_bound().cloned(), heights.end_bound().cloned());
+        self
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&event.kind()))
+            && self.account.is_none_or(|account| event.involves(account))
+    }
+}
+
+/// The receipts of a chain's blocks by height.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventLog {
+    blocks: BTreeMap<u64, Vec<Receipt>>,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the receipts of the block at `height`, replacing those of a block it reorged out.
+    pub fn insert(&mut self, height: u64, receipts: Vec<Receipt>) {
+        self.blocks.insert(height, receipts);
+    }
+
+    /// Forgets every block above `height`, e.g. when the chain reverts to it.
+    pub fn truncate(&mut self, height: u64) {
+        self.blocks.split_off(&(height.saturating_add(1)));
+    }
+
+    pub fn receipts(&self, height: u64) -> Option<&[Receipt]> {
+        self.blocks.get(&height).map(Vec::as_slice)
+    }
+
+    /// Every event `filter` matches, oldest first.
+    pub fn query(&self, filter: &EventFilter) -> Vec<EventRecord<'_>> {
+        if is_backwards(filter.blocks) {
+            return Vec::new()
+        }
+        let mut records = Vec::new();
+
+        for (height, receipts) in self.blocks.range(filter.blocks) {
+            for (transaction, receipt) in receipts.iter().enumerate() {
+                let matching = receipt.events.iter().filter(|event| filter.matches(event));
+                records.extend(matching.map(|event| EventRecord { height: *height, transaction, event }));
+            }
+        }
+        records
+    }
+}
+
+// `BTreeMap::range` panics on a range that ends before it starts.
+fn is_backwards(blocks: (Bound<u64>, Bound<u64>)) -> bool {
+    match blocks {
+        (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => start > end,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::state_machine::accounted_currency::NATIVE_ASSET;
+
+    fn transferred(from: User, to: User, amount: u64) -> Event {
+        Event::Transferred { asset: NATIVE_ASSET, from, to, amount }
+    }
+
+    fn log() -> EventLog {
+        let mut log = EventLog::new();
+        log.insert(1, vec![Receipt { events: vec![Event::Minted { asset: NATIVE_ASSET, to: User::Alice, amount: 100 }] }]);
+        log.insert(2, vec![
+            Receipt { events: vec![transferred(User::Alice, User::Bob, 10)] },
+            Receipt { events: vec![Event::CashDispensed { amount: 5 }] },
+        ]);
+        log.insert(3, vec![Receipt { events: vec![transferred(User::Bob, User::Charlie, 3)] }]);
+        log
+    }
+
+    #[test]
+    fn empty_filter_matches_everything_in_order() {
+        let log = log();
+        let heights: Vec<(u64, usize)> = log.query(&EventFilter::new()).iter().map(|r| (r.height, r.transaction)).collect();
+
+        assert_eq!(heights, vec![(1, 0), (2, 0), (2, 1), (3, 0)]);
+    }
+
+    #[test]
+    fn filters_by_kind_account_and_blocks() {
+        let log = log();
+        let bobs_transfers = EventFilter::new().kind(EventKind::Transferred).account(User::Bob);
+
+        assert_eq!(log.query(&bobs_transfers).len(), 2);
+        assert_eq!(log.query(&bobs_transfers.clone().blocks(3..)).len(), 1);
+        assert_eq!(log.query(&bobs_transfers.blocks(..=1)).len(), 0);
+        assert_eq!(log.query(&EventFilter::new().kind(EventKind::CashDispensed).kind(EventKind::Minted)).len(), 2);
+        assert_eq!(log.query(&EventFilter::new().account(User::Charlie))[0].event, &transferred(User::Bob, User::Charlie, 3));
+    }
+
+    #[test]
+    fn backwards_range_matches_nothing() {
+        #[allow(clippy::reversed_empty_ranges)]
+        let filter = EventFilter::new().blocks(3..1);
+
+        assert!(log().query(&filter).is_empty());
+        assert!(log().query(&EventFilter::new().blocks((Bound::Excluded(2), Bound::Excluded(2)))).is_empty());
+    }
+
+    #[test]
+    fn truncated_blocks_are_forgotten() {
+        let mut log = log();
+        log.truncate(1);
+
+        assert_eq!(log.query(&EventFilter::new()).len(), 1);
+        assert_eq!(log.receipts(2), None);
+    }
+}
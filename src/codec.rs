@@ -0,0 +1,230 @@
+#![allow(dead_code)]
+
+//! A compact byte encoding for blocks, transactions and the states traces record. Integers
+//! are little endian, strings and sequences are prefixed with their length as a `u32`, and
+//! enum variants with a one byte tag. Decoding never trusts a length prefix for more than the
+//! bytes that are left.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ended in the middle of a value.
+    UnexpectedEnd,
+    /// A tag that names no variant.
+    InvalidTag(u8),
+    InvalidUtf8,
+    /// A value its type does not allow, e.g. a zero it would divide by.
+    InvalidValue,
+    /// Bytes were left over after the value.
+    TrailingBytes,
+}
+
+pub trait Encode {
+    fn encode_to(&self, out: &mut Vec<u8>);
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_to(&mut out);
+        out
+    }
+}
+
+pub trait Decode: Sized {
+    /// Reads a value from the front of `input`, leaving the rest of it.
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError>;
+
+    /// Reads a value that has to take up all of `bytes`.
+    fn decode(mut bytes: &[u8]) -> Result<Self, DecodeError> {
+        let value = Self::decode_from(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(DecodeError::TrailingBytes)
+        }
+        Ok(value)
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], DecodeError> {
+    if input.len() < len {
+        return Err(DecodeError::UnexpectedEnd)
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Ok(taken)
+}
+
+fn take_array<const N: usize>(input: &mut &[u8]) -> Result<[u8; N], DecodeError> {
+    let mut array = [0; N];
+    array.copy_from_slice(take(input, N)?);
+    Ok(array)
+}
+
+/// Writes the length prefix of a string or sequence.
+fn encode_len(len: usize, out: &mut Vec<u8>) {
+    u32::try_from(len).expect("sequences are shorter than 2^32").encode_to(out);
+}
+
+impl Encode for u8 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+}
+
+impl Decode for u8 {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(take_array::<1>(input)?[0])
+    }
+}
+
+impl Encode for u32 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend(self.to_le_bytes());
+    }
+}
+
+impl Decode for u32 {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(u32::from_le_bytes(take_array(input)?))
+    }
+}
+
+impl Encode for u64 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend(self.to_le_bytes());
+    }
+}
+
+impl Decode for u64 {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(u64::from_le_bytes(take_array(input)?))
+    }
+}
+
+impl Encode for bool {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        (*self as u8).encode_to(out);
+    }
+}
+
+impl Decode for bool {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for String {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        encode_len(self.len(), out);
+        out.extend(self.as_bytes());
+    }
+}
+
+impl Decode for String {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = u32::decode_from(input)? as usize;
+        let bytes = take(input, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        encode_len(self.len(), out);
+        for item in self {
+            item.encode_to(out);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = u32::decode_from(input)? as usize;
+        // Every item takes at least a byte, so a prefix longer than the input is a lie.
+        let mut items = Vec::with_capacity(len.min(input.len()));
+        for _ in 0..len {
+            items.push(T::decode_from(input)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            None => 0u8.encode_to(out),
+            Some(value) => {
+                1u8.encode_to(out);
+                value.encode_to(out);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode_from(input)?)),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for () {
+    fn encode_to(&self, _: &mut Vec<u8>) {}
+}
+
+impl Decode for () {
+    fn decode_from(_: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(())
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.0.encode_to(out);
+        self.1.encode_to(out);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok((A::decode_from(input)?, B::decode_from(input)?))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn integers_are_little_endian() {
+        assert_eq!(0x0102u32.encode(), vec![2, 1, 0, 0]);
+        assert_eq!(u64::decode(&[1, 0, 0, 0, 0, 0, 0, 0]), Ok(1));
+    }
+
+    #[test]
+    fn sequences_round_trip() {
+        let value = vec![Some("hello".to_string()), None];
+
+        assert_eq!(Vec::<Option<String>>::decode(&value.encode()), Ok(value));
+        assert_eq!(<(u32, bool)>::decode(&(7u32, true).encode()), Ok((7, true)));
+    }
+
+    #[test]
+    fn truncated_and_padded_input_is_rejected() {
+        let bytes = 7u64.encode();
+
+        assert_eq!(u64::decode(&bytes[..7]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(u64::decode(&[bytes.clone(), vec![0]].concat()), Err(DecodeError::TrailingBytes));
+        assert_eq!(bool::decode(&[2]), Err(DecodeError::InvalidTag(2)));
+    }
+
+    #[test]
+    fn huge_length_prefix_does_not_allocate() {
+        assert_eq!(Vec::<u64>::decode(&u32::MAX.encode()), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(String::decode(&[2, 0, 0, 0, 0xff, 0xfe]), Err(DecodeError::InvalidUtf8));
+    }
+}
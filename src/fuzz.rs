@@ -0,0 +1,305 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`. Each one decodes untrusted bytes and
+//! runs them through the chain, ignoring input that does not decode. Whatever decodes must
+//! never make the chain panic, and checked machines must keep their invariants.
+//!
+//! Run a target with `cargo fuzz run <target>` from the crate root. The seed corpus in
+//! `fuzz/corpus` is built from the test fixtures, see `write_seed_corpus` below.
+
+use crate::blockchain::consensus;
+use crate::blockchain::executor::{self, Block};
+use crate::codec::Decode;
+use crate::state_machine::accounted_currency::{self, AccountedCurrency, AccountingTransaction, FeeConfig};
+use crate::state_machine::atm::{Action, Atm};
+use crate::state_machine::utxo_currency::{self, CashTransaction, DigitalCashSystem, Output};
+use crate::state_machine::{Checked, Invariants, StateMachine, User};
+use std::fmt::Debug;
+
+/// A chain of consensus headers, verified under every rule set from its first header on.
+pub fn headers(data: &[u8]) {
+    if let Ok(chain) = Vec::<consensus::Header>::decode(data) {
+        consensus::verify_under_every_rule(&chain);
+    }
+}
+
+/// Blocks of `AccountedCurrency` transactions, imported for as long as each one is a valid
+/// child of the one before.
+pub fn blocks(data: &[u8]) {
+    let Ok(blocks) = Vec::<Block<AccountingTransaction>>::decode(data) else {
+        return
+    };
+    let mut state = accounts();
+
+    for pair in blocks.windows(2) {
+        if !pair[0].verify_child(&pair[1]) {
+            return
+        }
+        if let Ok((next, _)) = executor::import_block::<Checked<AccountedCurrency>>(&state, &pair[1]) {
+            state = next;
+        }
+    }
+}
+
+pub fn accounted_currency(data: &[u8]) {
+    if let Ok(transactions) = Vec::<AccountingTransaction>::decode(data) {
+        run_checked::<AccountedCurrency>(accounts(), &transactions);
+    }
+}
+
+pub fn digital_cash(data: &[u8]) {
+    if let Ok(transactions) = Vec::<CashTransaction>::decode(data) {
+        run_checked::<DigitalCashSystem>(outputs(), &transactions);
+    }
+}
+
+pub fn atm(data: &[u8]) {
+    let Ok(actions) = Vec::<Action>::decode(data) else {
+        return
+    };
+    let mut state = Atm::new(10);
+
+    for action in &actions {
+        let expected = Atm::try_next_state(&state, action).unwrap_or_else(|_| state.clone());
+        state = Atm::next_state(&state, action);
+        assert_eq!(state, expected);
+    }
+}
+
+fn accounts() -> accounted_currency::State {
+    accounted_currency::State::from([(User::Alice, 100), (User::Bob, 50)])
+        .with_fees(FeeConfig { base_fee: 1, weight_fee: 1, block_reward: 10 })
+}
+
+fn outputs() -> utxo_currency::State {
+    utxo_currency::State::from([Output::owned_by(User::Alice, 100), Output::owned_by(User::Bob, 50)])
+}
+
+// Every way of taking a transition has to agree, and `Checked` panics on a broken invariant.
+fn run_checked<M>(mut state: M::State, transitions: &[M::Transition])
+where
+    M: Invariants,
+    M::State: Clone + Debug + PartialEq,
+{
+    for transition in transitions {
+        let accepted = M::try_next_state(&state, transition).is_ok();
+        let mut applied = state.clone();
+        assert_eq!(M::apply(&mut applied, transition).is_ok(), accepted);
+
+        state = Checked::<M>::next_state(&state, transition);
+        assert_eq!(applied, state);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::codec::Encode;
+    use crate::state_machine::accounted_currency::{ExistenceRequirement, Htlc, NATIVE_ASSET};
+    use crate::state_machine::atm::Key;
+    use crate::state_machine::script::{self, SecretKey};
+    use crate::state_machine::utxo_currency::{Input, OutPoint, GENESIS_TX};
+    use proptest::prelude::*;
+    use std::path::PathBuf;
+
+    type Target = fn(&[u8]);
+
+    const TARGETS: [(&str, Target); 5] = [
+        ("headers", headers),
+        ("blocks", blocks),
+        ("accounted_currency", accounted_currency),
+        ("digital_cash", digital_cash),
+        ("atm", atm),
+    ];
+
+    fn transfer(sender: User, receiver: User, amount: u64, nonce: u64) -> AccountingTransaction {
+        AccountingTransaction::Transfer {
+            sender,
+            receiver,
+            asset: NATIVE_ASSET,
+            amount,
+            existence: ExistenceRequirement::AllowDeath,
+            nonce,
+        }
+    }
+
+    fn spend(index: u32, owner: User, outputs: Vec<Output>) -> CashTransaction {
+        let mut transaction = CashTransaction::Transfer {
+            inputs: vec![Input::unsigned(OutPoint { tx_hash: GENESIS_TX, index })],
+            outputs,
+        };
+        transaction.sign(0, &SecretKey::dev(owner)).unwrap();
+        transaction
+    }
+
+    fn press(keys: &[Key]) -> Vec<Action> {
+        keys.iter().map(|key| Action::PressKey(key.clone())).collect()
+    }
+
+    /// The seed corpus of every target, by target and file name.
+    fn seeds() -> Vec<(&'static str, &'static str, Vec<u8>)> {
+        let genesis = Block::genesis();
+        let b1_body = vec![transfer(User::Alice, User::Bob, 10, 0), transfer(User::Bob, User::Charlie, 20, 0)];
+        let mut state = accounts();
+        let (block_1, _) = executor::build_block::<AccountedCurrency>(&genesis, &mut state, User::Charlie, b1_body).unwrap();
+        let b2_body = vec![transfer(User::Alice, User::Bob, 10, 1)];
+        let (block_2, _) = executor::build_block::<AccountedCurrency>(&block_1, &mut state, User::Alice, b2_body).unwrap();
+
+        let hash_lock = script::hash_of(7);
+        let htlc = vec![
+            AccountingTransaction::HtlcLock {
+                sender: User::Alice,
+                receiver: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 30,
+                hash_lock,
+                expiry: 10,
+                nonce: 0,
+            },
+            AccountingTransaction::HtlcClaim { claimer: User::Bob, id: Htlc::id(User::Alice, 0), preimage: 7, nonce: 0 },
+        ];
+        let assets = vec![
+            AccountingTransaction::CreateAsset {
+                creator: User::Bob,
+                decimals: 2,
+                name: "Token".into(),
+                symbol: "TKN".into(),
+                min_balance: 1,
+                nonce: 0,
+            },
+            AccountingTransaction::Mint { minter: User::Bob, asset: NATIVE_ASSET + 1, amount: 1_000, nonce: 1 },
+            AccountingTransaction::Approve { owner: User::Bob, spender: User::Alice, asset: NATIVE_ASSET + 1, amount: 50, nonce: 2 },
+            AccountingTransaction::TransferFrom {
+                spender: User::Alice,
+                owner: User::Bob,
+                receiver: User::Charlie,
+                asset: NATIVE_ASSET + 1,
+                amount: 40,
+                nonce: 0,
+            },
+        ];
+
+        let pay_bob = spend(0, User::Alice, vec![Output::owned_by(User::Bob, 60), Output::owned_by(User::Alice, 37)]);
+        let lock_htlc = spend(1, User::Bob, vec![Output::htlc(50, hash_lock, User::Alice, User::Bob, 10)]);
+        let mut claim_htlc = CashTransaction::Transfer {
+            inputs: vec![Input::unsigned(lock_htlc.outpoint(0))],
+            outputs: vec![Output::owned_by(User::Alice, 50)],
+        };
+        claim_htlc.claim_htlc(0, &SecretKey::dev(User::Alice), 7).unwrap();
+
+        let pin = [Key::One, Key::Two, Key::Three, Key::Four];
+        let mut withdraw = vec![Action::SwipeCard(crate::hash(&pin.to_vec()))];
+        withdraw.extend(press(&[Key::One, Key::Two, Key::Three, Key::Four, Key::Enter, Key::Four, Key::Enter]));
+        let mut wrong_pin = vec![Action::SwipeCard(crate::hash(&pin.to_vec()))];
+        wrong_pin.extend(press(&[Key::Four, Key::Enter, Key::One]));
+
+        vec![
+            ("headers", "genesis_only", consensus::mined_chain(&[]).encode()),
+            ("headers", "three_blocks", consensus::mined_chain(&[5, 6]).encode()),
+            ("blocks", "two_blocks", vec![genesis, block_1, block_2].encode()),
+            ("accounted_currency", "transfers", vec![transfer(User::Alice, User::Bob, 10, 0), transfer(User::Bob, User::Alice, 30, 0)].encode()),
+            ("accounted_currency", "htlc", htlc.encode()),
+            ("accounted_currency", "assets", assets.encode()),
+            ("digital_cash", "pay_bob", vec![pay_bob].encode()),
+            ("digital_cash", "htlc", vec![lock_htlc, claim_htlc].encode()),
+            ("atm", "withdraw", withdraw.encode()),
+            ("atm", "wrong_pin", wrong_pin.encode()),
+        ]
+    }
+
+    fn corpus(target: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fuzz").join("corpus").join(target)
+    }
+
+    fn seed(name: &str, seed: &str) -> Vec<u8> {
+        seeds().into_iter().find(|(target, file, _)| (*target, *file) == (name, seed)).expect("seed exists").2
+    }
+
+    fn target(name: &str) -> Target {
+        TARGETS.iter().find(|(target, _)| *target == name).expect("every seed has a target").1
+    }
+
+    /// Rewrites the seed corpus, e.g. after an encoding changed.
+    #[test]
+    #[ignore]
+    fn write_seed_corpus() {
+        for (name, seed, bytes) in seeds() {
+            std::fs::create_dir_all(corpus(name)).unwrap();
+            std::fs::write(corpus(name).join(seed), bytes).unwrap();
+        }
+    }
+
+    fn decodes(name: &str, bytes: &[u8]) -> bool {
+        match name {
+            "headers" => Vec::<consensus::Header>::decode(bytes).is_ok(),
+            "blocks" => Vec::<Block<AccountingTransaction>>::decode(bytes).is_ok(),
+            "accounted_currency" => Vec::<AccountingTransaction>::decode(bytes).is_ok(),
+            "digital_cash" => Vec::<CashTransaction>::decode(bytes).is_ok(),
+            _ => Vec::<Action>::decode(bytes).is_ok(),
+        }
+    }
+
+    // The seeds hash with `DefaultHasher`, whose output may change with the toolchain, so the
+    // checked-in corpus only has to stay decodable, not identical to freshly built seeds.
+    #[test]
+    fn checked_in_corpus_decodes_and_runs() {
+        for (name, target) in TARGETS {
+            let files: Vec<PathBuf> = std::fs::read_dir(corpus(name)).unwrap().map(|entry| entry.unwrap().path()).collect();
+            assert!(!files.is_empty(), "fuzz/corpus/{} is empty", name);
+
+            for file in files {
+                let bytes = std::fs::read(&file).unwrap();
+                assert!(decodes(name, &bytes), "{} does not decode, run write_seed_corpus", file.display());
+                target(&bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn seeds_decode_and_run() {
+        for (name, _, bytes) in seeds() {
+            target(name)(&bytes);
+        }
+        assert_eq!(Vec::<consensus::Header>::decode(&seed("headers", "three_blocks")).map(|chain| chain.len()), Ok(3));
+    }
+
+    fn accepts_all<M: StateMachine>(state: M::State, transitions: &[M::Transition]) -> bool {
+        transitions.iter().try_fold(state, |state, t| M::try_next_state(&state, t)).is_ok()
+    }
+
+    #[test]
+    fn seed_transactions_are_accepted() {
+        for name in ["transfers", "htlc", "assets"] {
+            let transactions = Vec::decode(&seed("accounted_currency", name)).unwrap();
+            assert!(accepts_all::<AccountedCurrency>(accounts(), &transactions), "{} is rejected", name);
+        }
+        for name in ["pay_bob", "htlc"] {
+            let transactions = Vec::decode(&seed("digital_cash", name)).unwrap();
+            assert!(accepts_all::<DigitalCashSystem>(outputs(), &transactions), "{} is rejected", name);
+        }
+        let blocks = Vec::<Block<AccountingTransaction>>::decode(&seed("blocks", "two_blocks")).unwrap();
+        let imported = blocks.windows(2).try_fold(accounts(), |state, pair| {
+            executor::import_block::<AccountedCurrency>(&state, &pair[1]).map(|(state, _)| state)
+        });
+        assert!(imported.is_ok(), "two_blocks is rejected");
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_bytes_do_not_panic(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+            for (_, target) in TARGETS {
+                target(&data);
+            }
+        }
+
+        #[test]
+        fn mutated_seeds_do_not_panic(index in 0..seeds().len(), at in any::<usize>(), byte in any::<u8>(), cut in any::<bool>()) {
+            let (name, _, mut bytes) = seeds().swap_remove(index);
+            let at = at % bytes.len();
+            if cut {
+                bytes.truncate(at);
+            } else {
+                bytes[at] = byte;
+            }
+            target(name)(&bytes);
+        }
+    }
+}
@@ -3,6 +3,8 @@ use std::hash::{Hash, Hasher};
 
 mod state_machine;
 mod blockchain;
+mod codec;
+pub mod fuzz;
 
 fn hash<T: Hash>(t: &T) -> u64 {
     let mut s = DefaultHasher::new();
@@ -1,345 +1,2858 @@
 #![allow(dead_code)]
 
-use super::{StateMachine, User};
+use super::journal::MapUndo;
+use super::script;
+use super::events::Event;
+use super::{BlockContext, BlockHooks, Emitter, Invariant, Invariants, Revertible, StateMachine, UndoEmitter, User};
+use crate::codec::{Decode, DecodeError, Encode};
+use crate::hash;
 use std::collections::HashMap;
 
 pub struct AccountedCurrency {}
 
-type Balances = HashMap<User, u64>;
+pub type AssetId = u32;
 
+/// The chain's own currency. It exists from genesis and has no admin, so anyone may mint it.
+pub const NATIVE_ASSET: AssetId = 0;
+
+type Assets = HashMap<AssetId, AssetDetails>;
+type Balances = HashMap<(AssetId, User), u64>;
+type Nonces = HashMap<User, u64>;
+/// What each spender may still move out of an owner's account, keyed by `(asset, owner, spender)`.
+type Allowances = HashMap<(AssetId, User, User), u64>;
+
+/// Balance every account has to keep unless it is configured otherwise.
+pub const DEFAULT_EXISTENTIAL_DEPOSIT: u64 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssetDetails {
+    /// The only account allowed to mint the asset, `None` lets anyone mint.
+    pub admin: Option<User>,
+    pub decimals: u8,
+    pub name: String,
+    pub symbol: String,
+    /// The existential deposit of the asset. Always at least 1.
+    pub min_balance: u64,
+    pub supply: u64,
+}
+
+impl AssetDetails {
+    fn native() -> Self {
+        AssetDetails {
+            admin: None,
+            decimals: 0,
+            name: "Native".into(),
+            symbol: "UNIT".into(),
+            min_balance: DEFAULT_EXISTENTIAL_DEPOSIT,
+            supply: 0,
+        }
+    }
+}
+
+/// Part of a native balance that can not be spent before block `until`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BalanceLock {
+    pub amount: u64,
+    pub until: u64,
+}
+
+/// Keeps `locked` native tokens frozen and releases `per_block` of them every block
+/// from `starting_block` on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VestingSchedule {
+    pub locked: u64,
+    pub per_block: u64,
+    pub starting_block: u64,
+}
+
+impl VestingSchedule {
+    pub fn locked_at(&self, height: u64) -> u64 {
+        let vested = self.per_block.saturating_mul(height.saturating_sub(self.starting_block));
+        self.locked.saturating_sub(vested)
+    }
+}
+
+pub type HtlcId = u64;
+
+/// Funds escrowed by a hash time-locked contract. They still count towards the asset's supply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Htlc {
+    pub sender: User,
+    pub receiver: User,
+    pub asset: AssetId,
+    pub amount: u64,
+    /// `script::hash_of` the preimage that releases the funds to the receiver.
+    pub hash_lock: u64,
+    /// From this block on the receiver can no longer claim and the sender can take the funds back.
+    pub expiry: u64,
+}
+
+impl Htlc {
+    /// The id of the contract locked by `sender`'s transaction with `nonce`.
+    pub fn id(sender: User, nonce: u64) -> HtlcId {
+        hash(&(sender, nonce))
+    }
+}
+
+/// What transactions cost and what a block author earns, all in the native asset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FeeConfig {
+    /// Charged once per transaction.
+    pub base_fee: u64,
+    /// Charged per unit of `AccountingTransaction::weight`.
+    pub weight_fee: u64,
+    /// Minted to the author of every block on top of the fees it collected.
+    pub block_reward: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct State {
+    assets: Assets,
+    next_asset_id: AssetId,
+    /// Free balances. Locks and vesting freeze part of them in place.
+    balances: Balances,
+    // Kept apart from the balances so that a removed account can not replay
+    // its old transactions once it is funded again.
+    nonces: Nonces,
+    allowances: Allowances,
+    /// Native tokens held aside, e.g. as a deposit. They count towards the supply but can not
+    /// be spent, only unreserved or slashed.
+    reserved: HashMap<User, u64>,
+    locks: HashMap<User, Vec<BalanceLock>>,
+    vesting: HashMap<User, VestingSchedule>,
+    htlcs: HashMap<HtlcId, Htlc>,
+    block_height: u64,
+    fees: FeeConfig,
+    /// Fees paid in the current block, held until the author is credited in `on_finalize`.
+    pending_fees: u64,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            assets: Assets::from([(NATIVE_ASSET, AssetDetails::native())]),
+            next_asset_id: NATIVE_ASSET + 1,
+            balances: Balances::new(),
+            nonces: Nonces::new(),
+            allowances: Allowances::new(),
+            reserved: HashMap::new(),
+            locks: HashMap::new(),
+            vesting: HashMap::new(),
+            htlcs: HashMap::new(),
+            block_height: 0,
+            fees: FeeConfig::default(),
+            pending_fees: 0,
+        }
+    }
+}
+
+impl State {
+    pub fn new() -> Self {
+        State::default()
+    }
+
+    /// Accounts holding less than `existential_deposit` of the native asset are reaped. It has
+    /// to be at least 1 so that no account is ever stored with a zero balance.
+    pub fn with_existential_deposit(mut self, existential_deposit: u64) -> Self {
+        assert!(existential_deposit > 0, "existential deposit must be at least 1");
+        self.native_mut().min_balance = existential_deposit;
+        self
+    }
+
+    pub fn with_fees(mut self, fees: FeeConfig) -> Self {
+        self.fees = fees;
+        self
+    }
+
+    pub fn fees(&self) -> FeeConfig {
+        self.fees
+    }
+
+    pub fn set_fees(&mut self, fees: FeeConfig) {
+        self.fees = fees;
+    }
+
+    /// What `transaction` costs its signer.
+    pub fn fee(&self, transaction: &AccountingTransaction) -> u64 {
+        self.weight_fee(transaction.weight())
+    }
+
+    /// What a transaction of `weight` costs, also for machines built on top of the currency.
+    pub fn weight_fee(&self, weight: u64) -> u64 {
+        self.fees.weight_fee.saturating_mul(weight).saturating_add(self.fees.base_fee)
+    }
+
+    pub fn pending_fees(&self) -> u64 {
+        self.pending_fees
+    }
+
+    pub fn balances(&self) -> &Balances {
+        &self.balances
+    }
+
+    /// Every account holding `asset`, with its balance.
+    pub fn balances_of(&self, asset: AssetId) -> HashMap<User, u64> {
+        self.balances
+            .iter()
+            .filter(|((id, _), _)| *id == asset)
+            .map(|((_, user), balance)| (*user, *balance))
+            .collect()
+    }
+
+    pub fn balance(&self, asset: AssetId, user: &User) -> u64 {
+        self.balances.get(&(asset, *user)).copied().unwrap_or(0)
+    }
+
+    pub fn asset(&self, asset: AssetId) -> Option<&AssetDetails> {
+        self.assets.get(&asset)
+    }
+
+    pub fn existential_deposit(&self) -> u64 {
+        self.assets[&NATIVE_ASSET].min_balance
+    }
+
+    /// Supply of the native asset.
+    pub fn total_issuance(&self) -> u64 {
+        self.assets[&NATIVE_ASSET].supply
+    }
+
+    /// The nonce the next transaction signed by `user` has to carry.
+    pub fn nonce(&self, user: &User) -> u64 {
+        self.nonces.get(user).copied().unwrap_or(0)
+    }
+
+    /// Consumes `nonce` if it is the one `user` has to sign with next. Machines built on the
+    /// currency use it so their transactions can not be replayed either.
+    pub fn use_nonce(&mut self, user: User, nonce: u64) -> Result<(), AccountingError> {
+        let expected = self.nonce(&user);
+
+        if nonce < expected {
+            return Err(AccountingError::StaleNonce)
+        }
+        if nonce > expected {
+            return Err(AccountingError::FutureNonce)
+        }
+        self.nonces.insert(user, expected.checked_add(1).ok_or(AccountingError::Overflow)?);
+        Ok(())
+    }
+
+    /// How much of `owner`'s `asset` the `spender` may still transfer.
+    pub fn allowance(&self, asset: AssetId, owner: &User, spender: &User) -> u64 {
+        self.allowances.get(&(asset, *owner, *spender)).copied().unwrap_or(0)
+    }
+
+    pub fn block_height(&self) -> u64 {
+        self.block_height
+    }
+
+    /// Moves the state to a new block, dropping the locks and vesting schedules that ran out.
+    pub fn set_block_height(&mut self, height: u64) {
+        self.block_height = height;
+
+        for locks in self.locks.values_mut() {
+            locks.retain(|lock| lock.until > height);
+        }
+        self.locks.retain(|_, locks| !locks.is_empty());
+        self.vesting.retain(|_, schedule| schedule.locked_at(height) > 0);
+    }
+
+    pub fn reserved(&self, user: &User) -> u64 {
+        self.reserved.get(user).copied().unwrap_or(0)
+    }
+
+    pub fn vesting(&self, user: &User) -> Option<&VestingSchedule> {
+        self.vesting.get(user)
+    }
+
+    /// An open hash time-locked contract, until it is claimed or refunded.
+    pub fn htlc(&self, id: HtlcId) -> Option<&Htlc> {
+        self.htlcs.get(&id)
+    }
+
+    /// The part of the free balance that locks and vesting keep from being spent right now.
+    pub fn frozen(&self, asset: AssetId, user: &User) -> u64 {
+        if asset != NATIVE_ASSET {
+            return 0
+        }
+        let locked = self
+            .locks
+            .get(user)
+            .into_iter()
+            .flatten()
+            .filter(|lock| lock.until > self.block_height)
+            .map(|lock| lock.amount)
+            .max()
+            .unwrap_or(0);
+        let vesting = self.vesting.get(user).map_or(0, |schedule| schedule.locked_at(self.block_height));
+
+        locked.max(vesting)
+    }
+
+    /// Freezes `amount` of `user`'s native balance until block `until`. Locks overlap, so a
+    /// balance is only ever frozen as far as its largest lock goes.
+    pub fn lock(&mut self, user: User, amount: u64, until: u64) {
+        if amount > 0 && until > self.block_height {
+            self.locks.entry(user).or_default().push(BalanceLock { amount, until });
+        }
+    }
+
+    /// Drops one of `user`'s locks that is equal to `lock`, e.g. when what it secured is replaced.
+    pub fn unlock(&mut self, user: User, lock: BalanceLock) {
+        let Some(locks) = self.locks.get_mut(&user) else {
+            return
+        };
+        if let Some(index) = locks.iter().position(|held| *held == lock) {
+            locks.remove(index);
+        }
+        if locks.is_empty() {
+            self.locks.remove(&user);
+        }
+    }
+
+    /// What `user` can spend of `asset` at the current block height.
+    pub fn usable_balance(&self, asset: AssetId, user: &User) -> u64 {
+        self.balance(asset, user).saturating_sub(self.frozen(asset, user))
+    }
+
+    /// Moves `amount` of the native asset from the free to the reserved balance. The free
+    /// balance has to stay above the existential deposit.
+    pub fn reserve(&mut self, user: User, amount: u64) -> Result<(), AccountingError> {
+        let balance = self.balance(NATIVE_ASSET, &user);
+
+        if amount > balance {
+            return Err(AccountingError::InsufficientBalance)
+        }
+        if amount > self.usable_balance(NATIVE_ASSET, &user) {
+            return Err(AccountingError::LiquidityRestrictions)
+        }
+        if balance - amount < self.existential_deposit() {
+            return Err(AccountingError::WouldReapAccount)
+        }
+        let reserved = self.reserved(&user).checked_add(amount).ok_or(AccountingError::Overflow)?;
+
+        self.set_balance(NATIVE_ASSET, user, balance - amount);
+        self.set_reserved(user, reserved);
+        Ok(())
+    }
+
+    /// Mints `amount` of the native asset into `user`'s free balance, e.g. as a reward.
+    pub fn issue(&mut self, user: User, amount: u64) -> Result<(), AccountingError> {
+        let supply = self.total_issuance().checked_add(amount).ok_or(AccountingError::Overflow)?;
+        let balance = self.balance(NATIVE_ASSET, &user).checked_add(amount).ok_or(AccountingError::Overflow)?;
+
+        if balance < self.existential_deposit() {
+            return Err(AccountingError::BelowExistentialDeposit)
+        }
+        self.native_mut().supply = supply;
+        self.set_balance(NATIVE_ASSET, user, balance);
+        Ok(())
+    }
+
+    /// Moves up to `amount` back to the free balance, returning how much was unreserved.
+    pub fn unreserve(&mut self, user: User, amount: u64) -> u64 {
+        let reserved = self.reserved(&user);
+        let unreserved = amount.min(reserved);
+
+        // Free and reserved balances together never exceed the supply, so this can not overflow.
+        self.set_balance(NATIVE_ASSET, user, self.balance(NATIVE_ASSET, &user) + unreserved);
+        self.set_reserved(user, reserved - unreserved);
+        unreserved
+    }
+
+    /// Destroys up to `amount` of the reserved balance, returning how much was slashed.
+    pub fn slash_reserved(&mut self, user: User, amount: u64) -> u64 {
+        let reserved = self.reserved(&user);
+        let slashed = amount.min(reserved);
+
+        self.native_mut().supply -= slashed;
+        self.set_reserved(user, reserved - slashed);
+        slashed
+    }
+
+    fn native_mut(&mut self) -> &mut AssetDetails {
+        self.assets.get_mut(&NATIVE_ASSET).expect("the native asset exists from genesis")
+    }
+
+    // Reserved funds, locks and vesting all need the account to stay around.
+    fn can_reap(&self, asset: AssetId, user: &User) -> bool {
+        asset != NATIVE_ASSET
+            || (self.reserved(user) == 0 && !self.locks.contains_key(user) && !self.vesting.contains_key(user))
+    }
+
+    fn burn(
+        &mut self,
+        asset: AssetId,
+        burner: User,
+        amount: u64,
+        existence: ExistenceRequirement,
+    ) -> Result<(), AccountingError> {
+        let min_balance = self.assets.get(&asset).ok_or(AccountingError::UnknownAsset)?.min_balance;
+        let balance = self.balances.get(&(asset, burner)).copied().ok_or(AccountingError::UnknownAccount)?;
+
+        if amount.min(balance) > self.usable_balance(asset, &burner) {
+            return Err(AccountingError::LiquidityRestrictions)
+        }
+        let remaining = balance.saturating_sub(amount);
+
+        let burned = if remaining >= min_balance {
+            amount
+        } else if existence == ExistenceRequirement::AllowDeath && self.can_reap(asset, &burner) {
+            // Whatever is left below the deposit is burned along with the account.
+            balance
+        } else {
+            return Err(AccountingError::WouldReapAccount)
+        };
+        self.assets.get_mut(&asset).expect("checked above").supply -= burned;
+        self.set_balance(asset, burner, balance - burned);
+
+        Ok(())
+    }
+
+    fn transfer(
+        &mut self,
+        asset: AssetId,
+        sender: User,
+        receiver: User,
+        amount: u64,
+        existence: ExistenceRequirement,
+    ) -> Result<(), AccountingError> {
+        let min_balance = self.assets.get(&asset).ok_or(AccountingError::UnknownAsset)?.min_balance;
+        let sender_balance = self.balances.get(&(asset, sender)).copied().ok_or(AccountingError::UnknownAccount)?;
+        let receiver_balance = self.balances.get(&(asset, receiver)).copied().unwrap_or(0);
+
+        if amount > sender_balance {
+            return Err(AccountingError::InsufficientBalance)
+        }
+        if amount > self.usable_balance(asset, &sender) {
+            return Err(AccountingError::LiquidityRestrictions)
+        }
+        let receiver_balance = receiver_balance.checked_add(amount).ok_or(AccountingError::Overflow)?;
+        if receiver_balance < min_balance {
+            return Err(AccountingError::BelowExistentialDeposit)
+        }
+        if sender == receiver {
+            return Ok(())
+        }
+
+        let remaining = sender_balance - amount;
+        if remaining >= min_balance {
+            self.set_balance(asset, sender, remaining);
+        } else if existence == ExistenceRequirement::AllowDeath && self.can_reap(asset, &sender) {
+            self.assets.get_mut(&asset).expect("checked above").supply -= remaining;
+            self.set_balance(asset, sender, 0);
+        } else {
+            return Err(AccountingError::WouldReapAccount)
+        }
+        self.set_balance(asset, receiver, receiver_balance);
+
+        Ok(())
+    }
+
+    /// Moves `fee` from `payer`'s spendable native funds to the fees of the block. Fees never
+    /// reap the payer.
+    pub fn withdraw_fee(&mut self, payer: User, fee: u64) -> Result<(), AccountingError> {
+        if fee == 0 {
+            return Ok(())
+        }
+        let balance = self.balance(NATIVE_ASSET, &payer);
+
+        if fee > self.usable_balance(NATIVE_ASSET, &payer) || balance - fee < self.existential_deposit() {
+            return Err(AccountingError::CannotPayFee)
+        }
+        self.set_balance(NATIVE_ASSET, payer, balance - fee);
+        // The fee stays part of the supply, so this can not overflow.
+        self.pending_fees += fee;
+
+        Ok(())
+    }
+
+    // Takes funds out of an account into an escrow, keeping the account alive.
+    fn escrow(&mut self, asset: AssetId, user: User, amount: u64) -> Result<(), AccountingError> {
+        let min_balance = self.assets.get(&asset).ok_or(AccountingError::UnknownAsset)?.min_balance;
+        let balance = self.balances.get(&(asset, user)).copied().ok_or(AccountingError::UnknownAccount)?;
+
+        if amount > balance {
+            return Err(AccountingError::InsufficientBalance)
+        }
+        if amount > self.usable_balance(asset, &user) {
+            return Err(AccountingError::LiquidityRestrictions)
+        }
+        if balance - amount < min_balance {
+            return Err(AccountingError::WouldReapAccount)
+        }
+        self.set_balance(asset, user, balance - amount);
+
+        Ok(())
+    }
+
+    fn set_reserved(&mut self, user: User, reserved: u64) {
+        if reserved == 0 {
+            self.reserved.remove(&user);
+        } else {
+            self.reserved.insert(user, reserved);
+        }
+    }
+
+    // Stores the balance, dropping the entry once it reaches zero.
+    fn set_balance(&mut self, asset: AssetId, user: User, balance: u64) {
+        if balance == 0 {
+            self.balances.remove(&(asset, user));
+        } else {
+            self.balances.insert((asset, user), balance);
+        }
+    }
+}
+
+/// Genesis balances of the native asset.
+impl FromIterator<(User, u64)> for State {
+    fn from_iter<I: IntoIterator<Item = (User, u64)>>(iter: I) -> Self {
+        let mut state = State::default();
+
+        for (user, balance) in iter {
+            let native = state.native_mut();
+            native.supply = native
+                .supply
+                .checked_add(balance)
+                .expect("genesis balances overflow the total issuance");
+            state.balances.insert((NATIVE_ASSET, user), balance);
+        }
+        state
+    }
+}
+
+impl<const N: usize> From<[(User, u64); N]> for State {
+    fn from(value: [(User, u64); N]) -> Self {
+        State::from_iter(value)
+    }
+}
+
+/// What to do with an account that a burn or transfer would leave below the existential deposit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ExistenceRequirement {
+    /// Reject the transaction.
+    KeepAlive,
+    /// Reap the account and destroy the dust left in it.
+    AllowDeath,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum AccountingTransaction {
-    Mint {minter: User, amount: u64},
-    Burn {burner: User, amount: u64},
-    Transfer {sender: User, receiver: User, amount: u64}
+    /// Registers a new asset under the next free id, with `creator` as its admin.
+    CreateAsset {creator: User, decimals: u8, name: String, symbol: String, min_balance: u64, nonce: u64},
+    Mint {minter: User, asset: AssetId, amount: u64, nonce: u64},
+    Burn {burner: User, asset: AssetId, amount: u64, existence: ExistenceRequirement, nonce: u64},
+    Transfer {sender: User, receiver: User, asset: AssetId, amount: u64, existence: ExistenceRequirement, nonce: u64},
+    /// Lets `spender` transfer up to `amount` out of `owner`'s account, replacing any earlier allowance.
+    Approve {owner: User, spender: User, asset: AssetId, amount: u64, nonce: u64},
+    /// Spends the allowance `owner` gave `spender`. The owner's account is never reaped by it.
+    TransferFrom {spender: User, owner: User, receiver: User, asset: AssetId, amount: u64, nonce: u64},
+    /// Freezes `amount` of the signer's native balance until block `until`.
+    Lock {who: User, amount: u64, until: u64, nonce: u64},
+    /// Transfers native tokens that vest linearly for the receiver, starting at the current block.
+    VestedTransfer {sender: User, receiver: User, amount: u64, per_block: u64, nonce: u64},
+    /// Escrows funds for `receiver` under `Htlc::id(sender, nonce)`.
+    HtlcLock {sender: User, receiver: User, asset: AssetId, amount: u64, hash_lock: u64, expiry: u64, nonce: u64},
+    /// Releases the escrow to its receiver, who has to reveal the preimage before it expires.
+    HtlcClaim {claimer: User, id: HtlcId, preimage: u64, nonce: u64},
+    /// Returns an expired escrow to its sender.
+    HtlcRefund {sender: User, id: HtlcId, nonce: u64},
+}
+
+impl AccountingTransaction {
+    /// The account that signed the transaction and whose nonce it consumes.
+    pub fn signer(&self) -> User {
+        match self {
+            AccountingTransaction::CreateAsset { creator, .. } => *creator,
+            AccountingTransaction::Mint { minter, .. } => *minter,
+            AccountingTransaction::Burn { burner, .. } => *burner,
+            AccountingTransaction::Transfer { sender, .. } => *sender,
+            AccountingTransaction::Approve { owner, .. } => *owner,
+            AccountingTransaction::TransferFrom { spender, .. } => *spender,
+            AccountingTransaction::Lock { who, .. } => *who,
+            AccountingTransaction::VestedTransfer { sender, .. } => *sender,
+            AccountingTransaction::HtlcLock { sender, .. } => *sender,
+            AccountingTransaction::HtlcClaim { claimer, .. } => *claimer,
+            AccountingTransaction::HtlcRefund { sender, .. } => *sender,
+        }
+    }
+
+    pub fn nonce(&self) -> u64 {
+        match self {
+            AccountingTransaction::CreateAsset { nonce, .. }
+            | AccountingTransaction::Mint { nonce, .. }
+            | AccountingTransaction::Burn { nonce, .. }
+            | AccountingTransaction::Transfer { nonce, .. }
+            | AccountingTransaction::Approve { nonce, .. }
+            | AccountingTransaction::TransferFrom { nonce, .. }
+            | AccountingTransaction::Lock { nonce, .. }
+            | AccountingTransaction::VestedTransfer { nonce, .. }
+            | AccountingTransaction::HtlcLock { nonce, .. }
+            | AccountingTransaction::HtlcClaim { nonce, .. }
+            | AccountingTransaction::HtlcRefund { nonce, .. } => *nonce,
+        }
+    }
+
+    /// Rough cost of including the transaction. Asset metadata is paid for by the byte.
+    pub fn weight(&self) -> u64 {
+        match self {
+            AccountingTransaction::CreateAsset { name, symbol, .. } => 10 + (name.len() + symbol.len()) as u64,
+            AccountingTransaction::Approve { .. } | AccountingTransaction::Lock { .. } => 1,
+            AccountingTransaction::Mint { .. }
+            | AccountingTransaction::Burn { .. }
+            | AccountingTransaction::Transfer { .. }
+            | AccountingTransaction::HtlcClaim { .. }
+            | AccountingTransaction::HtlcRefund { .. } => 2,
+            AccountingTransaction::TransferFrom { .. }
+            | AccountingTransaction::VestedTransfer { .. }
+            | AccountingTransaction::HtlcLock { .. } => 3,
+        }
+    }
+}
+
+impl Encode for ExistenceRequirement {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        (*self as u8).encode_to(out);
+    }
+}
+
+impl Decode for ExistenceRequirement {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(ExistenceRequirement::KeepAlive),
+            1 => Ok(ExistenceRequirement::AllowDeath),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for AccountingTransaction {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            AccountingTransaction::CreateAsset { creator, decimals, name, symbol, min_balance, nonce } => {
+                0u8.encode_to(out);
+                creator.encode_to(out);
+                decimals.encode_to(out);
+                name.encode_to(out);
+                symbol.encode_to(out);
+                min_balance.encode_to(out);
+                nonce.encode_to(out);
+            }
+            AccountingTransaction::Mint { minter, asset, amount, nonce } => {
+                1u8.encode_to(out);
+                minter.encode_to(out);
+                asset.encode_to(out);
+                amount.encode_to(out);
+                nonce.encode_to(out);
+            }
+            AccountingTransaction::Burn { burner, asset, amount, existence, nonce } => {
+                2u8.encode_to(out);
+                burner.encode_to(out);
+                asset.encode_to(out);
+                amount.encode_to(out);
+                existence.encode_to(out);
+                nonce.encode_to(out);
+            }
+            AccountingTransaction::Transfer { sender, receiver, asset, amount, existence, nonce } => {
+                3u8.encode_to(out);
+                sender.encode_to(out);
+                receiver.encode_to(out);
+                asset.encode_to(out);
+                amount.encode_to(out);
+                existence.encode_to(out);
+                nonce.encode_to(out);
+            }
+            AccountingTransaction::Approve { owner, spender, asset, amount, nonce } => {
+                4u8.encode_to(out);
+                owner.encode_to(out);
+                spender.encode_to(out);
+                asset.encode_to(out);
+                amount.encode_to(out);
+                nonce.encode_to(out);
+            }
+            AccountingTransaction::TransferFrom { spender, owner, receiver, asset, amount, nonce } => {
+                5u8.encode_to(out);
+                spender.encode_to(out);
+                owner.encode_to(out);
+                receiver.encode_to(out);
+                asset.encode_to(out);
+                amount.encode_to(out);
+                nonce.encode_to(out);
+            }
+            AccountingTransaction::Lock { who, amount, until, nonce } => {
+                6u8.encode_to(out);
+                who.encode_to(out);
+                amount.encode_to(out);
+                until.encode_to(out);
+                nonce.encode_to(out);
+            }
+            AccountingTransaction::VestedTransfer { sender, receiver, amount, per_block, nonce } => {
+                7u8.encode_to(out);
+                sender.encode_to(out);
+                receiver.encode_to(out);
+                amount.encode_to(out);
+                per_block.encode_to(out);
+                nonce.encode_to(out);
+            }
+            AccountingTransaction::HtlcLock { sender, receiver, asset, amount, hash_lock, expiry, nonce } => {
+                8u8.encode_to(out);
+                sender.encode_to(out);
+                receiver.encode_to(out);
+                asset.encode_to(out);
+                amount.encode_to(out);
+                hash_lock.encode_to(out);
+                expiry.encode_to(out);
+                nonce.encode_to(out);
+            }
+            AccountingTransaction::HtlcClaim { claimer, id, preimage, nonce } => {
+                9u8.encode_to(out);
+                claimer.encode_to(out);
+                id.encode_to(out);
+                preimage.encode_to(out);
+                nonce.encode_to(out);
+            }
+            AccountingTransaction::HtlcRefund { sender, id, nonce } => {
+                10u8.encode_to(out);
+                sender.encode_to(out);
+                id.encode_to(out);
+                nonce.encode_to(out);
+            }
+        }
+    }
+}
+
+impl Decode for AccountingTransaction {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let transaction = match u8::decode_from(input)? {
+            0 => AccountingTransaction::CreateAsset {
+                creator: User::decode_from(input)?,
+                decimals: u8::decode_from(input)?,
+                name: String::decode_from(input)?,
+                symbol: String::decode_from(input)?,
+                min_balance: u64::decode_from(input)?,
+                nonce: u64::decode_from(input)?,
+            },
+            1 => AccountingTransaction::Mint {
+                minter: User::decode_from(input)?,
+                asset: AssetId::decode_from(input)?,
+                amount: u64::decode_from(input)?,
+                nonce: u64::decode_from(input)?,
+            },
+            2 => AccountingTransaction::Burn {
+                burner: User::decode_from(input)?,
+                asset: AssetId::decode_from(input)?,
+                amount: u64::decode_from(input)?,
+                existence: ExistenceRequirement::decode_from(input)?,
+                nonce: u64::decode_from(input)?,
+            },
+            3 => AccountingTransaction::Transfer {
+                sender: User::decode_from(input)?,
+                receiver: User::decode_from(input)?,
+                asset: AssetId::decode_from(input)?,
+                amount: u64::decode_from(input)?,
+                existence: ExistenceRequirement::decode_from(input)?,
+                nonce: u64::decode_from(input)?,
+            },
+            4 => AccountingTransaction::Approve {
+                owner: User::decode_from(input)?,
+                spender: User::decode_from(input)?,
+                asset: AssetId::decode_from(input)?,
+                amount: u64::decode_from(input)?,
+                nonce: u64::decode_from(input)?,
+            },
+            5 => AccountingTransaction::TransferFrom {
+                spender: User::decode_from(input)?,
+                owner: User::decode_from(input)?,
+                receiver: User::decode_from(input)?,
+                asset: AssetId::decode_from(input)?,
+                amount: u64::decode_from(input)?,
+                nonce: u64::decode_from(input)?,
+            },
+            6 => AccountingTransaction::Lock {
+                who: User::decode_from(input)?,
+                amount: u64::decode_from(input)?,
+                until: u64::decode_from(input)?,
+                nonce: u64::decode_from(input)?,
+            },
+            7 => AccountingTransaction::VestedTransfer {
+                sender: User::decode_from(input)?,
+                receiver: User::decode_from(input)?,
+                amount: u64::decode_from(input)?,
+                per_block: u64::decode_from(input)?,
+                nonce: u64::decode_from(input)?,
+            },
+            8 => AccountingTransaction::HtlcLock {
+                sender: User::decode_from(input)?,
+                receiver: User::decode_from(input)?,
+                asset: AssetId::decode_from(input)?,
+                amount: u64::decode_from(input)?,
+                hash_lock: u64::decode_from(input)?,
+                expiry: u64::decode_from(input)?,
+                nonce: u64::decode_from(input)?,
+            },
+            9 => AccountingTransaction::HtlcClaim {
+                claimer: User::decode_from(input)?,
+                id: HtlcId::decode_from(input)?,
+                preimage: u64::decode_from(input)?,
+                nonce: u64::decode_from(input)?,
+            },
+            10 => AccountingTransaction::HtlcRefund {
+                sender: User::decode_from(input)?,
+                id: HtlcId::decode_from(input)?,
+                nonce: u64::decode_from(input)?,
+            },
+            tag => return Err(DecodeError::InvalidTag(tag)),
+        };
+        Ok(transaction)
+    }
+}
+
+/// Where a transaction's nonce stands relative to its signer's account.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NonceStatus {
+    /// Already used, the transaction can never be applied.
+    Stale,
+    /// Exactly the nonce expected next.
+    Ready,
+    /// Ahead of the account, a pool should hold the transaction until the gap is filled.
+    Future,
+}
+
+impl AccountedCurrency {
+    pub fn expected_nonce(state: &State, user: &User) -> u64 {
+        state.nonce(user)
+    }
+
+    pub fn nonce_status(state: &State, transaction: &AccountingTransaction) -> NonceStatus {
+        let expected = Self::expected_nonce(state, &transaction.signer());
+
+        match transaction.nonce() {
+            n if n < expected => NonceStatus::Stale,
+            n if n == expected => NonceStatus::Ready,
+            _ => NonceStatus::Future,
+        }
+    }
+
+    // Changes `state` as it goes, rejected transactions are rolled back by `apply_with_undo`.
+    fn execute(state: &mut State, transition: &AccountingTransaction) -> Result<(), AccountingError> {
+        state.use_nonce(transition.signer(), transition.nonce())?;
+        let fee = state.fee(transition);
+        state.withdraw_fee(transition.signer(), fee)?;
+
+        match transition {
+            AccountingTransaction::CreateAsset { creator, decimals, name, symbol, min_balance, .. } => {
+                if *min_balance == 0 {
+                    return Err(AccountingError::ZeroMinBalance)
+                }
+                let id = state.next_asset_id;
+                state.next_asset_id = id.checked_add(1).ok_or(AccountingError::Overflow)?;
+                state.assets.insert(id, AssetDetails {
+                    admin: Some(*creator),
+                    decimals: *decimals,
+                    name: name.clone(),
+                    symbol: symbol.clone(),
+                    min_balance: *min_balance,
+                    supply: 0,
+                });
+            },
+
+            AccountingTransaction::Mint { minter, asset, amount, .. } => {
+                let details = state.assets.get_mut(asset).ok_or(AccountingError::UnknownAsset)?;
+
+                if details.admin.is_some_and(|admin| admin != *minter) {
+                    return Err(AccountingError::NotAssetAdmin)
+                }
+                if *amount == 0 {
+                    return Err(AccountingError::ZeroAmount)
+                }
+                details.supply = details.supply.checked_add(*amount).ok_or(AccountingError::Overflow)?;
+                let min_balance = details.min_balance;
+
+                let balance = state.balance(*asset, minter);
+                let balance = balance.checked_add(*amount).ok_or(AccountingError::Overflow)?;
+
+                if balance < min_balance {
+                    return Err(AccountingError::BelowExistentialDeposit)
+                }
+                state.set_balance(*asset, *minter, balance);
+            },
+
+            AccountingTransaction::Burn { burner, asset, amount, existence, .. } => {
+                state.burn(*asset, *burner, *amount, *existence)?;
+            },
+            AccountingTransaction::Transfer { sender, receiver, asset, amount, existence, .. } => {
+                state.transfer(*asset, *sender, *receiver, *amount, *existence)?;
+            },
+            AccountingTransaction::Approve { owner, spender, asset, amount, .. } => {
+                if !state.assets.contains_key(asset) {
+                    return Err(AccountingError::UnknownAsset)
+                }
+                if *amount == 0 {
+                    state.allowances.remove(&(*asset, *owner, *spender));
+                } else {
+                    state.allowances.insert((*asset, *owner, *spender), *amount);
+                }
+            },
+            AccountingTransaction::TransferFrom { spender, owner, receiver, asset, amount, .. } => {
+                let allowance = state.allowance(*asset, owner, spender);
+                let left = allowance.checked_sub(*amount).ok_or(AccountingError::InsufficientAllowance)?;
+
+                // A spender must never be able to reap the account it spends from.
+                state.transfer(*asset, *owner, *receiver, *amount, ExistenceRequirement::KeepAlive)?;
+                if left == 0 {
+                    state.allowances.remove(&(*asset, *owner, *spender));
+                } else {
+                    state.allowances.insert((*asset, *owner, *spender), left);
+                }
+            },
+            AccountingTransaction::Lock { who, amount, until, .. } => {
+                if *amount == 0 {
+                    return Err(AccountingError::ZeroAmount)
+                }
+                if state.balance(NATIVE_ASSET, who) == 0 {
+                    return Err(AccountingError::UnknownAccount)
+                }
+                state.lock(*who, *amount, *until);
+            },
+            AccountingTransaction::VestedTransfer { sender, receiver, amount, per_block, .. } => {
+                if *per_block == 0 {
+                    return Err(AccountingError::ZeroAmount)
+                }
+                if state.vesting.contains_key(receiver) {
+                    return Err(AccountingError::VestingScheduleExists)
+                }
+                state.transfer(NATIVE_ASSET, *sender, *receiver, *amount, ExistenceRequirement::KeepAlive)?;
+                state.vesting.insert(*receiver, VestingSchedule {
+                    locked: *amount,
+                    per_block: *per_block,
+                    starting_block: state.block_height,
+                });
+            },
+            AccountingTransaction::HtlcLock { sender, receiver, asset, amount, hash_lock, expiry, nonce } => {
+                if *amount == 0 {
+                    return Err(AccountingError::ZeroAmount)
+                }
+                if *expiry <= state.block_height {
+                    return Err(AccountingError::HtlcExpired)
+                }
+                // Whoever ends up with the funds gets a valid account out of them.
+                let min_balance = state.assets.get(asset).ok_or(AccountingError::UnknownAsset)?.min_balance;
+                if *amount < min_balance {
+                    return Err(AccountingError::BelowExistentialDeposit)
+                }
+                state.escrow(*asset, *sender, *amount)?;
+                state.htlcs.insert(Htlc::id(*sender, *nonce), Htlc {
+                    sender: *sender,
+                    receiver: *receiver,
+                    asset: *asset,
+                    amount: *amount,
+                    hash_lock: *hash_lock,
+                    expiry: *expiry,
+                });
+            },
+            AccountingTransaction::HtlcClaim { claimer, id, preimage, .. } => {
+                let htlc = state.htlcs.remove(id).ok_or(AccountingError::UnknownHtlc)?;
+
+                if htlc.receiver != *claimer {
+                    return Err(AccountingError::NotHtlcParty)
+                }
+                if state.block_height >= htlc.expiry {
+                    return Err(AccountingError::HtlcExpired)
+                }
+                if script::hash_of(*preimage) != htlc.hash_lock {
+                    return Err(AccountingError::WrongPreimage)
+                }
+                // Escrowed funds are part of the supply, so this can not overflow.
+                let balance = state.balance(htlc.asset, claimer) + htlc.amount;
+                state.set_balance(htlc.asset, *claimer, balance);
+            },
+            AccountingTransaction::HtlcRefund { sender, id, .. } => {
+                let htlc = state.htlcs.remove(id).ok_or(AccountingError::UnknownHtlc)?;
+
+                if htlc.sender != *sender {
+                    return Err(AccountingError::NotHtlcParty)
+                }
+                if state.block_height < htlc.expiry {
+                    return Err(AccountingError::HtlcNotExpired)
+                }
+                let balance = state.balance(htlc.asset, sender) + htlc.amount;
+                state.set_balance(htlc.asset, *sender, balance);
+            }
+        }
+
+        Ok(())
+    }
 }
 
-impl StateMachine for AccountedCurrency {
-    type State = Balances;
-    type Transition = AccountingTransaction;
+/// Why an `AccountingTransaction` was rejected. A rejected transaction leaves the state,
+/// including the signer's nonce, untouched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccountingError {
+    StaleNonce,
+    FutureNonce,
+    ZeroAmount,
+    /// The account would be created with less than the existential deposit.
+    BelowExistentialDeposit,
+    UnknownAccount,
+    InsufficientBalance,
+    /// A keep-alive burn or transfer would have reaped the signer.
+    WouldReapAccount,
+    UnknownAsset,
+    /// Only the admin of an asset may mint it.
+    NotAssetAdmin,
+    /// Assets need a minimum balance of at least 1.
+    ZeroMinBalance,
+    InsufficientAllowance,
+    /// The funds exist but are frozen by a lock or vesting schedule.
+    LiquidityRestrictions,
+    /// An account can only vest one schedule at a time.
+    VestingScheduleExists,
+    /// The signer can not pay the fee out of its spendable native balance without being reaped.
+    CannotPayFee,
+    UnknownHtlc,
+    /// The contract can no longer be locked or claimed.
+    HtlcExpired,
+    /// The contract can not be refunded before its expiry.
+    HtlcNotExpired,
+    WrongPreimage,
+    /// Only the receiver may claim a contract and only its sender may refund it.
+    NotHtlcParty,
+    Overflow,
+}
+
+impl StateMachine for AccountedCurrency {
+    type State = State;
+    type Transition = AccountingTransaction;
+    type Error = AccountingError;
+
+    fn next_state(starting_state: &Self::State, transition: &Self::Transition) -> Self::State {
+        Self::try_next_state(starting_state, transition).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state(starting_state: &Self::State, transition: &Self::Transition) -> Result<Self::State, Self::Error> {
+        let mut new_state = starting_state.clone();
+        Self::execute(&mut new_state, transition)?;
+        Ok(new_state)
+    }
+
+    fn apply(state: &mut Self::State, transition: &Self::Transition) -> Result<(), Self::Error> {
+        Self::apply_with_undo(state, transition).map(|_| ())
+    }
+
+    fn human_name() -> String {
+        "Accounted currency".into()
+    }
+}
+
+impl BlockHooks for AccountedCurrency {
+    fn on_initialize(state: &mut State, context: &BlockContext) {
+        state.set_block_height(context.height);
+    }
+
+    /// Pays the block's fees and reward to its author. Without an author, or when the payout
+    /// would not create a valid account, the fees are burned and no reward is minted.
+    fn on_finalize(state: &mut State, context: &BlockContext) {
+        let fees = std::mem::take(&mut state.pending_fees);
+        let reward = state.fees.block_reward;
+
+        let payout = context.author.and_then(|author| {
+            let supply = state.total_issuance().checked_add(reward)?;
+            let balance = state.balance(NATIVE_ASSET, &author).checked_add(fees)?.checked_add(reward)?;
+            Some((author, supply, balance)).filter(|_| balance >= state.existential_deposit())
+        });
+
+        match payout {
+            Some((author, supply, balance)) => {
+                state.native_mut().supply = supply;
+                state.set_balance(NATIVE_ASSET, author, balance);
+            }
+            None => state.native_mut().supply -= fees,
+        }
+    }
+}
+
+/// The parts of a `State` a change touched, as they were before it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccountingUndo {
+    assets: MapUndo<AssetId, AssetDetails>,
+    next_asset_id: AssetId,
+    balances: MapUndo<(AssetId, User), u64>,
+    nonces: MapUndo<User, u64>,
+    allowances: MapUndo<(AssetId, User, User), u64>,
+    reserved: MapUndo<User, u64>,
+    locks: MapUndo<User, Vec<BalanceLock>>,
+    vesting: MapUndo<User, VestingSchedule>,
+    htlcs: MapUndo<HtlcId, Htlc>,
+    block_height: u64,
+    fees: FeeConfig,
+    pending_fees: u64,
+}
+
+impl AccountingUndo {
+    // Keeps the scalars of `state`, entries of its maps are remembered as they get touched.
+    fn new(state: &State) -> Self {
+        AccountingUndo {
+            assets: MapUndo::new(),
+            next_asset_id: state.next_asset_id,
+            balances: MapUndo::new(),
+            nonces: MapUndo::new(),
+            allowances: MapUndo::new(),
+            reserved: MapUndo::new(),
+            locks: MapUndo::new(),
+            vesting: MapUndo::new(),
+            htlcs: MapUndo::new(),
+            block_height: state.block_height,
+            fees: state.fees,
+            pending_fees: state.pending_fees,
+        }
+    }
+
+    fn balance(&mut self, state: &State, asset: AssetId, user: User) {
+        self.balances.remember(&state.balances, (asset, user));
+    }
+
+    /// Everything `transaction` may touch, whether it is accepted or not.
+    fn footprint(state: &State, transaction: &AccountingTransaction) -> Self {
+        let mut undo = AccountingUndo::new(state);
+        let signer = transaction.signer();
+
+        undo.nonces.remember(&state.nonces, signer);
+        undo.balance(state, NATIVE_ASSET, signer);
+        match transaction {
+            AccountingTransaction::CreateAsset { .. } => undo.assets.remember(&state.assets, state.next_asset_id),
+            AccountingTransaction::Mint { minter: user, asset, .. }
+            | AccountingTransaction::Burn { burner: user, asset, .. } => {
+                undo.assets.remember(&state.assets, *asset);
+                undo.balance(state, *asset, *user);
+            }
+            AccountingTransaction::Transfer { sender, receiver, asset, .. } => {
+                undo.assets.remember(&state.assets, *asset);
+                undo.balance(state, *asset, *sender);
+                undo.balance(state, *asset, *receiver);
+            }
+            AccountingTransaction::Approve { owner, spender, asset, .. } => {
+                undo.allowances.remember(&state.allowances, (*asset, *owner, *spender));
+            }
+            AccountingTransaction::TransferFrom { spender, owner, receiver, asset, .. } => {
+                undo.allowances.remember(&state.allowances, (*asset, *owner, *spender));
+                undo.assets.remember(&state.assets, *asset);
+                undo.balance(state, *asset, *owner);
+                undo.balance(state, *asset, *receiver);
+            }
+            AccountingTransaction::Lock { who, .. } => undo.locks.remember(&state.locks, *who),
+            AccountingTransaction::VestedTransfer { sender, receiver, .. } => {
+                undo.assets.remember(&state.assets, NATIVE_ASSET);
+                undo.balance(state, NATIVE_ASSET, *sender);
+                undo.balance(state, NATIVE_ASSET, *receiver);
+                undo.vesting.remember(&state.vesting, *receiver);
+            }
+            AccountingTransaction::HtlcLock { sender, asset, nonce, .. } => {
+                undo.balance(state, *asset, *sender);
+                undo.htlcs.remember(&state.htlcs, Htlc::id(*sender, *nonce));
+            }
+            AccountingTransaction::HtlcClaim { id, .. } | AccountingTransaction::HtlcRefund { id, .. } => {
+                undo.htlcs.remember(&state.htlcs, *id);
+                if let Some(htlc) = state.htlcs.get(id) {
+                    undo.balance(state, htlc.asset, signer);
+                }
+            }
+        }
+        undo
+    }
+}
+
+impl Revertible for AccountedCurrency {
+    type Undo = AccountingUndo;
+
+    fn undo(before: &State, after: &State) -> AccountingUndo {
+        AccountingUndo {
+            assets: MapUndo::record(&before.assets, &after.assets),
+            next_asset_id: before.next_asset_id,
+            balances: MapUndo::record(&before.balances, &after.balances),
+            nonces: MapUndo::record(&before.nonces, &after.nonces),
+            allowances: MapUndo::record(&before.allowances, &after.allowances),
+            reserved: MapUndo::record(&before.reserved, &after.reserved),
+            locks: MapUndo::record(&before.locks, &after.locks),
+            vesting: MapUndo::record(&before.vesting, &after.vesting),
+            htlcs: MapUndo::record(&before.htlcs, &after.htlcs),
+            block_height: before.block_height,
+            fees: before.fees,
+            pending_fees: before.pending_fees,
+        }
+    }
+
+    fn revert(state: &mut State, undo: AccountingUndo) {
+        undo.assets.revert(&mut state.assets);
+        state.next_asset_id = undo.next_asset_id;
+        undo.balances.revert(&mut state.balances);
+        undo.nonces.revert(&mut state.nonces);
+        undo.allowances.revert(&mut state.allowances);
+        undo.reserved.revert(&mut state.reserved);
+        undo.locks.revert(&mut state.locks);
+        undo.vesting.revert(&mut state.vesting);
+        undo.htlcs.revert(&mut state.htlcs);
+        state.block_height = undo.block_height;
+        state.fees = undo.fees;
+        state.pending_fees = undo.pending_fees;
+    }
+
+    fn apply_with_undo(state: &mut State, transition: &AccountingTransaction) -> Result<AccountingUndo, AccountingError> {
+        let undo = AccountingUndo::footprint(state, transition);
+
+        match Self::execute(state, transition) {
+            Ok(()) => Ok(undo),
+            Err(error) => {
+                Self::revert(state, undo);
+                Err(error)
+            }
+        }
+    }
+
+    fn hook_undo(state: &State, context: &BlockContext) -> AccountingUndo {
+        let mut undo = AccountingUndo::new(state);
+
+        // `on_initialize` drops what ran out by the new height.
+        for (user, locks) in &state.locks {
+            if locks.iter().any(|lock| lock.until <= context.height) {
+                undo.locks.remember(&state.locks, *user);
+            }
+        }
+        for (user, schedule) in &state.vesting {
+            if schedule.locked_at(context.height) == 0 {
+                undo.vesting.remember(&state.vesting, *user);
+            }
+        }
+        // `on_finalize` pays the author and changes the native supply.
+        undo.assets.remember(&state.assets, NATIVE_ASSET);
+        if let Some(author) = context.author {
+            undo.balance(state, NATIVE_ASSET, author);
+        }
+        undo
+    }
+}
+
+impl Emitter for AccountedCurrency {
+    type Event = Event;
+
+    /// Fees and block rewards are paid by `on_finalize`, see `hook_events`.
+    fn events(before: &State, transition: &AccountingTransaction, after: &State) -> Vec<Event> {
+        Self::emitted(Prior::State(before), transition, after)
+    }
+}
+
+impl UndoEmitter for AccountedCurrency {
+    fn transition_events(undo: &AccountingUndo, transition: &AccountingTransaction, after: &State) -> Vec<Event> {
+        Self::emitted(Prior::Undo(undo, after), transition, after)
+    }
+
+    /// `on_finalize` either pays the fees and the reward to the author or burns the fees.
+    fn hook_events(undo: &AccountingUndo, context: &BlockContext, after: &State) -> Vec<Event> {
+        let fees = undo.pending_fees - after.pending_fees;
+        let supply = Prior::Undo(undo, after).supply(NATIVE_ASSET);
+
+        if after.total_issuance() < supply {
+            return vec![Event::FeesBurned { amount: fees }]
+        }
+        let Some(author) = context.author else {
+            return Vec::new()
+        };
+        let reward = after.total_issuance() - supply;
+        let minted = (reward > 0).then_some(Event::Minted { asset: NATIVE_ASSET, to: author, amount: reward });
+        let paid = (fees > 0).then_some(Event::FeesPaid { to: author, amount: fees });
+
+        minted.into_iter().chain(paid).collect()
+    }
+}
+
+/// The state a transaction was applied to, either whole or as the parts its undo record kept
+/// next to the state it led to.
+#[derive(Clone, Copy)]
+enum Prior<'a> {
+    State(&'a State),
+    Undo(&'a AccountingUndo, &'a State),
+}
+
+impl<'a> Prior<'a> {
+    fn nonce(self, user: User) -> u64 {
+        match self {
+            Prior::State(state) => state.nonce(&user),
+            Prior::Undo(undo, after) => undo.nonces.before(&after.nonces, &user).copied().unwrap_or(0),
+        }
+    }
+
+    fn supply(self, asset: AssetId) -> u64 {
+        let details = match self {
+            Prior::State(state) => state.asset(asset),
+            Prior::Undo(undo, after) => undo.assets.before(&after.assets, &asset),
+        };
+        details.map_or(0, |details| details.supply)
+    }
+
+    fn has_account(self, asset: AssetId, who: User) -> bool {
+        match self {
+            Prior::State(state) => state.balances.contains_key(&(asset, who)),
+            Prior::Undo(undo, after) => undo.balances.before(&after.balances, &(asset, who)).is_some(),
+        }
+    }
+
+    fn htlc(self, id: HtlcId) -> Option<&'a Htlc> {
+        match self {
+            Prior::State(state) => state.htlc(id),
+            Prior::Undo(undo, after) => undo.htlcs.before(&after.htlcs, &id),
+        }
+    }
+}
+
+impl AccountedCurrency {
+    fn emitted(before: Prior, transition: &AccountingTransaction, after: &State) -> Vec<Event> {
+        // Every accepted transaction consumes its signer's nonce.
+        if after.nonce(&transition.signer()) == before.nonce(transition.signer()) {
+            return Vec::new()
+        }
+        let destroyed = |asset: &AssetId| {
+            let supply = after.asset(*asset).map_or(0, |details| details.supply);
+            before.supply(*asset).saturating_sub(supply)
+        };
+        let reaped = |asset: AssetId, who: User, dust: u64| {
+            let key = (asset, who);
+            (before.has_account(asset, who) && !after.balances.contains_key(&key))
+                .then_some(Event::AccountReaped { asset, who, dust })
+        };
+
+        match transition {
+            AccountingTransaction::Mint { minter, asset, amount, .. } => {
+                vec![Event::Minted { asset: *asset, to: *minter, amount: *amount }]
+            }
+            AccountingTransaction::Burn { burner, asset, amount, .. } => {
+                // A burn that reaps the burner destroys the rest of its balance as dust.
+                let destroyed = destroyed(asset);
+                let burned = (*amount).min(destroyed);
+                let mut events = vec![Event::Burned { asset: *asset, from: *burner, amount: burned }];
+                events.extend(reaped(*asset, *burner, destroyed - burned));
+                events
+            }
+            AccountingTransaction::Transfer { sender, receiver, asset, amount, .. } => {
+                let mut events = vec![Event::Transferred { asset: *asset, from: *sender, to: *receiver, amount: *amount }];
+                events.extend(reaped(*asset, *sender, destroyed(asset)));
+                events
+            }
+            AccountingTransaction::TransferFrom { owner, receiver, asset, amount, .. } => {
+                vec![Event::Transferred { asset: *asset, from: *owner, to: *receiver, amount: *amount }]
+            }
+            AccountingTransaction::VestedTransfer { sender, receiver, amount, .. } => {
+                vec![Event::Transferred { asset: NATIVE_ASSET, from: *sender, to: *receiver, amount: *amount }]
+            }
+            // The escrow only changes hands once it is claimed, a refund returns it.
+            AccountingTransaction::HtlcClaim { claimer, id, .. } => match before.htlc(*id) {
+                Some(htlc) => {
+                    vec![Event::Transferred { asset: htlc.asset, from: htlc.sender, to: *claimer, amount: htlc.amount }]
+                }
+                None => Vec::new(),
+            },
+            AccountingTransaction::CreateAsset { .. }
+            | AccountingTransaction::Approve { .. }
+            | AccountingTransaction::Lock { .. }
+            | AccountingTransaction::HtlcLock { .. }
+            | AccountingTransaction::HtlcRefund { .. } => Vec::new(),
+        }
+    }
+}
+
+impl Invariants for AccountedCurrency {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant { name: "accounts are above their minimum balance", check: accounts_are_above_minimum_balance },
+            Invariant { name: "escrows are not empty", check: escrows_are_not_empty },
+            Invariant { name: "reserved, locked and vesting funds have an account", check: dependents_have_an_account },
+            Invariant { name: "supplies match holdings", check: supplies_match_holdings },
+            Invariant { name: "transactions conserve balances", check: transactions_conserve_balances },
+        ]
+    }
+}
+
+fn accounts_are_above_minimum_balance(_: &State, _: &AccountingTransaction, after: &State) -> Result<(), String> {
+    for ((asset, user), balance) in &after.balances {
+        let details = after.assets.get(asset).ok_or(format!("{:?} holds unknown asset {}", user, asset))?;
+        if *balance < details.min_balance {
+            return Err(format!("{:?} holds {} of asset {}, below its minimum balance", user, balance, asset))
+        }
+    }
+    Ok(())
+}
+
+fn escrows_are_not_empty(_: &State, _: &AccountingTransaction, after: &State) -> Result<(), String> {
+    match after.htlcs.values().find(|htlc| htlc.amount == 0) {
+        Some(htlc) => Err(format!("{:?} holds an empty escrow", htlc.sender)),
+        None => Ok(()),
+    }
+}
+
+fn dependents_have_an_account(_: &State, _: &AccountingTransaction, after: &State) -> Result<(), String> {
+    let dependents = after.reserved.keys().chain(after.locks.keys()).chain(after.vesting.keys());
+    for user in dependents {
+        if after.balance(NATIVE_ASSET, user) == 0 {
+            return Err(format!("{:?} has reserved, locked or vesting funds but no account", user))
+        }
+    }
+    Ok(())
+}
+
+fn supplies_match_holdings(_: &State, _: &AccountingTransaction, after: &State) -> Result<(), String> {
+    let mut held: HashMap<AssetId, u64> = HashMap::new();
+
+    for ((asset, _), balance) in &after.balances {
+        let sum = held.entry(*asset).or_insert(0);
+        *sum = sum.checked_add(*balance).ok_or(format!("balances of asset {} overflow a u64", asset))?;
+    }
+    for reserved in after.reserved.values().chain([&after.pending_fees]) {
+        let sum = held.entry(NATIVE_ASSET).or_insert(0);
+        *sum = sum.checked_add(*reserved).ok_or("reserved balances and fees overflow a u64")?;
+    }
+    for htlc in after.htlcs.values() {
+        let sum = held.entry(htlc.asset).or_insert(0);
+        *sum = sum.checked_add(htlc.amount).ok_or(format!("escrows of asset {} overflow a u64", htlc.asset))?;
+    }
+    for (asset, details) in &after.assets {
+        let in_accounts = held.get(asset).copied().unwrap_or(0);
+        if in_accounts != details.supply {
+            return Err(format!("accounts hold {} of asset {} but its supply is {}", in_accounts, asset, details.supply))
+        }
+    }
+    Ok(())
+}
+
+fn transactions_conserve_balances(before: &State, transition: &AccountingTransaction, after: &State) -> Result<(), String> {
+    let supply = |state: &State, asset: &AssetId| state.asset(*asset).map_or(0, |details| details.supply);
+    // Fees only move native funds into `pending_fees`, they never change a supply.
+    let charged = after.pending_fees.saturating_sub(before.pending_fees);
+    let fee = |asset: &AssetId| if *asset == NATIVE_ASSET { charged } else { 0 };
+    let conserved = match transition {
+        AccountingTransaction::CreateAsset { .. } => {
+            before.assets.iter().all(|(asset, details)| after.assets[asset].supply == details.supply)
+        }
+        AccountingTransaction::Mint { asset, amount, .. } => {
+            // A rejected mint leaves the state as it was, an accepted one adds exactly `amount`.
+            after == before || Some(supply(after, asset)) == supply(before, asset).checked_add(*amount)
+        }
+        AccountingTransaction::Burn { asset, .. } => supply(after, asset) <= supply(before, asset),
+        AccountingTransaction::Transfer { sender, asset, amount, .. } => {
+            // Only the dust of a reaped sender may leave circulation.
+            let dust = match (before.balances.get(&(*asset, *sender)), after.balances.get(&(*asset, *sender))) {
+                (Some(balance), None) => balance.saturating_sub(amount.saturating_add(fee(asset))),
+                _ => 0,
+            };
+            supply(after, asset) + dust == supply(before, asset)
+        }
+        AccountingTransaction::Approve { .. }
+        | AccountingTransaction::Lock { .. }
+        | AccountingTransaction::VestedTransfer { .. }
+        | AccountingTransaction::HtlcLock { .. }
+        | AccountingTransaction::HtlcClaim { .. }
+        | AccountingTransaction::HtlcRefund { .. } => before.assets == after.assets,
+        AccountingTransaction::TransferFrom { spender, owner, receiver, asset, .. } => {
+            // The allowance has to shrink by exactly what left the owner's account.
+            let fee = if spender == owner { fee(asset) } else { 0 };
+            let spent = before.balance(*asset, owner).checked_sub(after.balance(*asset, owner));
+            let allowed = before
+                .allowance(*asset, owner, spender)
+                .checked_sub(after.allowance(*asset, owner, spender))
+                .and_then(|allowed| allowed.checked_add(fee));
+
+            before.assets == after.assets && (owner == receiver || spent == allowed)
+        }
+    };
+    if !conserved {
+        return Err(format!("{:?} did not conserve balances", transition))
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::state_machine::harness::Harness;
+    use crate::state_machine::journal::Journal;
+    use crate::state_machine::Checked;
+    use proptest::prelude::*;
+
+    #[test]
+    fn mint_creates_account() {
+        let start = State::new();
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Mint {
+                minter: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 100,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 100)]);
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+
+    #[test]
+    fn mint_creates_second_account() {
+        let start = State::from([(User::Alice, 100)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Mint {
+                minter: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 50,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+
+    #[test]
+    fn mint_increases_balance() {
+        let start = State::from([(User::Alice, 100)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Mint {
+                minter: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 50,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 150)]);
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+
+    #[test]
+    fn empty_mint() {
+        let start = State::new();
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Mint {
+                minter: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 0,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::new();
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+
+    #[test]
+    fn simple_burn() {
+        let start = State::from([(User::Alice, 100)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Burn {
+                burner: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 50,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 50)]);
+    
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+    
+    #[test]
+    fn burn_no_existential_deposit_left() {
+        let start = State::from([(User::Alice, 100), (User::Bob, 50)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Burn {
+                burner: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 50,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 100)]);
+    
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+    
+    #[test]
+    fn non_registered_burner() {
+        let start = State::from([(User::Alice, 100)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Burn {
+                burner: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 50,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 100)]);
+    
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+    
+    #[test]
+    fn burn_more_than_balance() {
+        let start = State::from([(User::Alice, 100), (User::Bob, 50)]);
+        let end2 = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Burn {
+                burner: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 100,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected2 = HashMap::from([(User::Alice, 100)]);
+    
+        assert_eq!(end2.balances_of(NATIVE_ASSET), expected2);
+    }
+
+    #[test]
+    fn empty_burn() {
+        let start = State::from([(User::Alice, 100)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Burn {
+                burner: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 0,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 100)]);
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+}
+
+    #[test]
+    fn burner_does_not_exist() {
+        let start = State::from([(User::Alice, 100)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Burn {
+                burner: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 50,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 100)]);
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+
+    #[test]
+    fn simple_transfer() {
+        let start = State::from([(User::Alice, 100), (User::Bob, 50)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Alice,
+                receiver: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 10,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 90), (User::Bob, 60)]);
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+
+        let start = State::from([(User::Alice, 90), (User::Bob, 60)]);
+        let end1 = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Bob,
+                receiver: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 50,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected1 = HashMap::from([(User::Alice, 140), (User::Bob, 10)]);
+
+        assert_eq!(end1.balances_of(NATIVE_ASSET), expected1);
+    }
+
+    #[test]
+    fn send_to_same_user() {
+        let start = State::from([(User::Alice, 100), (User::Bob, 50)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Bob,
+                receiver: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 10,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+
+    #[test]
+    fn insufficient_balance_transfer() {
+        let start = State::from([(User::Alice, 100), (User::Bob, 50)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Bob,
+                receiver: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 60,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+
+    #[test]
+    fn sender_not_registered() {
+        let start = State::from([(User::Alice, 100), (User::Bob, 50)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Charlie,
+                receiver: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 50,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+
+    #[test]
+    fn receiver_not_registered() {
+        let start = State::from([(User::Alice, 100), (User::Bob, 50)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Alice,
+                receiver: User::Charlie,
+                asset: NATIVE_ASSET,
+                amount: 50,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 50), (User::Bob, 50), (User::Charlie, 50)]);
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+
+    #[test]
+    fn sender_to_empty_balance() {
+        let start = State::from([(User::Alice, 100), (User::Bob, 50)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Bob,
+                receiver: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 50,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 150)]);
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+
+    #[test]
+    fn transfer() {
+        let start = State::from([(User::Alice, 100), (User::Bob, 50)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Bob,
+                receiver: User::Charlie,
+                asset: NATIVE_ASSET,
+                amount: 50,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+        let expected = HashMap::from([(User::Alice, 100), (User::Charlie, 50)]);
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), expected);
+    }
+
+    #[test]
+    fn transaction_increments_signer_nonce() {
+        let start = State::from([(User::Alice, 100)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Alice,
+                receiver: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 10,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+
+        assert_eq!(end.nonce(&User::Alice), 1);
+        assert_eq!(end.nonce(&User::Bob), 0);
+    }
+
+    #[test]
+    fn replayed_transfer_rejected() {
+        let start = State::from([(User::Alice, 100)]);
+        let transfer = AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            asset: NATIVE_ASSET,
+            amount: 10,
+            existence: ExistenceRequirement::AllowDeath,
+            nonce: 0,
+        };
+        let once = AccountedCurrency::next_state(&start, &transfer);
+        let twice = AccountedCurrency::next_state(&once, &transfer);
+        let expected = HashMap::from([(User::Alice, 90), (User::Bob, 10)]);
+
+        assert_eq!(twice, once);
+        assert_eq!(twice.balances_of(NATIVE_ASSET), expected);
+    }
+
+    #[test]
+    fn future_nonce_rejected() {
+        let start = State::from([(User::Alice, 100)]);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Burn {
+                burner: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 10,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 1,
+            },
+        );
+
+        assert_eq!(end, start);
+    }
+
+    #[test]
+    fn nonce_survives_account_removal() {
+        let start = State::from([(User::Bob, 50)]);
+        let burn = AccountingTransaction::Burn {
+            burner: User::Bob,
+            asset: NATIVE_ASSET,
+            amount: 50,
+            existence: ExistenceRequirement::AllowDeath,
+            nonce: 0,
+        };
+        let removed = AccountedCurrency::next_state(&start, &burn);
+        let refunded = AccountedCurrency::next_state(
+            &removed,
+            &AccountingTransaction::Mint {
+                minter: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 50,
+                nonce: 1,
+            },
+        );
+        let end = AccountedCurrency::next_state(&refunded, &burn);
+
+        assert!(removed.balances().is_empty());
+        assert_eq!(end.balances_of(NATIVE_ASSET), HashMap::from([(User::Bob, 50)]));
+        assert_eq!(end.nonce(&User::Bob), 2);
+    }
+
+    #[test]
+    fn nonce_status_for_pool() {
+        let mut state = State::from([(User::Alice, 100)]);
+        state.nonces.insert(User::Alice, 3);
+        let with_nonce = |nonce| AccountingTransaction::Mint {
+            minter: User::Alice,
+            asset: NATIVE_ASSET,
+            amount: 1,
+            nonce,
+        };
+
+        assert_eq!(AccountedCurrency::expected_nonce(&state, &User::Alice), 3);
+        assert_eq!(AccountedCurrency::nonce_status(&state, &with_nonce(2)), NonceStatus::Stale);
+        assert_eq!(AccountedCurrency::nonce_status(&state, &with_nonce(3)), NonceStatus::Ready);
+        assert_eq!(AccountedCurrency::nonce_status(&state, &with_nonce(4)), NonceStatus::Future);
+    }
+
+    #[test]
+    fn mint_below_existential_deposit_rejected() {
+        let start = State::new().with_existential_deposit(10);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Mint {
+                minter: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 5,
+                nonce: 0,
+            },
+        );
+
+        assert_eq!(end, start);
+    }
+
+    #[test]
+    fn transfer_creating_dust_account_rejected() {
+        let start = State::from([(User::Alice, 100)]).with_existential_deposit(10);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Alice,
+                receiver: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 5,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+
+        assert_eq!(end, start);
+    }
+
+    #[test]
+    fn keep_alive_transfer_leaving_dust_rejected() {
+        let start = State::from([(User::Alice, 100)]).with_existential_deposit(10);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Alice,
+                receiver: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 95,
+                existence: ExistenceRequirement::KeepAlive,
+                nonce: 0,
+            },
+        );
+
+        assert_eq!(end, start);
+    }
+
+    #[test]
+    fn allow_death_transfer_reaps_sender() {
+        let start = State::from([(User::Alice, 100)]).with_existential_deposit(10);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Alice,
+                receiver: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 95,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), HashMap::from([(User::Bob, 95)]));
+        assert_eq!(end.total_issuance(), 95);
+    }
+
+    #[test]
+    fn keep_alive_burn_leaving_dust_rejected() {
+        let start = State::from([(User::Alice, 100)]).with_existential_deposit(10);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Burn {
+                burner: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 95,
+                existence: ExistenceRequirement::KeepAlive,
+                nonce: 0,
+            },
+        );
+
+        assert_eq!(end, start);
+    }
+
+    #[test]
+    fn allow_death_burn_destroys_dust() {
+        let start = State::from([(User::Alice, 100), (User::Bob, 50)]).with_existential_deposit(10);
+        let end = AccountedCurrency::next_state(
+            &start,
+            &AccountingTransaction::Burn {
+                burner: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 95,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), HashMap::from([(User::Bob, 50)]));
+        assert_eq!(end.total_issuance(), 50);
+    }
+
+    #[test]
+    fn reaping_transfer_and_burn_emit_dust() {
+        let start = State::from([(User::Alice, 100)]).with_existential_deposit(10);
+        let transfer = AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            asset: NATIVE_ASSET,
+            amount: 95,
+            existence: ExistenceRequirement::AllowDeath,
+            nonce: 0,
+        };
+        let burn = |amount, existence| AccountingTransaction::Burn { burner: User::Alice, asset: NATIVE_ASSET, amount, existence, nonce: 0 };
+        let events = |transaction: &AccountingTransaction| {
+            AccountedCurrency::events(&start, transaction, &AccountedCurrency::next_state(&start, transaction))
+        };
+        let events_from_undo = |transaction: &AccountingTransaction| {
+            let mut end = start.clone();
+            let undo = AccountedCurrency::apply_with_undo(&mut end, transaction).unwrap();
+            AccountedCurrency::transition_events(&undo, transaction, &end)
+        };
+
+        assert_eq!(events(&transfer), vec![
+            Event::Transferred { asset: NATIVE_ASSET, from: User::Alice, to: User::Bob, amount: 95 },
+            Event::AccountReaped { asset: NATIVE_ASSET, who: User::Alice, dust: 5 },
+        ]);
+        assert_eq!(events(&burn(95, ExistenceRequirement::AllowDeath)), vec![
+            Event::Burned { asset: NATIVE_ASSET, from: User::Alice, amount: 95 },
+            Event::AccountReaped { asset: NATIVE_ASSET, who: User::Alice, dust: 5 },
+        ]);
+        assert_eq!(events(&burn(50, ExistenceRequirement::AllowDeath)), vec![
+            Event::Burned { asset: NATIVE_ASSET, from: User::Alice, amount: 50 },
+        ]);
+        for transaction in [transfer.clone(), burn(95, ExistenceRequirement::AllowDeath), burn(50, ExistenceRequirement::AllowDeath)] {
+            assert_eq!(events_from_undo(&transaction), events(&transaction));
+        }
+        // Rejected, so nothing happened.
+        assert_eq!(events(&burn(95, ExistenceRequirement::KeepAlive)), vec![]);
+    }
+
+    #[test]
+    fn mint_overflowing_balance_fails() {
+        let start = State::from([(User::Alice, u64::MAX - 1)]);
+        let result = AccountedCurrency::try_next_state(
+            &start,
+            &AccountingTransaction::Mint {
+                minter: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 2,
+                nonce: 0,
+            },
+        );
+
+        assert_eq!(result, Err(AccountingError::Overflow));
+    }
+
+    #[test]
+    fn mint_overflowing_total_issuance_fails() {
+        let start = State::from([(User::Alice, u64::MAX - 1)]);
+        let result = AccountedCurrency::try_next_state(
+            &start,
+            &AccountingTransaction::Mint {
+                minter: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 2,
+                nonce: 0,
+            },
+        );
+
+        assert_eq!(result, Err(AccountingError::Overflow));
+    }
+
+    #[test]
+    fn mint_up_to_max() {
+        let start = State::from([(User::Alice, u64::MAX - 1)]);
+        let end = AccountedCurrency::try_next_state(
+            &start,
+            &AccountingTransaction::Mint {
+                minter: User::Alice,
+                asset: NATIVE_ASSET,
+                amount: 1,
+                nonce: 0,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), HashMap::from([(User::Alice, u64::MAX)]));
+        assert_eq!(end.total_issuance(), u64::MAX);
+    }
+
+    #[test]
+    fn transfer_whole_max_balance() {
+        let start = State::from([(User::Alice, u64::MAX)]);
+        let end = AccountedCurrency::try_next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Alice,
+                receiver: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: u64::MAX,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), HashMap::from([(User::Bob, u64::MAX)]));
+    }
+
+    #[test]
+    fn rejected_transactions_report_why() {
+        let start = State::from([(User::Alice, 100)]).with_existential_deposit(10);
+        let transfer = |amount, existence, nonce| AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            asset: NATIVE_ASSET,
+            amount,
+            existence,
+            nonce,
+        };
+
+        assert_eq!(
+            AccountedCurrency::try_next_state(&start, &transfer(200, ExistenceRequirement::AllowDeath, 0)),
+            Err(AccountingError::InsufficientBalance)
+        );
+        assert_eq!(
+            AccountedCurrency::try_next_state(&start, &transfer(95, ExistenceRequirement::KeepAlive, 0)),
+            Err(AccountingError::WouldReapAccount)
+        );
+        assert_eq!(
+            AccountedCurrency::try_next_state(&start, &transfer(5, ExistenceRequirement::AllowDeath, 0)),
+            Err(AccountingError::BelowExistentialDeposit)
+        );
+        assert_eq!(
+            AccountedCurrency::try_next_state(&start, &transfer(50, ExistenceRequirement::AllowDeath, 1)),
+            Err(AccountingError::FutureNonce)
+        );
+    }
+
+    fn create_asset(creator: User, nonce: u64) -> AccountingTransaction {
+        AccountingTransaction::CreateAsset {
+            creator,
+            decimals: 2,
+            name: "Dollar".into(),
+            symbol: "USD".into(),
+            min_balance: 5,
+            nonce,
+        }
+    }
+
+    #[test]
+    fn create_asset_registers_admin() {
+        let start = State::new();
+        let end = AccountedCurrency::try_next_state(&start, &create_asset(User::Alice, 0)).unwrap();
+        let expected = AssetDetails {
+            admin: Some(User::Alice),
+            decimals: 2,
+            name: "Dollar".into(),
+            symbol: "USD".into(),
+            min_balance: 5,
+            supply: 0,
+        };
+
+        assert_eq!(end.asset(1), Some(&expected));
+        assert_eq!(end.asset(2), None);
+    }
+
+    #[test]
+    fn asset_admin_mints() {
+        let start = AccountedCurrency::next_state(&State::new(), &create_asset(User::Alice, 0));
+        let end = AccountedCurrency::try_next_state(
+            &start,
+            &AccountingTransaction::Mint {
+                minter: User::Alice,
+                asset: 1,
+                amount: 100,
+                nonce: 1,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(end.balances_of(1), HashMap::from([(User::Alice, 100)]));
+        assert_eq!(end.asset(1).map(|details| details.supply), Some(100));
+        assert!(end.balances_of(NATIVE_ASSET).is_empty());
+    }
+
+    #[test]
+    fn non_admin_mint_fails() {
+        let start = AccountedCurrency::next_state(&State::new(), &create_asset(User::Alice, 0));
+        let result = AccountedCurrency::try_next_state(
+            &start,
+            &AccountingTransaction::Mint {
+                minter: User::Bob,
+                asset: 1,
+                amount: 100,
+                nonce: 0,
+            },
+        );
+
+        assert_eq!(result, Err(AccountingError::NotAssetAdmin));
+    }
+
+    #[test]
+    fn unknown_asset_fails() {
+        let start = State::from([(User::Alice, 100)]);
+        let result = AccountedCurrency::try_next_state(
+            &start,
+            &AccountingTransaction::Transfer {
+                sender: User::Alice,
+                receiver: User::Bob,
+                asset: 7,
+                amount: 10,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 0,
+            },
+        );
+
+        assert_eq!(result, Err(AccountingError::UnknownAsset));
+    }
+
+    #[test]
+    fn assets_transfer_independently() {
+        let mut state = State::from([(User::Alice, 100)]);
+        for transaction in [
+            create_asset(User::Alice, 0),
+            AccountingTransaction::Mint { minter: User::Alice, asset: 1, amount: 50, nonce: 1 },
+            AccountingTransaction::Transfer {
+                sender: User::Alice,
+                receiver: User::Bob,
+                asset: 1,
+                amount: 20,
+                existence: ExistenceRequirement::KeepAlive,
+                nonce: 2,
+            },
+        ] {
+            state = AccountedCurrency::try_next_state(&state, &transaction).unwrap();
+        }
+
+        assert_eq!(state.balances_of(NATIVE_ASSET), HashMap::from([(User::Alice, 100)]));
+        assert_eq!(state.balances_of(1), HashMap::from([(User::Alice, 30), (User::Bob, 20)]));
+        assert_eq!(state.balance(1, &User::Bob), 20);
+    }
+
+    #[test]
+    fn asset_min_balance_applies_to_transfers() {
+        let mut state = AccountedCurrency::next_state(&State::new(), &create_asset(User::Alice, 0));
+        state = AccountedCurrency::next_state(
+            &state,
+            &AccountingTransaction::Mint { minter: User::Alice, asset: 1, amount: 50, nonce: 1 },
+        );
+        let result = AccountedCurrency::try_next_state(
+            &state,
+            &AccountingTransaction::Transfer {
+                sender: User::Alice,
+                receiver: User::Bob,
+                asset: 1,
+                amount: 4,
+                existence: ExistenceRequirement::AllowDeath,
+                nonce: 2,
+            },
+        );
+
+        assert_eq!(result, Err(AccountingError::BelowExistentialDeposit));
+    }
+
+    fn approve(amount: u64) -> AccountingTransaction {
+        AccountingTransaction::Approve {
+            owner: User::Alice,
+            spender: User::Bob,
+            asset: NATIVE_ASSET,
+            amount,
+            nonce: 0,
+        }
+    }
+
+    fn transfer_from(receiver: User, amount: u64, nonce: u64) -> AccountingTransaction {
+        AccountingTransaction::TransferFrom {
+            spender: User::Bob,
+            owner: User::Alice,
+            receiver,
+            asset: NATIVE_ASSET,
+            amount,
+            nonce,
+        }
+    }
+
+    #[test]
+    fn approve_sets_allowance() {
+        let start = State::from([(User::Alice, 100)]);
+        let end = AccountedCurrency::try_next_state(&start, &approve(30)).unwrap();
+
+        assert_eq!(end.allowance(NATIVE_ASSET, &User::Alice, &User::Bob), 30);
+        assert_eq!(end.allowance(NATIVE_ASSET, &User::Bob, &User::Alice), 0);
+        assert_eq!(end.balances_of(NATIVE_ASSET), HashMap::from([(User::Alice, 100)]));
+    }
+
+    #[test]
+    fn approve_replaces_allowance() {
+        let start = AccountedCurrency::next_state(&State::from([(User::Alice, 100)]), &approve(30));
+        let end = AccountedCurrency::try_next_state(
+            &start,
+            &AccountingTransaction::Approve {
+                owner: User::Alice,
+                spender: User::Bob,
+                asset: NATIVE_ASSET,
+                amount: 0,
+                nonce: 1,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(end.allowance(NATIVE_ASSET, &User::Alice, &User::Bob), 0);
+        assert!(end.allowances.is_empty());
+    }
+
+    fn native_transfer(sender: User, receiver: User, amount: u64, nonce: u64) -> AccountingTransaction {
+        AccountingTransaction::Transfer {
+            sender,
+            receiver,
+            asset: NATIVE_ASSET,
+            amount,
+            existence: ExistenceRequirement::AllowDeath,
+            nonce,
+        }
+    }
+
+    #[test]
+    fn reserve_moves_free_to_reserved() {
+        let mut state = State::from([(User::Alice, 100)]);
+        state.reserve(User::Alice, 40).unwrap();
+
+        assert_eq!(state.balance(NATIVE_ASSET, &User::Alice), 60);
+        assert_eq!(state.reserved(&User::Alice), 40);
+        assert_eq!(state.total_issuance(), 100);
+    }
+
+    #[test]
+    fn reserve_keeps_existential_deposit() {
+        let mut state = State::from([(User::Alice, 100)]).with_existential_deposit(10);
+
+        assert_eq!(state.reserve(User::Alice, 95), Err(AccountingError::WouldReapAccount));
+        assert_eq!(state.reserve(User::Alice, 101), Err(AccountingError::InsufficientBalance));
+        assert_eq!(state.reserved(&User::Alice), 0);
+    }
+
+    #[test]
+    fn unreserve_and_slash_are_capped_by_reserved() {
+        let mut state = State::from([(User::Alice, 100)]);
+        state.reserve(User::Alice, 40).unwrap();
+
+        assert_eq!(state.unreserve(User::Alice, 10), 10);
+        assert_eq!(state.slash_reserved(User::Alice, 50), 30);
+        assert_eq!(state.balance(NATIVE_ASSET, &User::Alice), 70);
+        assert_eq!(state.reserved(&User::Alice), 0);
+        assert_eq!(state.total_issuance(), 70);
+    }
+
+    #[test]
+    fn account_with_reserve_is_not_reaped() {
+        let mut start = State::from([(User::Alice, 100)]);
+        start.reserve(User::Alice, 40).unwrap();
+        let result = AccountedCurrency::try_next_state(&start, &native_transfer(User::Alice, User::Bob, 60, 0));
+
+        assert_eq!(result, Err(AccountingError::WouldReapAccount));
+    }
+
+    #[test]
+    fn lock_freezes_until_expiry() {
+        let lock = AccountingTransaction::Lock { who: User::Alice, amount: 80, until: 10, nonce: 0 };
+        let mut state = AccountedCurrency::try_next_state(&State::from([(User::Alice, 100)]), &lock).unwrap();
 
-    fn next_state(starting_state: &Self::State, transition: &Self::Transition) -> Self::State {
-        let mut new_state = starting_state.clone();
+        assert_eq!(state.usable_balance(NATIVE_ASSET, &User::Alice), 20);
+        let result = AccountedCurrency::try_next_state(&state, &native_transfer(User::Alice, User::Bob, 30, 1));
+        assert_eq!(result, Err(AccountingError::LiquidityRestrictions));
 
-        match transition {
-            AccountingTransaction::Mint { minter, amount } => {
-                if *amount > 0 {
-                    let balance = new_state.entry(*minter).or_insert(0);
-                    *balance += amount;
-                } else {
-                    if let Some(balance) = new_state.get(minter) {
-                        if *balance == 0 {
-                            new_state.remove(minter);
-                        }
-                    }
-                }
-            },
+        state.set_block_height(10);
+        let end = AccountedCurrency::try_next_state(&state, &native_transfer(User::Alice, User::Bob, 30, 1)).unwrap();
+        assert_eq!(end.balances_of(NATIVE_ASSET), HashMap::from([(User::Alice, 70), (User::Bob, 30)]));
+        assert!(end.locks.is_empty());
+    }
 
-            AccountingTransaction::Burn { burner, amount } => {
-                if let Some(balance) = new_state.get_mut(burner) {
-                    if *balance > *amount {
-                        *balance -= amount;
-                    } else {
-                        new_state.remove(burner);
-                    }
-                }
-            },
-            AccountingTransaction::Transfer { sender, receiver, amount } => {
-                if let Some(sender_balance) = new_state.get_mut(sender) {
-                    if *sender_balance > *amount {
-                        *sender_balance -= amount;
-                        let receiver_balance = new_state.entry(*receiver).or_insert(0);
-                        *receiver_balance += amount;
-                    } else if *sender_balance == *amount {
-                        let receiver_balance = new_state.entry(*receiver).or_insert(0);
-                        *receiver_balance += amount;
-                        new_state.remove(sender);
-                    }
-                }
-            }   
-        }
+    #[test]
+    fn overlapping_locks_freeze_the_largest() {
+        let start = State::from([(User::Alice, 100)]);
+        let first = AccountingTransaction::Lock { who: User::Alice, amount: 30, until: 10, nonce: 0 };
+        let second = AccountingTransaction::Lock { who: User::Alice, amount: 50, until: 5, nonce: 1 };
+        let mut state = AccountedCurrency::try_next_state(&start, &first).unwrap();
+        state = AccountedCurrency::try_next_state(&state, &second).unwrap();
 
-        return new_state
+        assert_eq!(state.frozen(NATIVE_ASSET, &User::Alice), 50);
+        state.set_block_height(5);
+        assert_eq!(state.frozen(NATIVE_ASSET, &User::Alice), 30);
     }
-}
 
+    #[test]
+    fn vested_transfer_releases_over_time() {
+        let vested = AccountingTransaction::VestedTransfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 50,
+            per_block: 10,
+            nonce: 0,
+        };
+        let mut state = AccountedCurrency::try_next_state(&State::from([(User::Alice, 100)]), &vested).unwrap();
 
-#[cfg(test)]
-pub mod tests {
+        assert_eq!(state.usable_balance(NATIVE_ASSET, &User::Bob), 0);
+        state.set_block_height(3);
+        assert_eq!(state.usable_balance(NATIVE_ASSET, &User::Bob), 30);
+        let result = AccountedCurrency::try_next_state(&state, &native_transfer(User::Bob, User::Charlie, 40, 0));
+        assert_eq!(result, Err(AccountingError::LiquidityRestrictions));
 
-    use super::*;
+        state.set_block_height(5);
+        assert_eq!(state.vesting(&User::Bob), None);
+        assert_eq!(state.usable_balance(NATIVE_ASSET, &User::Bob), 50);
+    }
 
     #[test]
-    fn mint_creates_account() {
-        let start = HashMap::new();
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Mint {
-                minter: User::Alice,
-                amount: 100,
-            },
+    fn second_vesting_schedule_is_rejected() {
+        let vested = |nonce| AccountingTransaction::VestedTransfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 20,
+            per_block: 1,
+            nonce,
+        };
+        let start = AccountedCurrency::try_next_state(&State::from([(User::Alice, 100)]), &vested(0)).unwrap();
+
+        assert_eq!(
+            AccountedCurrency::try_next_state(&start, &vested(1)),
+            Err(AccountingError::VestingScheduleExists)
         );
-        let expected = HashMap::from([(User::Alice, 100)]);
+    }
+
+    #[test]
+    fn frozen_funds_can_not_be_burned() {
+        let lock = AccountingTransaction::Lock { who: User::Alice, amount: 80, until: 10, nonce: 0 };
+        let start = AccountedCurrency::try_next_state(&State::from([(User::Alice, 100)]), &lock).unwrap();
+        let burn = AccountingTransaction::Burn {
+            burner: User::Alice,
+            asset: NATIVE_ASSET,
+            amount: 100,
+            existence: ExistenceRequirement::AllowDeath,
+            nonce: 1,
+        };
 
-        assert_eq!(end, expected);
+        assert_eq!(AccountedCurrency::try_next_state(&start, &burn), Err(AccountingError::LiquidityRestrictions));
     }
 
     #[test]
-    fn mint_creates_second_account() {
-        let start = HashMap::from([(User::Alice, 100)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Mint {
-                minter: User::Bob,
-                amount: 50,
-            },
-        );
-        let expected = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+    fn transfer_from_spends_allowance() {
+        let start = AccountedCurrency::next_state(&State::from([(User::Alice, 100)]), &approve(30));
+        let end = AccountedCurrency::try_next_state(&start, &transfer_from(User::Charlie, 20, 0)).unwrap();
 
-        assert_eq!(end, expected);
+        assert_eq!(end.balances_of(NATIVE_ASSET), HashMap::from([(User::Alice, 80), (User::Charlie, 20)]));
+        assert_eq!(end.allowance(NATIVE_ASSET, &User::Alice, &User::Bob), 10);
+        assert_eq!(end.nonce(&User::Bob), 1);
+        assert_eq!(end.nonce(&User::Alice), 1);
     }
 
     #[test]
-    fn mint_increases_balance() {
-        let start = HashMap::from([(User::Alice, 100)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Mint {
-                minter: User::Alice,
-                amount: 50,
-            },
-        );
-        let expected = HashMap::from([(User::Alice, 150)]);
+    fn transfer_from_beyond_allowance_fails() {
+        let start = AccountedCurrency::next_state(&State::from([(User::Alice, 100)]), &approve(30));
+        let result = AccountedCurrency::try_next_state(&start, &transfer_from(User::Charlie, 31, 0));
 
-        assert_eq!(end, expected);
+        assert_eq!(result, Err(AccountingError::InsufficientAllowance));
     }
 
     #[test]
-    fn empty_mint() {
-        let start = HashMap::new();
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Mint {
-                minter: User::Alice,
-                amount: 0,
-            },
-        );
-        let expected = HashMap::new();
+    fn transfer_from_without_approval_fails() {
+        let start = State::from([(User::Alice, 100)]);
+        let result = AccountedCurrency::try_next_state(&start, &transfer_from(User::Bob, 1, 0));
 
-        assert_eq!(end, expected);
+        assert_eq!(result, Err(AccountingError::InsufficientAllowance));
     }
 
     #[test]
-    fn simple_burn() {
-        let start = HashMap::from([(User::Alice, 100)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Burn {
-                burner: User::Alice,
-                amount: 50,
-            },
-        );
-        let expected = HashMap::from([(User::Alice, 50)]);
-    
-        assert_eq!(end, expected);
+    fn failed_transfer_from_keeps_allowance() {
+        // Alice approved more than she holds, so the balance check fails after the allowance one.
+        let start = AccountedCurrency::next_state(&State::from([(User::Alice, 100)]), &approve(500));
+        let result = AccountedCurrency::try_next_state(&start, &transfer_from(User::Charlie, 200, 0));
+        let end = AccountedCurrency::next_state(&start, &transfer_from(User::Charlie, 200, 0));
+
+        assert_eq!(result, Err(AccountingError::InsufficientBalance));
+        assert_eq!(end, start);
+        assert_eq!(end.allowance(NATIVE_ASSET, &User::Alice, &User::Bob), 500);
     }
-    
+
     #[test]
-    fn burn_no_existential_deposit_left() {
-        let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Burn {
-                burner: User::Bob,
-                amount: 50,
-            },
-        );
-        let expected = HashMap::from([(User::Alice, 100)]);
-    
-        assert_eq!(end, expected);
+    fn transfer_from_cannot_reap_owner() {
+        let start = AccountedCurrency::next_state(&State::from([(User::Alice, 100)]), &approve(100));
+        let result = AccountedCurrency::try_next_state(&start, &transfer_from(User::Charlie, 100, 0));
+
+        assert_eq!(result, Err(AccountingError::WouldReapAccount));
     }
-    
+
     #[test]
-    fn non_registered_burner() {
-        let start = HashMap::from([(User::Alice, 100)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Burn {
-                burner: User::Bob,
-                amount: 50,
-            },
-        );
-        let expected = HashMap::from([(User::Alice, 100)]);
-    
-        assert_eq!(end, expected);
+    fn spent_allowance_is_removed() {
+        let start = AccountedCurrency::next_state(&State::from([(User::Alice, 100)]), &approve(30));
+        let end = AccountedCurrency::try_next_state(&start, &transfer_from(User::Bob, 30, 0)).unwrap();
+
+        assert_eq!(end.balances_of(NATIVE_ASSET), HashMap::from([(User::Alice, 70), (User::Bob, 30)]));
+        assert!(end.allowances.is_empty());
     }
-    
+
+    fn with_fees() -> State {
+        State::from([(User::Alice, 100)]).with_fees(FeeConfig { base_fee: 3, weight_fee: 2, block_reward: 10 })
+    }
+
     #[test]
-    fn burn_more_than_balance() {
-        let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
-        let end2 = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Burn {
-                burner: User::Bob,
-                amount: 100,
-            },
-        );
-        let expected2 = HashMap::from([(User::Alice, 100)]);
-    
-        assert_eq!(end2, expected2);
+    fn fee_is_charged_to_signer() {
+        let transaction = native_transfer(User::Alice, User::Bob, 30, 0);
+        let end = AccountedCurrency::try_next_state(&with_fees(), &transaction).unwrap();
+
+        assert_eq!(with_fees().fee(&transaction), 7);
+        assert_eq!(end.balances_of(NATIVE_ASSET), HashMap::from([(User::Alice, 63), (User::Bob, 30)]));
+        assert_eq!(end.pending_fees(), 7);
+        assert_eq!(end.total_issuance(), 100);
     }
 
     #[test]
-    fn empty_burn() {
-        let start = HashMap::from([(User::Alice, 100)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Burn {
-                burner: User::Alice,
-                amount: 0,
-            },
-        );
-        let expected = HashMap::from([(User::Alice, 100)]);
+    fn transfer_and_fee_have_to_fit_in_the_balance() {
+        let result = AccountedCurrency::try_next_state(&with_fees(), &native_transfer(User::Alice, User::Bob, 94, 0));
 
-        assert_eq!(end, expected);
-}
+        assert_eq!(result, Err(AccountingError::InsufficientBalance));
+    }
 
     #[test]
-    fn burner_does_not_exist() {
-        let start = HashMap::from([(User::Alice, 100)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Burn {
-                burner: User::Bob,
-                amount: 50,
-            },
-        );
-        let expected = HashMap::from([(User::Alice, 100)]);
+    fn fee_can_not_reap_signer() {
+        let start = State::from([(User::Alice, 5)]).with_fees(with_fees().fees());
+        let lock = AccountingTransaction::Lock { who: User::Alice, amount: 1, until: 10, nonce: 0 };
+        assert_eq!(AccountedCurrency::try_next_state(&start, &lock), Err(AccountingError::CannotPayFee));
+    }
+
+    #[test]
+    fn author_collects_fees_and_reward() {
+        let context = BlockContext { height: 1, author: Some(User::Charlie) };
+        let mut state = with_fees();
+        AccountedCurrency::on_initialize(&mut state, &context);
+        state = AccountedCurrency::try_next_state(&state, &native_transfer(User::Alice, User::Bob, 30, 0)).unwrap();
+        let undo = AccountedCurrency::hook_undo(&state, &context);
+        AccountedCurrency::on_finalize(&mut state, &context);
 
-        assert_eq!(end, expected);
+        assert_eq!(state.block_height(), 1);
+        assert_eq!(state.balance(NATIVE_ASSET, &User::Charlie), 17);
+        assert_eq!(state.pending_fees(), 0);
+        assert_eq!(state.total_issuance(), 110);
+        assert_eq!(AccountedCurrency::hook_events(&undo, &context, &state), vec![
+            Event::Minted { asset: NATIVE_ASSET, to: User::Charlie, amount: 10 },
+            Event::FeesPaid { to: User::Charlie, amount: 7 },
+        ]);
     }
 
     #[test]
-    fn simple_transfer() {
-        let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Transfer {
-                sender: User::Alice,
-                receiver: User::Bob,
-                amount: 10,
-            },
-        );
-        let expected = HashMap::from([(User::Alice, 90), (User::Bob, 60)]);
+    fn fees_without_author_are_burned() {
+        let context = BlockContext { height: 0, author: None };
+        let mut state = AccountedCurrency::try_next_state(&with_fees(), &native_transfer(User::Alice, User::Bob, 30, 0)).unwrap();
+        let undo = AccountedCurrency::hook_undo(&state, &context);
+        AccountedCurrency::on_finalize(&mut state, &context);
 
-        assert_eq!(end, expected);
+        assert_eq!(state.balances_of(NATIVE_ASSET), HashMap::from([(User::Alice, 63), (User::Bob, 30)]));
+        assert_eq!(state.total_issuance(), 93);
+        assert_eq!(AccountedCurrency::hook_events(&undo, &context, &state), vec![Event::FeesBurned { amount: 7 }]);
+    }
 
-        let start = HashMap::from([(User::Alice, 90), (User::Bob, 60)]);
-        let end1 = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Transfer {
-                sender: User::Bob,
-                receiver: User::Alice,
-                amount: 50,
-            },
-        );
-        let expected1 = HashMap::from([(User::Alice, 140), (User::Bob, 10)]);
+    fn htlc_lock(amount: u64, expiry: u64) -> AccountingTransaction {
+        AccountingTransaction::HtlcLock {
+            sender: User::Alice,
+            receiver: User::Bob,
+            asset: NATIVE_ASSET,
+            amount,
+            hash_lock: script::hash_of(1234),
+            expiry,
+            nonce: 0,
+        }
+    }
 
-        assert_eq!(end1, expected1);
+    fn locked_htlc() -> State {
+        let start = State::from([(User::Alice, 100), (User::Bob, 10)]);
+        Checked::<AccountedCurrency>::try_next_state(&start, &htlc_lock(30, 20)).unwrap()
     }
 
     #[test]
-    fn send_to_same_user() {
-        let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Transfer {
-                sender: User::Bob,
-                receiver: User::Bob,
-                amount: 10,
-            },
-        );
-        let expected = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+    fn htlc_lock_escrows_funds() {
+        let state = locked_htlc();
+        let htlc = state.htlc(Htlc::id(User::Alice, 0)).unwrap();
 
-        assert_eq!(end, expected);
+        assert_eq!(htlc.amount, 30);
+        assert_eq!(state.balance(NATIVE_ASSET, &User::Alice), 70);
+        assert_eq!(state.total_issuance(), 110);
     }
 
     #[test]
-    fn insufficient_balance_transfer() {
-        let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Transfer {
-                sender: User::Bob,
-                receiver: User::Alice,
-                amount: 60,
-            },
-        );
-        let expected = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+    fn htlc_lock_must_not_be_expired() {
+        let mut start = State::from([(User::Alice, 100)]);
+        start.set_block_height(20);
 
-        assert_eq!(end, expected);
+        assert_eq!(AccountedCurrency::try_next_state(&start, &htlc_lock(30, 20)), Err(AccountingError::HtlcExpired));
     }
 
     #[test]
-    fn sender_not_registered() {
-        let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Transfer {
-                sender: User::Charlie,
-                receiver: User::Alice,
-                amount: 50,
-            },
-        );
-        let expected = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+    fn htlc_lock_keeps_sender_alive() {
+        let start = State::from([(User::Alice, 100)]);
 
-        assert_eq!(end, expected);
+        assert_eq!(AccountedCurrency::try_next_state(&start, &htlc_lock(100, 20)), Err(AccountingError::WouldReapAccount));
     }
 
     #[test]
-    fn receiver_not_registered() {
-        let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Transfer {
-                sender: User::Alice,
-                receiver: User::Charlie,
-                amount: 50,
-            },
+    fn htlc_claim_with_preimage() {
+        let claim = |preimage| AccountingTransaction::HtlcClaim {
+            claimer: User::Bob,
+            id: Htlc::id(User::Alice, 0),
+            preimage,
+            nonce: 0,
+        };
+
+        assert_eq!(AccountedCurrency::try_next_state(&locked_htlc(), &claim(4321)), Err(AccountingError::WrongPreimage));
+        let end = Checked::<AccountedCurrency>::try_next_state(&locked_htlc(), &claim(1234)).unwrap();
+
+        assert_eq!(end.htlc(Htlc::id(User::Alice, 0)), None);
+        assert_eq!(end.balances_of(NATIVE_ASSET), HashMap::from([(User::Alice, 70), (User::Bob, 40)]));
+        assert_eq!(
+            AccountedCurrency::events(&locked_htlc(), &claim(1234), &end),
+            vec![Event::Transferred { asset: NATIVE_ASSET, from: User::Alice, to: User::Bob, amount: 30 }]
         );
-        let expected = HashMap::from([(User::Alice, 50), (User::Bob, 50), (User::Charlie, 50)]);
+    }
+
+    #[test]
+    fn htlc_claim_only_by_receiver_before_expiry() {
+        let claim = |claimer| AccountingTransaction::HtlcClaim {
+            claimer,
+            id: Htlc::id(User::Alice, 0),
+            preimage: 1234,
+            nonce: if claimer == User::Alice { 1 } else { 0 },
+        };
+        let mut expired = locked_htlc();
+        expired.set_block_height(20);
 
-        assert_eq!(end, expected);
+        assert_eq!(AccountedCurrency::try_next_state(&locked_htlc(), &claim(User::Alice)), Err(AccountingError::NotHtlcParty));
+        assert_eq!(AccountedCurrency::try_next_state(&expired, &claim(User::Bob)), Err(AccountingError::HtlcExpired));
     }
 
     #[test]
-    fn sender_to_empty_balance() {
-        let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Transfer {
-                sender: User::Bob,
-                receiver: User::Alice,
-                amount: 50,
+    fn htlc_refund_after_expiry() {
+        let refund = |sender, id| AccountingTransaction::HtlcRefund { sender, id, nonce: 1 };
+        let id = Htlc::id(User::Alice, 0);
+        let mut state = locked_htlc();
+
+        assert_eq!(AccountedCurrency::try_next_state(&state, &refund(User::Alice, id)), Err(AccountingError::HtlcNotExpired));
+        state.set_block_height(20);
+        assert_eq!(AccountedCurrency::try_next_state(&state, &refund(User::Alice, 42)), Err(AccountingError::UnknownHtlc));
+
+        let end = Checked::<AccountedCurrency>::try_next_state(&state, &refund(User::Alice, id)).unwrap();
+        assert_eq!(end.balance(NATIVE_ASSET, &User::Alice), 100);
+        assert_eq!(end.htlc(id), None);
+    }
+
+    fn arb_user() -> impl Strategy<Value = User> {
+        prop_oneof![Just(User::Alice), Just(User::Bob), Just(User::Charlie)]
+    }
+
+    fn arb_transaction() -> impl Strategy<Value = AccountingTransaction> {
+        (0..11u8, arb_user(), arb_user(), arb_user(), 0..3 as AssetId, 0..200u64, any::<bool>()).prop_map(
+            |(kind, first, second, third, asset, amount, keep_alive)| {
+                let existence = if keep_alive {
+                    ExistenceRequirement::KeepAlive
+                } else {
+                    ExistenceRequirement::AllowDeath
+                };
+
+                match kind {
+                    0 => AccountingTransaction::CreateAsset {
+                        creator: first,
+                        decimals: 0,
+                        name: "Test".into(),
+                        symbol: "TST".into(),
+                        min_balance: amount % 20,
+                        nonce: 0,
+                    },
+                    1 => AccountingTransaction::Mint { minter: first, asset, amount, nonce: 0 },
+                    2 => AccountingTransaction::Burn { burner: first, asset, amount, existence, nonce: 0 },
+                    3 => AccountingTransaction::Transfer {
+                        sender: first,
+                        receiver: second,
+                        asset,
+                        amount,
+                        existence,
+                        nonce: 0,
+                    },
+                    4 => AccountingTransaction::Approve { owner: first, spender: second, asset, amount, nonce: 0 },
+                    5 => AccountingTransaction::TransferFrom {
+                        spender: first,
+                        owner: second,
+                        receiver: third,
+                        asset,
+                        amount,
+                        nonce: 0,
+                    },
+                    6 => AccountingTransaction::Lock { who: first, amount, until: amount % 20, nonce: 0 },
+                    7 => AccountingTransaction::VestedTransfer {
+                        sender: first,
+                        receiver: second,
+                        amount,
+                        per_block: amount % 20,
+                        nonce: 0,
+                    },
+                    8 => AccountingTransaction::HtlcLock {
+                        sender: first,
+                        receiver: second,
+                        asset,
+                        amount,
+                        hash_lock: script::hash_of(amount % 3),
+                        expiry: amount % 20,
+                        nonce: 0,
+                    },
+                    9 => AccountingTransaction::HtlcClaim {
+                        claimer: first,
+                        id: Htlc::id(second, amount % 5),
+                        preimage: amount % 3,
+                        nonce: 0,
+                    },
+                    _ => AccountingTransaction::HtlcRefund { sender: first, id: Htlc::id(first, amount % 5), nonce: 0 },
+                }
             },
-        );
-        let expected = HashMap::from([(User::Alice, 150)]);
+        )
+    }
 
-        assert_eq!(end, expected);
+    fn with_expected_nonce(state: &State, mut transaction: AccountingTransaction) -> AccountingTransaction {
+        let expected = state.nonce(&transaction.signer());
+
+        match &mut transaction {
+            AccountingTransaction::CreateAsset { nonce, .. }
+            | AccountingTransaction::Mint { nonce, .. }
+            | AccountingTransaction::Burn { nonce, .. }
+            | AccountingTransaction::Transfer { nonce, .. }
+            | AccountingTransaction::Approve { nonce, .. }
+            | AccountingTransaction::TransferFrom { nonce, .. }
+            | AccountingTransaction::Lock { nonce, .. }
+            | AccountingTransaction::VestedTransfer { nonce, .. }
+            | AccountingTransaction::HtlcLock { nonce, .. }
+            | AccountingTransaction::HtlcClaim { nonce, .. }
+            | AccountingTransaction::HtlcRefund { nonce, .. } => *nonce = expected,
+        }
+        transaction
     }
 
     #[test]
-    fn transfer() {
-        let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
-        let end = AccountedCurrency::next_state(
-            &start,
-            &AccountingTransaction::Transfer {
-                sender: User::Bob,
-                receiver: User::Charlie,
-                amount: 50,
-            },
-        );
-        let expected = HashMap::from([(User::Alice, 100), (User::Charlie, 50)]);
+    fn accepted_mint_has_to_raise_the_supply() {
+        let start = State::from([(User::Alice, 100)]);
+        let mint = AccountingTransaction::Mint { minter: User::Alice, asset: NATIVE_ASSET, amount: 10, nonce: 0 };
+        let minted = AccountedCurrency::try_next_state(&start, &mint).unwrap();
+        assert_eq!(transactions_conserve_balances(&start, &mint, &minted), Ok(()));
+        assert_eq!(transactions_conserve_balances(&start, &mint, &start), Ok(()));
+
+        // Only the nonce moved, so the mint was accepted without minting anything.
+        let mut unminted = start.clone();
+        unminted.use_nonce(User::Alice, 0).unwrap();
+        assert!(transactions_conserve_balances(&start, &mint, &unminted).is_err());
+    }
+
+    #[test]
+    fn random_transactions_keep_every_invariant() {
+        let fees = FeeConfig { base_fee: 1, weight_fee: 1, block_reward: 10 };
+        let genesis = (1..20u64).prop_map(move |existential_deposit| {
+            State::from([(User::Alice, 500), (User::Bob, 500)])
+                .with_existential_deposit(existential_deposit)
+                .with_fees(fees)
+        });
 
-        assert_eq!(end, expected);
+        Harness::<AccountedCurrency, _>::new(genesis, arb_transaction(), with_expected_nonce)
+            .invariants(AccountedCurrency::invariants())
+            .check();
     }
-}
\ No newline at end of file
+
+    proptest! {
+        #[test]
+        fn reverting_blocks_restores_every_earlier_state(
+            transactions in proptest::collection::vec(
+                (arb_transaction(), proptest::option::of(arb_user())),
+                0..30,
+            ),
+        ) {
+            let fees = FeeConfig { base_fee: 1, weight_fee: 1, block_reward: 10 };
+            let mut state = State::from([(User::Alice, 500), (User::Bob, 500)]).with_fees(fees);
+            let mut journal = Journal::<AccountedCurrency>::new();
+            let mut history = vec![state.clone()];
+
+            for (height, (transaction, author)) in transactions.into_iter().enumerate() {
+                let context = BlockContext { height: height as u64 + 1, author };
+                let mut undos = vec![AccountedCurrency::hook_undo(&state, &context)];
+                AccountedCurrency::on_initialize(&mut state, &context);
+
+                // Applying in place has to agree with the copying `try_next_state`.
+                let transaction = with_expected_nonce(&state, transaction);
+                let expected = AccountedCurrency::try_next_state(&state, &transaction);
+                let before = state.clone();
+                match AccountedCurrency::apply_with_undo(&mut state, &transaction) {
+                    Ok(undo) => undos.push(undo),
+                    Err(error) => {
+                        prop_assert_eq!(&state, &before);
+                        prop_assert_eq!(Err(error), expected.clone().map(|_| ()));
+                    }
+                }
+                prop_assert_eq!(expected.unwrap_or(before), state.clone());
+
+                undos.push(AccountedCurrency::hook_undo(&state, &context));
+                AccountedCurrency::on_finalize(&mut state, &context);
+
+                journal.push_block(undos);
+                history.push(state.clone());
+            }
+
+            history.pop();
+            while journal.revert_block(&mut state) {
+                prop_assert_eq!(&state, &history.pop().unwrap());
+            }
+            prop_assert!(history.is_empty());
+        }
+
+        #[test]
+        fn random_transactions_conserve_issuance(
+            existential_deposit in 1..20u64,
+            (base_fee, weight_fee, block_reward) in (0..5u64, 0..3u64, 0..50u64),
+            transactions in proptest::collection::vec(
+                (arb_transaction(), proptest::option::of(arb_user())),
+                0..50,
+            ),
+        ) {
+            let fees = FeeConfig { base_fee, weight_fee, block_reward };
+            let mut state = State::new().with_existential_deposit(existential_deposit).with_fees(fees);
+
+            // Every transaction gets a block of its own.
+            for (height, (transaction, author)) in transactions.into_iter().enumerate() {
+                let context = BlockContext { height: height as u64, author };
+                AccountedCurrency::on_initialize(&mut state, &context);
+
+                let transaction = with_expected_nonce(&state, transaction);
+                state = Checked::<AccountedCurrency>::next_state(&state, &transaction);
+
+                let issued_before = state.total_issuance();
+                let paid = state.pending_fees();
+                AccountedCurrency::on_finalize(&mut state, &context);
+                prop_assert_eq!(state.pending_fees(), 0);
+                prop_assert!(
+                    state.total_issuance() == issued_before + block_reward
+                        || state.total_issuance() == issued_before - paid
+                );
+            }
+        }
+    }
+}
@@ -1,5 +1,7 @@
 #![allow(dead_code)]
-use super::StateMachine;
+use super::events::Event;
+use super::{Emitter, StateMachine};
+use crate::codec::{Decode, DecodeError, Encode};
 
 #[derive(Hash, Debug, PartialEq, Eq, Clone)]
 pub enum Key {
@@ -10,6 +12,7 @@ pub enum Key {
     Enter
 }
 
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Action {
     SwipeCard(u64),
     PressKey(Key)
@@ -30,35 +33,98 @@ pub struct Atm {
     keystroke_register: Vec<Key>
 }
 
-pub fn key_to_digit(keys: &Vec<Key>) -> u64 {
+impl Atm {
+    /// An idle machine holding `cash_inside`.
+    pub fn new(cash_inside: u64) -> Self {
+        Atm { cash_inside, expected_pin_hash: Auth::Waiting, keystroke_register: Vec::new() }
+    }
+}
+
+impl Encode for Key {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        (self.clone() as u8).encode_to(out);
+    }
+}
+
+impl Decode for Key {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(Key::One),
+            1 => Ok(Key::Two),
+            2 => Ok(Key::Three),
+            3 => Ok(Key::Four),
+            4 => Ok(Key::Enter),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for Action {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Action::SwipeCard(pin_hash) => {
+                0u8.encode_to(out);
+                pin_hash.encode_to(out);
+            }
+            Action::PressKey(key) => {
+                1u8.encode_to(out);
+                key.encode_to(out);
+            }
+        }
+    }
+}
+
+impl Decode for Action {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(Action::SwipeCard(u64::decode_from(input)?)),
+            1 => Ok(Action::PressKey(Key::decode_from(input)?)),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+/// Reads the pressed keys as a decimal number, `None` if it does not fit in a `u64`.
+pub fn key_to_digit(keys: &Vec<Key>) -> Option<u64> {
     let mut number: u64 = 0;
 
     for key in keys {
         number = match key {
             Key::Enter => number, 
-            _ => number * 10 + match key {
+            _ => number.checked_mul(10)?.checked_add(match key {
                 Key::One => 1,
                 Key::Two => 2,
                 Key::Three => 3,
                 Key::Four => 4,
                 _ => 0,
-            },
+            })?,
         };
     }
 
-    number
+    Some(number)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AtmError {
+    /// The typed withdrawal amount does not fit in a `u64`.
+    AmountOverflow,
 }
 
 impl StateMachine for Atm {
     type State = Self;
     type Transition = Action;
+    type Error = AtmError;
 
     fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
-        match t {
+        Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state(starting_state: &Self::State, t: &Self::Transition) -> Result<Self::State, Self::Error> {
+        let new_state = match t {
             Action::SwipeCard(i) => {
                 match starting_state.expected_pin_hash {
                     Auth::Waiting => {
-                        return Self {
+                        Self {
                             cash_inside: starting_state.cash_inside,
                             expected_pin_hash: Auth::Authenticating(*i),
                             keystroke_register: starting_state.keystroke_register.clone()
@@ -66,14 +132,14 @@ impl StateMachine for Atm {
                         }
                     }
                     Auth::Authenticating(pin_hash) => {
-                        return Self {
+                        Self {
                             cash_inside: starting_state.cash_inside,
                             expected_pin_hash: Auth::Authenticating(pin_hash),
                             keystroke_register: starting_state.keystroke_register.clone()
                         }
                     }
                     Auth::Authenticated => {
-                        return Self {
+                        Self {
                             cash_inside: starting_state.cash_inside,
                             expected_pin_hash: Auth::Authenticated,
                             keystroke_register: starting_state.keystroke_register.clone()
@@ -84,7 +150,7 @@ impl StateMachine for Atm {
             Action::PressKey(i) => {
                 match starting_state.expected_pin_hash {
                     Auth::Waiting => {
-                        return Self {
+                        Self {
                             cash_inside: starting_state.cash_inside,
                             expected_pin_hash: Auth::Waiting,
                             keystroke_register: starting_state.keystroke_register.clone()
@@ -99,19 +165,19 @@ impl StateMachine for Atm {
                         }
 
                         if *i == Key::Enter && pin_hash != crate::hash(&original) {
-                            return Self {
+                            Self {
                                 cash_inside: starting_state.cash_inside,
                                 expected_pin_hash: Auth::Waiting,
                                 keystroke_register: Vec::new()
                             }
                         } else if pin_hash == 1234 {
-                            return Self {
+                            Self {
                                 cash_inside: starting_state.cash_inside,
                                 expected_pin_hash: Auth::Authenticating(1234),
                                 keystroke_register: original
                             }
                         } else {
-                            return Self {
+                            Self {
                                 cash_inside: starting_state.cash_inside,
                                 expected_pin_hash: Auth::Authenticated,
                                 keystroke_register: Vec::new()
@@ -124,23 +190,24 @@ impl StateMachine for Atm {
                         
                         if *i != Key::Enter {
                             original.push(i.clone());
-                            return Self {
+                            return Ok(Self {
                                 cash_inside: starting_state.cash_inside,
                                 expected_pin_hash: Auth::Authenticated,
                                 keystroke_register: original
-                            }
+                            })
                         }
 
+                        let amount = key_to_digit(&original).ok_or(AtmError::AmountOverflow)?;
 
-                        if key_to_digit(&original) > starting_state.cash_inside {
-                            return Self {
+                        if amount > starting_state.cash_inside {
+                            Self {
                                 cash_inside: starting_state.cash_inside,
                                 expected_pin_hash: Auth::Waiting,
                                 keystroke_register: Vec::new()
                             }
                         } else {
-                            return Self {
-                                cash_inside: starting_state.cash_inside - key_to_digit(&original),
+                            Self {
+                                cash_inside: starting_state.cash_inside - amount,
                                 expected_pin_hash: Auth::Waiting,
                                 keystroke_register: Vec::new()
                             }
@@ -149,6 +216,19 @@ impl StateMachine for Atm {
                     }
                 }
             }
+        };
+
+        Ok(new_state)
+    }
+}
+
+impl Emitter for Atm {
+    type Event = Event;
+
+    fn events(before: &Atm, _transition: &Action, after: &Atm) -> Vec<Event> {
+        match before.cash_inside.checked_sub(after.cash_inside) {
+            Some(amount) if amount > 0 => vec![Event::CashDispensed { amount }],
+            _ => Vec::new(),
         }
     }
 }
@@ -342,6 +422,7 @@ pub mod tests {
         };
 
         assert_eq!(end, expected);
+        assert_eq!(Atm::events(&start, &Action::PressKey(Key::Enter), &end), vec![]);
     }
 
     #[test]
@@ -359,6 +440,39 @@ pub mod tests {
         };
 
         assert_eq!(end, expected);
+        assert_eq!(Atm::events(&start, &Action::PressKey(Key::Enter), &end), vec![Event::CashDispensed { amount: 1 }]);
+    }
+
+    #[test]
+    fn withdraw_amount_overflowing_u64_fails() {
+        // 21 digits is more than a u64 can hold.
+        let start = Atm {
+            cash_inside: u64::MAX,
+            expected_pin_hash: Auth::Authenticated,
+            keystroke_register: vec![Key::Four; 21],
+        };
+        let result = Atm::try_next_state(&start, &Action::PressKey(Key::Enter));
+        assert_eq!(result, Err(AtmError::AmountOverflow));
+
+        let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
+        assert_eq!(end, start);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn withdraw_everything_from_full_atm() {
+        let start = Atm {
+            cash_inside: u64::MAX,
+            expected_pin_hash: Auth::Authenticated,
+            keystroke_register: vec![Key::One; 20],
+        };
+        let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
+        let expected = Atm {
+            cash_inside: u64::MAX - 11_111_111_111_111_111_111,
+            expected_pin_hash: Auth::Waiting,
+            keystroke_register: Vec::new(),
+        };
+
+        assert_eq!(end, expected);
+    }
+
+}
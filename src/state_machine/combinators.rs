@@ -0,0 +1,324 @@
+#![allow(dead_code)]
+
+//! Builds bigger state machines out of smaller ones.
+
+use super::{BlockContext, BlockHooks, Emitter, StateMachine};
+use std::marker::PhantomData;
+
+/// One of two things, e.g. a transition for the left or the right machine of a `Product`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+/// Runs `A` and `B` side by side. Every transition goes to exactly one of them and leaves
+/// the other one's state as it was.
+pub struct Product<A, B>(PhantomData<(A, B)>);
+
+impl<A, B> StateMachine for Product<A, B>
+where
+    A: StateMachine,
+    B: StateMachine,
+    A::State: Clone,
+    B::State: Clone,
+{
+    type State = (A::State, B::State);
+    type Transition = Either<A::Transition, B::Transition>;
+    type Error = Either<A::Error, B::Error>;
+
+    fn next_state(starting_state: &Self::State, transition: &Self::Transition) -> Self::State {
+        let (a, b) = starting_state;
+
+        match transition {
+            Either::Left(t) => (A::next_state(a, t), b.clone()),
+            Either::Right(t) => (a.clone(), B::next_state(b, t)),
+        }
+    }
+
+    fn try_next_state(starting_state: &Self::State, transition: &Self::Transition) -> Result<Self::State, Self::Error> {
+        let (a, b) = starting_state;
+
+        match transition {
+            Either::Left(t) => Ok((A::try_next_state(a, t).map_err(Either::Left)?, b.clone())),
+            Either::Right(t) => Ok((a.clone(), B::try_next_state(b, t).map_err(Either::Right)?)),
+        }
+    }
+
+    // Only the component the transition goes to is touched, so nothing is copied.
+    fn apply(state: &mut Self::State, transition: &Self::Transition) -> Result<(), Self::Error> {
+        match transition {
+            Either::Left(t) => A::apply(&mut state.0, t).map_err(Either::Left),
+            Either::Right(t) => B::apply(&mut state.1, t).map_err(Either::Right),
+        }
+    }
+
+    fn human_name() -> String {
+        format!("{} and {}", A::human_name(), B::human_name())
+    }
+}
+
+/// Both components see every block, `A` first.
+impl<A, B> BlockHooks for Product<A, B>
+where
+    A: BlockHooks,
+    B: BlockHooks,
+    A::State: Clone,
+    B::State: Clone,
+{
+    fn on_initialize(state: &mut Self::State, context: &BlockContext) {
+        A::on_initialize(&mut state.0, context);
+        B::on_initialize(&mut state.1, context);
+    }
+
+    fn on_finalize(state: &mut Self::State, context: &BlockContext) {
+        A::on_finalize(&mut state.0, context);
+        B::on_finalize(&mut state.1, context);
+    }
+}
+
+/// Why a `Sum` rejected a transition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SumError<L, R> {
+    Left(L),
+    Right(R),
+    /// The transition is meant for the machine the state is not in.
+    Mismatched,
+}
+
+/// Is either `A` or `B`, and only takes the transitions of the machine it currently is.
+pub struct Sum<A, B>(PhantomData<(A, B)>);
+
+impl<A, B> StateMachine for Sum<A, B>
+where
+    A: StateMachine,
+    B: StateMachine,
+    A::State: Clone,
+    B::State: Clone,
+{
+    type State = Either<A::State, B::State>;
+    type Transition = Either<A::Transition, B::Transition>;
+    type Error = SumError<A::Error, B::Error>;
+
+    fn next_state(starting_state: &Self::State, transition: &Self::Transition) -> Self::State {
+        Self::try_next_state(starting_state, transition).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state(starting_state: &Self::State, transition: &Self::Transition) -> Result<Self::State, Self::Error> {
+        match (starting_state, transition) {
+            (Either::Left(s), Either::Left(t)) => A::try_next_state(s, t).map(Either::Left).map_err(SumError::Left),
+            (Either::Right(s), Either::Right(t)) => B::try_next_state(s, t).map(Either::Right).map_err(SumError::Right),
+            _ => Err(SumError::Mismatched),
+        }
+    }
+
+    fn apply(state: &mut Self::State, transition: &Self::Transition) -> Result<(), Self::Error> {
+        match (state, transition) {
+            (Either::Left(s), Either::Left(t)) => A::apply(s, t).map_err(SumError::Left),
+            (Either::Right(s), Either::Right(t)) => B::apply(s, t).map_err(SumError::Right),
+            _ => Err(SumError::Mismatched),
+        }
+    }
+
+    fn human_name() -> String {
+        format!("{} or {}", A::human_name(), B::human_name())
+    }
+}
+
+/// Runs `A` and turns every event it emits into a transition of `B`. When `B` rejects one
+/// of them, the whole transition is rejected and neither state changes.
+pub struct Chained<A, B>(PhantomData<(A, B)>);
+
+impl<A, B> StateMachine for Chained<A, B>
+where
+    A: Emitter,
+    B: StateMachine,
+    A::State: Clone,
+    B::State: Clone,
+    A::Event: Into<B::Transition>,
+{
+    type State = (A::State, B::State);
+    type Transition = A::Transition;
+    type Error = Either<A::Error, B::Error>;
+
+    fn next_state(starting_state: &Self::State, transition: &Self::Transition) -> Self::State {
+        Self::try_next_state(starting_state, transition).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state(starting_state: &Self::State, transition: &Self::Transition) -> Result<Self::State, Self::Error> {
+        let (a, b) = starting_state;
+        let new_a = A::try_next_state(a, transition).map_err(Either::Left)?;
+        let mut new_b = b.clone();
+
+        for event in A::events(a, transition, &new_a) {
+            B::apply(&mut new_b, &event.into()).map_err(Either::Right)?;
+        }
+
+        Ok((new_a, new_b))
+    }
+
+    fn human_name() -> String {
+        format!("{} then {}", A::human_name(), B::human_name())
+    }
+}
+
+/// A chain emits what its last machine emitted, so chains can be chained again.
+impl<A, B> Emitter for Chained<A, B>
+where
+    A: Emitter,
+    B: Emitter,
+    A::State: Clone,
+    B::State: Clone,
+    A::Event: Into<B::Transition>,
+{
+    type Event = B::Event;
+
+    fn events(before: &Self::State, transition: &Self::Transition, after: &Self::State) -> Vec<Self::Event> {
+        let mut b = before.1.clone();
+        let mut events = Vec::new();
+
+        for event in A::events(&before.0, transition, &after.0) {
+            let t = event.into();
+            let next = B::try_next_state(&b, &t).unwrap_or_else(|_| b.clone());
+            events.extend(B::events(&b, &t, &next));
+            b = next;
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::state_machine::accounted_currency::{self, AccountedCurrency};
+    use crate::state_machine::laundry::{ClothesAction, ClothesError, ClothesMachine, ClothesState};
+    use crate::state_machine::swithces::LightSwitch;
+    use crate::state_machine::utxo_currency::{self, DigitalCashSystem};
+    use std::convert::Infallible;
+
+    /// A decimal digit that carries into the next one when it wraps around.
+    struct Digit;
+
+    struct Tick;
+
+    struct Carry;
+
+    impl From<Carry> for Tick {
+        fn from(_: Carry) -> Self {
+            Tick
+        }
+    }
+
+    impl StateMachine for Digit {
+        type State = u8;
+        type Transition = Tick;
+        type Error = Infallible;
+
+        fn next_state(starting_state: &u8, _transition: &Tick) -> u8 {
+            (starting_state + 1) % 10
+        }
+
+        fn human_name() -> String {
+            "Digit".into()
+        }
+    }
+
+    impl Emitter for Digit {
+        type Event = Carry;
+
+        fn events(before: &u8, _transition: &Tick, after: &u8) -> Vec<Carry> {
+            if after < before { vec![Carry] } else { Vec::new() }
+        }
+    }
+
+    /// Counts carries up to a limit and rejects any beyond it.
+    struct Limit;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Full;
+
+    impl StateMachine for Limit {
+        type State = u8;
+        type Transition = Tick;
+        type Error = Full;
+
+        fn next_state(starting_state: &u8, transition: &Tick) -> u8 {
+            Self::try_next_state(starting_state, transition).unwrap_or(*starting_state)
+        }
+
+        fn try_next_state(starting_state: &u8, _transition: &Tick) -> Result<u8, Full> {
+            if *starting_state == 1 { Err(Full) } else { Ok(starting_state + 1) }
+        }
+    }
+
+    #[test]
+    fn product_routes_transitions() {
+        type Machine = Product<LightSwitch, ClothesMachine>;
+        let start = (false, ClothesState::Clean(3));
+
+        let switched = Machine::next_state(&start, &Either::Left(()));
+        assert_eq!(switched, (true, ClothesState::Clean(3)));
+        let worn = Machine::try_next_state(&switched, &Either::Right(ClothesAction::Wear));
+        assert_eq!(worn, Ok((true, ClothesState::Dirty(2))));
+    }
+
+    #[test]
+    fn product_reports_component_error() {
+        type Machine = Product<LightSwitch, ClothesMachine>;
+        let mut state = (false, ClothesState::Clean(0));
+
+        assert_eq!(Machine::apply(&mut state, &Either::Right(ClothesAction::Wear)), Err(Either::Right(ClothesError::NoLifeLeft)));
+        assert_eq!(Machine::apply(&mut state, &Either::Left(())), Ok(()));
+        assert_eq!(state, (true, ClothesState::Clean(0)));
+    }
+
+    #[test]
+    fn product_runs_hooks_of_both_components() {
+        type Machine = Product<AccountedCurrency, DigitalCashSystem>;
+        let mut state = (accounted_currency::State::new(), utxo_currency::State::new());
+
+        Machine::on_initialize(&mut state, &BlockContext { height: 7, author: None });
+        assert_eq!((state.0.block_height(), state.1.block_height()), (7, 7));
+    }
+
+    #[test]
+    fn sum_takes_transitions_of_current_machine_only() {
+        type Machine = Sum<LightSwitch, ClothesMachine>;
+        let start = Either::Left(false);
+
+        assert_eq!(Machine::try_next_state(&start, &Either::Left(())), Ok(Either::Left(true)));
+        assert_eq!(Machine::try_next_state(&start, &Either::Right(ClothesAction::Dry)), Err(SumError::Mismatched));
+        assert_eq!(
+            Machine::try_next_state(&Either::Right(ClothesState::Wet(0)), &Either::Right(ClothesAction::Dry)),
+            Err(SumError::Right(ClothesError::NoLifeLeft))
+        );
+    }
+
+    #[test]
+    fn chained_digits_count_like_an_odometer() {
+        type Odometer = Chained<Digit, Chained<Digit, Digit>>;
+        let mut state = (9, (9, 4));
+
+        Odometer::apply(&mut state, &Tick).unwrap();
+        assert_eq!(state, (0, (0, 5)));
+        Odometer::apply(&mut state, &Tick).unwrap();
+        assert_eq!(state, (1, (0, 5)));
+    }
+
+    #[test]
+    fn chained_rejection_leaves_both_states() {
+        type Machine = Chained<Digit, Limit>;
+        let start = (9, 1);
+
+        assert_eq!(Machine::try_next_state(&start, &Tick), Err(Either::Right(Full)));
+        assert_eq!(Machine::next_state(&start, &Tick), start);
+        assert_eq!(Machine::next_state(&(9, 0), &Tick), (0, 1));
+    }
+
+    #[test]
+    fn combinators_keep_component_names() {
+        assert_eq!(Product::<AccountedCurrency, DigitalCashSystem>::human_name(), "Accounted currency and Digital cash system");
+        assert_eq!(Sum::<Digit, AccountedCurrency>::human_name(), "Digit or Accounted currency");
+        assert_eq!(Chained::<Digit, Digit>::human_name(), "Digit then Digit");
+    }
+}
@@ -0,0 +1,175 @@
+#![allow(dead_code)]
+
+//! Graphviz diagrams of explored state graphs, e.g. `dot -Tsvg machine.dot > machine.svg`.
+
+use super::explorer::StateGraph;
+use super::StateMachine;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/// Names the group a state is drawn in.
+type Key<'a, S> = Box<dyn Fn(&S) -> String + 'a>;
+
+/// Renders a `StateGraph` in the DOT language, with states as nodes and accepted transitions
+/// as edges. States and transitions are labelled with their `Debug` output.
+pub struct Dot<'a, M: StateMachine> {
+    graph: &'a StateGraph<M>,
+    name: String,
+    collapse: Option<Key<'a, M::State>>,
+    highlight_terminal: bool,
+}
+
+impl<'a, M> Dot<'a, M>
+where
+    M: StateMachine,
+    M::State: Clone + Eq + Hash + Debug,
+    M::Transition: Clone + Debug,
+{
+    pub fn new(graph: &'a StateGraph<M>) -> Self {
+        Dot { graph, name: M::human_name(), collapse: None, highlight_terminal: false }
+    }
+
+    pub fn named(mut self, name: &str) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Draws all states with the same key as one node, labelled with the key. Handy to hide
+    /// counters, e.g. the life left in a piece of clothing.
+    pub fn collapse_by(mut self, key: impl Fn(&M::State) -> String + 'a) -> Self {
+        self.collapse = Some(Box::new(key));
+        self
+    }
+
+    /// Draws the states the machine can never leave with a double border.
+    pub fn highlight_terminal(mut self) -> Self {
+        self.highlight_terminal = true;
+        self
+    }
+
+    pub fn render(&self) -> String {
+        // Nodes in the order their first state was found, each with its label.
+        let mut labels: Vec<String> = Vec::new();
+        let mut node_of_label: HashMap<String, usize> = HashMap::new();
+        let node_of_state: Vec<usize> = self
+            .graph
+            .states
+            .iter()
+            .map(|state| {
+                let label = match &self.collapse {
+                    Some(key) => key(state),
+                    None => format!("{:?}", state),
+                };
+                *node_of_label.entry(label.clone()).or_insert_with(|| {
+                    labels.push(label);
+                    labels.len() - 1
+                })
+            })
+            .collect();
+
+        // Parallel edges are merged into one, listing every transition on it.
+        let mut edges: BTreeMap<(usize, usize), Vec<usize>> = BTreeMap::new();
+        for edge in &self.graph.edges {
+            let transitions = edges.entry((node_of_state[edge.from], node_of_state[edge.to])).or_default();
+            if !transitions.contains(&edge.transition) {
+                transitions.push(edge.transition);
+            }
+        }
+        let initial: Vec<usize> = self.graph.initial.iter().map(|id| node_of_state[*id]).collect();
+        let terminal = |node: usize| edges.keys().all(|(from, to)| *from != node || *to == node);
+
+        let mut dot = format!("digraph \"{}\" {{\n    rankdir=LR;\n", escape(&self.name));
+        for (node, label) in labels.iter().enumerate() {
+            let mut attributes = vec![format!("label=\"{}\"", escape(label))];
+            if initial.contains(&node) {
+                attributes.push("style=bold".into());
+            }
+            if self.highlight_terminal && terminal(node) {
+                attributes.push("shape=doublecircle".into());
+            }
+            dot.push_str(&format!("    n{} [{}];\n", node, attributes.join(", ")));
+        }
+        for ((from, to), transitions) in &edges {
+            let mut transitions = transitions.clone();
+            transitions.sort();
+            let label: Vec<String> = transitions.iter().map(|t| format!("{:?}", self.graph.alphabet[*t])).collect();
+            dot.push_str(&format!("    n{} -> n{} [label=\"{}\"];\n", from, to, escape(&label.join(", "))));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::state_machine::explorer::Explorer;
+    use crate::state_machine::laundry::{ClothesAction, ClothesMachine, ClothesState};
+    use crate::state_machine::swithces::{LightSwitch, Toggle, TwoSwitches, WeirdStateMachine};
+
+    fn clothes() -> StateGraph<ClothesMachine> {
+        let alphabet = vec![ClothesAction::Wear, ClothesAction::Wash, ClothesAction::Dry];
+        Explorer::new(vec![ClothesState::Clean(3)], alphabet).explore()
+    }
+
+    fn lifecycle(state: &ClothesState) -> String {
+        match state {
+            ClothesState::Clean(_) => "Clean",
+            ClothesState::Dirty(_) => "Dirty",
+            ClothesState::Wet(_) => "Wet",
+            ClothesState::Tattered => "Tattered",
+        }
+        .into()
+    }
+
+    #[test]
+    fn light_switch_diagram() {
+        let graph = Explorer::<LightSwitch>::new(vec![false], vec![()]).explore();
+
+        assert_eq!(
+            Dot::new(&graph).named("Light switch").render(),
+            "digraph \"Light switch\" {\n    rankdir=LR;\n    n0 [label=\"false\", style=bold];\n    n1 [label=\"true\"];\n    n0 -> n1 [label=\"()\"];\n    n1 -> n0 [label=\"()\"];\n}\n"
+        );
+    }
+
+    #[test]
+    fn two_switches_diagram_has_every_state_and_toggle() {
+        let alphabet = vec![Toggle::FirstSwitch, Toggle::SecondSwitch];
+        let graph = Explorer::<WeirdStateMachine>::new(vec![TwoSwitches::new(false, false)], alphabet).explore();
+        let dot = Dot::new(&graph).render();
+
+        assert_eq!(dot.matches("[label=\"TwoSwitches").count(), 4);
+        assert_eq!(dot.matches(" -> ").count(), 8);
+        assert!(dot.starts_with("digraph \"Unnamed state machine\" {"));
+    }
+
+    #[test]
+    fn collapsed_clothes_lifecycle() {
+        let graph = clothes();
+        let dot = Dot::new(&graph).collapse_by(lifecycle).highlight_terminal().render();
+
+        assert_eq!(dot.matches("[label=").count() - dot.matches(" -> ").count(), 4);
+        assert!(dot.contains("[label=\"Tattered\", shape=doublecircle]"));
+        assert!(dot.contains("[label=\"Clean\", style=bold]"));
+        // Drying dirty clothes keeps them dirty and wearing them does too.
+        assert!(dot.contains("[label=\"Wear, Dry\"]"));
+    }
+
+    #[test]
+    fn terminal_states_are_only_highlighted_on_request() {
+        let graph = clothes();
+
+        assert!(!Dot::new(&graph).render().contains("doublecircle"));
+        assert_eq!(Dot::new(&graph).highlight_terminal().render().matches("doublecircle").count(), 1);
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        assert_eq!(escape("say \"hi\" \\o/"), "say \\\"hi\\\" \\\\o/");
+    }
+}
@@ -0,0 +1,87 @@
+#![allow(dead_code)]
+
+//! What transitions did, as seen from outside. A successful transfer, one that burned the
+//! sender's dust and one that removed the sender's account look the same in a block body, but
+//! not in the events they emit.
+
+use super::accounted_currency::AssetId;
+use super::utxo_currency::OutPoint;
+use super::User;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Event {
+    Minted { asset: AssetId, to: User, amount: u64 },
+    Burned { asset: AssetId, from: User, amount: u64 },
+    Transferred { asset: AssetId, from: User, to: User, amount: u64 },
+    /// An account fell below its asset's minimum balance and was removed, destroying `dust`.
+    AccountReaped { asset: AssetId, who: User, dust: u64 },
+    /// `owner` is only known for outputs paying to a single key.
+    BillSpent { outpoint: OutPoint, owner: Option<User>, amount: u64 },
+    BillCreated { outpoint: OutPoint, owner: Option<User>, amount: u64 },
+    CashDispensed { amount: u64 },
+    /// The fees of a block went to its author.
+    FeesPaid { to: User, amount: u64 },
+    /// The fees of a block without anyone to pay them to were destroyed.
+    FeesBurned { amount: u64 },
+}
+
+/// An `Event` without its data, to filter events by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Minted,
+    Burned,
+    Transferred,
+    AccountReaped,
+    BillSpent,
+    BillCreated,
+    CashDispensed,
+    FeesPaid,
+    FeesBurned,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Minted { .. } => EventKind::Minted,
+            Event::Burned { .. } => EventKind::Burned,
+            Event::Transferred { .. } => EventKind::Transferred,
+            Event::AccountReaped { .. } => EventKind::AccountReaped,
+            Event::BillSpent { .. } => EventKind::BillSpent,
+            Event::BillCreated { .. } => EventKind::BillCreated,
+            Event::CashDispensed { .. } => EventKind::CashDispensed,
+            Event::FeesPaid { .. } => EventKind::FeesPaid,
+            Event::FeesBurned { .. } => EventKind::FeesBurned,
+        }
+    }
+
+    /// Whether the event is about `user`'s funds.
+    pub fn involves(&self, user: User) -> bool {
+        match self {
+            Event::Minted { to, .. } | Event::FeesPaid { to, .. } => *to == user,
+            Event::Burned { from, .. } => *from == user,
+            Event::Transferred { from, to, .. } => *from == user || *to == user,
+            Event::AccountReaped { who, .. } => *who == user,
+            Event::BillSpent { owner, .. } | Event::BillCreated { owner, .. } => *owner == Some(user),
+            Event::CashDispensed { .. } | Event::FeesBurned { .. } => false,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::state_machine::accounted_currency::NATIVE_ASSET;
+    use crate::state_machine::utxo_currency::GENESIS_TX;
+
+    #[test]
+    fn events_involve_the_accounts_they_move_funds_of() {
+        let transferred = Event::Transferred { asset: NATIVE_ASSET, from: User::Alice, to: User::Bob, amount: 1 };
+        let spent = Event::BillSpent { outpoint: OutPoint { tx_hash: GENESIS_TX, index: 0 }, owner: None, amount: 1 };
+
+        assert!(transferred.involves(User::Alice) && transferred.involves(User::Bob));
+        assert!(!transferred.involves(User::Charlie));
+        assert!(!spent.involves(User::Alice));
+        assert!(!Event::CashDispensed { amount: 1 }.involves(User::Alice));
+        assert_eq!(spent.kind(), EventKind::BillSpent);
+    }
+}
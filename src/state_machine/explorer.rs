@@ -0,0 +1,378 @@
+#![allow(dead_code)]
+
+//! Exhaustive exploration of small state spaces, checking properties on every reachable state.
+
+use super::StateMachine;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// States the explorer visits before it gives up, unless configured otherwise.
+pub const DEFAULT_MAX_STATES: usize = 10_000;
+
+/// A run of a machine: where it started and every transition it took with the state it led to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trace<S, T> {
+    pub initial: S,
+    pub steps: Vec<(T, S)>,
+}
+
+impl<S, T> Trace<S, T> {
+    pub fn last(&self) -> &S {
+        self.steps.last().map_or(&self.initial, |(_, state)| state)
+    }
+}
+
+/// A shortest run showing that `property` does not hold.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Counterexample<S, T> {
+    pub property: String,
+    pub trace: Trace<S, T>,
+    /// For liveness properties, the step count after which the trace's last state was already
+    /// reached, i.e. where the machine loops forever. `None` when the trace ends in a state
+    /// without any accepted transition, or breaks an invariant.
+    pub loops_back_to: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckError<S, T> {
+    Violated(Counterexample<S, T>),
+    /// The state space is larger than the explorer was allowed to visit, nothing was proven.
+    StateLimitReached(usize),
+}
+
+// `(transition, state)` steps by index, from some source state.
+type Path = Vec<(usize, usize)>;
+
+/// A transition the machine accepted, between states given by their index in `StateGraph::states`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edge {
+    pub from: usize,
+    /// Index into the alphabet the graph was explored with.
+    pub transition: usize,
+    pub to: usize,
+}
+
+/// Breadth-first search over the states reachable from a set of initial states, trying every
+/// transition of an alphabet on each of them.
+pub struct Explorer<M: StateMachine> {
+    initial: Vec<M::State>,
+    alphabet: Vec<M::Transition>,
+    max_states: usize,
+}
+
+impl<M> Explorer<M>
+where
+    M: StateMachine,
+    M::State: Clone + Eq + Hash,
+    M::Transition: Clone,
+{
+    pub fn new(initial: Vec<M::State>, alphabet: Vec<M::Transition>) -> Self {
+        Explorer { initial, alphabet, max_states: DEFAULT_MAX_STATES }
+    }
+
+    pub fn with_max_states(mut self, max_states: usize) -> Self {
+        self.max_states = max_states;
+        self
+    }
+
+    /// Rejected transitions do not become edges.
+    pub fn explore(&self) -> StateGraph<M> {
+        let mut graph = StateGraph {
+            states: Vec::new(),
+            index: HashMap::new(),
+            alphabet: self.alphabet.clone(),
+            edges: Vec::new(),
+            successors: Vec::new(),
+            initial: Vec::new(),
+            parent: Vec::new(),
+            complete: true,
+        };
+        let mut queue = VecDeque::new();
+
+        for state in &self.initial {
+            if let Some((id, new)) = graph.insert(state, None, self.max_states) {
+                graph.initial.push(id);
+                if new {
+                    queue.push_back(id);
+                }
+            }
+        }
+        while let Some(from) = queue.pop_front() {
+            for (transition, t) in self.alphabet.iter().enumerate() {
+                let Ok(next) = M::try_next_state(&graph.states[from], t) else {
+                    continue
+                };
+                match graph.insert(&next, Some((from, transition)), self.max_states) {
+                    Some((to, new)) => {
+                        graph.edges.push(Edge { from, transition, to });
+                        if new {
+                            queue.push_back(to);
+                        }
+                    }
+                    None => graph.complete = false,
+                }
+            }
+        }
+        graph.successors = vec![Vec::new(); graph.states.len()];
+        for edge in &graph.edges {
+            graph.successors[edge.from].push(*edge);
+        }
+        graph
+    }
+}
+
+/// Every state an `Explorer` reached, in the order it found them, and the transitions between them.
+pub struct StateGraph<M: StateMachine> {
+    pub states: Vec<M::State>,
+    index: HashMap<M::State, usize>,
+    pub alphabet: Vec<M::Transition>,
+    pub edges: Vec<Edge>,
+    // The edges leaving each state, so searches don't scan all of `edges` at every step.
+    successors: Vec<Vec<Edge>>,
+    pub initial: Vec<usize>,
+    // The edge each state was first reached through, for shortest traces.
+    parent: Vec<Option<(usize, usize)>>,
+    /// Whether every reachable state was visited, `false` once the state limit was hit.
+    pub complete: bool,
+}
+
+impl<M> StateGraph<M>
+where
+    M: StateMachine,
+    M::State: Clone + Eq + Hash,
+    M::Transition: Clone,
+{
+    // Returns the state's index and whether it is new, `None` when there is no room for it.
+    fn insert(&mut self, state: &M::State, parent: Option<(usize, usize)>, max_states: usize) -> Option<(usize, bool)> {
+        if let Some(id) = self.index.get(state) {
+            return Some((*id, false))
+        }
+        if self.states.len() >= max_states {
+            return None
+        }
+        let id = self.states.len();
+        self.states.push(state.clone());
+        self.index.insert(state.clone(), id);
+        self.parent.push(parent);
+        Some((id, true))
+    }
+
+    pub fn id(&self, state: &M::State) -> Option<usize> {
+        self.index.get(state).copied()
+    }
+
+    pub fn successors(&self, id: usize) -> impl Iterator<Item = &Edge> {
+        self.successors[id].iter()
+    }
+
+    /// A state the machine can not leave: every transition it accepts leads back to it.
+    pub fn is_terminal(&self, id: usize) -> bool {
+        self.successors(id).all(|edge| edge.to == id)
+    }
+
+    /// A shortest run from an initial state to the state `id`.
+    pub fn trace_to(&self, id: usize) -> Trace<M::State, M::Transition> {
+        let mut steps = Vec::new();
+        let mut current = id;
+
+        while let Some((from, transition)) = self.parent[current] {
+            steps.push((self.alphabet[transition].clone(), self.states[current].clone()));
+            current = from;
+        }
+        steps.reverse();
+        Trace { initial: self.states[current].clone(), steps }
+    }
+
+    /// Checks that `holds` is true in every reachable state.
+    pub fn check_invariant(
+        &self,
+        property: &str,
+        holds: impl Fn(&M::State) -> bool,
+    ) -> Result<(), CheckError<M::State, M::Transition>> {
+        // States are stored in breadth-first order, so the first violation is a closest one.
+        if let Some(id) = self.states.iter().position(|state| !holds(state)) {
+            return Err(CheckError::Violated(Counterexample {
+                property: property.into(),
+                trace: self.trace_to(id),
+                loops_back_to: None,
+            }))
+        }
+        if !self.complete {
+            return Err(CheckError::StateLimitReached(self.states.len()))
+        }
+        Ok(())
+    }
+
+    /// Checks that every run eventually reaches a state where `goal` holds. A run fails to when
+    /// it gets stuck before the goal, or loops forever among states that miss it.
+    pub fn check_eventually(
+        &self,
+        property: &str,
+        goal: impl Fn(&M::State) -> bool,
+    ) -> Result<(), CheckError<M::State, M::Transition>> {
+        if !self.complete {
+            return Err(CheckError::StateLimitReached(self.states.len()))
+        }
+        let avoiding: Vec<bool> = self.states.iter().map(|state| !goal(state)).collect();
+        let sources: Vec<usize> = self.initial.iter().copied().filter(|id| avoiding[*id]).collect();
+        let prefixes = self.shortest_paths(&sources, &avoiding);
+
+        // Visiting states by distance finds a shortest prefix, then a shortest loop through it.
+        let mut candidates: Vec<usize> = (0..self.states.len()).filter(|id| prefixes[*id].is_some()).collect();
+        candidates.sort_by_key(|id| prefixes[*id].as_ref().map(|(_, path)| path.len()));
+
+        for id in candidates {
+            let (source, prefix) = prefixes[id].clone().expect("filtered above");
+            let stuck = self.successors(id).next().is_none();
+            let cycle = self.shortest_cycle(id, &avoiding);
+
+            if stuck || cycle.is_some() {
+                let loops_back_to = cycle.as_ref().map(|_| prefix.len());
+                let steps = prefix.into_iter().chain(cycle.into_iter().flatten());
+                let trace = Trace {
+                    initial: self.states[source].clone(),
+                    steps: steps
+                        .map(|(transition, to)| (self.alphabet[transition].clone(), self.states[to].clone()))
+                        .collect(),
+                };
+                return Err(CheckError::Violated(Counterexample { property: property.into(), trace, loops_back_to }))
+            }
+        }
+        Ok(())
+    }
+
+    // Breadth-first search through `allowed` states, giving each reached state the source it
+    // was reached from and the path that got there.
+    fn shortest_paths(&self, sources: &[usize], allowed: &[bool]) -> Vec<Option<(usize, Path)>> {
+        let mut paths: Vec<Option<(usize, Path)>> = vec![None; self.states.len()];
+        let mut queue = VecDeque::new();
+
+        for source in sources {
+            if paths[*source].is_none() {
+                paths[*source] = Some((*source, Vec::new()));
+                queue.push_back(*source);
+            }
+        }
+        while let Some(from) = queue.pop_front() {
+            for edge in self.successors(from) {
+                if allowed[edge.to] && paths[edge.to].is_none() {
+                    let (source, mut path) = paths[from].clone().expect("queued states have a path");
+                    path.push((edge.transition, edge.to));
+                    paths[edge.to] = Some((source, path));
+                    queue.push_back(edge.to);
+                }
+            }
+        }
+        paths
+    }
+
+    // The shortest way from `id` back to itself through `allowed` states.
+    fn shortest_cycle(&self, id: usize, allowed: &[bool]) -> Option<Path> {
+        let mut shortest: Option<Path> = None;
+
+        for edge in self.successors(id) {
+            let cycle = if edge.to == id {
+                Some(vec![(edge.transition, id)])
+            } else if allowed[edge.to] {
+                self.shortest_paths(&[edge.to], allowed)[id].clone().map(|(_, path)| {
+                    std::iter::once((edge.transition, edge.to)).chain(path).collect()
+                })
+            } else {
+                None
+            };
+            if let Some(cycle) = cycle.filter(|cycle| shortest.as_ref().is_none_or(|best| cycle.len() < best.len())) {
+                shortest = Some(cycle);
+            }
+        }
+        shortest
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::state_machine::laundry::{ClothesAction, ClothesMachine, ClothesState};
+    use crate::state_machine::swithces::{LightSwitch, Toggle, TwoSwitches, WeirdStateMachine};
+
+    fn clothes(life: u64) -> StateGraph<ClothesMachine> {
+        let alphabet = vec![ClothesAction::Wear, ClothesAction::Wash, ClothesAction::Dry];
+        Explorer::new(vec![ClothesState::Clean(life)], alphabet).explore()
+    }
+
+    fn two_switches() -> StateGraph<WeirdStateMachine> {
+        let alphabet = vec![Toggle::FirstSwitch, Toggle::SecondSwitch];
+        Explorer::new(vec![TwoSwitches::new(false, false)], alphabet).explore()
+    }
+
+    #[test]
+    fn light_switch_has_two_states() {
+        let graph = Explorer::<LightSwitch>::new(vec![false], vec![()]).explore();
+
+        assert_eq!(graph.states, vec![false, true]);
+        assert_eq!(graph.edges.len(), 2);
+        assert!(graph.complete);
+    }
+
+    #[test]
+    fn two_switches_reach_every_combination() {
+        let graph = two_switches();
+
+        assert_eq!(graph.states.len(), 4);
+        assert_eq!(graph.check_invariant("anything goes", |_| true), Ok(()));
+    }
+
+    #[test]
+    fn invariant_violation_has_shortest_trace() {
+        let result = two_switches().check_invariant("never both on", |s| *s != TwoSwitches::new(true, true));
+        let Err(CheckError::Violated(counterexample)) = result else {
+            panic!("both switches can be on")
+        };
+
+        assert_eq!(counterexample.trace.steps.len(), 2);
+        assert_eq!(*counterexample.trace.last(), TwoSwitches::new(true, true));
+        assert_eq!(counterexample.loops_back_to, None);
+    }
+
+    #[test]
+    fn clothes_eventually_tatter() {
+        let graph = clothes(5);
+
+        assert_eq!(graph.check_eventually("eventually tattered", |s| *s == ClothesState::Tattered), Ok(()));
+        assert!(graph.is_terminal(graph.id(&ClothesState::Tattered).unwrap()));
+        assert_eq!(graph.check_invariant("life never grows", |s| !matches!(s, ClothesState::Clean(6..))), Ok(()));
+    }
+
+    #[test]
+    fn liveness_violation_loops_forever() {
+        let result = clothes(2).check_eventually("eventually wet", |s| matches!(s, ClothesState::Wet(_)));
+        let Err(CheckError::Violated(counterexample)) = result else {
+            panic!("clothes can be worn until tattered without ever getting wet")
+        };
+
+        // Clean(2) -Wear-> Dirty(1) -Wear-> Tattered -Wear-> Tattered.
+        assert_eq!(counterexample.trace.steps.len(), 3);
+        assert_eq!(counterexample.loops_back_to, Some(2));
+        assert_eq!(*counterexample.trace.last(), ClothesState::Tattered);
+    }
+
+    #[test]
+    fn two_switches_never_have_to_turn_on() {
+        let result = two_switches().check_eventually("eventually both on", |s| *s == TwoSwitches::new(true, true));
+        let Err(CheckError::Violated(counterexample)) = result else {
+            panic!("the second switch can be toggled forever")
+        };
+
+        assert_eq!(counterexample.loops_back_to, Some(0));
+        assert_eq!(*counterexample.trace.last(), counterexample.trace.initial);
+    }
+
+    #[test]
+    fn state_limit_proves_nothing() {
+        let graph = Explorer::<ClothesMachine>::new(vec![ClothesState::Clean(1_000)], vec![ClothesAction::Dry])
+            .with_max_states(10)
+            .explore();
+
+        assert!(!graph.complete);
+        assert_eq!(graph.check_invariant("anything goes", |_| true), Err(CheckError::StateLimitReached(10)));
+    }
+}
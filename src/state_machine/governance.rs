@@ -0,0 +1,539 @@
+#![allow(dead_code)]
+
+//! On-chain governance by the holders of an `AccountedCurrency`'s native asset. Anyone can put
+//! a `Call` to a vote by reserving a deposit, holders vote with their stake, and once the voting
+//! period is over the call is enacted if the ayes outweigh the nays.
+
+use super::accounted_currency::{
+    self, AccountedCurrency, AccountingError, AccountingTransaction, BalanceLock, FeeConfig, NATIVE_ASSET,
+};
+use super::runtime::ScheduledUpgrade;
+use super::{BlockContext, BlockHooks, RuntimeVersion, StateMachine, User};
+use std::collections::{BTreeMap, HashMap};
+
+pub struct Governance;
+
+pub type ProposalId = u32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GovernanceConfig {
+    /// Reserved from the proposer until the proposal is decided, and slashed if its ayes weigh
+    /// nothing at all.
+    pub deposit: u64,
+    /// Blocks a proposal stays open for votes.
+    pub voting_period: u64,
+}
+
+impl Default for GovernanceConfig {
+    fn default() -> Self {
+        GovernanceConfig { deposit: 10, voting_period: 10 }
+    }
+}
+
+/// What an enacted proposal does. Calls act for the whole chain, so nobody signs them.
+///
+/// The set of calls is closed: a referendum can only do what a variant spells out, and not
+/// dispatch any transition of another machine. Giving governance a new power takes a new
+/// variant and its arm in `Governance::enact`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    /// Changes what currency transactions cost.
+    SetFees(FeeConfig),
+    /// Changes how proposals are made and decided. Open proposals keep their deposit and end.
+    SetConfig(GovernanceConfig),
+    /// Upgrades the runtime `delay` blocks after enactment, at the earliest in the next block.
+    /// See `runtime::Runtime::take_upgrade`.
+    UpgradeRuntime { version: RuntimeVersion, delay: u64 },
+}
+
+/// How much longer than the vote itself a voter locks its stake, in return for more weight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Conviction {
+    None,
+    Locked1x,
+    Locked2x,
+    Locked3x,
+    Locked4x,
+    Locked5x,
+    Locked6x,
+}
+
+impl Conviction {
+    /// What `stake` counts for. A vote without conviction counts a tenth of its stake.
+    pub fn weight(self, stake: u64) -> u64 {
+        match self as u64 {
+            0 => stake / 10,
+            multiplier => stake.saturating_mul(multiplier),
+        }
+    }
+
+    /// Voting periods the stake stays locked after the vote ends, doubling with every step.
+    pub fn lock_periods(self) -> u64 {
+        match self as u32 {
+            0 => 0,
+            n => 1 << (n - 1),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Vote {
+    pub aye: bool,
+    pub stake: u64,
+    pub conviction: Conviction,
+}
+
+/// The weight of the votes cast on a proposal so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Tally {
+    pub ayes: u64,
+    pub nays: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proposal {
+    pub proposer: User,
+    pub call: Call,
+    pub deposit: u64,
+    /// The block that decides the proposal as it starts.
+    pub end: u64,
+    votes: HashMap<User, Vote>,
+    /// What each vote froze, to release when the voter votes again.
+    locks: HashMap<User, BalanceLock>,
+}
+
+impl Proposal {
+    pub fn vote(&self, voter: &User) -> Option<&Vote> {
+        self.votes.get(voter)
+    }
+
+    pub fn tally(&self) -> Tally {
+        let mut tally = Tally::default();
+        for vote in self.votes.values() {
+            let side = if vote.aye { &mut tally.ayes } else { &mut tally.nays };
+            *side = side.saturating_add(vote.conviction.weight(vote.stake));
+        }
+        tally
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Enacted,
+    Rejected,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct State {
+    currency: accounted_currency::State,
+    config: GovernanceConfig,
+    proposals: BTreeMap<ProposalId, Proposal>,
+    next_proposal: ProposalId,
+    outcomes: BTreeMap<ProposalId, Outcome>,
+    /// An upgrade enacted by referendum, until the runtime takes it.
+    upgrade: Option<ScheduledUpgrade>,
+}
+
+impl State {
+    pub fn new(currency: accounted_currency::State) -> Self {
+        State {
+            currency,
+            config: GovernanceConfig::default(),
+            proposals: BTreeMap::new(),
+            next_proposal: 0,
+            outcomes: BTreeMap::new(),
+            upgrade: None,
+        }
+    }
+
+    pub fn with_config(mut self, config: GovernanceConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn currency(&self) -> &accounted_currency::State {
+        &self.currency
+    }
+
+    pub fn config(&self) -> GovernanceConfig {
+        self.config
+    }
+
+    /// A proposal that is still open for votes.
+    pub fn proposal(&self, id: ProposalId) -> Option<&Proposal> {
+        self.proposals.get(&id)
+    }
+
+    /// How a proposal was decided, once it is.
+    pub fn outcome(&self, id: ProposalId) -> Option<Outcome> {
+        self.outcomes.get(&id).copied()
+    }
+
+    pub fn take_upgrade(&mut self) -> Option<ScheduledUpgrade> {
+        self.upgrade.take()
+    }
+}
+
+/// Proposals and votes consume their signer's currency nonce and pay a fee, like currency
+/// transactions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GovernanceTransaction {
+    Currency(AccountingTransaction),
+    /// Puts `call` to a vote under the next free id, reserving the deposit.
+    Propose { proposer: User, call: Call, nonce: u64 },
+    /// Votes on an open proposal, replacing `voter`'s earlier vote on it. The stake stays
+    /// locked until the vote ends and then for as many voting periods as the conviction asks.
+    Vote { voter: User, proposal: ProposalId, vote: Vote, nonce: u64 },
+}
+
+impl GovernanceTransaction {
+    /// What the fee is charged for, see `accounted_currency::State::weight_fee`.
+    pub fn weight(&self) -> u64 {
+        match self {
+            GovernanceTransaction::Currency(t) => t.weight(),
+            GovernanceTransaction::Propose { .. } => 3,
+            GovernanceTransaction::Vote { .. } => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GovernanceError {
+    Currency(AccountingError),
+    /// The proposal does not exist or was decided already.
+    UnknownProposal,
+    /// The same call is up for a vote already.
+    DuplicateProposal,
+    /// Proposals need a voting period of at least one block.
+    ZeroVotingPeriod,
+    ZeroStake,
+    /// Voters can only stake what they hold.
+    InsufficientBalance,
+    Overflow,
+}
+
+impl Governance {
+    // Changes `state` as it goes, `try_next_state` works on a copy.
+    fn execute(state: &mut State, transition: &GovernanceTransaction) -> Result<(), GovernanceError> {
+        match transition {
+            GovernanceTransaction::Currency(t) => {
+                AccountedCurrency::apply(&mut state.currency, t).map_err(GovernanceError::Currency)?;
+            }
+            GovernanceTransaction::Propose { proposer, call, nonce } => {
+                Self::charge(state, *proposer, *nonce, transition.weight())?;
+
+                if matches!(call, Call::SetConfig(config) if config.voting_period == 0) {
+                    return Err(GovernanceError::ZeroVotingPeriod)
+                }
+                if state.proposals.values().any(|proposal| proposal.call == *call) {
+                    return Err(GovernanceError::DuplicateProposal)
+                }
+                let id = state.next_proposal;
+                state.next_proposal = id.checked_add(1).ok_or(GovernanceError::Overflow)?;
+                let end = state.currency.block_height().checked_add(state.config.voting_period).ok_or(GovernanceError::Overflow)?;
+
+                state.currency.reserve(*proposer, state.config.deposit).map_err(GovernanceError::Currency)?;
+                state.proposals.insert(id, Proposal {
+                    proposer: *proposer,
+                    call: call.clone(),
+                    deposit: state.config.deposit,
+                    end,
+                    votes: HashMap::new(),
+                    locks: HashMap::new(),
+                });
+            }
+            GovernanceTransaction::Vote { voter, proposal, vote, nonce } => {
+                Self::charge(state, *voter, *nonce, transition.weight())?;
+                let open = state.proposals.get_mut(proposal).ok_or(GovernanceError::UnknownProposal)?;
+
+                if vote.stake == 0 {
+                    return Err(GovernanceError::ZeroStake)
+                }
+                if vote.stake > state.currency.balance(NATIVE_ASSET, voter) {
+                    return Err(GovernanceError::InsufficientBalance)
+                }
+                let locked_for = state.config.voting_period.checked_mul(vote.conviction.lock_periods());
+                let until = locked_for.and_then(|blocks| open.end.checked_add(blocks)).ok_or(GovernanceError::Overflow)?;
+
+                let lock = BalanceLock { amount: vote.stake, until };
+                if let Some(earlier) = open.locks.insert(*voter, lock) {
+                    state.currency.unlock(*voter, earlier);
+                }
+                open.votes.insert(*voter, *vote);
+                state.currency.lock(*voter, lock.amount, lock.until);
+            }
+        }
+        Ok(())
+    }
+
+    fn charge(state: &mut State, signer: User, nonce: u64, weight: u64) -> Result<(), GovernanceError> {
+        state.currency.use_nonce(signer, nonce).map_err(GovernanceError::Currency)?;
+        let fee = state.currency.weight_fee(weight);
+        state.currency.withdraw_fee(signer, fee).map_err(GovernanceError::Currency)
+    }
+
+    fn decide(state: &mut State, id: ProposalId, proposal: Proposal, height: u64) {
+        let tally = proposal.tally();
+
+        let outcome = if tally.ayes > tally.nays {
+            Self::enact(state, &proposal.call, height);
+            Outcome::Enacted
+        } else {
+            Outcome::Rejected
+        };
+        if tally.ayes == 0 {
+            state.currency.slash_reserved(proposal.proposer, proposal.deposit);
+        } else {
+            state.currency.unreserve(proposal.proposer, proposal.deposit);
+        }
+        state.outcomes.insert(id, outcome);
+    }
+
+    fn enact(state: &mut State, call: &Call, height: u64) {
+        match call {
+            Call::SetFees(fees) => state.currency.set_fees(*fees),
+            Call::SetConfig(config) => state.config = *config,
+            Call::UpgradeRuntime { version, delay } => {
+                state.upgrade = Some(ScheduledUpgrade { version: *version, at: height.saturating_add((*delay).max(1)) });
+            }
+        }
+    }
+}
+
+impl StateMachine for Governance {
+    type State = State;
+    type Transition = GovernanceTransaction;
+    type Error = GovernanceError;
+
+    fn next_state(starting_state: &State, transition: &GovernanceTransaction) -> State {
+        Self::try_next_state(starting_state, transition).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state(starting_state: &State, transition: &GovernanceTransaction) -> Result<State, GovernanceError> {
+        let mut new_state = starting_state.clone();
+        Self::execute(&mut new_state, transition)?;
+        Ok(new_state)
+    }
+
+    fn human_name() -> String {
+        "Governance".into()
+    }
+}
+
+/// Proposals are decided at the start of the block their voting period ends in, oldest first.
+impl BlockHooks for Governance {
+    fn on_initialize(state: &mut State, context: &BlockContext) {
+        AccountedCurrency::on_initialize(&mut state.currency, context);
+
+        let (decided, open): (BTreeMap<_, _>, BTreeMap<_, _>) =
+            std::mem::take(&mut state.proposals).into_iter().partition(|(_, proposal)| proposal.end <= context.height);
+        state.proposals = open;
+
+        for (id, proposal) in decided {
+            Self::decide(state, id, proposal, context.height);
+        }
+    }
+
+    fn on_finalize(state: &mut State, context: &BlockContext) {
+        AccountedCurrency::on_finalize(&mut state.currency, context);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::state_machine::runtime::{self, Runtime, RuntimeCall, Upgradable};
+
+    const NEW_FEES: FeeConfig = FeeConfig { base_fee: 2, weight_fee: 0, block_reward: 5 };
+
+    fn genesis() -> State {
+        State::new(accounted_currency::State::from([(User::Alice, 100), (User::Bob, 200), (User::Charlie, 50)]))
+    }
+
+    fn proposal(state: &State, call: Call) -> GovernanceTransaction {
+        GovernanceTransaction::Propose { proposer: User::Charlie, call, nonce: state.currency.nonce(&User::Charlie) }
+    }
+
+    fn propose(state: &State, call: Call) -> Result<State, GovernanceError> {
+        Governance::try_next_state(state, &proposal(state, call))
+    }
+
+    fn vote(state: State, voter: User, aye: bool, stake: u64, conviction: Conviction) -> State {
+        let vote = Vote { aye, stake, conviction };
+        let nonce = state.currency.nonce(&voter);
+        Governance::try_next_state(&state, &GovernanceTransaction::Vote { voter, proposal: 0, vote, nonce }).unwrap()
+    }
+
+    fn run_until(state: &mut State, height: u64) {
+        for height in state.currency.block_height() + 1..=height {
+            let context = BlockContext { height, author: None };
+            Governance::on_initialize(state, &context);
+            Governance::on_finalize(state, &context);
+        }
+    }
+
+    #[test]
+    fn proposal_reserves_deposit() {
+        let state = propose(&genesis(), Call::SetFees(NEW_FEES)).unwrap();
+
+        assert_eq!(state.currency().reserved(&User::Charlie), 10);
+        assert_eq!(state.currency().balance(NATIVE_ASSET, &User::Charlie), 40);
+        assert_eq!(state.proposal(0).map(|proposal| proposal.end), Some(10));
+        assert_eq!(propose(&state, Call::SetFees(NEW_FEES)), Err(GovernanceError::DuplicateProposal));
+        let zero_period = Call::SetConfig(GovernanceConfig { deposit: 1, voting_period: 0 });
+        assert_eq!(propose(&state, zero_period), Err(GovernanceError::ZeroVotingPeriod));
+    }
+
+    #[test]
+    fn votes_lock_stake_for_their_conviction() {
+        let state = propose(&genesis(), Call::SetFees(NEW_FEES)).unwrap();
+        let mut state = vote(state, User::Alice, true, 60, Conviction::Locked2x);
+        let over_balance = GovernanceTransaction::Vote {
+            voter: User::Bob,
+            proposal: 0,
+            vote: Vote { aye: false, stake: 201, conviction: Conviction::None },
+            nonce: 0,
+        };
+
+        assert_eq!(Governance::try_next_state(&state, &over_balance), Err(GovernanceError::InsufficientBalance));
+        assert_eq!(state.proposal(0).unwrap().tally(), Tally { ayes: 120, nays: 0 });
+        assert_eq!(state.currency().usable_balance(NATIVE_ASSET, &User::Alice), 40);
+
+        // Locked through the vote and two more voting periods.
+        run_until(&mut state, 29);
+        assert_eq!(state.currency().usable_balance(NATIVE_ASSET, &User::Alice), 40);
+        run_until(&mut state, 30);
+        assert_eq!(state.currency().usable_balance(NATIVE_ASSET, &User::Alice), 100);
+    }
+
+    #[test]
+    fn proposals_and_votes_pay_fees() {
+        let fees = FeeConfig { base_fee: 1, weight_fee: 1, block_reward: 0 };
+        let genesis = State::new(genesis().currency.with_fees(fees));
+        let state = vote(propose(&genesis, Call::SetFees(NEW_FEES)).unwrap(), User::Alice, true, 10, Conviction::None);
+
+        assert_eq!(state.currency().balance(NATIVE_ASSET, &User::Charlie), 36);
+        assert_eq!(state.currency().balance(NATIVE_ASSET, &User::Alice), 97);
+        assert_eq!(state.currency().pending_fees(), 7);
+
+        let poor = State::new(accounted_currency::State::from([(User::Charlie, 14)]).with_fees(fees));
+        assert_eq!(propose(&poor, Call::SetFees(NEW_FEES)), Err(GovernanceError::Currency(AccountingError::WouldReapAccount)));
+    }
+
+    #[test]
+    fn new_vote_replaces_the_earlier_lock() {
+        let state = propose(&genesis(), Call::SetFees(NEW_FEES)).unwrap();
+        let mut state = vote(vote(state, User::Alice, true, 60, Conviction::Locked2x), User::Alice, false, 10, Conviction::None);
+
+        assert_eq!(state.proposal(0).unwrap().tally(), Tally { ayes: 0, nays: 1 });
+        assert_eq!(state.currency().usable_balance(NATIVE_ASSET, &User::Alice), 90);
+        run_until(&mut state, 10);
+        assert_eq!(state.currency().usable_balance(NATIVE_ASSET, &User::Alice), 100);
+    }
+
+    #[test]
+    fn passed_proposal_is_enacted_at_the_end_of_its_vote() {
+        let state = propose(&genesis(), Call::SetFees(NEW_FEES)).unwrap();
+        let mut state = vote(vote(state, User::Alice, true, 100, Conviction::Locked1x), User::Bob, false, 200, Conviction::None);
+
+        run_until(&mut state, 9);
+        assert_eq!(state.outcome(0), None);
+        run_until(&mut state, 10);
+
+        assert_eq!(state.outcome(0), Some(Outcome::Enacted));
+        assert_eq!(state.proposal(0), None);
+        assert_eq!(state.currency().fees(), NEW_FEES);
+        assert_eq!(state.currency().reserved(&User::Charlie), 0);
+        assert_eq!(state.currency().balance(NATIVE_ASSET, &User::Charlie), 50);
+    }
+
+    #[test]
+    fn conviction_outweighs_a_larger_stake() {
+        let state = propose(&genesis(), Call::SetFees(NEW_FEES)).unwrap();
+        let mut state = vote(vote(state, User::Bob, true, 200, Conviction::None), User::Alice, false, 30, Conviction::Locked1x);
+        run_until(&mut state, 10);
+
+        // Rejected, but the ayes were not empty, so the deposit comes back.
+        assert_eq!(state.outcome(0), Some(Outcome::Rejected));
+        assert_eq!(state.currency().fees(), FeeConfig::default());
+        assert_eq!(state.currency().balance(NATIVE_ASSET, &User::Charlie), 50);
+    }
+
+    #[test]
+    fn proposal_without_ayes_loses_its_deposit() {
+        let mut state = propose(&genesis(), Call::SetFees(NEW_FEES)).unwrap();
+        run_until(&mut state, 10);
+
+        assert_eq!(state.outcome(0), Some(Outcome::Rejected));
+        assert_eq!(state.currency().reserved(&User::Charlie), 0);
+        assert_eq!(state.currency().total_issuance(), 340);
+    }
+
+    #[test]
+    fn proposals_and_votes_can_not_be_replayed() {
+        let propose = proposal(&genesis(), Call::SetFees(NEW_FEES));
+        let mut state = Governance::try_next_state(&genesis(), &propose).unwrap();
+        let vote = GovernanceTransaction::Vote {
+            voter: User::Alice,
+            proposal: 0,
+            vote: Vote { aye: true, stake: 10, conviction: Conviction::None },
+            nonce: 0,
+        };
+        state = Governance::try_next_state(&state, &vote).unwrap();
+        assert_eq!(Governance::try_next_state(&state, &vote), Err(GovernanceError::Currency(AccountingError::StaleNonce)));
+
+        // Once the proposal is decided the same call could be proposed again, but not by
+        // replaying the old transaction and its deposit.
+        run_until(&mut state, 10);
+        assert_eq!(Governance::try_next_state(&state, &propose), Err(GovernanceError::Currency(AccountingError::StaleNonce)));
+        assert_eq!(state.currency().reserved(&User::Charlie), 0);
+    }
+
+    /// A chain whose only rules are governance, in every version.
+    struct Governed;
+
+    impl Runtime for Governed {
+        type State = State;
+        type Transition = GovernanceTransaction;
+        type Error = GovernanceError;
+
+        fn supports(version: RuntimeVersion) -> bool {
+            (1..=2).contains(&version)
+        }
+
+        fn dispatch(_version: RuntimeVersion, starting_state: &State, transition: &GovernanceTransaction) -> Result<State, GovernanceError> {
+            Governance::try_next_state(starting_state, transition)
+        }
+
+        fn on_initialize(_version: RuntimeVersion, state: &mut State, context: &BlockContext) {
+            Governance::on_initialize(state, context);
+        }
+
+        fn on_finalize(_version: RuntimeVersion, state: &mut State, context: &BlockContext) {
+            Governance::on_finalize(state, context);
+        }
+
+        fn take_upgrade(state: &mut State) -> Option<ScheduledUpgrade> {
+            state.take_upgrade()
+        }
+    }
+
+    #[test]
+    fn referendum_upgrades_the_runtime() {
+        let state = propose(&genesis(), Call::UpgradeRuntime { version: 2, delay: 3 }).unwrap();
+        let state = vote(state, User::Bob, true, 50, Conviction::Locked1x);
+        let mut chain = runtime::State::new(1, None, state);
+
+        for height in 1..=13 {
+            let context = BlockContext { height, author: None };
+            Upgradable::<Governed>::on_initialize(&mut chain, &context);
+            Upgradable::<Governed>::on_finalize(&mut chain, &context);
+
+            let expected = if height < 13 { 1 } else { 2 };
+            assert_eq!(chain.version(), expected, "at height {}", height);
+        }
+        // Nobody holds the admin key, yet the chain upgraded.
+        let set_code = RuntimeCall::SetCode { who: User::Alice, version: 3, at: 20 };
+        assert!(Upgradable::<Governed>::try_next_state(&chain, &set_code).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+//! Property-based testing of any `StateMachine`. A `Harness` runs the machine through random
+//! sequences of transitions, checks declared invariants after every step and, when a run
+//! fails, lets proptest shrink it to a shortest one that still fails.
+
+use super::{Check, Invariant, StateMachine};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestError, TestRunner};
+use std::fmt::Debug;
+use std::ops::Range;
+
+/// A simpler implementation of `M` to compare it against, e.g. a plain counter for a
+/// balance. The model only has to track what it can tell about `M`'s state.
+pub trait Model<M: StateMachine> {
+    type State: Debug + PartialEq;
+
+    /// What the model tells about a state of the machine.
+    fn abstraction(state: &M::State) -> Self::State;
+
+    /// The state the model expects after `transition`, or `None` if it should be rejected.
+    fn step(state: &Self::State, transition: &M::Transition) -> Option<Self::State>;
+}
+
+/// A transition of a run with the state it led to, `None` if it was rejected.
+type Step<M> = (<M as StateMachine>::Transition, Option<<M as StateMachine>::State>);
+
+type Comparison<M> = fn(&<M as StateMachine>::State, &[Step<M>]) -> Result<(), String>;
+
+/// A minimal failing run: the initial state and the actions taken from it.
+pub type Failure<M, A> = TestError<(<M as StateMachine>::State, Vec<A>)>;
+
+/// Generates runs of `M` as random sequences of actions. Each action is turned into a
+/// transition against the state it is taken in, so generated transitions can refer to what
+/// exists at that point, like the nonce an account expects.
+pub struct Harness<M: StateMachine, A> {
+    initial: BoxedStrategy<M::State>,
+    actions: BoxedStrategy<A>,
+    resolve: fn(&M::State, A) -> M::Transition,
+    invariants: Vec<Invariant<M>>,
+    model: Option<Comparison<M>>,
+    steps: Range<usize>,
+    cases: u32,
+}
+
+impl<M> Harness<M, M::Transition>
+where
+    M: StateMachine,
+    M::State: Clone + Debug + PartialEq + 'static,
+    M::Transition: Clone + Debug + 'static,
+{
+    /// Runs of transitions drawn from `transitions` as they are.
+    pub fn of_transitions(
+        initial: impl Strategy<Value = M::State> + 'static,
+        transitions: impl Strategy<Value = M::Transition> + 'static,
+    ) -> Self {
+        Harness::new(initial, transitions, |_, transition| transition)
+    }
+}
+
+impl<M, A> Harness<M, A>
+where
+    M: StateMachine,
+    M::State: Clone + Debug + PartialEq + 'static,
+    M::Transition: Clone + Debug,
+    A: Clone + Debug + 'static,
+{
+    pub fn new(
+        initial: impl Strategy<Value = M::State> + 'static,
+        actions: impl Strategy<Value = A> + 'static,
+        resolve: fn(&M::State, A) -> M::Transition,
+    ) -> Self {
+        Harness {
+            initial: initial.boxed(),
+            actions: actions.boxed(),
+            resolve,
+            invariants: Vec::new(),
+            model: None,
+            steps: 0..30,
+            cases: Config::default().cases,
+        }
+    }
+
+    pub fn invariant(mut self, name: &'static str, check: Check<M>) -> Self {
+        self.invariants.push(Invariant { name, check });
+        self
+    }
+
+    /// Adds a whole set of invariants, e.g. `AccountedCurrency::invariants()`.
+    pub fn invariants(mut self, invariants: Vec<Invariant<M>>) -> Self {
+        self.invariants.extend(invariants);
+        self
+    }
+
+    /// Requires every run to agree with the model `R`, on the states reached and on which
+    /// transitions are rejected.
+    pub fn model<R: Model<M>>(mut self) -> Self {
+        self.model = Some(compare::<M, R>);
+        self
+    }
+
+    /// How many actions a run takes.
+    pub fn steps(mut self, steps: Range<usize>) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    /// How many runs to generate.
+    pub fn cases(mut self, cases: u32) -> Self {
+        self.cases = cases;
+        self
+    }
+
+    /// Generates runs until one fails, returning it shrunk as far as it goes.
+    pub fn run(&self) -> Result<(), Failure<M, A>> {
+        let mut runner = TestRunner::new(Config { cases: self.cases, failure_persistence: None, ..Config::default() });
+        let runs = (self.initial.clone(), vec(self.actions.clone(), self.steps.clone()));
+
+        runner.run(&runs, |(initial, actions)| self.run_once(initial, actions).map_err(TestCaseError::fail))
+    }
+
+    /// Like `run`, but panics with the shrunk failing run.
+    pub fn check(&self) {
+        if let Err(failure) = self.run() {
+            panic!("{} failed its harness: {}", M::human_name(), failure);
+        }
+    }
+
+    fn run_once(&self, initial: M::State, actions: Vec<A>) -> Result<(), String> {
+        let mut state = initial.clone();
+        let mut steps: Vec<Step<M>> = Vec::new();
+
+        for (i, action) in actions.into_iter().enumerate() {
+            let transition = (self.resolve)(&state, action);
+            let outcome = M::try_next_state(&state, &transition).ok();
+
+            // The other ways to take a transition have to agree with `try_next_state`.
+            let expected = outcome.clone().unwrap_or_else(|| state.clone());
+            if M::next_state(&state, &transition) != expected {
+                return Err(format!("step {}: next_state and try_next_state disagree on {:?}", i, transition))
+            }
+            let mut applied = state.clone();
+            if M::apply(&mut applied, &transition).is_ok() != outcome.is_some() || applied != expected {
+                return Err(format!("step {}: apply and try_next_state disagree on {:?}", i, transition))
+            }
+
+            if let Some(after) = &outcome {
+                for invariant in &self.invariants {
+                    (invariant.check)(&state, &transition, after).map_err(|violation| {
+                        format!("step {}: {:?} broke `{}`: {}", i, transition, invariant.name, violation)
+                    })?;
+                }
+                state = after.clone();
+            }
+            steps.push((transition, outcome));
+        }
+
+        match self.model {
+            Some(compare) => compare(&initial, &steps),
+            None => Ok(()),
+        }
+    }
+}
+
+fn compare<M, R>(initial: &M::State, steps: &[Step<M>]) -> Result<(), String>
+where
+    M: StateMachine,
+    M::Transition: Debug,
+    R: Model<M>,
+{
+    let mut model = R::abstraction(initial);
+
+    for (i, (transition, outcome)) in steps.iter().enumerate() {
+        match (R::step(&model, transition), outcome) {
+            (Some(expected), Some(after)) => {
+                let actual = R::abstraction(after);
+                if actual != expected {
+                    return Err(format!("step {}: after {:?} the model expected {:?} but got {:?}", i, transition, expected, actual))
+                }
+                model = expected;
+            }
+            (None, None) => {}
+            (Some(_), None) => return Err(format!("step {}: {:?} was rejected but the model accepts it", i, transition)),
+            (None, Some(_)) => return Err(format!("step {}: {:?} was accepted but the model rejects it", i, transition)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::state_machine::laundry::{ClothesAction, ClothesMachine, ClothesState};
+    use std::convert::Infallible;
+
+    /// Counts up to 5 and wraps around, but skips 3 on the way.
+    struct Skipping;
+
+    impl StateMachine for Skipping {
+        type State = u8;
+        type Transition = ();
+        type Error = Infallible;
+
+        fn next_state(starting_state: &u8, _transition: &()) -> u8 {
+            match starting_state {
+                2 => 4,
+                5 => 0,
+                n => n + 1,
+            }
+        }
+    }
+
+    /// A plain counter modulo 6.
+    struct Counter;
+
+    impl Model<Skipping> for Counter {
+        type State = u8;
+
+        fn abstraction(state: &u8) -> u8 {
+            *state
+        }
+
+        fn step(state: &u8, _transition: &()) -> Option<u8> {
+            Some((state + 1) % 6)
+        }
+    }
+
+    fn life_left(state: &ClothesState) -> u64 {
+        match state {
+            ClothesState::Clean(life) | ClothesState::Dirty(life) | ClothesState::Wet(life) => *life,
+            ClothesState::Tattered => 0,
+        }
+    }
+
+    fn arb_action() -> impl Strategy<Value = ClothesAction> {
+        prop_oneof![Just(ClothesAction::Wear), Just(ClothesAction::Wash), Just(ClothesAction::Dry)]
+    }
+
+    /// Only counts the life left and whether it is up.
+    struct Lifetime;
+
+    impl Model<ClothesMachine> for Lifetime {
+        type State = Option<u64>;
+
+        fn abstraction(state: &ClothesState) -> Option<u64> {
+            match state {
+                ClothesState::Tattered => None,
+                state => Some(life_left(state)),
+            }
+        }
+
+        fn step(state: &Option<u64>, _transition: &ClothesAction) -> Option<Option<u64>> {
+            match state {
+                None => Some(None),
+                Some(0) => None,
+                Some(1) => Some(None),
+                Some(life) => Some(Some(life - 1)),
+            }
+        }
+    }
+
+    #[test]
+    fn clothes_agree_with_lifetime_model() {
+        Harness::<ClothesMachine, _>::of_transitions((0..10u64).prop_map(ClothesState::Clean), arb_action())
+            .invariant("life never grows", |before, _, after| {
+                if life_left(after) > life_left(before) { Err(format!("{:?} became {:?}", before, after)) } else { Ok(()) }
+            })
+            .model::<Lifetime>()
+            .check();
+    }
+
+    #[test]
+    fn broken_invariant_is_shrunk_to_shortest_run() {
+        let failure = Harness::<Skipping, _>::of_transitions(Just(0), Just(()))
+            .invariant("stays below 4", |_, _, after| if *after < 4 { Ok(()) } else { Err(format!("reached {}", after)) })
+            .run()
+            .unwrap_err();
+
+        match failure {
+            TestError::Fail(reason, (_, actions)) => {
+                assert_eq!(actions.len(), 3);
+                assert!(reason.message().contains("`stays below 4`"));
+            }
+            TestError::Abort(reason) => panic!("aborted: {}", reason),
+        }
+    }
+
+    #[test]
+    fn disagreement_with_model_is_found() {
+        let failure = Harness::<Skipping, _>::of_transitions(Just(0), Just(())).model::<Counter>().run().unwrap_err();
+
+        match failure {
+            TestError::Fail(reason, (_, actions)) => {
+                assert_eq!(actions.len(), 3);
+                assert!(reason.message().contains("the model expected 3 but got 4"));
+            }
+            TestError::Abort(reason) => panic!("aborted: {}", reason),
+        }
+    }
+
+    #[test]
+    fn actions_are_resolved_against_current_state() {
+        // Always washes clothes that are not wet yet, and dries them otherwise.
+        let resolve = |state: &ClothesState, _: ()| match state {
+            ClothesState::Wet(_) => ClothesAction::Dry,
+            _ => ClothesAction::Wash,
+        };
+
+        Harness::<ClothesMachine, ()>::new(Just(ClothesState::Dirty(50)), Just(()), resolve)
+            .invariant("never dirty again", |_, _, after| match after {
+                ClothesState::Dirty(_) => Err("clothes got dirty".into()),
+                _ => Ok(()),
+            })
+            .steps(1..40)
+            .cases(20)
+            .check();
+    }
+}
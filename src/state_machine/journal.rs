@@ -0,0 +1,239 @@
+#![allow(dead_code)]
+
+//! Reverse diffs of applied transitions, so a chain can roll its state back to a fork's
+//! common ancestor instead of replaying from genesis.
+
+use super::{BlockContext, BlockHooks, Revertible, UndoEmitter};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// The previous value of every entry that changed between two versions of a map, `None` for
+/// entries that did not exist before.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MapUndo<K, V> {
+    previous: Vec<(K, Option<V>)>,
+}
+
+impl<K, V> Default for MapUndo<K, V> {
+    fn default() -> Self {
+        MapUndo { previous: Vec::new() }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone + PartialEq> MapUndo<K, V> {
+    pub fn new() -> Self {
+        MapUndo::default()
+    }
+
+    pub fn record(before: &HashMap<K, V>, after: &HashMap<K, V>) -> Self {
+        let changed = after
+            .iter()
+            .filter(|(key, value)| before.get(*key) != Some(*value))
+            .map(|(key, _)| (key.clone(), before.get(key).cloned()));
+        let removed = before
+            .iter()
+            .filter(|(key, _)| !after.contains_key(*key))
+            .map(|(key, value)| (key.clone(), Some(value.clone())));
+
+        MapUndo { previous: changed.chain(removed).collect() }
+    }
+
+    /// Remembers the current value of `key`, before a change that may touch it.
+    pub fn remember(&mut self, map: &HashMap<K, V>, key: K) {
+        let previous = map.get(&key).cloned();
+        self.previous.push((key, previous));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.previous.is_empty()
+    }
+
+    /// The remembered keys, whether their entries changed or not.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.previous.iter().map(|(key, _)| key)
+    }
+
+    /// The value `key` had before the change, given the map it led to. Keys that were not
+    /// remembered did not change.
+    pub fn before<'a>(&'a self, after: &'a HashMap<K, V>, key: &K) -> Option<&'a V> {
+        match self.previous.iter().find(|(remembered, _)| remembered == key) {
+            Some((_, previous)) => previous.as_ref(),
+            None => after.get(key),
+        }
+    }
+
+    /// Restores the remembered values. A key remembered twice ends up with its oldest value.
+    pub fn revert(self, map: &mut HashMap<K, V>) {
+        for (key, previous) in self.previous.into_iter().rev() {
+            match previous {
+                Some(value) => map.insert(key, value),
+                None => map.remove(&key),
+            };
+        }
+    }
+}
+
+/// The undo records of the blocks applied so far, newest last.
+pub struct Journal<M: Revertible> {
+    blocks: Vec<Vec<M::Undo>>,
+}
+
+impl<M: Revertible> Default for Journal<M> {
+    fn default() -> Self {
+        Journal { blocks: Vec::new() }
+    }
+}
+
+impl<M: Revertible> Journal<M> {
+    pub fn new() -> Self {
+        Journal::default()
+    }
+
+    /// How many blocks can be reverted.
+    pub fn depth(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Records a block given the undo records of its changes, in the order they were applied.
+    pub fn push_block(&mut self, undos: Vec<M::Undo>) {
+        self.blocks.push(undos);
+    }
+
+    /// Rolls `state` back over the newest recorded block. Returns `false` if there is none.
+    pub fn revert_block(&mut self, state: &mut M::State) -> bool {
+        let Some(undos) = self.blocks.pop() else {
+            return false
+        };
+        for undo in undos.into_iter().rev() {
+            M::revert(state, undo);
+        }
+        true
+    }
+
+    /// Reverts blocks until only `depth` of them are left, e.g. down to a common ancestor.
+    pub fn revert_to(&mut self, depth: usize, state: &mut M::State) {
+        while self.depth() > depth {
+            self.revert_block(state);
+        }
+    }
+}
+
+/// Changes applied speculatively to a state in place, with what it takes to discard them.
+/// Dropping an overlay without committing it discards its changes.
+pub struct Overlay<'a, M: Revertible> {
+    state: &'a mut M::State,
+    undos: Vec<M::Undo>,
+}
+
+impl<'a, M> Overlay<'a, M>
+where
+    M: Revertible,
+    M::State: Clone,
+{
+    pub fn new(state: &'a mut M::State) -> Self {
+        Overlay { state, undos: Vec::new() }
+    }
+
+    pub fn state(&self) -> &M::State {
+        self.state
+    }
+
+    /// Applies `transition` on top of the overlay. A rejected transition changes nothing.
+    pub fn apply(&mut self, transition: &M::Transition) -> Result<(), M::Error> {
+        let undo = M::apply_with_undo(self.state, transition)?;
+        self.undos.push(undo);
+        Ok(())
+    }
+
+    /// Keeps the changes, returning how to undo them in the order they were made.
+    pub fn commit(mut self) -> Vec<M::Undo> {
+        std::mem::take(&mut self.undos)
+    }
+
+    pub fn discard(self) {}
+}
+
+impl<'a, M> Overlay<'a, M>
+where
+    M: BlockHooks + Revertible,
+    M::State: Clone,
+{
+    pub fn on_initialize(&mut self, context: &BlockContext) {
+        self.undos.push(M::hook_undo(self.state, context));
+        M::on_initialize(self.state, context);
+    }
+
+    pub fn on_finalize(&mut self, context: &BlockContext) {
+        self.undos.push(M::hook_undo(self.state, context));
+        M::on_finalize(self.state, context);
+    }
+}
+
+impl<'a, M> Overlay<'a, M>
+where
+    M: BlockHooks + UndoEmitter,
+    M::State: Clone,
+{
+    /// Like `apply`, also returning the events of `transition`.
+    pub fn apply_with_events(&mut self, transition: &M::Transition) -> Result<Vec<M::Event>, M::Error> {
+        self.apply(transition)?;
+        Ok(self.events_of_last(|undo, after| M::transition_events(undo, transition, after)))
+    }
+
+    pub fn on_initialize_with_events(&mut self, context: &BlockContext) -> Vec<M::Event> {
+        self.on_initialize(context);
+        self.events_of_last(|undo, after| M::hook_events(undo, context, after))
+    }
+
+    pub fn on_finalize_with_events(&mut self, context: &BlockContext) -> Vec<M::Event> {
+        self.on_finalize(context);
+        self.events_of_last(|undo, after| M::hook_events(undo, context, after))
+    }
+
+    fn events_of_last(&self, events: impl FnOnce(&M::Undo, &M::State) -> Vec<M::Event>) -> Vec<M::Event> {
+        let undo = self.undos.last().expect("every change leaves an undo record");
+        events(undo, self.state)
+    }
+}
+
+impl<'a, M: Revertible> Drop for Overlay<'a, M> {
+    fn drop(&mut self) {
+        while let Some(undo) = self.undos.pop() {
+            M::revert(self.state, undo);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn map_undo_restores_changed_created_and_removed_entries() {
+        let before = HashMap::from([(1, 10), (2, 20), (3, 30)]);
+        let mut after = HashMap::from([(1, 10), (2, 25), (4, 40)]);
+        let undo = MapUndo::record(&before, &after);
+
+        assert!(!undo.is_empty());
+        undo.revert(&mut after);
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn map_undo_knows_the_entries_before_the_change() {
+        let before = HashMap::from([(1, 10), (2, 20)]);
+        let after = HashMap::from([(1, 15), (3, 30)]);
+        let undo = MapUndo::record(&before, &after);
+
+        for key in [1, 2, 3, 4] {
+            assert_eq!(undo.before(&after, &key), before.get(&key));
+        }
+    }
+
+    #[test]
+    fn unchanged_map_records_nothing() {
+        let map = HashMap::from([(1, 10)]);
+
+        assert!(MapUndo::record(&map, &map).is_empty());
+    }
+}
@@ -1,9 +1,12 @@
+#![allow(dead_code)]
+
 use super::StateMachine;
+use crate::codec::{Decode, DecodeError, Encode};
 
 pub struct ClothesMachine;
 
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
 // Piece of clothing through its lifecycle
 pub enum ClothesState {
     Clean(u64),
@@ -13,97 +16,115 @@ pub enum ClothesState {
 }
 
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ClothesAction {
     Wear,
     Wash,
     Dry,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClothesError {
+    /// The clothes have no life left to lose but are not tattered yet.
+    NoLifeLeft,
+}
+
+impl Encode for ClothesState {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            ClothesState::Clean(life) => {
+                0u8.encode_to(out);
+                life.encode_to(out);
+            }
+            ClothesState::Dirty(life) => {
+                1u8.encode_to(out);
+                life.encode_to(out);
+            }
+            ClothesState::Wet(life) => {
+                2u8.encode_to(out);
+                life.encode_to(out);
+            }
+            ClothesState::Tattered => 3u8.encode_to(out),
+        }
+    }
+}
+
+impl Decode for ClothesState {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(ClothesState::Clean(u64::decode_from(input)?)),
+            1 => Ok(ClothesState::Dirty(u64::decode_from(input)?)),
+            2 => Ok(ClothesState::Wet(u64::decode_from(input)?)),
+            3 => Ok(ClothesState::Tattered),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for ClothesAction {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        (*self as u8).encode_to(out);
+    }
+}
+
+impl Decode for ClothesAction {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(ClothesAction::Wear),
+            1 => Ok(ClothesAction::Wash),
+            2 => Ok(ClothesAction::Dry),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+// Takes one from the remaining life, tattering the clothes once none is left.
+fn wear_out(life: u64, next: fn(u64) -> ClothesState) -> Result<ClothesState, ClothesError> {
+    match life.checked_sub(1) {
+        None => Err(ClothesError::NoLifeLeft),
+        Some(0) => Ok(ClothesState::Tattered),
+        Some(left) => Ok(next(left)),
+    }
+}
+
 impl StateMachine for ClothesMachine {
     type State = ClothesState;
     type Transition = ClothesAction;
+    type Error = ClothesError;
 
     fn next_state(starting_state: &Self::State, transition: &Self::Transition) -> Self::State {
-        match transition {
+        Self::try_next_state(starting_state, transition).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state(starting_state: &Self::State, transition: &Self::Transition) -> Result<Self::State, Self::Error> {
+        let new_state = match transition {
             ClothesAction::Wear => {
                 match starting_state {
-                    ClothesState::Clean(i) => {
-                        if i - 1 == 0  || *i == 0 {
-                            ClothesState::Tattered
-                        } else {
-                            ClothesState::Dirty(i - 1)
-                        }
-                    }
-                    ClothesState::Dirty(i) => {
-                        if i - 1 == 0  || *i == 0 {
-                            ClothesState::Tattered
-                        } else {
-                            ClothesState::Dirty(i - 1)
-                        }
-                    }
-                    ClothesState::Wet(i) => {
-                        if i - 1 == 0  || *i == 0 {
-                            ClothesState::Tattered
-                        } else {
-                            ClothesState::Dirty(i - 1)
-                        }
-                    }
-                    ClothesState::Tattered => ClothesState::Tattered
+                    ClothesState::Clean(i) => wear_out(*i, ClothesState::Dirty)?,
+                    ClothesState::Dirty(i) => wear_out(*i, ClothesState::Dirty)?,
+                    ClothesState::Wet(i) => wear_out(*i, ClothesState::Dirty)?,
+                    ClothesState::Tattered => ClothesState::Tattered,
                 }
             }
             ClothesAction::Wash => {
                 match starting_state {
-                    ClothesState::Clean(i) => {
-                        if i - 1 == 0  || *i == 0 {
-                            ClothesState::Tattered
-                        } else {
-                            ClothesState::Wet(i - 1)
-                        }
-                    }
-                    ClothesState::Dirty(i) => {
-                        if i - 1 == 0  || *i == 0 {
-                            ClothesState::Tattered
-                        } else {
-                            ClothesState::Wet(i - 1)
-                        }
-                    }
-                    ClothesState::Wet(i) => {
-                        if i - 1 == 0  || *i == 0 {
-                            ClothesState::Tattered
-                        } else {
-                            ClothesState::Wet(i - 1)
-                        }
-                    }
-                    ClothesState::Tattered => ClothesState::Tattered
+                    ClothesState::Clean(i) => wear_out(*i, ClothesState::Wet)?,
+                    ClothesState::Dirty(i) => wear_out(*i, ClothesState::Wet)?,
+                    ClothesState::Wet(i) => wear_out(*i, ClothesState::Wet)?,
+                    ClothesState::Tattered => ClothesState::Tattered,
                 }
             }
             ClothesAction::Dry => {
                 match starting_state {
-                    ClothesState::Clean(i) => {
-                        if i - 1 == 0  || *i == 0 {
-                            ClothesState::Tattered
-                        } else {
-                            ClothesState::Clean(i - 1)
-                        }
-                    }
-                    ClothesState::Dirty(i) => {
-                        if i - 1 == 0  || *i == 0 {
-                            ClothesState::Tattered
-                        } else {
-                            ClothesState::Dirty(i - 1)
-                        }
-                    }
-                    ClothesState::Wet(i) => {
-                        if i - 1 == 0  || *i == 0 {
-                            ClothesState::Tattered
-                        } else {
-                            ClothesState::Clean(i - 1)
-                        }
-                    }
-                    ClothesState::Tattered => ClothesState::Tattered
+                    ClothesState::Clean(i) => wear_out(*i, ClothesState::Clean)?,
+                    ClothesState::Dirty(i) => wear_out(*i, ClothesState::Dirty)?,
+                    ClothesState::Wet(i) => wear_out(*i, ClothesState::Clean)?,
+                    ClothesState::Tattered => ClothesState::Tattered,
                 }
             }
-        }
+        };
+
+        Ok(new_state)
     }
 }
 
@@ -287,4 +308,22 @@ pub mod tests {
         assert_eq!(new_state, expected);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn wear_clothes_without_life_fails() {
+        let current_state = ClothesState::Clean(0);
+        let result = ClothesMachine::try_next_state(&current_state, &ClothesAction::Wear);
+        assert_eq!(result, Err(ClothesError::NoLifeLeft));
+
+        let new_state = ClothesMachine::next_state(&current_state, &ClothesAction::Wear);
+        assert_eq!(new_state, ClothesState::Clean(0));
+    }
+
+    #[test]
+    fn wash_clothes_with_max_life() {
+        let current_state = ClothesState::Dirty(u64::MAX);
+        let new_state = ClothesMachine::next_state(&current_state, &ClothesAction::Wash);
+        let expected = ClothesState::Wet(u64::MAX - 1);
+        assert_eq!(new_state, expected);
+    }
+
+}
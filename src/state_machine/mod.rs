@@ -1,24 +1,273 @@
+#![allow(dead_code)]
+
+use crate::codec::{Decode, DecodeError, Encode};
+use std::marker::PhantomData;
+
 mod swithces;
 mod laundry;
-mod atm;
-mod accounted_currency;
-mod utxo_currency;
+pub(crate) mod atm;
+pub(crate) mod accounted_currency;
+pub(crate) mod utxo_currency;
+pub(crate) mod script;
+pub(crate) mod wallet;
+pub(crate) mod journal;
+pub(crate) mod combinators;
+pub(crate) mod explorer;
+pub(crate) mod dot;
+pub(crate) mod tracer;
+pub(crate) mod events;
+pub(crate) mod runtime;
+pub(crate) mod governance;
+pub(crate) mod staking;
+#[cfg(test)]
+pub(crate) mod harness;
 pub trait StateMachine {
     type State;
     type Transition;
+    /// Why a transition was rejected. Machines that accept every transition use `Infallible`.
+    type Error;
 
 
     fn next_state(starting_state: &Self::State, transition: &Self::Transition) -> Self::State;
 
+    /// Like `next_state`, but reports why a rejected transition left the state untouched.
+    fn try_next_state(
+        starting_state: &Self::State,
+        transition: &Self::Transition,
+    ) -> Result<Self::State, Self::Error> {
+        Ok(Self::next_state(starting_state, transition))
+    }
+
+    /// Applies `transition` to `state` in place. A rejected transition leaves `state` untouched.
+    /// Machines with large states should override it, the default copies the state.
+    fn apply(state: &mut Self::State, transition: &Self::Transition) -> Result<(), Self::Error> {
+        *state = Self::try_next_state(state, transition)?;
+        Ok(())
+    }
+
     fn human_name() -> String {
         "Unnamed state machine".into()
     }
 
 }
-#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+
+/// What the block executor tells a state machine about the block it is executing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockContext {
+    pub height: u64,
+    /// Who authored the block and collects its fees. Genesis has no author.
+    pub author: Option<User>,
+}
+
+/// A state machine that runs inside blocks. The executor calls `on_initialize` before the
+/// block's first transition and `on_finalize` after its last one.
+pub trait BlockHooks: StateMachine {
+    fn on_initialize(_state: &mut Self::State, _context: &BlockContext) {}
+
+    fn on_finalize(_state: &mut Self::State, _context: &BlockContext) {}
+}
+
+/// Names the rules a chain runs under, see `runtime::Upgradable`.
+pub type RuntimeVersion = u32;
+
+/// A state machine whose rules change over time, keeping the version in force in its state.
+pub trait Versioned: StateMachine {
+    /// The version a block at `height` runs under when it is built on `state`.
+    fn runtime_version(state: &Self::State, height: u64) -> RuntimeVersion;
+}
+
+/// A state machine whose changes can be undone, see `journal::Journal`.
+pub trait Revertible: StateMachine {
+    /// What it takes to turn a changed state back into the one it was changed from.
+    type Undo;
+
+    /// Records how to get from `after` back to `before`. Comparing whole states covers the
+    /// block hooks as well as the transitions.
+    fn undo(before: &Self::State, after: &Self::State) -> Self::Undo;
+
+    fn revert(state: &mut Self::State, undo: Self::Undo);
+
+    /// Like `try_next_state`, also returning how to undo the transition.
+    fn try_next_state_with_undo(
+        starting_state: &Self::State,
+        transition: &Self::Transition,
+    ) -> Result<(Self::State, Self::Undo), Self::Error> {
+        let new_state = Self::try_next_state(starting_state, transition)?;
+        let undo = Self::undo(starting_state, &new_state);
+        Ok((new_state, undo))
+    }
+
+    /// Like `apply`, also returning how to undo the transition. The default copies the state
+    /// to compare it, machines that know what a transition touches should override it.
+    fn apply_with_undo(state: &mut Self::State, transition: &Self::Transition) -> Result<Self::Undo, Self::Error>
+    where
+        Self::State: Clone,
+    {
+        let before = state.clone();
+        Self::apply(state, transition)?;
+        Ok(Self::undo(&before, state))
+    }
+
+    /// How to undo whatever a block hook at `context` may change, taken right before it runs.
+    fn hook_undo(state: &Self::State, context: &BlockContext) -> Self::Undo;
+}
+
+/// A state machine that reports what its transitions did, e.g. to feed them into another
+/// machine with `combinators::Chained`.
+pub trait Emitter: StateMachine {
+    type Event;
+
+    /// What `transition` did on its way from `before` to `after`.
+    fn events(before: &Self::State, transition: &Self::Transition, after: &Self::State) -> Vec<Self::Event>;
+}
+
+/// An emitter that tells what a change did from how to undo it, so blocks applied in place
+/// report their events without keeping a copy of the state, see `journal::Overlay`.
+pub trait UndoEmitter: Emitter + Revertible {
+    /// What the accepted `transition` did, given how to undo it and the state it led to.
+    fn transition_events(undo: &Self::Undo, transition: &Self::Transition, after: &Self::State) -> Vec<Self::Event>;
+
+    /// What a block hook at `context` did, e.g. paying the author, given its `hook_undo`.
+    fn hook_events(undo: &Self::Undo, context: &BlockContext, after: &Self::State) -> Vec<Self::Event>;
+}
+
+/// Describes how `after`, reached from `before` by a transition, breaks a property.
+pub type Check<M> = fn(&<M as StateMachine>::State, &<M as StateMachine>::Transition, &<M as StateMachine>::State) -> Result<(), String>;
+
+/// One named property every accepted transition of `M` has to keep.
+pub struct Invariant<M: StateMachine> {
+    pub name: &'static str,
+    pub check: Check<M>,
+}
+
+impl<M: StateMachine> Clone for Invariant<M> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M: StateMachine> Copy for Invariant<M> {}
+
+/// A state machine that can tell whether a transition kept its books straight.
+pub trait Invariants: StateMachine + Sized {
+    /// Everything a transition of this machine has to keep.
+    fn invariants() -> Vec<Invariant<Self>>;
+
+    /// Describes the first invariant `after` breaks, given it was reached from `before`.
+    fn check_invariants(
+        before: &Self::State,
+        transition: &Self::Transition,
+        after: &Self::State,
+    ) -> Result<(), String> {
+        for invariant in Self::invariants() {
+            (invariant.check)(before, transition, after).map_err(|violation| format!("{}: {}", invariant.name, violation))?;
+        }
+        Ok(())
+    }
+}
+
+/// Invariant-checking mode: runs `M` and panics on the first transition that breaks one of
+/// its invariants.
+pub struct Checked<M>(PhantomData<M>);
+
+impl<M: Invariants> StateMachine for Checked<M> {
+    type State = M::State;
+    type Transition = M::Transition;
+    type Error = M::Error;
+
+    fn next_state(starting_state: &Self::State, transition: &Self::Transition) -> Self::State {
+        let new_state = M::next_state(starting_state, transition);
+        Self::assert_invariants(starting_state, transition, &new_state);
+        new_state
+    }
+
+    fn try_next_state(
+        starting_state: &Self::State,
+        transition: &Self::Transition,
+    ) -> Result<Self::State, Self::Error> {
+        let new_state = M::try_next_state(starting_state, transition)?;
+        Self::assert_invariants(starting_state, transition, &new_state);
+        Ok(new_state)
+    }
+
+    fn human_name() -> String {
+        format!("{} (checked)", M::human_name())
+    }
+}
+
+impl<M: Invariants + BlockHooks> BlockHooks for Checked<M> {
+    fn on_initialize(state: &mut Self::State, context: &BlockContext) {
+        M::on_initialize(state, context)
+    }
+
+    fn on_finalize(state: &mut Self::State, context: &BlockContext) {
+        M::on_finalize(state, context)
+    }
+}
+
+impl<M: Invariants + Emitter> Emitter for Checked<M> {
+    type Event = M::Event;
+
+    fn events(before: &Self::State, transition: &Self::Transition, after: &Self::State) -> Vec<Self::Event> {
+        M::events(before, transition, after)
+    }
+}
+
+impl<M: Invariants + Revertible> Revertible for Checked<M> {
+    type Undo = M::Undo;
+
+    fn undo(before: &Self::State, after: &Self::State) -> Self::Undo {
+        M::undo(before, after)
+    }
+
+    fn revert(state: &mut Self::State, undo: Self::Undo) {
+        M::revert(state, undo)
+    }
+
+    fn hook_undo(state: &Self::State, context: &BlockContext) -> Self::Undo {
+        M::hook_undo(state, context)
+    }
+}
+
+impl<M: Invariants + UndoEmitter> UndoEmitter for Checked<M> {
+    fn transition_events(undo: &Self::Undo, transition: &Self::Transition, after: &Self::State) -> Vec<Self::Event> {
+        M::transition_events(undo, transition, after)
+    }
+
+    fn hook_events(undo: &Self::Undo, context: &BlockContext, after: &Self::State) -> Vec<Self::Event> {
+        M::hook_events(undo, context, after)
+    }
+}
+
+impl<M: Invariants> Checked<M> {
+    fn assert_invariants(before: &M::State, transition: &M::Transition, after: &M::State) {
+        if let Err(violation) = M::check_invariants(before, transition, after) {
+            panic!("{} broke an invariant: {}", M::human_name(), violation);
+        }
+    }
+}
+
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy)]
 #[allow(dead_code)]
 pub enum User {
     Alice,
     Bob,
     Charlie
 }
+
+impl Encode for User {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        (*self as u8).encode_to(out);
+    }
+}
+
+impl Decode for User {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(User::Alice),
+            1 => Ok(User::Bob),
+            2 => Ok(User::Charlie),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
@@ -0,0 +1,320 @@
+#![allow(dead_code)]
+
+//! Upgrades a chain's rules without forking it. The chain keeps its runtime version in its
+//! state and changes it with a `SetCode` transaction, which takes effect at a later height so
+//! every node switches in the same block.
+
+use super::{BlockContext, BlockHooks, RuntimeVersion, StateMachine, User, Versioned};
+use std::marker::PhantomData;
+
+/// The rules of every version a chain may run, usually one `StateMachine` per version.
+pub trait Runtime {
+    type State;
+    type Transition;
+    type Error;
+
+    /// Whether this build knows the rules of `version`. Upgrades to anything else are rejected.
+    fn supports(version: RuntimeVersion) -> bool;
+
+    /// Takes `transition` under the rules of `version`, which is always supported.
+    fn dispatch(
+        version: RuntimeVersion,
+        starting_state: &Self::State,
+        transition: &Self::Transition,
+    ) -> Result<Self::State, Self::Error>;
+
+    fn on_initialize(_version: RuntimeVersion, _state: &mut Self::State, _context: &BlockContext) {}
+
+    fn on_finalize(_version: RuntimeVersion, _state: &mut Self::State, _context: &BlockContext) {}
+
+    /// Turns a state of version `from` into one `to` can run on. It runs at the start of the
+    /// first block of the new version, before its `on_initialize`.
+    fn migrate(_from: RuntimeVersion, _to: RuntimeVersion, _state: &mut Self::State) {}
+
+    /// An upgrade the chain decided on by itself, e.g. by a referendum. `Upgradable` takes it
+    /// at the end of every block and schedules it if a `SetCode` for it would be accepted.
+    fn take_upgrade(_state: &mut Self::State) -> Option<ScheduledUpgrade> {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ScheduledUpgrade {
+    pub version: RuntimeVersion,
+    /// The first block that runs the new version.
+    pub at: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct State<S> {
+    version: RuntimeVersion,
+    /// Who may schedule upgrades. Without an admin the runtime is frozen.
+    admin: Option<User>,
+    scheduled: Option<ScheduledUpgrade>,
+    block_height: u64,
+    inner: S,
+}
+
+impl<S> State<S> {
+    pub fn new(version: RuntimeVersion, admin: Option<User>, inner: S) -> Self {
+        State { version, admin, scheduled: None, block_height: 0, inner }
+    }
+
+    pub fn version(&self) -> RuntimeVersion {
+        self.version
+    }
+
+    pub fn scheduled(&self) -> Option<ScheduledUpgrade> {
+        self.scheduled
+    }
+
+    /// The state of whatever version is running.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RuntimeCall<T> {
+    /// A transition for the version that is running.
+    Dispatch(T),
+    /// Switches to `version` from the block at height `at` on, replacing any upgrade scheduled
+    /// before.
+    SetCode { who: User, version: RuntimeVersion, at: u64 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpgradeError<E> {
+    /// The running version rejected the transition.
+    Dispatch(E),
+    NotAdmin,
+    UnsupportedVersion,
+    /// Versions only go up, so no two of them ever run under the same number.
+    NotNewer,
+    /// The upgrade has to wait for a later block than the current one.
+    NotInFuture,
+}
+
+/// Runs whichever version of `R` its state says is in force.
+pub struct Upgradable<R>(PhantomData<R>);
+
+impl<R: Runtime> StateMachine for Upgradable<R>
+where
+    R::State: Clone,
+{
+    type State = State<R::State>;
+    type Transition = RuntimeCall<R::Transition>;
+    type Error = UpgradeError<R::Error>;
+
+    fn next_state(starting_state: &Self::State, transition: &Self::Transition) -> Self::State {
+        Self::try_next_state(starting_state, transition).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state(starting_state: &Self::State, transition: &Self::Transition) -> Result<Self::State, Self::Error> {
+        match transition {
+            RuntimeCall::Dispatch(t) => {
+                let inner = R::dispatch(starting_state.version, &starting_state.inner, t).map_err(UpgradeError::Dispatch)?;
+                Ok(State { inner, ..starting_state.clone() })
+            }
+            RuntimeCall::SetCode { who, version, at } => {
+                if starting_state.admin != Some(*who) {
+                    return Err(UpgradeError::NotAdmin)
+                }
+                let upgrade = ScheduledUpgrade { version: *version, at: *at };
+                Self::check_upgrade(starting_state, upgrade)?;
+                Ok(State { scheduled: Some(upgrade), ..starting_state.clone() })
+            }
+        }
+    }
+
+    fn human_name() -> String {
+        "Upgradable runtime".into()
+    }
+}
+
+impl<R: Runtime> Upgradable<R> {
+    fn check_upgrade(state: &State<R::State>, upgrade: ScheduledUpgrade) -> Result<(), UpgradeError<R::Error>> {
+        if !R::supports(upgrade.version) {
+            return Err(UpgradeError::UnsupportedVersion)
+        }
+        if upgrade.version <= state.version {
+            return Err(UpgradeError::NotNewer)
+        }
+        if upgrade.at <= state.block_height {
+            return Err(UpgradeError::NotInFuture)
+        }
+        Ok(())
+    }
+}
+
+impl<R: Runtime> BlockHooks for Upgradable<R>
+where
+    R::State: Clone,
+{
+    fn on_initialize(state: &mut Self::State, context: &BlockContext) {
+        state.block_height = context.height;
+
+        if let Some(upgrade) = state.scheduled.filter(|upgrade| upgrade.at <= context.height) {
+            R::migrate(state.version, upgrade.version, &mut state.inner);
+            state.version = upgrade.version;
+            state.scheduled = None;
+        }
+        R::on_initialize(state.version, &mut state.inner, context);
+    }
+
+    fn on_finalize(state: &mut Self::State, context: &BlockContext) {
+        R::on_finalize(state.version, &mut state.inner, context);
+
+        if let Some(upgrade) = R::take_upgrade(&mut state.inner) {
+            if Self::check_upgrade(state, upgrade).is_ok() {
+                state.scheduled = Some(upgrade);
+            }
+        }
+    }
+}
+
+impl<R: Runtime> Versioned for Upgradable<R>
+where
+    R::State: Clone,
+{
+    fn runtime_version(state: &Self::State, height: u64) -> RuntimeVersion {
+        match state.scheduled {
+            Some(upgrade) if upgrade.at <= height => upgrade.version,
+            _ => state.version,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::blockchain::executor::{self, Block, ImportError};
+    use crate::state_machine::accounted_currency::{
+        self, AccountedCurrency, AccountingError, AccountingTransaction, ExistenceRequirement, FeeConfig, NATIVE_ASSET,
+    };
+    use crate::state_machine::Checked;
+
+    /// Version 1 is a plain `AccountedCurrency`. Version 2 checks its invariants on every
+    /// transaction and starts charging fees, which its migration switches on.
+    struct Currency;
+
+    const FEES: FeeConfig = FeeConfig { base_fee: 1, weight_fee: 1, block_reward: 0 };
+
+    impl Runtime for Currency {
+        type State = accounted_currency::State;
+        type Transition = AccountingTransaction;
+        type Error = AccountingError;
+
+        fn supports(version: RuntimeVersion) -> bool {
+            (1..=2).contains(&version)
+        }
+
+        fn dispatch(
+            version: RuntimeVersion,
+            starting_state: &accounted_currency::State,
+            transition: &AccountingTransaction,
+        ) -> Result<accounted_currency::State, AccountingError> {
+            match version {
+                1 => AccountedCurrency::try_next_state(starting_state, transition),
+                _ => Checked::<AccountedCurrency>::try_next_state(starting_state, transition),
+            }
+        }
+
+        fn on_initialize(_version: RuntimeVersion, state: &mut accounted_currency::State, context: &BlockContext) {
+            AccountedCurrency::on_initialize(state, context);
+        }
+
+        fn on_finalize(_version: RuntimeVersion, state: &mut accounted_currency::State, context: &BlockContext) {
+            AccountedCurrency::on_finalize(state, context);
+        }
+
+        fn migrate(_from: RuntimeVersion, to: RuntimeVersion, state: &mut accounted_currency::State) {
+            if to == 2 {
+                *state = std::mem::take(state).with_fees(FEES);
+            }
+        }
+    }
+
+    fn genesis() -> State<accounted_currency::State> {
+        State::new(1, Some(User::Charlie), accounted_currency::State::from([(User::Alice, 100)]))
+    }
+
+    fn transfer(amount: u64, nonce: u64) -> RuntimeCall<AccountingTransaction> {
+        RuntimeCall::Dispatch(AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            asset: NATIVE_ASSET,
+            amount,
+            existence: ExistenceRequirement::KeepAlive,
+            nonce,
+        })
+    }
+
+    fn set_code(who: User, version: RuntimeVersion, at: u64) -> RuntimeCall<AccountingTransaction> {
+        RuntimeCall::SetCode { who, version, at }
+    }
+
+    #[test]
+    fn set_code_is_checked() {
+        let mut state = genesis();
+        Upgradable::<Currency>::on_initialize(&mut state, &BlockContext { height: 5, author: None });
+        let try_set_code = |call| Upgradable::<Currency>::try_next_state(&state, &call);
+
+        assert_eq!(try_set_code(set_code(User::Alice, 2, 10)), Err(UpgradeError::NotAdmin));
+        assert_eq!(try_set_code(set_code(User::Charlie, 3, 10)), Err(UpgradeError::UnsupportedVersion));
+        assert_eq!(try_set_code(set_code(User::Charlie, 1, 10)), Err(UpgradeError::NotNewer));
+        assert_eq!(try_set_code(set_code(User::Charlie, 2, 5)), Err(UpgradeError::NotInFuture));
+        assert_eq!(try_set_code(set_code(User::Charlie, 2, 6)).unwrap().scheduled(), Some(ScheduledUpgrade { version: 2, at: 6 }));
+    }
+
+    #[test]
+    fn runtime_without_admin_is_frozen() {
+        let state = State::new(1, None, accounted_currency::State::new());
+
+        assert_eq!(Upgradable::<Currency>::try_next_state(&state, &set_code(User::Charlie, 2, 1)), Err(UpgradeError::NotAdmin));
+    }
+
+    #[test]
+    fn upgrade_migrates_at_its_height() {
+        let state = Upgradable::<Currency>::try_next_state(&genesis(), &set_code(User::Charlie, 2, 3)).unwrap();
+        let mut state = Upgradable::<Currency>::try_next_state(&state, &transfer(10, 0)).unwrap();
+        assert_eq!(state.inner().balance(NATIVE_ASSET, &User::Alice), 90);
+
+        for height in 1..3 {
+            Upgradable::<Currency>::on_initialize(&mut state, &BlockContext { height, author: None });
+            assert_eq!(state.version(), 1);
+        }
+        assert_eq!(Upgradable::<Currency>::runtime_version(&state, 3), 2);
+        Upgradable::<Currency>::on_initialize(&mut state, &BlockContext { height: 3, author: None });
+
+        assert_eq!((state.version(), state.scheduled()), (2, None));
+        let state = Upgradable::<Currency>::try_next_state(&state, &transfer(10, 1)).unwrap();
+        assert_eq!(state.inner().balance(NATIVE_ASSET, &User::Alice), 77);
+    }
+
+    #[test]
+    fn blocks_carry_the_version_they_run_under() {
+        let mut state = genesis();
+        let mut head = Block::genesis().with_runtime_version(1);
+
+        let bodies = [vec![set_code(User::Charlie, 2, 2)], vec![transfer(10, 0)], vec![transfer(10, 1)]];
+        for body in bodies {
+            let height = head.header.height() + 1;
+            let block = head.child(User::Charlie, body).with_runtime_version(Upgradable::<Currency>::runtime_version(&state, height));
+
+            // Claiming the version of the parent is only right before the upgrade.
+            let stale = head.child(User::Charlie, block.body.clone());
+            if block.header.runtime_version() != head.header.runtime_version() {
+                assert_eq!(
+                    executor::execute_versioned_block::<Upgradable<Currency>>(&state, &stale),
+                    Err(ImportError::WrongRuntimeVersion { expected: 2, found: 1 })
+                );
+            }
+            state = executor::execute_versioned_block::<Upgradable<Currency>>(&state, &block).unwrap();
+            head = block;
+        }
+
+        assert_eq!(head.header.runtime_version(), 2);
+        assert_eq!(state.inner().balance(NATIVE_ASSET, &User::Alice), 74);
+    }
+}
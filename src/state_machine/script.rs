@@ -0,0 +1,650 @@
+#![allow(dead_code)]
+
+//! A small stack language for locking UTXO outputs.
+//!
+//! An input's witness runs first and may only push data. The locking script of the output
+//! then runs on the same stack, and the spend is valid when it leaves a true value on top.
+//!
+//! Signatures are Schnorr signatures in the multiplicative group modulo a 32-bit prime. Making
+//! one takes the signer's `SecretKey`, checking it only the `PublicKey`. The group is small
+//! enough to break with a laptop, though, and the keys of `User`s are the well-known
+//! development keys of `SecretKey::dev`, so they show how signatures authorize spends rather
+//! than protect anything.
+
+use super::User;
+use crate::codec::{Decode, DecodeError, Encode};
+use crate::hash;
+
+/// Scripts longer than this are rejected before they run.
+pub const MAX_SCRIPT_SIZE: usize = 256;
+/// Opcodes a witness and locking script may step through together, skipped branches included.
+pub const MAX_STEPS: usize = 300;
+pub const MAX_STACK_SIZE: usize = 64;
+
+pub type Script = Vec<Op>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Item {
+    Num(u64),
+    Key(User),
+}
+
+impl Item {
+    fn is_true(&self) -> bool {
+        *self != Item::Num(0)
+    }
+
+    fn bool(value: bool) -> Self {
+        Item::Num(value as u64)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Op {
+    PushNum(u64),
+    PushKey(User),
+    Dup,
+    Drop,
+    Swap,
+    /// Replaces the top item with its hash.
+    Hash,
+    Equal,
+    /// Fails the script unless the top item is true, consuming it.
+    Verify,
+    EqualVerify,
+    /// Pops a key and then a signature, pushing whether the signature signs the spending
+    /// transaction for that key.
+    CheckSig,
+    /// Pops `n`, `n` keys, `m` and `m` signatures, pushing whether every signature matches
+    /// a different key, in the order the keys were given.
+    CheckMultiSig,
+    /// Pops a block height and fails unless the spend happens at or after it.
+    CheckLockTime,
+    /// Pops a number of blocks and fails unless the output is at least that old.
+    CheckSequence,
+    Not,
+    BoolAnd,
+    BoolOr,
+    /// Runs the following branch if the popped item is true, the `Else` branch otherwise.
+    If,
+    Else,
+    EndIf,
+}
+
+impl Op {
+    fn is_push(&self) -> bool {
+        matches!(self, Op::PushNum(_) | Op::PushKey(_))
+    }
+}
+
+impl Encode for Op {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        let tag: u8 = match self {
+            Op::PushNum(_) => 0,
+            Op::PushKey(_) => 1,
+            Op::Dup => 2,
+            Op::Drop => 3,
+            Op::Swap => 4,
+            Op::Hash => 5,
+            Op::Equal => 6,
+            Op::Verify => 7,
+            Op::EqualVerify => 8,
+            Op::CheckSig => 9,
+            Op::CheckMultiSig => 10,
+            Op::CheckLockTime => 11,
+            Op::CheckSequence => 12,
+            Op::Not => 13,
+            Op::BoolAnd => 14,
+            Op::BoolOr => 15,
+            Op::If => 16,
+            Op::Else => 17,
+            Op::EndIf => 18,
+        };
+        tag.encode_to(out);
+
+        match self {
+            Op::PushNum(n) => n.encode_to(out),
+            Op::PushKey(key) => key.encode_to(out),
+            _ => {}
+        }
+    }
+}
+
+impl Decode for Op {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let op = match u8::decode_from(input)? {
+            0 => Op::PushNum(u64::decode_from(input)?),
+            1 => Op::PushKey(User::decode_from(input)?),
+            2 => Op::Dup,
+            3 => Op::Drop,
+            4 => Op::Swap,
+            5 => Op::Hash,
+            6 => Op::Equal,
+            7 => Op::Verify,
+            8 => Op::EqualVerify,
+            9 => Op::CheckSig,
+            10 => Op::CheckMultiSig,
+            11 => Op::CheckLockTime,
+            12 => Op::CheckSequence,
+            13 => Op::Not,
+            14 => Op::BoolAnd,
+            15 => Op::BoolOr,
+            16 => Op::If,
+            17 => Op::Else,
+            18 => Op::EndIf,
+            tag => return Err(DecodeError::InvalidTag(tag)),
+        };
+        Ok(op)
+    }
+}
+
+/// Why a spend was not authorized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScriptError {
+    ScriptTooLarge,
+    TooManySteps,
+    StackOverflow,
+    StackUnderflow,
+    /// The witness may only push data.
+    WitnessNotPushOnly,
+    /// An opcode expected a number and found a key, or the other way around.
+    TypeMismatch,
+    UnbalancedConditional,
+    VerifyFailed,
+    InvalidMultiSig,
+    LockTimeNotReached,
+    SequenceNotReached,
+    /// The script ran to the end without leaving a true value on top.
+    EvaluatedFalse,
+}
+
+/// What a script can learn about the spend it is authorizing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScriptContext {
+    /// The message signatures commit to.
+    pub sighash: u64,
+    /// Height of the block the spend is included in.
+    pub height: u64,
+    /// Height of the block that created the spent output.
+    pub output_height: u64,
+}
+
+/// The prime signatures work modulo, the largest below 2^32.
+const MODULUS: u64 = 4_294_967_291;
+/// Generates the whole group modulo `MODULUS`, so exponents count modulo `MODULUS - 1`.
+const GENERATOR: u64 = 2;
+
+/// What signatures are made with. Whoever holds it can spend what its `PublicKey` locks.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SecretKey(u64);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PublicKey(u64);
+
+impl SecretKey {
+    pub fn new(secret: u64) -> Self {
+        SecretKey(secret % (MODULUS - 1))
+    }
+
+    /// The development key of `user`. Every copy of the crate knows it, like the keys of the
+    /// accounts a test network starts with.
+    pub fn dev(user: User) -> Self {
+        SecretKey::new(hash(&("development key", user)))
+    }
+
+    pub fn public(&self) -> PublicKey {
+        PublicKey(pow_mod(GENERATOR, self.0))
+    }
+}
+
+/// The key `Op::PushKey(user)` stands for.
+pub fn public_key(user: User) -> PublicKey {
+    SecretKey::dev(user).public()
+}
+
+/// Signs `message` with a deterministic nonce. The challenge goes in the high half of the
+/// signature and the response in the low half.
+pub fn sign(key: &SecretKey, message: u64) -> u64 {
+    let nonce = hash(&(key.0, message)) % (MODULUS - 1);
+    let challenge = challenge(pow_mod(GENERATOR, nonce), key.public(), message);
+    let response = (nonce as u128 + challenge as u128 * key.0 as u128) % (MODULUS - 1) as u128;
+
+    (challenge << 32) | response as u64
+}
+
+/// Whether `signature` is `key`'s signature of `message`.
+pub fn verify_signature(key: PublicKey, message: u64, signature: u64) -> bool {
+    let (challenge, response) = (signature >> 32, signature & u32::MAX as u64);
+    if response >= MODULUS - 1 {
+        return false
+    }
+    // `GENERATOR^response = commitment * key^challenge`, so the public key is enough to recover
+    // the commitment the challenge was made from.
+    let inverse = pow_mod(key.0, MODULUS - 1 - challenge % (MODULUS - 1));
+    let commitment = mul_mod(pow_mod(GENERATOR, response), inverse);
+
+    challenge == self::challenge(commitment, key, message)
+}
+
+fn challenge(commitment: u64, key: PublicKey, message: u64) -> u64 {
+    hash(&(commitment, key, message)) & u32::MAX as u64
+}
+
+fn mul_mod(a: u64, b: u64) -> u64 {
+    (a as u128 * b as u128 % MODULUS as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exponent: u64) -> u64 {
+    let mut result = 1;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul_mod(result, base);
+        }
+        base = mul_mod(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+pub fn pay_to_key(user: User) -> Script {
+    vec![Op::PushKey(user), Op::CheckSig]
+}
+
+/// Who a `pay_to_key` script pays to, `None` for any other script.
+pub fn key_owner(script: &[Op]) -> Option<User> {
+    match script {
+        [Op::PushKey(user), Op::CheckSig] => Some(*user),
+        _ => None,
+    }
+}
+
+/// Spendable with signatures of `m` of `keys`, given in the same order as the keys.
+pub fn multisig(m: u64, keys: &[User]) -> Script {
+    let mut script = vec![Op::PushNum(m)];
+    script.extend(keys.iter().map(|key| Op::PushKey(*key)));
+    script.push(Op::PushNum(keys.len() as u64));
+    script.push(Op::CheckMultiSig);
+    script
+}
+
+/// Spendable by whoever reveals a preimage of `digest`, see `hash_of`.
+pub fn hash_lock(digest: u64) -> Script {
+    vec![Op::Hash, Op::PushNum(digest), Op::Equal]
+}
+
+/// A hash time-locked contract: `receiver` can spend with a preimage of `digest`, and
+/// `sender` can take the funds back from block `expiry` on.
+pub fn htlc(digest: u64, receiver: User, sender: User, expiry: u64) -> Script {
+    vec![
+        Op::If,
+        Op::Hash,
+        Op::PushNum(digest),
+        Op::EqualVerify,
+        Op::PushKey(receiver),
+        Op::Else,
+        Op::PushNum(expiry),
+        Op::CheckLockTime,
+        Op::PushKey(sender),
+        Op::EndIf,
+        Op::CheckSig,
+    ]
+}
+
+/// Witness taking the claim branch of an `htlc`. It reveals the preimage to everyone.
+pub fn htlc_claim(signature: u64, preimage: u64) -> Script {
+    vec![Op::PushNum(signature), Op::PushNum(preimage), Op::PushNum(1)]
+}
+
+/// Witness taking the refund branch of an `htlc`.
+pub fn htlc_refund(signature: u64) -> Script {
+    vec![Op::PushNum(signature), Op::PushNum(0)]
+}
+
+/// The preimage an `htlc_claim` witness revealed, if `witness` is one.
+pub fn revealed_preimage(witness: &[Op]) -> Option<u64> {
+    match witness {
+        [Op::PushNum(_), Op::PushNum(preimage), Op::PushNum(1)] => Some(*preimage),
+        _ => None,
+    }
+}
+
+/// What `Op::Hash` turns `preimage` into.
+pub fn hash_of(preimage: u64) -> u64 {
+    hash(&Item::Num(preimage))
+}
+
+/// Runs `witness` and then `lock`, failing unless the spend is authorized.
+pub fn verify(witness: &[Op], lock: &[Op], context: &ScriptContext) -> Result<(), ScriptError> {
+    if witness.len() > MAX_SCRIPT_SIZE || lock.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::ScriptTooLarge)
+    }
+    if !witness.iter().all(Op::is_push) {
+        return Err(ScriptError::WitnessNotPushOnly)
+    }
+
+    let mut interpreter = Interpreter { stack: Vec::new(), steps: 0, context };
+    interpreter.run(witness)?;
+    interpreter.run(lock)?;
+
+    match interpreter.stack.last() {
+        Some(item) if item.is_true() => Ok(()),
+        _ => Err(ScriptError::EvaluatedFalse),
+    }
+}
+
+struct Interpreter<'a> {
+    stack: Vec<Item>,
+    steps: usize,
+    context: &'a ScriptContext,
+}
+
+impl Interpreter<'_> {
+    fn run(&mut self, script: &[Op]) -> Result<(), ScriptError> {
+        // One entry per open `If`, telling whether its current branch runs.
+        let mut branches: Vec<bool> = Vec::new();
+
+        for op in script {
+            self.steps += 1;
+            if self.steps > MAX_STEPS {
+                return Err(ScriptError::TooManySteps)
+            }
+            let executing = branches.iter().all(|runs| *runs);
+
+            match op {
+                Op::If => {
+                    let runs = executing && self.pop()?.is_true();
+                    branches.push(runs);
+                }
+                Op::Else => {
+                    let outer = branches.len() < 2 || branches[..branches.len() - 1].iter().all(|runs| *runs);
+                    let runs = branches.last_mut().ok_or(ScriptError::UnbalancedConditional)?;
+                    *runs = outer && !*runs;
+                }
+                Op::EndIf => {
+                    branches.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                }
+                _ if !executing => {}
+                op => self.step(op)?,
+            }
+        }
+        if !branches.is_empty() {
+            return Err(ScriptError::UnbalancedConditional)
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, op: &Op) -> Result<(), ScriptError> {
+        match op {
+            Op::PushNum(n) => self.push(Item::Num(*n))?,
+            Op::PushKey(key) => self.push(Item::Key(*key))?,
+            Op::Dup => {
+                let top = *self.stack.last().ok_or(ScriptError::StackUnderflow)?;
+                self.push(top)?;
+            }
+            Op::Drop => {
+                self.pop()?;
+            }
+            Op::Swap => {
+                let (a, b) = (self.pop()?, self.pop()?);
+                self.push(a)?;
+                self.push(b)?;
+            }
+            Op::Hash => {
+                let item = self.pop()?;
+                self.push(Item::Num(hash(&item)))?;
+            }
+            Op::Equal => {
+                let equal = self.pop()? == self.pop()?;
+                self.push(Item::bool(equal))?;
+            }
+            Op::Verify => self.verify()?,
+            Op::EqualVerify => {
+                self.step(&Op::Equal)?;
+                self.verify()?;
+            }
+            Op::CheckSig => {
+                let key = self.pop_key()?;
+                let signature = self.pop_num()?;
+                self.push(Item::bool(verify_signature(public_key(key), self.context.sighash, signature)))?;
+            }
+            Op::CheckMultiSig => {
+                let valid = self.check_multisig()?;
+                self.push(Item::bool(valid))?;
+            }
+            Op::CheckLockTime => {
+                if self.context.height < self.pop_num()? {
+                    return Err(ScriptError::LockTimeNotReached)
+                }
+            }
+            Op::CheckSequence => {
+                let age = self.context.height.saturating_sub(self.context.output_height);
+                if age < self.pop_num()? {
+                    return Err(ScriptError::SequenceNotReached)
+                }
+            }
+            Op::Not => {
+                let value = self.pop()?.is_true();
+                self.push(Item::bool(!value))?;
+            }
+            Op::BoolAnd => {
+                let (a, b) = (self.pop()?.is_true(), self.pop()?.is_true());
+                self.push(Item::bool(a && b))?;
+            }
+            Op::BoolOr => {
+                let (a, b) = (self.pop()?.is_true(), self.pop()?.is_true());
+                self.push(Item::bool(a || b))?;
+            }
+            Op::If | Op::Else | Op::EndIf => unreachable!("conditionals are handled by `run`"),
+        }
+        Ok(())
+    }
+
+    fn check_multisig(&mut self) -> Result<bool, ScriptError> {
+        let n = self.pop_num()?;
+        if n as usize > self.stack.len() {
+            return Err(ScriptError::InvalidMultiSig)
+        }
+        let mut keys = (0..n).map(|_| self.pop_key()).collect::<Result<Vec<_>, _>>()?;
+        keys.reverse();
+
+        let m = self.pop_num()?;
+        if m > n || m as usize > self.stack.len() {
+            return Err(ScriptError::InvalidMultiSig)
+        }
+        let mut signatures = (0..m).map(|_| self.pop_num()).collect::<Result<Vec<_>, _>>()?;
+        signatures.reverse();
+
+        // Each signature has to match a later key than the one before it.
+        let mut keys = keys.into_iter();
+        Ok(signatures
+            .iter()
+            .all(|signature| keys.any(|key| verify_signature(public_key(key), self.context.sighash, *signature))))
+    }
+
+    fn verify(&mut self) -> Result<(), ScriptError> {
+        if self.pop()?.is_true() {
+            Ok(())
+        } else {
+            Err(ScriptError::VerifyFailed)
+        }
+    }
+
+    fn push(&mut self, item: Item) -> Result<(), ScriptError> {
+        if self.stack.len() >= MAX_STACK_SIZE {
+            return Err(ScriptError::StackOverflow)
+        }
+        self.stack.push(item);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Item, ScriptError> {
+        self.stack.pop().ok_or(ScriptError::StackUnderflow)
+    }
+
+    fn pop_num(&mut self) -> Result<u64, ScriptError> {
+        match self.pop()? {
+            Item::Num(n) => Ok(n),
+            Item::Key(_) => Err(ScriptError::TypeMismatch),
+        }
+    }
+
+    fn pop_key(&mut self) -> Result<User, ScriptError> {
+        match self.pop()? {
+            Item::Key(key) => Ok(key),
+            Item::Num(_) => Err(ScriptError::TypeMismatch),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    const CONTEXT: ScriptContext = ScriptContext { sighash: 42, height: 10, output_height: 4 };
+
+    fn signed_by(user: User) -> Op {
+        Op::PushNum(sign(&SecretKey::dev(user), CONTEXT.sighash))
+    }
+
+    #[test]
+    fn pay_to_key_needs_owner_signature() {
+        let lock = pay_to_key(User::Alice);
+
+        assert_eq!(verify(&[signed_by(User::Alice)], &lock, &CONTEXT), Ok(()));
+        assert_eq!(verify(&[signed_by(User::Bob)], &lock, &CONTEXT), Err(ScriptError::EvaluatedFalse));
+    }
+
+    #[test]
+    fn signature_commits_to_sighash() {
+        let other = ScriptContext { sighash: 43, ..CONTEXT };
+
+        assert_eq!(
+            verify(&[signed_by(User::Alice)], &pay_to_key(User::Alice), &other),
+            Err(ScriptError::EvaluatedFalse)
+        );
+    }
+
+    #[test]
+    fn signatures_need_the_secret_key() {
+        let alice = SecretKey::dev(User::Alice);
+        let signature = sign(&alice, CONTEXT.sighash);
+        assert!(verify_signature(alice.public(), CONTEXT.sighash, signature));
+
+        // Neither what used to pass for Alice's signature nor one made with another secret
+        // checks against her public key.
+        let forged = [hash(&(User::Alice, CONTEXT.sighash)), sign(&SecretKey::new(1234), CONTEXT.sighash), signature ^ 1];
+        for forgery in forged {
+            assert!(!verify_signature(public_key(User::Alice), CONTEXT.sighash, forgery));
+            assert_eq!(verify(&[Op::PushNum(forgery)], &pay_to_key(User::Alice), &CONTEXT), Err(ScriptError::EvaluatedFalse));
+        }
+    }
+
+    #[test]
+    fn hash_lock_needs_preimage() {
+        let lock = hash_lock(hash_of(7));
+
+        assert_eq!(verify(&[Op::PushNum(7)], &lock, &CONTEXT), Ok(()));
+        assert_eq!(verify(&[Op::PushNum(8)], &lock, &CONTEXT), Err(ScriptError::EvaluatedFalse));
+    }
+
+    #[test]
+    fn two_of_three_multisig() {
+        let lock = multisig(2, &[User::Alice, User::Bob, User::Charlie]);
+        let witness = |first, second| [signed_by(first), signed_by(second)];
+
+        assert_eq!(verify(&witness(User::Alice, User::Charlie), &lock, &CONTEXT), Ok(()));
+        assert_eq!(verify(&witness(User::Bob, User::Charlie), &lock, &CONTEXT), Ok(()));
+        assert_eq!(
+            verify(&witness(User::Charlie, User::Alice), &lock, &CONTEXT),
+            Err(ScriptError::EvaluatedFalse)
+        );
+        assert_eq!(
+            verify(&witness(User::Alice, User::Alice), &lock, &CONTEXT),
+            Err(ScriptError::EvaluatedFalse)
+        );
+    }
+
+    #[test]
+    fn timelocks() {
+        let absolute = |height| [vec![Op::PushNum(height), Op::CheckLockTime], pay_to_key(User::Alice)].concat();
+        let relative = |age| [vec![Op::PushNum(age), Op::CheckSequence], pay_to_key(User::Alice)].concat();
+        let witness = [signed_by(User::Alice)];
+
+        assert_eq!(verify(&witness, &absolute(10), &CONTEXT), Ok(()));
+        assert_eq!(verify(&witness, &absolute(11), &CONTEXT), Err(ScriptError::LockTimeNotReached));
+        assert_eq!(verify(&witness, &relative(6), &CONTEXT), Ok(()));
+        assert_eq!(verify(&witness, &relative(7), &CONTEXT), Err(ScriptError::SequenceNotReached));
+    }
+
+    #[test]
+    fn branches_pick_spending_path() {
+        // Alice can spend any time, Bob only from block 20 on.
+        let lock = vec![
+            Op::If,
+            Op::PushKey(User::Alice),
+            Op::Else,
+            Op::PushNum(20),
+            Op::CheckLockTime,
+            Op::PushKey(User::Bob),
+            Op::EndIf,
+            Op::CheckSig,
+        ];
+
+        assert_eq!(verify(&[signed_by(User::Alice), Op::PushNum(1)], &lock, &CONTEXT), Ok(()));
+        assert_eq!(
+            verify(&[signed_by(User::Bob), Op::PushNum(0)], &lock, &CONTEXT),
+            Err(ScriptError::LockTimeNotReached)
+        );
+        let later = ScriptContext { height: 20, ..CONTEXT };
+        assert_eq!(verify(&[signed_by(User::Bob), Op::PushNum(0)], &lock, &later), Ok(()));
+    }
+
+    #[test]
+    fn htlc_claim_and_refund() {
+        let lock = htlc(hash_of(7), User::Bob, User::Alice, 20);
+        let claim = |preimage| htlc_claim(sign(&SecretKey::dev(User::Bob), CONTEXT.sighash), preimage);
+        let refund = htlc_refund(sign(&SecretKey::dev(User::Alice), CONTEXT.sighash));
+        let expired = ScriptContext { height: 20, ..CONTEXT };
+
+        assert_eq!(verify(&claim(7), &lock, &CONTEXT), Ok(()));
+        assert_eq!(verify(&claim(8), &lock, &CONTEXT), Err(ScriptError::VerifyFailed));
+        assert_eq!(verify(&refund, &lock, &CONTEXT), Err(ScriptError::LockTimeNotReached));
+        assert_eq!(verify(&refund, &lock, &expired), Ok(()));
+        assert_eq!(revealed_preimage(&claim(7)), Some(7));
+        assert_eq!(revealed_preimage(&refund), None);
+    }
+
+    #[test]
+    fn boolean_combinators() {
+        let lock = vec![Op::PushNum(1), Op::PushNum(0), Op::BoolOr, Op::PushNum(1), Op::BoolAnd, Op::Not, Op::Not];
+
+        assert_eq!(verify(&[], &lock, &CONTEXT), Ok(()));
+    }
+
+    #[test]
+    fn witness_must_be_push_only() {
+        let witness = [Op::PushNum(1), Op::Dup];
+
+        assert_eq!(verify(&witness, &[], &CONTEXT), Err(ScriptError::WitnessNotPushOnly));
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let too_large = vec![Op::PushNum(1); MAX_SCRIPT_SIZE + 1];
+        let overflowing = vec![Op::PushNum(1); MAX_STACK_SIZE + 1];
+        let witness = vec![Op::PushNum(1); MAX_STACK_SIZE - 1];
+        let too_long = [Op::Drop, Op::PushNum(1)].repeat((MAX_STEPS - witness.len()) / 2 + 1);
+
+        assert_eq!(verify(&[], &too_large, &CONTEXT), Err(ScriptError::ScriptTooLarge));
+        assert_eq!(verify(&[], &overflowing, &CONTEXT), Err(ScriptError::StackOverflow));
+        assert_eq!(verify(&witness, &too_long, &CONTEXT), Err(ScriptError::TooManySteps));
+    }
+
+    #[test]
+    fn malformed_scripts_fail() {
+        assert_eq!(verify(&[], &[Op::Drop], &CONTEXT), Err(ScriptError::StackUnderflow));
+        assert_eq!(verify(&[Op::PushNum(1)], &[Op::If], &CONTEXT), Err(ScriptError::UnbalancedConditional));
+        assert_eq!(verify(&[], &[Op::EndIf], &CONTEXT), Err(ScriptError::UnbalancedConditional));
+        assert_eq!(verify(&[Op::PushKey(User::Bob)], &pay_to_key(User::Alice), &CONTEXT), Err(ScriptError::TypeMismatch));
+    }
+}
@@ -0,0 +1,701 @@
+#![allow(dead_code)]
+
+//! Proof-of-Stake on top of an `AccountedCurrency`. Holders bond part of their native balance,
+//! either to run a validator themselves or to back validators they trust. Every era the most
+//! backed candidates are elected as the set of validators a consensus engine accepts blocks
+//! from. They earn rewards for it and lose part of their stake when caught equivocating.
+
+use super::accounted_currency::{self, AccountedCurrency, AccountingError, AccountingTransaction};
+use super::{BlockContext, BlockHooks, StateMachine, User};
+use crate::blockchain::executor::Header;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+
+pub struct Staking;
+
+/// Nominators back at most this many validators.
+pub const MAX_NOMINATIONS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StakingConfig {
+    /// Blocks in an era. The set of validators changes at the first block of every era.
+    pub era_length: u64,
+    /// Blocks unbonded stake stays reserved, and slashable, before it can be withdrawn.
+    pub unbonding_period: u64,
+    /// How many candidates an election picks at most.
+    pub validator_count: usize,
+    /// Minted at the end of every era and shared evenly by the validators elected for it.
+    pub era_reward: u64,
+    /// Percentage of its exposure an equivocating validator and its nominators lose.
+    pub slash_percent: u64,
+}
+
+impl Default for StakingConfig {
+    fn default() -> Self {
+        StakingConfig { era_length: 10, unbonding_period: 20, validator_count: 2, era_reward: 100, slash_percent: 10 }
+    }
+}
+
+/// Stake on its way out of the bond.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Unlocking {
+    pub amount: u64,
+    /// The first block `amount` can be withdrawn in.
+    pub until: u64,
+}
+
+/// What a staker has bonded. All of it is held in the reserved balance.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Ledger {
+    /// Stake that counts in elections.
+    pub active: u64,
+    pub unlocking: Vec<Unlocking>,
+}
+
+impl Ledger {
+    pub fn total(&self) -> u64 {
+        self.unlocking.iter().fold(self.active, |total, chunk| total.saturating_add(chunk.amount))
+    }
+
+    // Takes up to `amount` from the active stake first, then from the newest unlocking chunks.
+    fn slash(&mut self, amount: u64) -> u64 {
+        let mut slashed = amount.min(self.active);
+        self.active -= slashed;
+
+        for chunk in self.unlocking.iter_mut().rev() {
+            let taken = (amount - slashed).min(chunk.amount);
+            chunk.amount -= taken;
+            slashed += taken;
+        }
+        self.unlocking.retain(|chunk| chunk.amount > 0);
+        slashed
+    }
+}
+
+/// An elected validator and the stake backing it for the era.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Exposure {
+    pub validator: User,
+    /// The validator's own active stake.
+    pub own: u64,
+    /// The share of each nominator's stake that backs this validator.
+    pub others: Vec<(User, u64)>,
+    pub total: u64,
+}
+
+impl Exposure {
+    /// Everyone with stake behind the validator, the validator first.
+    pub fn stakers(&self) -> impl Iterator<Item = (User, u64)> + '_ {
+        std::iter::once((self.validator, self.own)).chain(self.others.iter().copied())
+    }
+}
+
+/// Proof that `offender` authored two different blocks at the same height: both headers, each
+/// signed by the offender.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Equivocation {
+    pub offender: User,
+    pub first: Header,
+    pub second: Header,
+}
+
+impl Equivocation {
+    /// The height both blocks claim.
+    pub fn height(&self) -> u64 {
+        self.first.height()
+    }
+
+    fn is_valid(&self) -> bool {
+        let authored = |header: &Header| header.author() == Some(self.offender) && header.is_signed_by_author();
+
+        authored(&self.first)
+            && authored(&self.second)
+            && self.first.height() == self.second.height()
+            && self.first.signing_hash() != self.second.signing_hash()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct State {
+    currency: accounted_currency::State,
+    config: StakingConfig,
+    ledgers: BTreeMap<User, Ledger>,
+    /// Who wants to validate from the next election on.
+    candidates: BTreeSet<User>,
+    nominations: BTreeMap<User, Vec<User>>,
+    era: u64,
+    /// The validators of the current era, most backed first.
+    elected: Vec<Exposure>,
+    /// Offences that were slashed already, so none is punished twice.
+    offences: BTreeSet<(User, u64)>,
+}
+
+impl State {
+    pub fn new(currency: accounted_currency::State) -> Self {
+        State {
+            currency,
+            config: StakingConfig::default(),
+            ledgers: BTreeMap::new(),
+            candidates: BTreeSet::new(),
+            nominations: BTreeMap::new(),
+            era: 0,
+            elected: Vec::new(),
+            offences: BTreeSet::new(),
+        }
+    }
+
+    pub fn with_config(mut self, config: StakingConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn currency(&self) -> &accounted_currency::State {
+        &self.currency
+    }
+
+    pub fn config(&self) -> StakingConfig {
+        self.config
+    }
+
+    pub fn ledger(&self, user: &User) -> Option<&Ledger> {
+        self.ledgers.get(user)
+    }
+
+    pub fn is_candidate(&self, user: &User) -> bool {
+        self.candidates.contains(user)
+    }
+
+    pub fn nominations(&self, user: &User) -> &[User] {
+        self.nominations.get(user).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn era(&self) -> u64 {
+        self.era
+    }
+
+    /// The height the next era, and with it the next set of validators, starts at.
+    pub fn next_era_start(&self) -> u64 {
+        self.era.saturating_add(1).saturating_mul(self.config.era_length)
+    }
+
+    pub fn elected(&self) -> &[Exposure] {
+        &self.elected
+    }
+
+    /// The authors a consensus engine should accept blocks from this era, most backed first.
+    pub fn validators(&self) -> Vec<User> {
+        self.elected.iter().map(|exposure| exposure.validator).collect()
+    }
+
+    fn active(&self, user: &User) -> u64 {
+        self.ledgers.get(user).map_or(0, |ledger| ledger.active)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StakingTransaction {
+    Currency(AccountingTransaction),
+    /// Adds `amount` of the free balance to `who`'s active stake.
+    Bond { who: User, amount: u64, nonce: u64 },
+    /// Starts taking `amount` out of the active stake. It can be withdrawn once the unbonding
+    /// period is over. Unbonding everything stops validating and nominating.
+    Unbond { who: User, amount: u64, nonce: u64 },
+    /// Returns every unlocking chunk whose unbonding period is over to the free balance.
+    WithdrawUnbonded { who: User, nonce: u64 },
+    /// Stands for election as a validator, giving up any nominations.
+    Validate { who: User, nonce: u64 },
+    /// Backs `targets` with the active stake in elections, giving up any candidacy.
+    Nominate { who: User, targets: Vec<User>, nonce: u64 },
+    /// Stops validating and nominating from the next election on.
+    Chill { who: User, nonce: u64 },
+    /// Slashes the stake behind a validator of this era that equivocated and removes it from the
+    /// validator set.
+    ReportEquivocation { reporter: User, proof: Equivocation, nonce: u64 },
+}
+
+impl StakingTransaction {
+    /// The account that signed the transaction and whose nonce it consumes.
+    pub fn signer(&self) -> User {
+        match self {
+            StakingTransaction::Currency(t) => t.signer(),
+            StakingTransaction::Bond { who, .. }
+            | StakingTransaction::Unbond { who, .. }
+            | StakingTransaction::WithdrawUnbonded { who, .. }
+            | StakingTransaction::Validate { who, .. }
+            | StakingTransaction::Nominate { who, .. }
+            | StakingTransaction::Chill { who, .. } => *who,
+            StakingTransaction::ReportEquivocation { reporter, .. } => *reporter,
+        }
+    }
+
+    pub fn nonce(&self) -> u64 {
+        match self {
+            StakingTransaction::Currency(t) => t.nonce(),
+            StakingTransaction::Bond { nonce, .. }
+            | StakingTransaction::Unbond { nonce, .. }
+            | StakingTransaction::WithdrawUnbonded { nonce, .. }
+            | StakingTransaction::Validate { nonce, .. }
+            | StakingTransaction::Nominate { nonce, .. }
+            | StakingTransaction::Chill { nonce, .. }
+            | StakingTransaction::ReportEquivocation { nonce, .. } => *nonce,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StakingError {
+    Currency(AccountingError),
+    ZeroAmount,
+    /// Validating and nominating need active stake.
+    NotBonded,
+    /// Stakers can only unbond what is active.
+    InsufficientBond,
+    NoTargets,
+    TooManyTargets,
+    /// The headers are not two different blocks of the offender at one height.
+    InvalidProof,
+    /// The blocks are not from the current era, or not from the past.
+    OutsideEra,
+    /// The offender is not a validator of this era.
+    NotValidator,
+    DuplicateReport,
+    Overflow,
+}
+
+impl Staking {
+    // Changes `state` as it goes, `try_next_state` works on a copy.
+    fn execute(state: &mut State, transition: &StakingTransaction) -> Result<(), StakingError> {
+        // Currency transactions consume their nonce themselves.
+        if !matches!(transition, StakingTransaction::Currency(_)) {
+            state.currency.use_nonce(transition.signer(), transition.nonce()).map_err(StakingError::Currency)?;
+        }
+        match transition {
+            StakingTransaction::Currency(t) => {
+                AccountedCurrency::apply(&mut state.currency, t).map_err(StakingError::Currency)?;
+            }
+            StakingTransaction::Bond { who, amount, .. } => {
+                if *amount == 0 {
+                    return Err(StakingError::ZeroAmount)
+                }
+                let ledger = state.ledgers.entry(*who).or_default();
+                let active = ledger.active.checked_add(*amount).ok_or(StakingError::Overflow)?;
+
+                state.currency.reserve(*who, *amount).map_err(StakingError::Currency)?;
+                ledger.active = active;
+            }
+            StakingTransaction::Unbond { who, amount, .. } => {
+                if *amount == 0 {
+                    return Err(StakingError::ZeroAmount)
+                }
+                let until = state.currency.block_height().checked_add(state.config.unbonding_period).ok_or(StakingError::Overflow)?;
+                let ledger = state.ledgers.get_mut(who).ok_or(StakingError::NotBonded)?;
+
+                if *amount > ledger.active {
+                    return Err(StakingError::InsufficientBond)
+                }
+                ledger.active -= amount;
+                ledger.unlocking.push(Unlocking { amount: *amount, until });
+
+                if ledger.active == 0 {
+                    Self::chill(state, who);
+                }
+            }
+            StakingTransaction::WithdrawUnbonded { who, .. } => {
+                let height = state.currency.block_height();
+                let ledger = state.ledgers.get_mut(who).ok_or(StakingError::NotBonded)?;
+
+                let (ready, waiting): (Vec<Unlocking>, _) = ledger.unlocking.iter().partition(|chunk| chunk.until <= height);
+                ledger.unlocking = waiting;
+                let withdrawn = ready.iter().map(|chunk| chunk.amount).fold(0, u64::saturating_add);
+
+                if ledger.total() == 0 {
+                    state.ledgers.remove(who);
+                }
+                state.currency.unreserve(*who, withdrawn);
+            }
+            StakingTransaction::Validate { who, .. } => {
+                if state.active(who) == 0 {
+                    return Err(StakingError::NotBonded)
+                }
+                state.nominations.remove(who);
+                state.candidates.insert(*who);
+            }
+            StakingTransaction::Nominate { who, targets, .. } => {
+                if state.active(who) == 0 {
+                    return Err(StakingError::NotBonded)
+                }
+                let targets: Vec<User> = targets.iter().collect::<BTreeSet<_>>().into_iter().copied().collect();
+
+                if targets.is_empty() {
+                    return Err(StakingError::NoTargets)
+                }
+                if targets.len() > MAX_NOMINATIONS {
+                    return Err(StakingError::TooManyTargets)
+                }
+                state.candidates.remove(who);
+                state.nominations.insert(*who, targets);
+            }
+            StakingTransaction::Chill { who, .. } => Self::chill(state, who),
+            StakingTransaction::ReportEquivocation { proof, .. } => {
+                if !proof.is_valid() {
+                    return Err(StakingError::InvalidProof)
+                }
+                let era_start = state.era.saturating_mul(state.config.era_length);
+                let height = proof.height();
+
+                if height < era_start || height >= state.next_era_start() || height > state.currency.block_height() {
+                    return Err(StakingError::OutsideEra)
+                }
+                let position =
+                    state.elected.iter().position(|exposure| exposure.validator == proof.offender).ok_or(StakingError::NotValidator)?;
+
+                if !state.offences.insert((proof.offender, height)) {
+                    return Err(StakingError::DuplicateReport)
+                }
+                let exposure = state.elected.remove(position);
+                Self::chill(state, &proof.offender);
+                Self::slash(state, &exposure);
+            }
+        }
+        Ok(())
+    }
+
+    fn chill(state: &mut State, who: &User) {
+        state.candidates.remove(who);
+        state.nominations.remove(who);
+    }
+
+    // Everyone behind the offender loses the same share of what they exposed, even if they
+    // started unbonding it since.
+    fn slash(state: &mut State, exposure: &Exposure) {
+        for (staker, stake) in exposure.stakers() {
+            let amount = (stake as u128 * state.config.slash_percent.min(100) as u128 / 100) as u64;
+
+            if let Some(ledger) = state.ledgers.get_mut(&staker) {
+                let slashed = ledger.slash(amount);
+                state.currency.slash_reserved(staker, slashed);
+            }
+        }
+    }
+
+    /// Picks the validators for the next era. Candidates are ranked by their own stake plus
+    /// the whole stake of everyone nominating them. The stake of a nominator is then split
+    /// evenly between the winners it nominated.
+    pub fn elect(state: &State) -> Vec<Exposure> {
+        let mut approvals: BTreeMap<User, u64> = state.candidates.iter().map(|candidate| (*candidate, state.active(candidate))).collect();
+        for (nominator, targets) in &state.nominations {
+            for target in targets {
+                if let Some(approval) = approvals.get_mut(target) {
+                    *approval = approval.saturating_add(state.active(nominator));
+                }
+            }
+        }
+        let mut ranked: Vec<(User, u64)> = approvals.into_iter().filter(|(_, approval)| *approval > 0).collect();
+        // A stable sort, so ties go to the lower `User`.
+        ranked.sort_by_key(|(_, approval)| Reverse(*approval));
+        ranked.truncate(state.config.validator_count);
+
+        let mut exposures: BTreeMap<User, Exposure> = ranked
+            .iter()
+            .map(|(validator, _)| {
+                let own = state.active(validator);
+                (*validator, Exposure { validator: *validator, own, others: Vec::new(), total: own })
+            })
+            .collect();
+        for (nominator, targets) in &state.nominations {
+            let backed: Vec<User> = targets.iter().filter(|target| exposures.contains_key(target)).copied().collect();
+            if backed.is_empty() {
+                continue
+            }
+            let stake = state.active(nominator);
+            let share = stake / backed.len() as u64;
+            let remainder = stake % backed.len() as u64;
+
+            for (i, validator) in backed.iter().enumerate() {
+                let amount = if i == 0 { share + remainder } else { share };
+                let exposure = exposures.get_mut(validator).expect("only winners are backed");
+                if amount > 0 {
+                    exposure.others.push((*nominator, amount));
+                    exposure.total = exposure.total.saturating_add(amount);
+                }
+            }
+        }
+        ranked.iter().filter_map(|(validator, _)| exposures.remove(validator)).collect()
+    }
+
+    // Splits the era reward evenly between the validators, and each validator's part between
+    // its stakers by their stake. Rewards go to the free balance.
+    fn pay_rewards(state: &mut State) {
+        let elected = std::mem::take(&mut state.elected);
+        if elected.is_empty() {
+            return
+        }
+        let part = state.config.era_reward / elected.len() as u64;
+
+        for exposure in elected.iter().filter(|exposure| exposure.total > 0) {
+            for (staker, stake) in exposure.stakers() {
+                let reward = (part as u128 * stake as u128 / exposure.total as u128) as u64;
+                // Stakers keep at least the existential deposit free, so only an overflowing
+                // supply can refuse a reward, and then there is nothing left to mint anyway.
+                let _ = state.currency.issue(staker, reward);
+            }
+        }
+    }
+}
+
+impl StateMachine for Staking {
+    type State = State;
+    type Transition = StakingTransaction;
+    type Error = StakingError;
+
+    fn next_state(starting_state: &State, transition: &StakingTransaction) -> State {
+        Self::try_next_state(starting_state, transition).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state(starting_state: &State, transition: &StakingTransaction) -> Result<State, StakingError> {
+        let mut new_state = starting_state.clone();
+        Self::execute(&mut new_state, transition)?;
+        Ok(new_state)
+    }
+
+    fn human_name() -> String {
+        "Staking".into()
+    }
+}
+
+/// The first block of every era pays the validators of the one that ended and elects the next.
+impl BlockHooks for Staking {
+    fn on_initialize(state: &mut State, context: &BlockContext) {
+        AccountedCurrency::on_initialize(&mut state.currency, context);
+
+        if context.height > 0 && context.height.checked_rem(state.config.era_length) == Some(0) {
+            Self::pay_rewards(state);
+            state.era += 1;
+            state.elected = Self::elect(state);
+        }
+    }
+
+    fn on_finalize(state: &mut State, context: &BlockContext) {
+        AccountedCurrency::on_finalize(&mut state.currency, context);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::state_machine::accounted_currency::NATIVE_ASSET;
+    use crate::state_machine::script::SecretKey;
+
+    fn genesis() -> State {
+        State::new(accounted_currency::State::from([(User::Alice, 1_000), (User::Bob, 1_000), (User::Charlie, 1_000)]))
+    }
+
+    fn apply(state: State, transitions: &[StakingTransaction]) -> State {
+        transitions.iter().fold(state, |state, t| Staking::try_next_state(&state, t).unwrap())
+    }
+
+    fn bond(who: User, amount: u64, nonce: u64) -> StakingTransaction {
+        StakingTransaction::Bond { who, amount, nonce }
+    }
+
+    fn run_until(state: &mut State, height: u64) {
+        for height in state.currency.block_height() + 1..=height {
+            let context = BlockContext { height, author: None };
+            Staking::on_initialize(state, &context);
+            Staking::on_finalize(state, &context);
+        }
+    }
+
+    /// Alice validates with 300, Bob with 100, and Charlie nominates both with 400.
+    fn elected() -> State {
+        let mut state = apply(genesis(), &[
+            bond(User::Alice, 300, 0),
+            StakingTransaction::Validate { who: User::Alice, nonce: 1 },
+            bond(User::Bob, 100, 0),
+            StakingTransaction::Validate { who: User::Bob, nonce: 1 },
+            bond(User::Charlie, 400, 0),
+            StakingTransaction::Nominate { who: User::Charlie, targets: vec![User::Bob, User::Alice, User::Bob], nonce: 1 },
+        ]);
+        run_until(&mut state, 10);
+        state
+    }
+
+    #[test]
+    fn unbonded_stake_waits_out_the_unbonding_period() {
+        let state = apply(genesis(), &[bond(User::Alice, 300, 0)]);
+        assert_eq!(state.currency().reserved(&User::Alice), 300);
+        assert_eq!(state.currency().balance(NATIVE_ASSET, &User::Alice), 700);
+
+        let unbond = |amount| StakingTransaction::Unbond { who: User::Alice, amount, nonce: 1 };
+        assert_eq!(Staking::try_next_state(&state, &unbond(301)), Err(StakingError::InsufficientBond));
+        let mut state = apply(state, &[unbond(100)]);
+        assert_eq!(state.ledger(&User::Alice).map(Ledger::total), Some(300));
+
+        let withdraw = StakingTransaction::WithdrawUnbonded { who: User::Alice, nonce: 2 };
+        run_until(&mut state, 19);
+        let early = apply(state.clone(), std::slice::from_ref(&withdraw));
+        assert_eq!(early.currency().reserved(&User::Alice), 300);
+
+        run_until(&mut state, 20);
+        let state = apply(state, &[withdraw]);
+        assert_eq!(state.currency().reserved(&User::Alice), 200);
+        assert_eq!(state.ledger(&User::Alice), Some(&Ledger { active: 200, unlocking: Vec::new() }));
+    }
+
+    #[test]
+    fn validating_and_nominating_need_a_bond_and_exclude_each_other() {
+        let validate = |nonce| StakingTransaction::Validate { who: User::Alice, nonce };
+        assert_eq!(Staking::try_next_state(&genesis(), &validate(0)), Err(StakingError::NotBonded));
+
+        let state = apply(genesis(), &[bond(User::Alice, 100, 0), validate(1)]);
+        assert!(state.is_candidate(&User::Alice));
+        let nominate = |targets| StakingTransaction::Nominate { who: User::Alice, targets, nonce: 2 };
+        assert_eq!(Staking::try_next_state(&state, &nominate(vec![])), Err(StakingError::NoTargets));
+
+        let state = apply(state, &[nominate(vec![User::Bob])]);
+        assert!(!state.is_candidate(&User::Alice));
+        assert_eq!(state.nominations(&User::Alice), &[User::Bob]);
+
+        let state = apply(state, &[StakingTransaction::Unbond { who: User::Alice, amount: 100, nonce: 3 }]);
+        assert_eq!(state.nominations(&User::Alice), &[] as &[User]);
+    }
+
+    #[test]
+    fn election_at_the_era_boundary_ranks_candidates_by_backing() {
+        let mut state = apply(genesis(), &[bond(User::Alice, 300, 0), StakingTransaction::Validate { who: User::Alice, nonce: 1 }]);
+        run_until(&mut state, 9);
+        assert_eq!((state.era(), state.validators()), (0, vec![]));
+
+        let mut state = elected();
+        assert_eq!((state.era(), state.next_era_start()), (1, 20));
+        // Charlie's whole 400 counts for both in the ranking, but is split between them after.
+        assert_eq!(state.validators(), vec![User::Alice, User::Bob]);
+        assert_eq!(state.elected()[0], Exposure { validator: User::Alice, own: 300, others: vec![(User::Charlie, 200)], total: 500 });
+        assert_eq!(state.elected()[1].total, 300);
+
+        // A smaller set only keeps the most backed candidate.
+        state.config.validator_count = 1;
+        assert_eq!(Staking::elect(&state).len(), 1);
+        assert_eq!(Staking::elect(&state)[0].validator, User::Alice);
+    }
+
+    #[test]
+    fn rewards_are_shared_by_exposure() {
+        let mut state = elected();
+        let issuance = state.currency().total_issuance();
+        run_until(&mut state, 20);
+
+        // 50 for each validator, split by stake: Alice 300 of 500, Bob 100 of 300.
+        assert_eq!(state.currency().balance(NATIVE_ASSET, &User::Alice), 700 + 30);
+        assert_eq!(state.currency().balance(NATIVE_ASSET, &User::Bob), 900 + 16);
+        assert_eq!(state.currency().balance(NATIVE_ASSET, &User::Charlie), 600 + 20 + 33);
+        assert_eq!(state.currency().total_issuance(), issuance + 99);
+        assert_eq!(state.era(), 2);
+    }
+
+    /// A header at `height` claiming to be by `author`, told apart by `root` and not signed yet.
+    fn unsigned_header(author: User, height: u64, root: u64) -> Header {
+        (1..height).fold(Header::genesis(), |parent, _| parent.child(User::Bob, 0)).child(author, root)
+    }
+
+    /// A header `author` signed at `height`, told apart by `root`.
+    fn header(author: User, height: u64, root: u64) -> Header {
+        let mut header = unsigned_header(author, height, root);
+        header.sign(&SecretKey::dev(author));
+        header
+    }
+
+    fn equivocation(offender: User, height: u64) -> Equivocation {
+        Equivocation { offender, first: header(offender, height, 1), second: header(offender, height, 2) }
+    }
+
+    fn report(proof: Equivocation, nonce: u64) -> StakingTransaction {
+        StakingTransaction::ReportEquivocation { reporter: User::Bob, proof, nonce }
+    }
+
+    #[test]
+    fn equivocation_slashes_the_exposure() {
+        let mut state = elected();
+        run_until(&mut state, 12);
+        let proof = equivocation(User::Alice, 12);
+
+        let charlie = equivocation(User::Charlie, 12);
+        assert_eq!(Staking::try_next_state(&state, &report(charlie, 2)), Err(StakingError::NotValidator));
+        let mut state = apply(state, &[report(proof.clone(), 2)]);
+
+        // Alice loses 10% of 300 and Charlie 10% of the 200 exposed to her, not of the 400 bonded.
+        assert_eq!(state.ledger(&User::Alice).unwrap().active, 270);
+        assert_eq!(state.ledger(&User::Charlie).unwrap().active, 380);
+        assert_eq!(state.currency().reserved(&User::Charlie), 380);
+        assert_eq!(state.currency().total_issuance(), 3_000 - 50);
+        assert_eq!(state.validators(), vec![User::Bob]);
+        assert!(!state.is_candidate(&User::Alice));
+
+        let rejoined = apply(state.clone(), &[StakingTransaction::Validate { who: User::Alice, nonce: 2 }]);
+        assert_eq!(Staking::try_next_state(&rejoined, &report(proof, 3)), Err(StakingError::NotValidator));
+        run_until(&mut state, 20);
+        assert_eq!(state.validators(), vec![User::Bob]);
+    }
+
+    #[test]
+    fn malformed_equivocations_are_rejected() {
+        let mut state = elected();
+        run_until(&mut state, 12);
+        let reject = |proof| Staking::try_next_state(&state, &report(proof, 2));
+
+        let same_block = Equivocation { second: header(User::Alice, 12, 1), ..equivocation(User::Alice, 12) };
+        assert_eq!(reject(same_block), Err(StakingError::InvalidProof));
+        let someone_elses = Equivocation { second: header(User::Bob, 12, 2), ..equivocation(User::Alice, 12) };
+        assert_eq!(reject(someone_elses), Err(StakingError::InvalidProof));
+        let other_height = Equivocation { second: header(User::Alice, 11, 2), ..equivocation(User::Alice, 12) };
+        assert_eq!(reject(other_height), Err(StakingError::InvalidProof));
+
+        // Blocks from the era before, or not built yet, can't be reported.
+        assert_eq!(reject(equivocation(User::Alice, 9)), Err(StakingError::OutsideEra));
+        assert_eq!(reject(equivocation(User::Alice, 13)), Err(StakingError::OutsideEra));
+        assert!(reject(equivocation(User::Alice, 10)).is_ok());
+    }
+
+    #[test]
+    fn equivocations_need_the_offenders_signatures() {
+        let mut state = elected();
+        run_until(&mut state, 12);
+        let reject = |proof| Staking::try_next_state(&state, &report(proof, 2));
+
+        let unsigned = Equivocation {
+            offender: User::Alice,
+            first: unsigned_header(User::Alice, 12, 1),
+            second: unsigned_header(User::Alice, 12, 2),
+        };
+        assert_eq!(reject(unsigned.clone()), Err(StakingError::InvalidProof));
+
+        let mut forged = unsigned_header(User::Alice, 12, 2);
+        forged.sign(&SecretKey::dev(User::Bob));
+        assert_eq!(reject(Equivocation { second: forged, ..equivocation(User::Alice, 12) }), Err(StakingError::InvalidProof));
+        let half_signed = Equivocation { first: header(User::Alice, 12, 1), ..unsigned };
+        assert_eq!(reject(half_signed), Err(StakingError::InvalidProof));
+    }
+
+    #[test]
+    fn staking_transactions_can_not_be_replayed() {
+        let state = apply(genesis(), &[bond(User::Alice, 300, 0)]);
+        let stale = Err(StakingError::Currency(AccountingError::StaleNonce));
+        assert_eq!(Staking::try_next_state(&state, &bond(User::Alice, 300, 0)), stale);
+        assert_eq!(state.currency().nonce(&User::Alice), 1);
+
+        // A rejected transaction leaves the nonce for the next one.
+        let chill = StakingTransaction::Chill { who: User::Alice, nonce: 2 };
+        assert_eq!(Staking::try_next_state(&state, &chill), Err(StakingError::Currency(AccountingError::FutureNonce)));
+        let state = apply(state, &[StakingTransaction::Chill { who: User::Alice, nonce: 1 }]);
+        assert_eq!(state.currency().nonce(&User::Alice), 2);
+    }
+
+    #[test]
+    fn slashing_reaches_unlocking_stake() {
+        let mut ledger = Ledger { active: 10, unlocking: vec![Unlocking { amount: 5, until: 3 }, Unlocking { amount: 5, until: 4 }] };
+
+        assert_eq!(ledger.slash(17), 17);
+        assert_eq!(ledger, Ledger { active: 0, unlocking: vec![Unlocking { amount: 3, until: 3 }] });
+        assert_eq!(ledger.slash(10), 3);
+    }
+}
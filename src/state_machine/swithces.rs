@@ -1,4 +1,7 @@
+#![allow(dead_code)]
+
 use super::StateMachine;
+use std::convert::Infallible;
 
 pub struct LightSwitch;
 
@@ -6,6 +9,7 @@ pub struct LightSwitch;
 impl StateMachine for LightSwitch {
     type State = bool;
     type Transition = ();
+    type Error = Infallible;
 
     fn next_state(starting_state: &bool, _transition: &()) -> Self::State {
         match starting_state {
@@ -15,10 +19,10 @@ impl StateMachine for LightSwitch {
     }
 }
 
-struct WeirdStateMachine;
+pub struct WeirdStateMachine;
 
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
 pub struct TwoSwitches {
     first_switch: bool,
     second_switch: bool
@@ -26,7 +30,7 @@ pub struct TwoSwitches {
 
 #[allow(dead_code)]
 impl TwoSwitches {
-    fn new(first_switch: bool, second_switch: bool) -> Self {
+    pub fn new(first_switch: bool, second_switch: bool) -> Self {
         TwoSwitches {
             first_switch,
             second_switch
@@ -35,7 +39,8 @@ impl TwoSwitches {
 }
 
 #[allow(dead_code)]
-enum Toggle {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Toggle {
     FirstSwitch,
     SecondSwitch
 }
@@ -43,11 +48,12 @@ enum Toggle {
 impl StateMachine for WeirdStateMachine {
     type State = TwoSwitches;
     type Transition = Toggle;
+    type Error = Infallible;
 
     fn next_state(starting_state: &TwoSwitches, transition: &Toggle) -> Self::State {
         match transition {
             Toggle::FirstSwitch => {
-                if starting_state.first_switch == true {
+                if starting_state.first_switch {
                     TwoSwitches {
                         first_switch: false,
                         second_switch: false
@@ -61,7 +67,7 @@ impl StateMachine for WeirdStateMachine {
             }
 
             Toggle::SecondSwitch => {
-                if starting_state.second_switch == true {
+                if starting_state.second_switch {
                     TwoSwitches {
                         first_switch: starting_state.first_switch,
                         second_switch: false
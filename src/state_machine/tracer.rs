@@ -0,0 +1,613 @@
+#![allow(dead_code)]
+
+//! Records what a state machine did, step by step, so a failing run can be read back or
+//! replayed instead of only showing where it ended up. A `Trace` can be saved with `to_json`,
+//! or more compactly with `to_bytes`, and loaded with `from_json` or `from_bytes` to replay a
+//! run recorded somewhere else. Both need states and transitions in the byte encoding of
+//! `codec`: the JSON shows them in their `Debug` form, which can not be parsed back, and loads
+//! them from the hex of their encoding next to it.
+
+use super::StateMachine;
+use crate::codec::{Decode, DecodeError, Encode};
+use std::fmt::{self, Debug, Display};
+
+/// A transition the tracer saw with the states around it. A rejected transition leaves `after`
+/// equal to `before` and keeps the error it was rejected with.
+pub struct TraceStep<M: StateMachine> {
+    pub transition: M::Transition,
+    pub before: M::State,
+    pub after: M::State,
+    pub error: Option<String>,
+}
+
+/// What a `Tracer` recorded, without the tracer.
+pub struct Trace<M: StateMachine> {
+    pub initial: M::State,
+    pub steps: Vec<TraceStep<M>>,
+}
+
+/// Runs `M` and records every transition it takes.
+pub struct Tracer<M: StateMachine> {
+    trace: Trace<M>,
+    state: M::State,
+}
+
+/// What a step led to: the new state or the error the transition was rejected with.
+pub type Outcome<S> = Result<S, String>;
+
+/// The first step where a replay did not do what the trace recorded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence<S> {
+    pub step: usize,
+    pub transition: String,
+    pub recorded: Outcome<S>,
+    pub replayed: Outcome<S>,
+}
+
+impl<S: Debug> Display for Divergence<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step {} ({}) recorded {:?} but replayed {:?}", self.step, self.transition, self.recorded, self.replayed)
+    }
+}
+
+impl<M> Tracer<M>
+where
+    M: StateMachine,
+    M::State: Debug + Clone + PartialEq,
+    M::Transition: Debug + Clone,
+    M::Error: Debug,
+{
+    pub fn new(initial: M::State) -> Self {
+        Tracer { state: initial.clone(), trace: Trace { initial, steps: Vec::new() } }
+    }
+
+    pub fn initial(&self) -> &M::State {
+        &self.trace.initial
+    }
+
+    pub fn state(&self) -> &M::State {
+        &self.state
+    }
+
+    pub fn steps(&self) -> &[TraceStep<M>] {
+        &self.trace.steps
+    }
+
+    pub fn trace(&self) -> &Trace<M> {
+        &self.trace
+    }
+
+    /// Takes `transition` like `try_next_state` and records it, rejected or not.
+    pub fn step(&mut self, transition: &M::Transition) -> Result<(), M::Error> {
+        let before = self.state.clone();
+        let result = M::apply(&mut self.state, transition);
+
+        self.trace.steps.push(TraceStep {
+            transition: transition.clone(),
+            before,
+            after: self.state.clone(),
+            error: result.as_ref().err().map(|error| format!("{:?}", error)),
+        });
+        result
+    }
+
+    /// See `Trace::replay`.
+    pub fn replay<N>(&self) -> Result<(), Divergence<M::State>>
+    where
+        N: StateMachine<State = M::State, Transition = M::Transition>,
+        N::Error: Debug,
+    {
+        self.trace.replay::<N>()
+    }
+
+    /// See `Trace::to_json`.
+    pub fn to_json(&self) -> String
+    where
+        M::State: Encode,
+        M::Transition: Encode,
+    {
+        self.trace.to_json()
+    }
+}
+
+impl<M> Trace<M>
+where
+    M: StateMachine,
+    M::State: Debug + Clone + PartialEq,
+    M::Transition: Debug,
+{
+    /// Where the recorded run ended up.
+    pub fn final_state(&self) -> &M::State {
+        self.steps.last().map_or(&self.initial, |step| &step.after)
+    }
+
+    /// Re-executes the recorded transitions with `N`, e.g. `M` itself after a change or another
+    /// implementation of the same rules, and stops at the first step that turns out differently.
+    pub fn replay<N>(&self) -> Result<(), Divergence<M::State>>
+    where
+        N: StateMachine<State = M::State, Transition = M::Transition>,
+        N::Error: Debug,
+    {
+        let mut state = self.initial.clone();
+
+        for (i, step) in self.steps.iter().enumerate() {
+            let recorded = match &step.error {
+                None => Ok(step.after.clone()),
+                Some(error) => Err(error.clone()),
+            };
+            let replayed = N::try_next_state(&state, &step.transition).map_err(|error| format!("{:?}", error));
+
+            if replayed != recorded {
+                return Err(Divergence { step: i, transition: format!("{:?}", step.transition), recorded, replayed })
+            }
+            if let Ok(after) = replayed {
+                state = after;
+            }
+        }
+        Ok(())
+    }
+
+    /// The trace as JSON. States and transitions are objects with their `Debug` form under
+    /// `debug`, for people, and the hex of their byte encoding under `bytes`, for `from_json`.
+    pub fn to_json(&self) -> String
+    where
+        M::State: Encode,
+        M::Transition: Encode,
+    {
+        let steps: Vec<String> = self
+            .steps
+            .iter()
+            .map(|step| {
+                let error = step.error.as_deref().map_or("null".into(), json_string);
+                format!(
+                    "    {{\"transition\": {}, \"before\": {}, \"after\": {}, \"error\": {}}}",
+                    value_json(&step.transition),
+                    value_json(&step.before),
+                    value_json(&step.after),
+                    error
+                )
+            })
+            .collect();
+        let steps = if steps.is_empty() { "[]".into() } else { format!("[\n{}\n  ]", steps.join(",\n")) };
+
+        format!(
+            "{{\n  \"machine\": {},\n  \"initial\": {},\n  \"steps\": {},\n  \"final\": {}\n}}\n",
+            json_string(&M::human_name()),
+            value_json(&self.initial),
+            steps,
+            value_json(self.final_state())
+        )
+    }
+}
+
+impl<M> Trace<M>
+where
+    M: StateMachine,
+    M::State: Encode + Decode,
+    M::Transition: Encode + Decode,
+{
+    /// The trace in the crate's byte encoding, see `codec`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.initial.encode();
+        self.steps.encode_to(&mut out);
+        out
+    }
+
+    /// Loads a trace saved with `to_bytes`, e.g. by a failing test, to replay it.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, DecodeError> {
+        let initial = M::State::decode_from(&mut bytes)?;
+        let steps = Vec::decode_from(&mut bytes)?;
+
+        if !bytes.is_empty() {
+            return Err(DecodeError::TrailingBytes)
+        }
+        Ok(Trace { initial, steps })
+    }
+
+    /// Loads a trace saved with `to_json`. Only the `bytes` of states and transitions are read,
+    /// the `debug` forms and the final state are there for people.
+    pub fn from_json(text: &str) -> Result<Self, JsonError> {
+        let mut reader = JsonReader { text, position: 0 };
+        let json = reader.value()?;
+        reader.skip_whitespace();
+        if reader.position != text.len() {
+            return Err(JsonError::Syntax(reader.position))
+        }
+
+        let Json::Array(steps) = json.field("steps")? else {
+            return Err(JsonError::WrongField("steps"))
+        };
+        let steps = steps
+            .iter()
+            .map(|step| {
+                let error = match step.field("error")? {
+                    Json::Null => None,
+                    Json::String(error) => Some(error.clone()),
+                    _ => return Err(JsonError::WrongField("error")),
+                };
+                Ok(TraceStep {
+                    transition: step.field("transition")?.decoded("transition")?,
+                    before: step.field("before")?.decoded("before")?,
+                    after: step.field("after")?.decoded("after")?,
+                    error,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Trace { initial: json.field("initial")?.decoded("initial")?, steps })
+    }
+}
+
+/// Why `Trace::from_json` could not load a trace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JsonError {
+    /// The text is not JSON, reading stopped at this byte offset.
+    Syntax(usize),
+    MissingField(&'static str),
+    /// The field holds something else than `to_json` writes there.
+    WrongField(&'static str),
+    /// The `bytes` of a state or transition do not decode.
+    Decode(DecodeError),
+}
+
+// The JSON `to_json` writes: no numbers or booleans, and objects keep their fields in order.
+enum Json {
+    Null,
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn field(&self, name: &'static str) -> Result<&Json, JsonError> {
+        let Json::Object(fields) = self else {
+            return Err(JsonError::MissingField(name))
+        };
+        fields.iter().find(|(key, _)| key == name).map(|(_, value)| value).ok_or(JsonError::MissingField(name))
+    }
+
+    // Decodes the hex under `bytes` of a value `value_json` wrote as the field `name`.
+    fn decoded<T: Decode>(&self, name: &'static str) -> Result<T, JsonError> {
+        let Ok(Json::String(hex)) = self.field("bytes") else {
+            return Err(JsonError::WrongField(name))
+        };
+        let bytes = from_hex(hex).ok_or(JsonError::WrongField(name))?;
+        T::decode(&bytes).map_err(JsonError::Decode)
+    }
+}
+
+struct JsonReader<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl JsonReader<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.position..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        if self.peek() != Some(expected) {
+            return Err(JsonError::Syntax(self.position))
+        }
+        self.next();
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('"') => self.string().map(Json::String),
+            Some('[') => self.list('[', ']', Self::value).map(Json::Array),
+            Some('{') => self
+                .list('{', '}', |reader| {
+                    let key = reader.string()?;
+                    reader.expect(':')?;
+                    Ok((key, reader.value()?))
+                })
+                .map(Json::Object),
+            Some('n') if self.text[self.position..].starts_with("null") => {
+                self.position += "null".len();
+                Ok(Json::Null)
+            }
+            _ => Err(JsonError::Syntax(self.position)),
+        }
+    }
+
+    // Items separated by commas between `open` and `close`.
+    fn list<T>(&mut self, open: char, close: char, item: impl Fn(&mut Self) -> Result<T, JsonError>) -> Result<Vec<T>, JsonError> {
+        let mut items = Vec::new();
+        self.expect(open)?;
+        self.skip_whitespace();
+
+        if self.peek() == Some(close) {
+            self.next();
+            return Ok(items)
+        }
+        loop {
+            items.push(item(self)?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(c) if c == close => return Ok(items),
+                _ => return Err(JsonError::Syntax(self.position)),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.expect('"')?;
+        let mut string = String::new();
+
+        loop {
+            let c = match self.next().ok_or(JsonError::Syntax(self.position))? {
+                '"' => return Ok(string),
+                '\\' => match self.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let digits = self.text.get(self.position..self.position + 4);
+                        let code = digits.and_then(|digits| u32::from_str_radix(digits, 16).ok()).and_then(char::from_u32);
+                        let code = code.ok_or(JsonError::Syntax(self.position))?;
+                        self.position += 4;
+                        code
+                    }
+                    _ => return Err(JsonError::Syntax(self.position)),
+                },
+                c => c,
+            };
+            string.push(c);
+        }
+    }
+}
+
+impl<M> Encode for TraceStep<M>
+where
+    M: StateMachine,
+    M::State: Encode,
+    M::Transition: Encode,
+{
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.transition.encode_to(out);
+        self.before.encode_to(out);
+        self.after.encode_to(out);
+        self.error.encode_to(out);
+    }
+}
+
+impl<M> Decode for TraceStep<M>
+where
+    M: StateMachine,
+    M::State: Decode,
+    M::Transition: Decode,
+{
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(TraceStep {
+            transition: M::Transition::decode_from(input)?,
+            before: M::State::decode_from(input)?,
+            after: M::State::decode_from(input)?,
+            error: Option::decode_from(input)?,
+        })
+    }
+}
+
+fn value_json<T: Debug + Encode>(value: &T) -> String {
+    format!("{{\"debug\": {}, \"bytes\": \"{}\"}}", json_string(&format!("{:?}", value)), to_hex(&value.encode()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::state_machine::accounted_currency::{
+        AccountedCurrency, AccountingError, AccountingTransaction, ExistenceRequirement, State, NATIVE_ASSET,
+    };
+    use crate::state_machine::laundry::{ClothesAction, ClothesError, ClothesMachine, ClothesState};
+    use crate::state_machine::script::SecretKey;
+    use crate::state_machine::swithces::LightSwitch;
+    use crate::state_machine::utxo_currency::{self, CashError, CashTransaction, DigitalCashSystem, Input, OutPoint, Output};
+    use crate::state_machine::User;
+
+    /// Clothes that lose two life in the wash.
+    struct DelicateClothes;
+
+    impl StateMachine for DelicateClothes {
+        type State = ClothesState;
+        type Transition = ClothesAction;
+        type Error = ClothesError;
+
+        fn next_state(starting_state: &ClothesState, transition: &ClothesAction) -> ClothesState {
+            Self::try_next_state(starting_state, transition).unwrap_or_else(|_| starting_state.clone())
+        }
+
+        fn try_next_state(starting_state: &ClothesState, transition: &ClothesAction) -> Result<ClothesState, ClothesError> {
+            match (transition, ClothesMachine::try_next_state(starting_state, transition)?) {
+                (ClothesAction::Wash, ClothesState::Wet(1)) => Ok(ClothesState::Tattered),
+                (ClothesAction::Wash, ClothesState::Wet(life)) => Ok(ClothesState::Wet(life - 1)),
+                (_, worn) => Ok(worn),
+            }
+        }
+    }
+
+    fn transfer(amount: u64, nonce: u64) -> AccountingTransaction {
+        AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            asset: NATIVE_ASSET,
+            amount,
+            existence: ExistenceRequirement::KeepAlive,
+            nonce,
+        }
+    }
+
+    #[test]
+    fn records_accepted_and_rejected_steps() {
+        let mut tracer = Tracer::<AccountedCurrency>::new(State::from([(User::Alice, 100)]));
+
+        assert_eq!(tracer.step(&transfer(30, 0)), Ok(()));
+        assert_eq!(tracer.step(&transfer(300, 1)), Err(AccountingError::InsufficientBalance));
+
+        let steps = tracer.steps();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].after.balance(NATIVE_ASSET, &User::Bob), 30);
+        assert_eq!(steps[1].before, steps[1].after);
+        assert_eq!(steps[1].error.as_deref(), Some("InsufficientBalance"));
+        assert_eq!(tracer.state(), &steps[0].after);
+    }
+
+    #[test]
+    fn light_switch_trace_as_json() {
+        let mut tracer = Tracer::<LightSwitch>::new(false);
+        tracer.step(&()).unwrap();
+
+        assert_eq!(
+            tracer.to_json(),
+            concat!(
+                "{\n  \"machine\": \"Unnamed state machine\",\n  \"initial\": {\"debug\": \"false\", \"bytes\": \"00\"},\n",
+                "  \"steps\": [\n    {\"transition\": {\"debug\": \"()\", \"bytes\": \"\"}, ",
+                "\"before\": {\"debug\": \"false\", \"bytes\": \"00\"}, ",
+                "\"after\": {\"debug\": \"true\", \"bytes\": \"01\"}, \"error\": null}\n  ],\n",
+                "  \"final\": {\"debug\": \"true\", \"bytes\": \"01\"}\n}\n"
+            )
+        );
+        assert!(Tracer::<LightSwitch>::new(true).to_json().contains("\"steps\": [],"));
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("a \"b\"\\\n\u{7}"), "\"a \\\"b\\\"\\\\\\n\\u0007\"");
+
+        let text = json_string("a \"b\"\\\n\u{7} é");
+        assert_eq!(JsonReader { text: &text, position: 0 }.string(), Ok("a \"b\"\\\n\u{7} é".to_string()));
+    }
+
+    #[test]
+    fn replay_under_same_machine_matches() {
+        let mut tracer = Tracer::<ClothesMachine>::new(ClothesState::Clean(2));
+        for action in [ClothesAction::Wear, ClothesAction::Wash, ClothesAction::Dry, ClothesAction::Wear] {
+            let _ = tracer.step(&action);
+        }
+
+        assert_eq!(tracer.replay::<ClothesMachine>(), Ok(()));
+    }
+
+    #[test]
+    fn replay_stops_at_first_divergence() {
+        let mut tracer = Tracer::<ClothesMachine>::new(ClothesState::Clean(5));
+        for action in [ClothesAction::Wear, ClothesAction::Wash, ClothesAction::Dry, ClothesAction::Wash] {
+            tracer.step(&action).unwrap();
+        }
+        let divergence = tracer.replay::<DelicateClothes>().unwrap_err();
+
+        assert_eq!(divergence.step, 1);
+        assert_eq!(divergence.recorded, Ok(ClothesState::Wet(3)));
+        assert_eq!(divergence.replayed, Ok(ClothesState::Wet(2)));
+        assert_eq!(divergence.to_string(), "step 1 (Wash) recorded Ok(Wet(3)) but replayed Ok(Wet(2))");
+    }
+
+    #[test]
+    fn exported_trace_loads_and_replays() {
+        let mut tracer = Tracer::<ClothesMachine>::new(ClothesState::Clean(5));
+        for action in [ClothesAction::Wear, ClothesAction::Wash, ClothesAction::Dry, ClothesAction::Wash] {
+            tracer.step(&action).unwrap();
+        }
+        let bytes = tracer.trace().to_bytes();
+        let loaded = Trace::<ClothesMachine>::from_bytes(&bytes).unwrap();
+
+        assert_eq!(loaded.final_state(), tracer.state());
+        assert_eq!(loaded.replay::<ClothesMachine>(), Ok(()));
+        assert_eq!(loaded.replay::<DelicateClothes>().unwrap_err().step, 1);
+        assert_eq!(Trace::<ClothesMachine>::from_bytes(&bytes[..bytes.len() - 1]).err(), Some(DecodeError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn json_trace_loads_and_replays() {
+        let mut tracer = Tracer::<ClothesMachine>::new(ClothesState::Clean(5));
+        for action in [ClothesAction::Wear, ClothesAction::Wash, ClothesAction::Dry, ClothesAction::Wash] {
+            tracer.step(&action).unwrap();
+        }
+        let loaded = Trace::<ClothesMachine>::from_json(&tracer.to_json()).unwrap();
+
+        assert_eq!(loaded.final_state(), tracer.state());
+        assert_eq!(loaded.replay::<ClothesMachine>(), Ok(()));
+        assert_eq!(loaded.replay::<DelicateClothes>().unwrap_err().step, 1);
+    }
+
+    #[test]
+    fn malformed_json_traces_are_rejected() {
+        let json = Tracer::<LightSwitch>::new(false).to_json();
+        let load = |text: &str| Trace::<LightSwitch>::from_json(text).err();
+
+        assert_eq!(load(&json[..json.len() - 3]), Some(JsonError::Syntax(json.len() - 3)));
+        assert_eq!(load(&json.replace("\"initial\"", "\"start\"")), Some(JsonError::MissingField("initial")));
+        assert_eq!(load(&json.replace("\"00\"", "\"0\"")), Some(JsonError::WrongField("initial")));
+        assert_eq!(load(&json.replace("\"00\"", "\"02\"")), Some(JsonError::Decode(DecodeError::InvalidTag(2))));
+        let trailing = "{\"steps\": [], \"initial\": {\"bytes\": \"01\"}} x";
+        assert_eq!(load(trailing), Some(JsonError::Syntax(trailing.len() - 1)));
+        assert_eq!(load("\"\\u12"), Some(JsonError::Syntax(3)));
+    }
+
+    #[test]
+    fn exported_cash_transfers_replay_with_their_errors() {
+        let start = utxo_currency::State::from([Output::owned_by(User::Charlie, 68)]);
+        let mut transfer = CashTransaction::Transfer {
+            inputs: vec![Input::unsigned(OutPoint { tx_hash: utxo_currency::GENESIS_TX, index: 0 })],
+            outputs: vec![Output::owned_by(User::Alice, 42), Output::owned_by(User::Bob, 5), Output::owned_by(User::Charlie, 21)],
+        };
+        transfer.sign(0, &SecretKey::dev(User::Charlie)).unwrap();
+
+        let mut tracer = Tracer::<DigitalCashSystem>::new(start);
+        tracer.step(&transfer).unwrap();
+        assert_eq!(tracer.step(&transfer), Err(CashError::MissingInput));
+        let from_bytes = Trace::<DigitalCashSystem>::from_bytes(&tracer.trace().to_bytes()).unwrap();
+        let from_json = Trace::<DigitalCashSystem>::from_json(&tracer.to_json()).unwrap();
+
+        for loaded in [from_bytes, from_json] {
+            assert_eq!(loaded.steps[1].error.as_deref(), Some("MissingInput"));
+            assert_eq!(loaded.final_state(), tracer.state());
+            assert_eq!(loaded.replay::<DigitalCashSystem>(), Ok(()));
+        }
+    }
+}
@@ -1,143 +1,713 @@
 #![allow(dead_code, unused_variables)]
 
-use super::{StateMachine, User};
-use std::collections::HashSet;
+use super::journal::MapUndo;
+use super::script::{self, Op, Script, ScriptContext, ScriptError, SecretKey};
+use super::events::Event;
+use super::{BlockContext, BlockHooks, Emitter, Invariant, Invariants, Revertible, StateMachine, UndoEmitter, User};
+use crate::codec::{Decode, DecodeError, Encode};
+use crate::hash;
+use std::collections::{HashMap, HashSet};
 
 pub struct DigitalCashSystem;
 
+pub type TxHash = u64;
+
+/// Outputs created at genesis are not part of any transaction and live under this hash.
+pub const GENESIS_TX: TxHash = 0;
+
+/// What the coinbase of the first blocks may mint on top of the fees.
+pub const DEFAULT_SUBSIDY: u64 = 50;
+/// The subsidy halves every this many blocks.
+pub const DEFAULT_HALVING_INTERVAL: u64 = 210;
+/// Blocks a coinbase output has to wait before it can be spent, so that a reorg can not
+/// invalidate transactions built on rewards that disappeared.
+pub const COINBASE_MATURITY: u64 = 100;
+
+/// Points at one output of an earlier transaction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct OutPoint {
+    pub tx_hash: TxHash,
+    pub index: u32,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Output {
+    pub amount: u64,
+    /// Has to evaluate to true, after the spending input's witness, for the output to be spent.
+    pub lock: Script,
+}
+
+impl Output {
+    pub fn owned_by(owner: User, amount: u64) -> Self {
+        Output { amount, lock: script::pay_to_key(owner) }
+    }
+
+    /// Claimable by `receiver` with a preimage of `digest`, refunded to `sender` from block
+    /// `expiry` on, see `script::htlc`.
+    pub fn htlc(amount: u64, digest: u64, receiver: User, sender: User, expiry: u64) -> Self {
+        Output { amount, lock: script::htlc(digest, receiver, sender, expiry) }
+    }
+}
+
+/// Spends the output at `outpoint`. The witness may only push data, see `script::verify`.
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
-pub struct Bill {
-    owner: User,
-    amount: u64,
-    serial: u64,
+pub struct Input {
+    pub outpoint: OutPoint,
+    pub witness: Script,
+}
+
+impl Input {
+    /// An input still waiting for its witness, see `CashTransaction::sign`.
+    pub fn unsigned(outpoint: OutPoint) -> Self {
+        Input { outpoint, witness: Script::new() }
+    }
+}
+
+/// An unspent output, with the height of the block that created it for relative timelocks.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Utxo {
+    pub output: Output,
+    pub height: u64,
+    /// Coinbase outputs only become spendable after `COINBASE_MATURITY` blocks.
+    pub coinbase: bool,
+}
+
+impl Utxo {
+    /// Whether the output may be spent in a block at `height`.
+    pub fn is_mature(&self, height: u64) -> bool {
+        !self.coinbase || height.saturating_sub(self.height) >= COINBASE_MATURITY
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct State {
-    bills: HashSet<Bill>,
-    next_serial: u64,
+    /// The unspent outputs, keyed by the outpoint that spends them.
+    utxos: HashMap<OutPoint, Utxo>,
+    block_height: u64,
+    total_issuance: u64,
+    initial_subsidy: u64,
+    halving_interval: u64,
+    /// What the current block's transfers left unspent, paid to the author in `on_finalize`.
+    pending_fees: u64,
 }
 
 impl State {
     pub fn new() -> Self {
         State {
-            bills: HashSet::<Bill>::new(),
-            next_serial: 0,
+            utxos: HashMap::new(),
+            block_height: 0,
+            total_issuance: 0,
+            initial_subsidy: DEFAULT_SUBSIDY,
+            halving_interval: DEFAULT_HALVING_INTERVAL,
+            pending_fees: 0,
         }
     }
 
-    pub fn set_serial(&mut self, serial: u64) {
-        self.next_serial = serial;
+    pub fn with_subsidy(mut self, initial_subsidy: u64, halving_interval: u64) -> Self {
+        assert!(halving_interval > 0, "halving interval must be at least 1");
+        self.initial_subsidy = initial_subsidy;
+        self.halving_interval = halving_interval;
+        self
     }
 
-    pub fn next_serial(&self) -> u64 {
-        self.next_serial
+    /// What the coinbase at `height` may mint on top of the block's fees.
+    pub fn subsidy(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval;
+        self.initial_subsidy.checked_shr(halvings.try_into().unwrap_or(u32::MAX)).unwrap_or(0)
     }
 
-    fn increment_serial(&mut self) {
-        self.next_serial += 1
+    pub fn pending_fees(&self) -> u64 {
+        self.pending_fees
     }
 
-    fn add_bill(&mut self, elem: Bill) {
-        self.bills.insert(elem);
-        self.increment_serial()
+    pub fn total_issuance(&self) -> u64 {
+        self.total_issuance
+    }
+
+    pub fn block_height(&self) -> u64 {
+        self.block_height
+    }
+
+    pub fn set_block_height(&mut self, height: u64) {
+        self.block_height = height;
+    }
+
+    pub fn utxo(&self, outpoint: &OutPoint) -> Option<&Utxo> {
+        self.utxos.get(outpoint)
+    }
+
+    pub fn utxos(&self) -> &HashMap<OutPoint, Utxo> {
+        &self.utxos
+    }
+
+    /// The unspent outputs without their creation heights.
+    pub fn outputs(&self) -> HashMap<OutPoint, Output> {
+        self.utxos.iter().map(|(outpoint, utxo)| (*outpoint, utxo.output.clone())).collect()
+    }
+
+    // Refuses to overwrite unspent outputs of an identical earlier transaction.
+    fn add_outputs(&mut self, tx_hash: TxHash, outputs: &[Output], coinbase: bool) -> Result<(), CashError> {
+        let index = |i: usize| u32::try_from(i).map_err(|_| CashError::Overflow);
+
+        for i in 0..outputs.len() {
+            if self.utxos.contains_key(&OutPoint { tx_hash, index: index(i)? }) {
+                return Err(CashError::DuplicateTransaction)
+            }
+        }
+        for (i, output) in outputs.iter().enumerate() {
+            let utxo = Utxo { output: output.clone(), height: self.block_height, coinbase };
+            self.utxos.insert(OutPoint { tx_hash, index: index(i)? }, utxo);
+        }
+        Ok(())
     }
 }
 
-impl FromIterator<Bill> for State {
-    fn from_iter<I: IntoIterator<Item = Bill>>(iter: I) -> Self {
+/// Genesis outputs, spendable as `OutPoint { tx_hash: GENESIS_TX, index }` in the order given.
+impl FromIterator<Output> for State {
+    fn from_iter<I: IntoIterator<Item = Output>>(iter: I) -> Self {
         let mut state = State::new();
+        let outputs: Vec<Output> = iter.into_iter().collect();
 
-        for i in iter {
-            state.add_bill(i)
+        for output in &outputs {
+            state.total_issuance = state
+                .total_issuance
+                .checked_add(output.amount)
+                .expect("genesis outputs overflow the total issuance");
         }
+        state.add_outputs(GENESIS_TX, &outputs, false).expect("genesis outputs fit in a u32 index");
         state
     }
 }
 
-impl<const N: usize> From<[Bill; N]> for State {
-    fn from(value: [Bill; N]) -> Self {
+impl<const N: usize> From<[Output; N]> for State {
+    fn from(value: [Output; N]) -> Self {
         State::from_iter(value)
     }
 }
 
 
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum CashTransaction {
-    Mint { minter: User, amount: u64 },
+    /// Mints the block's subsidy and collects its fees. Only the block executor may insert it,
+    /// through `on_finalize`. The height makes every coinbase hash unique.
+    Coinbase { height: u64, outputs: Vec<Output> },
     Transfer {
-        spends: Vec<Bill>,
-        receives: Vec<Bill>,
+        inputs: Vec<Input>,
+        outputs: Vec<Output>,
     },
 }
 
+impl CashTransaction {
+    /// Leaves the witnesses out, so the hash is what signatures commit to and signing does not
+    /// move the outputs to different outpoints.
+    pub fn hash(&self) -> TxHash {
+        match self {
+            CashTransaction::Coinbase { .. } => hash(self),
+            CashTransaction::Transfer { inputs, outputs } => {
+                let outpoints: Vec<OutPoint> = inputs.iter().map(|input| input.outpoint).collect();
+                hash(&(outpoints, outputs))
+            }
+        }
+    }
+
+    /// Sets the witness of input `index` to a signature with `key`, which unlocks `pay_to_key`
+    /// outputs of its holder.
+    pub fn sign(&mut self, index: usize, key: &SecretKey) -> Result<(), CashError> {
+        let signature = script::sign(key, self.hash());
+        *self.witness_mut(index)? = vec![Op::PushNum(signature)];
+        Ok(())
+    }
+
+    /// Claims the `htlc` output spent by input `index`, revealing `preimage` in its witness.
+    pub fn claim_htlc(&mut self, index: usize, receiver: &SecretKey, preimage: u64) -> Result<(), CashError> {
+        let signature = script::sign(receiver, self.hash());
+        *self.witness_mut(index)? = script::htlc_claim(signature, preimage);
+        Ok(())
+    }
+
+    /// Takes the expired `htlc` output spent by input `index` back to its sender.
+    pub fn refund_htlc(&mut self, index: usize, sender: &SecretKey) -> Result<(), CashError> {
+        let signature = script::sign(sender, self.hash());
+        *self.witness_mut(index)? = script::htlc_refund(signature);
+        Ok(())
+    }
+
+    // A coinbase has no inputs to sign.
+    fn witness_mut(&mut self, index: usize) -> Result<&mut Script, CashError> {
+        match self {
+            CashTransaction::Coinbase { .. } => Err(CashError::InputOutOfRange),
+            CashTransaction::Transfer { inputs, .. } => {
+                inputs.get_mut(index).map(|input| &mut input.witness).ok_or(CashError::InputOutOfRange)
+            }
+        }
+    }
+
+    /// Preimages revealed by the htlc claims among the inputs.
+    pub fn revealed_preimages(&self) -> Vec<u64> {
+        match self {
+            CashTransaction::Coinbase { .. } => Vec::new(),
+            CashTransaction::Transfer { inputs, .. } => {
+                inputs.iter().filter_map(|input| script::revealed_preimage(&input.witness)).collect()
+            }
+        }
+    }
+
+    /// Where output `index` of this transaction ends up once it is applied.
+    pub fn outpoint(&self, index: u32) -> OutPoint {
+        OutPoint { tx_hash: self.hash(), index }
+    }
+}
+
+impl Encode for OutPoint {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.tx_hash.encode_to(out);
+        self.index.encode_to(out);
+    }
+}
+
+impl Decode for OutPoint {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(OutPoint { tx_hash: TxHash::decode_from(input)?, index: u32::decode_from(input)? })
+    }
+}
+
+impl Encode for Output {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.amount.encode_to(out);
+        self.lock.encode_to(out);
+    }
+}
+
+impl Decode for Output {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Output { amount: u64::decode_from(input)?, lock: Script::decode_from(input)? })
+    }
+}
+
+impl Encode for Input {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.outpoint.encode_to(out);
+        self.witness.encode_to(out);
+    }
+}
+
+impl Decode for Input {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Input { outpoint: OutPoint::decode_from(input)?, witness: Script::decode_from(input)? })
+    }
+}
+
+impl Encode for CashTransaction {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            CashTransaction::Coinbase { height, outputs } => {
+                0u8.encode_to(out);
+                height.encode_to(out);
+                outputs.encode_to(out);
+            }
+            CashTransaction::Transfer { inputs, outputs } => {
+                1u8.encode_to(out);
+                inputs.encode_to(out);
+                outputs.encode_to(out);
+            }
+        }
+    }
+}
+
+impl Decode for CashTransaction {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(CashTransaction::Coinbase { height: u64::decode_from(input)?, outputs: Vec::decode_from(input)? }),
+            1 => Ok(CashTransaction::Transfer { inputs: Vec::decode_from(input)?, outputs: Vec::decode_from(input)? }),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for Utxo {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.output.encode_to(out);
+        self.height.encode_to(out);
+        self.coinbase.encode_to(out);
+    }
+}
+
+impl Decode for Utxo {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Utxo { output: Output::decode_from(input)?, height: u64::decode_from(input)?, coinbase: bool::decode_from(input)? })
+    }
+}
+
+/// Unspent outputs go in outpoint order, so equal states encode the same.
+impl Encode for State {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        let mut utxos: Vec<(OutPoint, Utxo)> = self.utxos.iter().map(|(outpoint, utxo)| (*outpoint, utxo.clone())).collect();
+        utxos.sort_by_key(|(outpoint, _)| *outpoint);
+
+        utxos.encode_to(out);
+        self.block_height.encode_to(out);
+        self.total_issuance.encode_to(out);
+        self.initial_subsidy.encode_to(out);
+        self.halving_interval.encode_to(out);
+        self.pending_fees.encode_to(out);
+    }
+}
+
+impl Decode for State {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let state = State {
+            utxos: Vec::<(OutPoint, Utxo)>::decode_from(input)?.into_iter().collect(),
+            block_height: u64::decode_from(input)?,
+            total_issuance: u64::decode_from(input)?,
+            initial_subsidy: u64::decode_from(input)?,
+            halving_interval: u64::decode_from(input)?,
+            pending_fees: u64::decode_from(input)?,
+        };
+        if state.halving_interval == 0 {
+            return Err(DecodeError::InvalidValue)
+        }
+        Ok(state)
+    }
+}
+
+/// Why a `CashTransaction` was rejected. A rejected transaction leaves the state untouched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CashError {
+    NoInputs,
+    /// An input spends an outpoint that is not in the UTXO set, it never existed or was spent.
+    MissingInput,
+    /// The same outpoint is spent twice in one transaction.
+    DoubleSpend,
+    /// Signing refers to an input the transaction does not have.
+    InputOutOfRange,
+    /// An input's witness does not unlock the output it spends.
+    Script(ScriptError),
+    ZeroValueOutput,
+    /// The outputs are worth more than the inputs.
+    Overspend,
+    /// An identical transaction still has unspent outputs.
+    DuplicateTransaction,
+    /// Coinbase transactions can only be inserted by the block executor.
+    CoinbaseNotAllowed,
+    /// Only a coinbase can be applied as one.
+    NotCoinbase,
+    WrongCoinbaseHeight,
+    /// The coinbase claims more than the block's subsidy and fees.
+    CoinbaseTooLarge,
+    /// A coinbase output is spent before `COINBASE_MATURITY` blocks passed.
+    ImmatureCoinbase,
+    Overflow,
+}
+
 impl StateMachine for DigitalCashSystem {
     type State = State;
     type Transition = CashTransaction;
+    type Error = CashError;
 
     fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+        Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state(starting_state: &Self::State, t: &Self::Transition) -> Result<Self::State, Self::Error> {
+        let mut new_state = starting_state.clone();
+        Self::execute(&mut new_state, t)?;
+        Ok(new_state)
+    }
+
+    fn apply(state: &mut Self::State, t: &Self::Transition) -> Result<(), Self::Error> {
+        Self::apply_with_undo(state, t).map(|_| ())
+    }
+
+    fn human_name() -> String {
+        "Digital cash system".into()
+    }
+}
+
+impl DigitalCashSystem {
+    // Changes `state` as it goes, rejected transactions are rolled back by `apply_with_undo`.
+    fn execute(state: &mut State, t: &CashTransaction) -> Result<(), CashError> {
         match t {
-            CashTransaction::Mint { minter, amount } => {
-                let mut new_state = starting_state.clone();
-                let bill: Bill = Bill { owner: *minter, amount: *amount, serial: 0 };
-                new_state.add_bill(bill);  
-                new_state
-            }
-            CashTransaction::Transfer { spends, receives } => {
-                // clone state into memory from starting_state
-                let mut new_state = starting_state.clone();
-                let mut spends_amount = 0;
-                let mut receives_amount = 0;
-                let mut new_bills: Vec<Bill> = Vec::new();
-
-                if spends.is_empty() {
-                    return new_state
+            CashTransaction::Coinbase { .. } => return Err(CashError::CoinbaseNotAllowed),
+            CashTransaction::Transfer { inputs, outputs } => {
+                let mut inputs_amount: u64 = 0;
+                let mut outputs_amount: u64 = 0;
+                let mut spent = HashSet::new();
+
+                if inputs.is_empty() {
+                    return Err(CashError::NoInputs)
                 }
-                
-                let spenders_len = spends.len();
-                let last_serial = &spends[spenders_len - 1].serial;
-
-                for spend in spends {
-                    if new_state.bills.contains(spend) {
-                        spends_amount += spend.amount;
-                        if spend.serial != last_serial + 1 && !receives.is_empty() {
-                            return new_state
-                        }
-                    } else {
-                        return new_state
+
+                let sighash = t.hash();
+                for input in inputs {
+                    if !spent.insert(input.outpoint) {
+                        return Err(CashError::DoubleSpend)
+                    }
+                    let utxo = state.utxos.remove(&input.outpoint).ok_or(CashError::MissingInput)?;
+                    if !utxo.is_mature(state.block_height) {
+                        return Err(CashError::ImmatureCoinbase)
                     }
+                    let context = ScriptContext { sighash, height: state.block_height, output_height: utxo.height };
+                    script::verify(&input.witness, &utxo.output.lock, &context).map_err(CashError::Script)?;
+
+                    inputs_amount = inputs_amount.checked_add(utxo.output.amount).ok_or(CashError::Overflow)?;
                 }
 
-                for receive in receives {
-                    receives_amount += receive.amount;
+                for output in outputs {
+                    if output.amount == 0 {
+                        return Err(CashError::ZeroValueOutput)
+                    }
+                    outputs_amount = outputs_amount.checked_add(output.amount).ok_or(CashError::Overflow)?;
+                }
 
-                    let bill = Bill {
-                        owner: receive.owner,
-                        amount: receive.amount,
-                        serial: receive.serial,
-                    };
+                // A transfer can not create money. Whatever it leaves unspent is its fee.
+                let fee = inputs_amount.checked_sub(outputs_amount).ok_or(CashError::Overspend)?;
+                state.pending_fees += fee;
+                state.add_outputs(t.hash(), outputs, false)?;
+            }
+        }
 
-                    new_bills.push(bill);
-                }
-                
-                if spends_amount > receives_amount && !receives.is_empty() {
-                    return new_state
-                };
+        Ok(())
+    }
 
-                for spend in spends {
-                    if !new_state.bills.contains(spend) {
-                        
-                    }
-                    new_state.bills.remove(spend);
-                };
+    /// Applies the coinbase of the current block. Whatever part of the subsidy it leaves
+    /// unclaimed is never minted, and unclaimed fees are burned. Private, like `apply_coinbase`,
+    /// so only `on_finalize` can mint.
+    fn try_apply_coinbase(starting_state: &State, coinbase: &CashTransaction) -> Result<State, CashError> {
+        let mut new_state = starting_state.clone();
+        Self::apply_coinbase(&mut new_state, coinbase)?;
+        Ok(new_state)
+    }
 
-                for bill in new_bills {
-                    new_state.add_bill(bill);
-                }
+    /// The coinbase `on_finalize` inserts, paying the block's fees and full subsidy to its author.
+    pub fn coinbase(state: &State, context: &BlockContext) -> Option<CashTransaction> {
+        let amount = state.pending_fees.saturating_add(state.subsidy(context.height));
+
+        context.author.filter(|_| amount > 0).map(|author| CashTransaction::Coinbase {
+            height: context.height,
+            outputs: vec![Output::owned_by(author, amount)],
+        })
+    }
+
+    /// Like `try_apply_coinbase`, but in place. A rejected coinbase leaves `state` untouched.
+    fn apply_coinbase(state: &mut State, coinbase: &CashTransaction) -> Result<(), CashError> {
+        let CashTransaction::Coinbase { height, outputs } = coinbase else {
+            return Err(CashError::NotCoinbase)
+        };
+        if *height != state.block_height {
+            return Err(CashError::WrongCoinbaseHeight)
+        }
+        let fees = state.pending_fees;
+
+        let mut claimed: u64 = 0;
+        for output in outputs {
+            if output.amount == 0 {
+                return Err(CashError::ZeroValueOutput)
+            }
+            claimed = claimed.checked_add(output.amount).ok_or(CashError::Overflow)?;
+        }
+        if claimed > fees.saturating_add(state.subsidy(*height)) {
+            return Err(CashError::CoinbaseTooLarge)
+        }
+
+        // The fees already count towards the issuance, only the claimed subsidy is new.
+        let total_issuance = (state.total_issuance - fees).checked_add(claimed).ok_or(CashError::Overflow)?;
+        state.add_outputs(coinbase.hash(), outputs, true)?;
+        state.total_issuance = total_issuance;
+        state.pending_fees = 0;
+
+        Ok(())
+    }
+}
+
+impl BlockHooks for DigitalCashSystem {
+    fn on_initialize(state: &mut State, context: &BlockContext) {
+        state.set_block_height(context.height);
+    }
+
+    /// Pays the block's subsidy and fees to its author with a coinbase. Without an author, or
+    /// if the coinbase is rejected, the fees are burned and nothing is minted.
+    fn on_finalize(state: &mut State, context: &BlockContext) {
+        let applied = Self::coinbase(state, context).is_some_and(|coinbase| Self::apply_coinbase(state, &coinbase).is_ok());
+
+        if !applied {
+            state.total_issuance -= std::mem::take(&mut state.pending_fees);
+        }
+    }
+}
 
-                new_state
+/// The outputs a change created or spent, with the totals from before it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CashUndo {
+    /// `None` for the outputs that were created, the spent output otherwise.
+    utxos: MapUndo<OutPoint, Utxo>,
+    block_height: u64,
+    total_issuance: u64,
+    pending_fees: u64,
+}
+
+impl CashUndo {
+    // Keeps the totals of `state`, outputs are remembered as they get touched.
+    fn new(state: &State) -> Self {
+        CashUndo {
+            utxos: MapUndo::new(),
+            block_height: state.block_height,
+            total_issuance: state.total_issuance,
+            pending_fees: state.pending_fees,
+        }
+    }
+
+    /// Everything `transaction` may touch: the outputs it spends and the ones it creates.
+    fn footprint(state: &State, transaction: &CashTransaction) -> Self {
+        let mut undo = CashUndo::new(state);
+
+        if let CashTransaction::Transfer { inputs, .. } = transaction {
+            for input in inputs {
+                undo.utxos.remember(&state.utxos, input.outpoint);
             }
-        } 
-     }
+        }
+        undo.created(state, transaction);
+        undo
+    }
+
+    fn created(&mut self, state: &State, transaction: &CashTransaction) {
+        let (CashTransaction::Coinbase { outputs, .. } | CashTransaction::Transfer { outputs, .. }) = transaction;
+
+        for index in (0..outputs.len()).filter_map(|index| u32::try_from(index).ok()) {
+            self.utxos.remember(&state.utxos, transaction.outpoint(index));
+        }
+    }
+}
+
+impl Revertible for DigitalCashSystem {
+    type Undo = CashUndo;
+
+    fn undo(before: &State, after: &State) -> CashUndo {
+        CashUndo { utxos: MapUndo::record(&before.utxos, &after.utxos), ..CashUndo::new(before) }
+    }
+
+    fn revert(state: &mut State, undo: CashUndo) {
+        undo.utxos.revert(&mut state.utxos);
+        state.block_height = undo.block_height;
+        state.total_issuance = undo.total_issuance;
+        state.pending_fees = undo.pending_fees;
+    }
+
+    fn apply_with_undo(state: &mut State, transition: &CashTransaction) -> Result<CashUndo, CashError> {
+        let undo = CashUndo::footprint(state, transition);
+
+        match Self::execute(state, transition) {
+            Ok(()) => Ok(undo),
+            Err(error) => {
+                Self::revert(state, undo);
+                Err(error)
+            }
+        }
+    }
+
+    fn hook_undo(state: &State, context: &BlockContext) -> CashUndo {
+        let mut undo = CashUndo::new(state);
+
+        if let Some(coinbase) = Self::coinbase(state, context) {
+            undo.created(state, &coinbase);
+        }
+        undo
+    }
+}
+
+impl Emitter for DigitalCashSystem {
+    type Event = Event;
+
+    /// The coinbase is inserted by `on_finalize`, so only transfers emit events.
+    fn events(before: &State, transition: &CashTransaction, after: &State) -> Vec<Event> {
+        Self::emitted(|outpoint| before.utxo(outpoint), transition, after)
+    }
+}
+
+impl UndoEmitter for DigitalCashSystem {
+    fn transition_events(undo: &CashUndo, transition: &CashTransaction, after: &State) -> Vec<Event> {
+        Self::emitted(|outpoint| undo.utxos.before(&after.utxos, outpoint), transition, after)
+    }
+
+    /// `on_finalize` either creates the outputs of a coinbase or burns the fees.
+    fn hook_events(undo: &CashUndo, _: &BlockContext, after: &State) -> Vec<Event> {
+        let created: Vec<Event> = undo
+            .utxos
+            .keys()
+            .filter(|outpoint| undo.utxos.before(&after.utxos, outpoint).is_none())
+            .filter_map(|outpoint| after.utxo(outpoint).map(|utxo| Self::bill_created(*outpoint, &utxo.output)))
+            .collect();
+        let fees = undo.pending_fees - after.pending_fees;
+
+        if created.is_empty() && fees > 0 {
+            return vec![Event::FeesBurned { amount: fees }]
+        }
+        created
+    }
+}
+
+impl DigitalCashSystem {
+    fn emitted<'a>(before: impl Fn(&OutPoint) -> Option<&'a Utxo>, transition: &CashTransaction, after: &State) -> Vec<Event> {
+        let CashTransaction::Transfer { inputs, outputs } = transition else {
+            return Vec::new()
+        };
+        // An accepted transfer spends every one of its inputs, a rejected one none of them.
+        let spent: Vec<Event> = inputs
+            .iter()
+            .filter_map(|input| {
+                let utxo = before(&input.outpoint).filter(|_| after.utxo(&input.outpoint).is_none())?;
+                let owner = script::key_owner(&utxo.output.lock);
+                Some(Event::BillSpent { outpoint: input.outpoint, owner, amount: utxo.output.amount })
+            })
+            .collect();
+        if spent.is_empty() {
+            return spent
+        }
+        let created = outputs.iter().zip(0..).map(|(output, index)| Self::bill_created(transition.outpoint(index), output));
+
+        spent.into_iter().chain(created).collect()
+    }
+
+    fn bill_created(outpoint: OutPoint, output: &Output) -> Event {
+        Event::BillCreated { outpoint, owner: script::key_owner(&output.lock), amount: output.amount }
+    }
+}
+
+impl Invariants for DigitalCashSystem {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant { name: "outputs and fees match the issuance", check: outputs_match_issuance },
+            Invariant { name: "transactions do not mint", check: transactions_do_not_mint },
+        ]
+    }
+}
+
+fn outputs_match_issuance(_: &State, _: &CashTransaction, after: &State) -> Result<(), String> {
+    let in_utxos = after
+        .utxos
+        .values()
+        .try_fold(0u64, |sum, utxo| sum.checked_add(utxo.output.amount))
+        .ok_or("unspent outputs overflow a u64")?;
+
+    let in_utxos = in_utxos.checked_add(after.pending_fees).ok_or("unspent outputs and fees overflow a u64")?;
+
+    if in_utxos != after.total_issuance {
+        return Err(format!(
+            "unspent outputs and fees hold {} but total issuance is {}",
+            in_utxos, after.total_issuance
+        ))
+    }
+    Ok(())
+}
+
+// Money is only minted by coinbases, which never go through `next_state`.
+fn transactions_do_not_mint(before: &State, transition: &CashTransaction, after: &State) -> Result<(), String> {
+    if after.total_issuance != before.total_issuance {
+        return Err(format!(
+            "{:?} changed the total issuance from {} to {}",
+            transition, before.total_issuance, after.total_issuance
+        ))
+    }
+    Ok(())
 }
 
 
@@ -145,586 +715,648 @@ impl StateMachine for DigitalCashSystem {
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::state_machine::harness::Harness;
+    use crate::state_machine::journal::Journal;
+    use crate::state_machine::Checked;
+    use proptest::prelude::*;
+
+    fn genesis(index: u32) -> OutPoint {
+        OutPoint { tx_hash: GENESIS_TX, index }
+    }
+
+    fn spend(index: u32, signer: User) -> (OutPoint, User) {
+        (genesis(index), signer)
+    }
+
+    // Signs every input for the user given with it.
+    fn transfer(inputs: Vec<(OutPoint, User)>, outputs: Vec<Output>) -> CashTransaction {
+        let mut transaction = CashTransaction::Transfer {
+            inputs: inputs.iter().map(|(outpoint, _)| Input::unsigned(*outpoint)).collect(),
+            outputs,
+        };
+        for (index, (_, signer)) in inputs.into_iter().enumerate() {
+            transaction.sign(index, &SecretKey::dev(signer)).unwrap();
+        }
+        transaction
+    }
+
+    fn coinbase(height: u64, amount: u64) -> CashTransaction {
+        CashTransaction::Coinbase {
+            height,
+            outputs: vec![Output::owned_by(User::Alice, amount)],
+        }
+    }
 
     #[test]
-    fn sm_5_mint_new_cash() {
-        let start = State::new();
-        let end = DigitalCashSystem::next_state(
-            &start,
-            &CashTransaction::Mint {
-                minter: User::Alice,
-                amount: 20,
-            },
-        );
+    fn sm_5_coinbase_pays_author() {
+        let mut state = State::new().with_subsidy(50, 10);
+        DigitalCashSystem::on_finalize(&mut state, &BlockContext { height: 0, author: Some(User::Alice) });
 
-        let expected = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        assert_eq!(end, expected);
+        assert_eq!(state.outputs(), HashMap::from([(coinbase(0, 50).outpoint(0), Output::owned_by(User::Alice, 50))]));
+        assert_eq!(state.total_issuance(), 50);
     }
 
     #[test]
-    fn sm_5_overflow_receives_fails() {
-        let start = State::from([Bill {
-            owner: User::Alice,
-            amount: 42,
-            serial: 0,
-        }]);
-        let end = DigitalCashSystem::next_state(
-            &start,
-            &CashTransaction::Transfer {
-                spends: vec![Bill {
-                    owner: User::Alice,
-                    amount: 42,
-                    serial: 0,
-                }],
-                receives: vec![
-                    Bill {
-                        owner: User::Alice,
-                        amount: u64::MAX,
-                        serial: 1,
-                    },
-                    Bill {
-                        owner: User::Alice,
-                        amount: 42,
-                        serial: 2,
-                    },
-                ],
-            },
-        );
-        let expected = State::from([Bill {
-            owner: User::Alice,
-            amount: 42,
-            serial: 0,
-        }]);
-        assert_eq!(end, expected);
+    fn sm_5_coinbase_can_not_be_submitted() {
+        let result = DigitalCashSystem::try_next_state(&State::new(), &coinbase(0, 50));
+
+        assert_eq!(result, Err(CashError::CoinbaseNotAllowed));
+    }
+
+    #[test]
+    fn sm_5_coinbase_is_capped_by_subsidy_and_fees() {
+        let start = State::from([Output::owned_by(User::Alice, 20)]).with_subsidy(50, 10);
+        let state = DigitalCashSystem::try_next_state(&start, &pay_bob_with_fee(3)).unwrap();
+
+        assert_eq!(DigitalCashSystem::try_apply_coinbase(&state, &coinbase(0, 54)), Err(CashError::CoinbaseTooLarge));
+        let end = DigitalCashSystem::try_apply_coinbase(&state, &coinbase(0, 40)).unwrap();
+        // The unclaimed fees are burned along with the unclaimed subsidy.
+        assert_eq!(end.total_issuance(), 57);
+        assert_eq!(end.pending_fees(), 0);
+    }
+
+    #[test]
+    fn sm_5_coinbase_must_match_block_height() {
+        let mut state = State::new();
+        state.set_block_height(3);
+
+        assert_eq!(DigitalCashSystem::try_apply_coinbase(&state, &coinbase(2, 1)), Err(CashError::WrongCoinbaseHeight));
+        assert!(DigitalCashSystem::try_apply_coinbase(&state, &coinbase(3, 1)).is_ok());
+    }
+
+    #[test]
+    fn sm_5_subsidy_halves() {
+        let state = State::new().with_subsidy(50, 10);
+
+        assert_eq!(state.subsidy(0), 50);
+        assert_eq!(state.subsidy(9), 50);
+        assert_eq!(state.subsidy(10), 25);
+        assert_eq!(state.subsidy(25), 12);
+        assert_eq!(state.subsidy(10 * 64), 0);
+    }
+
+    #[test]
+    fn sm_5_coinbase_output_matures() {
+        let mut state = State::new();
+        DigitalCashSystem::on_finalize(&mut state, &BlockContext { height: 0, author: Some(User::Alice) });
+        let spend = transfer(vec![(coinbase(0, 50).outpoint(0), User::Alice)], vec![Output::owned_by(User::Bob, 50)]);
+
+        state.set_block_height(COINBASE_MATURITY - 1);
+        assert_eq!(DigitalCashSystem::try_next_state(&state, &spend), Err(CashError::ImmatureCoinbase));
+        state.set_block_height(COINBASE_MATURITY);
+        assert!(DigitalCashSystem::try_next_state(&state, &spend).is_ok());
     }
 
     #[test]
     fn sm_5_empty_spend_fails() {
-        let start = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        let end = DigitalCashSystem::next_state(
-            &start,
-            &CashTransaction::Transfer {
-                spends: vec![],
-                receives: vec![Bill {
-                    owner: User::Alice,
-                    amount: 15,
-                    serial: 1,
-                }],
-            },
-        );
-        let expected = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        assert_eq!(end, expected);
+        let start = State::from([Output::owned_by(User::Alice, 20)]);
+        let result = DigitalCashSystem::try_next_state(&start, &transfer(vec![], vec![Output::owned_by(User::Alice, 15)]));
+        assert_eq!(result, Err(CashError::NoInputs));
     }
 
     #[test]
     fn sm_5_empty_receive_fails() {
-        let start = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        let end = DigitalCashSystem::next_state(
-            &start,
-            &CashTransaction::Transfer {
-                spends: vec![Bill {
-                    owner: User::Alice,
-                    amount: 20,
-                    serial: 0,
-                }],
-                receives: vec![],
-            },
-        );
-        let mut expected = State::from([]);
-        expected.set_serial(1);
-        assert_eq!(end, expected);
+        let start = State::from([Output::owned_by(User::Alice, 20)]);
+        let end = DigitalCashSystem::try_next_state(&start, &transfer(vec![spend(0, User::Alice)], vec![])).unwrap();
+        assert_eq!(end.outputs(), HashMap::new());
     }
 
     #[test]
     fn sm_5_output_value_0_fails() {
-        let start = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        let end = DigitalCashSystem::next_state(
+        let start = State::from([Output::owned_by(User::Alice, 20)]);
+        let result = DigitalCashSystem::try_next_state(
             &start,
-            &CashTransaction::Transfer {
-                spends: vec![Bill {
-                    owner: User::Alice,
-                    amount: 20,
-                    serial: 0,
-                }],
-                receives: vec![Bill {
-                    owner: User::Bob,
-                    amount: 0,
-                    serial: 1,
-                }],
-            },
+            &transfer(vec![spend(0, User::Alice)], vec![Output::owned_by(User::Bob, 0)]),
         );
-        let expected = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        assert_eq!(end, expected);
+        assert_eq!(result, Err(CashError::ZeroValueOutput));
     }
 
     #[test]
-    fn sm_5_serial_number_already_seen_fails() {
-        let start = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        let end = DigitalCashSystem::next_state(
+    fn sm_5_spending_same_output_twice_fails() {
+        let start = State::from([Output::owned_by(User::Alice, 40), Output::owned_by(User::Bob, 40)]);
+        let result = DigitalCashSystem::try_next_state(
             &start,
-            &CashTransaction::Transfer {
-                spends: vec![Bill {
-                    owner: User::Alice,
-                    amount: 20,
-                    serial: 0,
-                }],
-                receives: vec![Bill {
-                    owner: User::Alice,
-                    amount: 18,
-                    serial: 0,
-                }],
-            },
+            &transfer(
+                vec![spend(0, User::Alice), spend(0, User::Alice)],
+                vec![Output::owned_by(User::Bob, 80)],
+            ),
         );
-        let expected = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        assert_eq!(end, expected);
+        assert_eq!(result, Err(CashError::DoubleSpend));
     }
 
     #[test]
-    fn sm_5_spending_and_receiving_same_bill_fails() {
-        let start = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        let end = DigitalCashSystem::next_state(
-            &start,
-            &CashTransaction::Transfer {
-                spends: vec![Bill {
-                    owner: User::Alice,
-                    amount: 20,
-                    serial: 0,
-                }],
-                receives: vec![Bill {
-                    owner: User::Alice,
-                    amount: 20,
-                    serial: 0,
-                }],
-            },
-        );
-        let expected = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        assert_eq!(end, expected);
+    fn sm_5_spending_spent_output_fails() {
+        let start = State::from([Output::owned_by(User::Alice, 40)]);
+        let pay_bob = |amount| transfer(vec![spend(0, User::Alice)], vec![Output::owned_by(User::Bob, amount)]);
+        let end = DigitalCashSystem::try_next_state(&start, &pay_bob(40)).unwrap();
+
+        assert_eq!(DigitalCashSystem::try_next_state(&end, &pay_bob(39)), Err(CashError::MissingInput));
     }
 
     #[test]
-    fn sm_5_receiving_bill_with_incorrect_serial_fails() {
-        let start = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        let end = DigitalCashSystem::next_state(
+    fn sm_5_spending_more_than_inputs_fails() {
+        let start = State::from([Output::owned_by(User::Alice, 40), Output::owned_by(User::Charlie, 42)]);
+        let result = DigitalCashSystem::try_next_state(
             &start,
-            &CashTransaction::Transfer {
-                spends: vec![Bill {
-                    owner: User::Alice,
-                    amount: 20,
-                    serial: 0,
-                }],
-                receives: vec![
-                    Bill {
-                        owner: User::Alice,
-                        amount: 10,
-                        serial: u64::MAX,
-                    },
-                    Bill {
-                        owner: User::Bob,
-                        amount: 10,
-                        serial: 4000,
-                    },
+            &transfer(
+                vec![spend(0, User::Alice), spend(1, User::Charlie)],
+                vec![
+                    Output::owned_by(User::Bob, 20),
+                    Output::owned_by(User::Bob, 20),
+                    Output::owned_by(User::Alice, 52),
                 ],
-            },
+            ),
         );
-        let expected = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        assert_eq!(end, expected);
+        assert_eq!(result, Err(CashError::Overspend));
     }
 
     #[test]
-    fn sm_5_spending_bill_with_incorrect_amount_fails() {
-        let start = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        let end = DigitalCashSystem::next_state(
+    fn sm_5_spending_non_existent_output_fails() {
+        let start = State::from([Output::owned_by(User::Alice, 32)]);
+        let result = DigitalCashSystem::try_next_state(
             &start,
-            &CashTransaction::Transfer {
-                spends: vec![Bill {
-                    owner: User::Alice,
-                    amount: 40,
-                    serial: 0,
-                }],
-                receives: vec![Bill {
-                    owner: User::Bob,
-                    amount: 40,
-                    serial: 1,
-                }],
-            },
+            &transfer(vec![spend(32, User::Bob)], vec![Output::owned_by(User::Bob, 1000)]),
         );
-        let expected = State::from([Bill {
-            owner: User::Alice,
-            amount: 20,
-            serial: 0,
-        }]);
-        assert_eq!(end, expected);
+        assert_eq!(result, Err(CashError::MissingInput));
     }
 
     #[test]
-    fn sm_5_spending_same_bill_fails() {
-        let start = State::from([Bill {
-            owner: User::Alice,
-            amount: 40,
-            serial: 0,
-        }]);
-        let end = DigitalCashSystem::next_state(
+    fn sm_5_spending_someone_elses_output_fails() {
+        let start = State::from([Output::owned_by(User::Alice, 32)]);
+        let result = DigitalCashSystem::try_next_state(
             &start,
-            &CashTransaction::Transfer {
-                spends: vec![
-                    Bill {
-                        owner: User::Alice,
-                        amount: 40,
-                        serial: 0,
-                    },
-                    Bill {
-                        owner: User::Alice,
-                        amount: 40,
-                        serial: 0,
-                    },
-                ],
-                receives: vec![
-                    Bill {
-                        owner: User::Bob,
-                        amount: 20,
-                        serial: 1,
-                    },
-                    Bill {
-                        owner: User::Bob,
-                        amount: 20,
-                        serial: 2,
-                    },
-                    Bill {
-                        owner: User::Alice,
-                        amount: 40,
-                        serial: 3,
-                    },
-                ],
-            },
+            &transfer(vec![spend(0, User::Bob)], vec![Output::owned_by(User::Bob, 32)]),
         );
-        let expected = State::from([Bill {
-            owner: User::Alice,
-            amount: 40,
-            serial: 0,
-        }]);
-        assert_eq!(end, expected);
-    }
-
-    #[test]
-    fn sm_5_spending_more_than_bill_fails() {
-        let start = State::from([
-            Bill {
-                owner: User::Alice,
-                amount: 40,
-                serial: 0,
-            },
-            Bill {
-                owner: User::Charlie,
-                amount: 42,
-                serial: 1,
-            },
-        ]);
-        let end = DigitalCashSystem::next_state(
-            &start,
-            &CashTransaction::Transfer {
-                spends: vec![
-                    Bill {
-                        owner: User::Alice,
-                        amount: 40,
-                        serial: 0,
-                    },
-                    Bill {
-                        owner: User::Charlie,
-                        amount: 42,
-                        serial: 1,
-                    },
-                ],
-                receives: vec![
-                    Bill {
-                        owner: User::Bob,
-                        amount: 20,
-                        serial: 2,
-                    },
-                    Bill {
-                        owner: User::Bob,
-                        amount: 20,
-                        serial: 3,
-                    },
-                    Bill {
-                        owner: User::Alice,
-                        amount: 52,
-                        serial: 4,
-                    },
-                ],
-            },
-        );
-        let expected = State::from([
-            Bill {
-                owner: User::Alice,
-                amount: 40,
-                serial: 0,
-            },
-            Bill {
-                owner: User::Charlie,
-                amount: 42,
-                serial: 1,
-            },
-        ]);
-        assert_eq!(end, expected);
+        assert_eq!(result, Err(CashError::Script(ScriptError::EvaluatedFalse)));
     }
 
     #[test]
-    fn sm_5_spending_non_existent_bill_fails() {
-        let start = State::from([Bill {
-            owner: User::Alice,
-            amount: 32,
-            serial: 0,
-        }]);
+    fn sm_5_rejected_transfer_leaves_state_untouched() {
+        let start = State::from([Output::owned_by(User::Alice, 32)]);
         let end = DigitalCashSystem::next_state(
             &start,
-            &CashTransaction::Transfer {
-                spends: vec![Bill {
-                    owner: User::Bob,
-                    amount: 1000,
-                    serial: 32,
-                }],
-                receives: vec![Bill {
-                    owner: User::Bob,
-                    amount: 1000,
-                    serial: 33,
-                }],
-            },
+            &transfer(vec![spend(0, User::Alice)], vec![Output::owned_by(User::Bob, 33)]),
         );
-        let expected = State::from([Bill {
-            owner: User::Alice,
-            amount: 32,
-            serial: 0,
-        }]);
-        assert_eq!(end, expected);
+        assert_eq!(end, start);
     }
 
     #[test]
     fn sm_5_spending_from_alice_to_all() {
-        let start = State::from([Bill {
-            owner: User::Alice,
-            amount: 42,
-            serial: 0,
-        }]);
-        let end = DigitalCashSystem::next_state(
-            &start,
-            &CashTransaction::Transfer {
-                spends: vec![Bill {
-                    owner: User::Alice,
-                    amount: 42,
-                    serial: 0,
-                }],
-                receives: vec![
-                    Bill {
-                        owner: User::Alice,
-                        amount: 10,
-                        serial: 1,
-                    },
-                    Bill {
-                        owner: User::Bob,
-                        amount: 10,
-                        serial: 2,
-                    },
-                    Bill {
-                        owner: User::Charlie,
-                        amount: 10,
-                        serial: 3,
-                    },
-                ],
-            },
+        let start = State::from([Output::owned_by(User::Alice, 42)]);
+        let transfer = transfer(
+            vec![spend(0, User::Alice)],
+            vec![
+                Output::owned_by(User::Alice, 10),
+                Output::owned_by(User::Bob, 10),
+                Output::owned_by(User::Charlie, 10),
+            ],
         );
-        let mut expected = State::from([
-            Bill {
-                owner: User::Alice,
-                amount: 10,
-                serial: 1,
-            },
-            Bill {
-                owner: User::Bob,
-                amount: 10,
-                serial: 2,
-            },
-            Bill {
-                owner: User::Charlie,
-                amount: 10,
-                serial: 3,
-            },
+        let end = DigitalCashSystem::try_next_state(&start, &transfer).unwrap();
+
+        let expected = HashMap::from([
+            (transfer.outpoint(0), Output::owned_by(User::Alice, 10)),
+            (transfer.outpoint(1), Output::owned_by(User::Bob, 10)),
+            (transfer.outpoint(2), Output::owned_by(User::Charlie, 10)),
         ]);
-        expected.set_serial(4);
-        assert_eq!(end, expected);
+        assert_eq!(end.outputs(), expected);
     }
 
     #[test]
     fn sm_5_spending_from_bob_to_all() {
-        let start = State::from([Bill {
-            owner: User::Bob,
-            amount: 42,
-            serial: 0,
-        }]);
-        let end = DigitalCashSystem::next_state(
-            &start,
-            &CashTransaction::Transfer {
-                spends: vec![Bill {
-                    owner: User::Bob,
-                    amount: 42,
-                    serial: 0,
-                }],
-                receives: vec![
-                    Bill {
-                        owner: User::Alice,
-                        amount: 10,
-                        serial: 1,
-                    },
-                    Bill {
-                        owner: User::Bob,
-                        amount: 10,
-                        serial: 2,
-                    },
-                    Bill {
-                        owner: User::Charlie,
-                        amount: 22,
-                        serial: 3,
-                    },
-                ],
-            },
+        let start = State::from([Output::owned_by(User::Bob, 42)]);
+        let transfer = transfer(
+            vec![spend(0, User::Bob)],
+            vec![
+                Output::owned_by(User::Alice, 10),
+                Output::owned_by(User::Bob, 10),
+                Output::owned_by(User::Charlie, 22),
+            ],
         );
-        let mut expected = State::from([
-            Bill {
-                owner: User::Alice,
-                amount: 10,
-                serial: 1,
-            },
-            Bill {
-                owner: User::Bob,
-                amount: 10,
-                serial: 2,
-            },
-            Bill {
-                owner: User::Charlie,
-                amount: 22,
-                serial: 3,
-            },
+        let end = DigitalCashSystem::try_next_state(&start, &transfer).unwrap();
+
+        let expected = HashMap::from([
+            (transfer.outpoint(0), Output::owned_by(User::Alice, 10)),
+            (transfer.outpoint(1), Output::owned_by(User::Bob, 10)),
+            (transfer.outpoint(2), Output::owned_by(User::Charlie, 22)),
         ]);
-        expected.set_serial(4);
-        assert_eq!(end, expected);
+        assert_eq!(end.outputs(), expected);
     }
 
     #[test]
     fn sm_5_spending_from_charlie_to_all() {
-        let mut start = State::from([
-            Bill {
-                owner: User::Charlie,
-                amount: 68,
-                serial: 54,
-            },
-            Bill {
-                owner: User::Alice,
-                amount: 4000,
-                serial: 58,
-            },
+        // Charlie's output comes from an earlier transfer, not from genesis.
+        let start = State::from([Output::owned_by(User::Alice, 4068)]);
+        let earlier = transfer(
+            vec![spend(0, User::Alice)],
+            vec![Output::owned_by(User::Charlie, 68), Output::owned_by(User::Alice, 4000)],
+        );
+        let start = DigitalCashSystem::try_next_state(&start, &earlier).unwrap();
+        let transfer = transfer(
+            vec![(earlier.outpoint(0), User::Charlie)],
+            vec![
+                Output::owned_by(User::Alice, 42),
+                Output::owned_by(User::Bob, 5),
+                Output::owned_by(User::Charlie, 5),
+            ],
+        );
+        let end = DigitalCashSystem::try_next_state(&start, &transfer).unwrap();
+
+        let expected = HashMap::from([
+            (earlier.outpoint(1), Output::owned_by(User::Alice, 4000)),
+            (transfer.outpoint(0), Output::owned_by(User::Alice, 42)),
+            (transfer.outpoint(1), Output::owned_by(User::Bob, 5)),
+            (transfer.outpoint(2), Output::owned_by(User::Charlie, 5)),
         ]);
-        start.set_serial(59);
-        let end = DigitalCashSystem::next_state(
+        assert_eq!(end.outputs(), expected);
+    }
+
+    #[test]
+    fn sm_5_transfers_conserve_issuance() {
+        let start = State::from([Output::owned_by(User::Alice, 42)]);
+        let checked = |state: &State, transaction: &CashTransaction| Checked::<DigitalCashSystem>::next_state(state, transaction);
+
+        // What the outputs leave of the inputs is held as fees until the block ends.
+        let short = transfer(vec![spend(0, User::Alice)], vec![Output::owned_by(User::Bob, 10), Output::owned_by(User::Charlie, 10)]);
+        let end = checked(&start, &short);
+        assert_eq!((end.total_issuance(), end.pending_fees()), (42, 22));
+
+        let burn = transfer(vec![(short.outpoint(0), User::Bob)], vec![]);
+        let end = checked(&end, &burn);
+        assert_eq!((end.total_issuance(), end.pending_fees()), (42, 32));
+
+        let mut end = end;
+        DigitalCashSystem::on_finalize(&mut end, &BlockContext { height: 0, author: None });
+        assert_eq!((end.total_issuance(), end.pending_fees()), (10, 0));
+    }
+
+    #[test]
+    fn sm_5_spending_outputs_of_earlier_transfer() {
+        let start = State::from([Output::owned_by(User::Alice, 42)]);
+        let first = transfer(
+            vec![spend(0, User::Alice)],
+            vec![Output::owned_by(User::Bob, 20), Output::owned_by(User::Charlie, 22)],
+        );
+        let second = transfer(
+            vec![(first.outpoint(1), User::Charlie), (first.outpoint(0), User::Bob)],
+            vec![Output::owned_by(User::Alice, 42)],
+        );
+        let middle = DigitalCashSystem::try_next_state(&start, &first).unwrap();
+        let end = DigitalCashSystem::try_next_state(&middle, &second).unwrap();
+
+        assert_eq!(end.outputs(), HashMap::from([(second.outpoint(0), Output::owned_by(User::Alice, 42))]));
+    }
+
+    #[test]
+    fn sm_5_transfer_emits_spent_and_created_bills() {
+        let start = State::from([Output::owned_by(User::Alice, 42)]);
+        let shared = Output { amount: 22, lock: script::multisig(1, &[User::Bob, User::Charlie]) };
+        let transfer = transfer(vec![spend(0, User::Alice)], vec![Output::owned_by(User::Bob, 20), shared]);
+        let end = DigitalCashSystem::try_next_state(&start, &transfer).unwrap();
+        let mut applied = start.clone();
+        let undo = DigitalCashSystem::apply_with_undo(&mut applied, &transfer).unwrap();
+
+        assert_eq!(DigitalCashSystem::transition_events(&undo, &transfer, &applied), DigitalCashSystem::events(&start, &transfer, &end));
+        assert_eq!(DigitalCashSystem::events(&start, &transfer, &end), vec![
+            Event::BillSpent { outpoint: genesis(0), owner: Some(User::Alice), amount: 42 },
+            Event::BillCreated { outpoint: transfer.outpoint(0), owner: Some(User::Bob), amount: 20 },
+            Event::BillCreated { outpoint: transfer.outpoint(1), owner: None, amount: 22 },
+        ]);
+        // Spent already, so the transfer is rejected.
+        assert_eq!(DigitalCashSystem::events(&end, &transfer, &end), vec![]);
+    }
+
+    #[test]
+    fn sm_5_overflow_receives_fails() {
+        let start = State::from([Output::owned_by(User::Alice, 42)]);
+        let transfer = transfer(
+            vec![spend(0, User::Alice)],
+            vec![Output::owned_by(User::Alice, u64::MAX), Output::owned_by(User::Alice, 42)],
+        );
+
+        assert_eq!(DigitalCashSystem::try_next_state(&start, &transfer), Err(CashError::Overflow));
+        assert_eq!(DigitalCashSystem::next_state(&start, &transfer), start);
+    }
+
+    #[test]
+    fn sm_5_coinbase_overflowing_total_issuance_fails() {
+        let start = State::from([Output::owned_by(User::Alice, u64::MAX)]);
+        let result = DigitalCashSystem::try_apply_coinbase(&start, &coinbase(0, 1));
+
+        assert_eq!(result, Err(CashError::Overflow));
+    }
+
+    #[test]
+    fn sm_5_spending_max_output() {
+        let start = State::from([Output::owned_by(User::Alice, u64::MAX)]);
+        let end = DigitalCashSystem::try_next_state(
             &start,
-            &CashTransaction::Transfer {
-                spends: vec![Bill {
-                    owner: User::Charlie,
-                    amount: 68,
-                    serial: 54,
-                }],
-                receives: vec![
-                    Bill {
-                        owner: User::Alice,
-                        amount: 42,
-                        serial: 59,
-                    },
-                    Bill {
-                        owner: User::Bob,
-                        amount: 5,
-                        serial: 60,
-                    },
-                    Bill {
-                        owner: User::Charlie,
-                        amount: 5,
-                        serial: 61,
-                    },
-                ],
-            },
+            &transfer(
+                vec![spend(0, User::Alice)],
+                vec![Output::owned_by(User::Bob, u64::MAX - 1), Output::owned_by(User::Alice, 1)],
+            ),
         );
-        let mut expected = State::from([
-            Bill {
-                owner: User::Alice,
-                amount: 4000,
-                serial: 58,
-            },
-            Bill {
-                owner: User::Alice,
-                amount: 42,
-                serial: 59,
-            },
-            Bill {
-                owner: User::Bob,
-                amount: 5,
-                serial: 60,
-            },
-            Bill {
-                owner: User::Charlie,
-                amount: 5,
-                serial: 61,
-            },
+        assert_eq!(end.map(|state| state.total_issuance()), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn sm_5_signature_commits_to_outputs() {
+        let start = State::from([Output::owned_by(User::Alice, 20)]);
+        let mut transaction = transfer(vec![spend(0, User::Alice)], vec![Output::owned_by(User::Bob, 20)]);
+        if let CashTransaction::Transfer { outputs, .. } = &mut transaction {
+            outputs[0] = Output::owned_by(User::Charlie, 20);
+        }
+
+        assert_eq!(
+            DigitalCashSystem::try_next_state(&start, &transaction),
+            Err(CashError::Script(ScriptError::EvaluatedFalse))
+        );
+    }
+
+    #[test]
+    fn sm_5_signing_a_missing_input_fails() {
+        let mut transaction = transfer(vec![spend(0, User::Alice)], vec![Output::owned_by(User::Bob, 20)]);
+        let signed = transaction.clone();
+        let alice = SecretKey::dev(User::Alice);
+
+        assert_eq!(transaction.sign(1, &alice), Err(CashError::InputOutOfRange));
+        assert_eq!(transaction.claim_htlc(1, &alice, 0), Err(CashError::InputOutOfRange));
+        assert_eq!(transaction.refund_htlc(1, &alice), Err(CashError::InputOutOfRange));
+        assert_eq!(transaction, signed);
+        assert_eq!(coinbase(0, 50).sign(0, &alice), Err(CashError::InputOutOfRange));
+    }
+
+    #[test]
+    fn sm_5_spending_multisig_output() {
+        let lock = script::multisig(2, &[User::Alice, User::Bob, User::Charlie]);
+        let start = State::from([Output { amount: 30, lock }]);
+        let mut transaction = CashTransaction::Transfer {
+            inputs: vec![Input::unsigned(genesis(0))],
+            outputs: vec![Output::owned_by(User::Charlie, 30)],
+        };
+        let sign = |user| Op::PushNum(script::sign(&SecretKey::dev(user), transaction.hash()));
+        let witness = vec![sign(User::Alice), sign(User::Charlie)];
+        if let CashTransaction::Transfer { inputs, .. } = &mut transaction {
+            inputs[0].witness = witness;
+        }
+
+        assert!(DigitalCashSystem::try_next_state(&start, &transaction).is_ok());
+    }
+
+    #[test]
+    fn sm_5_hash_locked_output_needs_preimage() {
+        let start = State::from([Output { amount: 30, lock: script::hash_lock(script::hash_of(1234)) }]);
+        let claim = |preimage| CashTransaction::Transfer {
+            inputs: vec![Input { outpoint: genesis(0), witness: vec![Op::PushNum(preimage)] }],
+            outputs: vec![Output::owned_by(User::Bob, 30)],
+        };
+
+        assert!(DigitalCashSystem::try_next_state(&start, &claim(1234)).is_ok());
+        assert_eq!(
+            DigitalCashSystem::try_next_state(&start, &claim(4321)),
+            Err(CashError::Script(ScriptError::EvaluatedFalse))
+        );
+    }
+
+    #[test]
+    fn sm_5_relative_timelock_counts_from_output_creation() {
+        let lock = [vec![Op::PushNum(5), Op::CheckSequence], script::pay_to_key(User::Alice)].concat();
+        let mut state = State::from([Output::owned_by(User::Alice, 30)]);
+        state.set_block_height(10);
+        let lock_up = transfer(vec![spend(0, User::Alice)], vec![Output { amount: 30, lock }]);
+        state = DigitalCashSystem::try_next_state(&state, &lock_up).unwrap();
+        let spend_later = transfer(vec![(lock_up.outpoint(0), User::Alice)], vec![Output::owned_by(User::Bob, 30)]);
+
+        state.set_block_height(14);
+        assert_eq!(
+            DigitalCashSystem::try_next_state(&state, &spend_later),
+            Err(CashError::Script(ScriptError::SequenceNotReached))
+        );
+        state.set_block_height(15);
+        assert!(DigitalCashSystem::try_next_state(&state, &spend_later).is_ok());
+    }
+
+    #[test]
+    fn sm_5_htlc_is_claimed_with_preimage_or_refunded_after_expiry() {
+        let mut state = State::from([Output::htlc(30, script::hash_of(1234), User::Bob, User::Alice, 20)]);
+        state.set_block_height(10);
+        let mut claim = CashTransaction::Transfer {
+            inputs: vec![Input::unsigned(genesis(0))],
+            outputs: vec![Output::owned_by(User::Bob, 30)],
+        };
+        let mut refund = CashTransaction::Transfer {
+            inputs: vec![Input::unsigned(genesis(0))],
+            outputs: vec![Output::owned_by(User::Alice, 30)],
+        };
+        claim.claim_htlc(0, &SecretKey::dev(User::Bob), 4321).unwrap();
+        refund.refund_htlc(0, &SecretKey::dev(User::Alice)).unwrap();
+
+        assert_eq!(
+            DigitalCashSystem::try_next_state(&state, &claim),
+            Err(CashError::Script(ScriptError::VerifyFailed))
+        );
+        assert_eq!(
+            DigitalCashSystem::try_next_state(&state, &refund),
+            Err(CashError::Script(ScriptError::LockTimeNotReached))
+        );
+        claim.claim_htlc(0, &SecretKey::dev(User::Bob), 1234).unwrap();
+        assert!(DigitalCashSystem::try_next_state(&state, &claim).is_ok());
+        assert_eq!(claim.revealed_preimages(), vec![1234]);
+
+        state.set_block_height(20);
+        assert!(DigitalCashSystem::try_next_state(&state, &refund).is_ok());
+    }
+
+    fn pay_bob_with_fee(fee: u64) -> CashTransaction {
+        transfer(vec![spend(0, User::Alice)], vec![Output::owned_by(User::Bob, 20 - fee)])
+    }
+
+    #[test]
+    fn sm_5_unspent_value_is_collected_as_fee() {
+        let start = State::from([Output::owned_by(User::Alice, 20)]);
+        let end = DigitalCashSystem::try_next_state(&start, &pay_bob_with_fee(3)).unwrap();
+
+        assert_eq!(end.pending_fees(), 3);
+        assert_eq!(end.total_issuance(), 20);
+    }
+
+    #[test]
+    fn sm_5_author_collects_fees_and_subsidy() {
+        let context = BlockContext { height: 1, author: Some(User::Charlie) };
+        let mut start = State::from([Output::owned_by(User::Alice, 20)]).with_subsidy(10, 10);
+        DigitalCashSystem::on_initialize(&mut start, &context);
+        let mut end = DigitalCashSystem::try_next_state(&start, &pay_bob_with_fee(3)).unwrap();
+        let coinbase = DigitalCashSystem::coinbase(&end, &context).unwrap();
+        let undo = DigitalCashSystem::hook_undo(&end, &context);
+        DigitalCashSystem::on_finalize(&mut end, &context);
+
+        assert!(end.outputs().values().any(|output| *output == Output::owned_by(User::Charlie, 13)));
+        assert_eq!(end.pending_fees(), 0);
+        assert_eq!(end.total_issuance(), 30);
+        assert_eq!(DigitalCashSystem::hook_events(&undo, &context, &end), vec![
+            Event::BillCreated { outpoint: coinbase.outpoint(0), owner: Some(User::Charlie), amount: 13 },
         ]);
-        expected.set_serial(62);
-        assert_eq!(end, expected);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn sm_5_fees_without_author_are_burned() {
+        let start = State::from([Output::owned_by(User::Alice, 20)]);
+        let mut end = DigitalCashSystem::try_next_state(&start, &pay_bob_with_fee(3)).unwrap();
+        let context = BlockContext { height: 0, author: None };
+        let undo = DigitalCashSystem::hook_undo(&end, &context);
+        DigitalCashSystem::on_finalize(&mut end, &context);
+
+        assert_eq!(end.outputs().len(), 1);
+        assert_eq!(end.total_issuance(), 17);
+        assert_eq!(DigitalCashSystem::hook_events(&undo, &context, &end), vec![Event::FeesBurned { amount: 3 }]);
+    }
+
+    #[derive(Clone, Debug)]
+    enum Action {
+        // Indices into the unspent outputs sorted by outpoint, receivers with their share of the
+        // total, and the fee left unspent.
+        Transfer(Vec<usize>, Vec<(User, u64)>, u64),
+    }
+
+    fn arb_user() -> impl Strategy<Value = User> {
+        prop_oneof![Just(User::Alice), Just(User::Bob), Just(User::Charlie)]
+    }
+
+    fn arb_action() -> impl Strategy<Value = Action> {
+        (
+            proptest::collection::vec(0..10usize, 1..3),
+            proptest::collection::vec((arb_user(), 1..10u64), 1..4),
+            0..5u64,
+        )
+            .prop_map(|(picks, shares, fee)| Action::Transfer(picks, shares, fee))
+    }
+
+    fn to_transaction(state: &State, action: Action) -> CashTransaction {
+        match action {
+            Action::Transfer(picks, shares, fee) => {
+                let mut utxos: Vec<(&OutPoint, &Utxo)> = state.utxos.iter().collect();
+                utxos.sort_by_key(|(outpoint, _)| **outpoint);
+
+                let spent: Vec<(&OutPoint, &Utxo)> = if utxos.is_empty() {
+                    vec![]
+                } else {
+                    picks.iter().map(|pick| utxos[pick % utxos.len()]).collect()
+                };
+                // Every output here pays to a key, which is the first opcode of its lock.
+                let inputs = spent
+                    .iter()
+                    .map(|(outpoint, utxo)| match utxo.output.lock[0] {
+                        Op::PushKey(owner) => (**outpoint, owner),
+                        _ => unreachable!("only pay-to-key outputs are created"),
+                    })
+                    .collect();
+                let total: u64 = spent.iter().map(|(_, utxo)| utxo.output.amount).sum::<u64>().saturating_sub(fee);
+                let total_shares: u64 = shares.iter().map(|(_, share)| share).sum();
+
+                let mut left = total;
+                let outputs = shares
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (owner, share))| {
+                        let amount = if i == shares.len() - 1 { left } else { total * share / total_shares };
+                        left -= amount;
+                        Output::owned_by(*owner, amount)
+                    })
+                    .collect();
+
+                transfer(inputs, outputs)
+            }
+        }
+    }
+
+    #[test]
+    fn sm_5_random_transactions_keep_every_invariant() {
+        let genesis = proptest::collection::vec((arb_user(), 1..1_000u64), 1..5)
+            .prop_map(|genesis| State::from_iter(genesis.into_iter().map(|(owner, amount)| Output::owned_by(owner, amount))));
+
+        Harness::<DigitalCashSystem, _>::new(genesis, arb_action(), to_transaction)
+            .invariants(DigitalCashSystem::invariants())
+            .check();
+    }
+
+    proptest! {
+        #[test]
+        fn sm_5_reverting_blocks_restores_every_earlier_state(
+            genesis in proptest::collection::vec((arb_user(), 1..1_000u64), 1..5),
+            blocks in proptest::collection::vec(
+                (proptest::collection::vec(arb_action(), 0..10), proptest::option::of(arb_user())),
+                0..6,
+            ),
+        ) {
+            let genesis = genesis.into_iter().map(|(owner, amount)| Output::owned_by(owner, amount));
+            let mut state = State::from_iter(genesis);
+            let mut journal = Journal::<DigitalCashSystem>::new();
+            let mut history = vec![state.clone()];
+
+            for (i, (actions, author)) in blocks.into_iter().enumerate() {
+                let context = BlockContext { height: (i as u64 + 1) * COINBASE_MATURITY / 2, author };
+                let mut undos = vec![DigitalCashSystem::hook_undo(&state, &context)];
+                DigitalCashSystem::on_initialize(&mut state, &context);
+
+                // Applying in place has to agree with the copying `try_next_state`.
+                for action in actions {
+                    let transaction = to_transaction(&state, action);
+                    let expected = DigitalCashSystem::try_next_state(&state, &transaction);
+                    let before = state.clone();
+                    if let Ok(undo) = DigitalCashSystem::apply_with_undo(&mut state, &transaction) {
+                        undos.push(undo);
+                    }
+                    prop_assert_eq!(expected.unwrap_or(before), state.clone());
+                }
+
+                undos.push(DigitalCashSystem::hook_undo(&state, &context));
+                DigitalCashSystem::on_finalize(&mut state, &context);
+
+                journal.push_block(undos);
+                history.push(state.clone());
+            }
+
+            history.pop();
+            while journal.revert_block(&mut state) {
+                prop_assert_eq!(&state, &history.pop().unwrap());
+            }
+            prop_assert!(history.is_empty());
+        }
+
+        #[test]
+        fn sm_5_random_transactions_conserve_issuance(
+            genesis in proptest::collection::vec((arb_user(), 1..1_000u64), 1..5),
+            (initial_subsidy, halving_interval) in (0..100u64, 1..200u64),
+            blocks in proptest::collection::vec(
+                (proptest::collection::vec(arb_action(), 0..10), proptest::option::of(arb_user())),
+                0..6,
+            ),
+        ) {
+            let genesis = genesis.into_iter().map(|(owner, amount)| Output::owned_by(owner, amount));
+            let mut state = State::from_iter(genesis).with_subsidy(initial_subsidy, halving_interval);
+
+            // Blocks are spaced out so that some coinbases mature along the way.
+            for (i, (actions, author)) in blocks.into_iter().enumerate() {
+                let context = BlockContext { height: i as u64 * COINBASE_MATURITY / 2, author };
+                DigitalCashSystem::on_initialize(&mut state, &context);
+                let issued_before = state.total_issuance();
+
+                for action in actions {
+                    let transaction = to_transaction(&state, action);
+                    state = Checked::<DigitalCashSystem>::next_state(&state, &transaction);
+                }
+                let fees = state.pending_fees();
+                let subsidy = state.subsidy(context.height);
+                DigitalCashSystem::on_finalize(&mut state, &context);
+
+                let in_utxos: u64 = state.utxos.values().map(|utxo| utxo.output.amount).sum();
+                prop_assert_eq!(in_utxos, state.total_issuance());
+                prop_assert_eq!(state.pending_fees(), 0);
+                let expected = match author {
+                    Some(_) if fees + subsidy > 0 => issued_before + subsidy,
+                    _ => issued_before - fees,
+                };
+                prop_assert_eq!(state.total_issuance(), expected);
+            }
+        }
+    }
+}
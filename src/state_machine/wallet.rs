@@ -0,0 +1,359 @@
+#![allow(dead_code)]
+
+//! Builds signed `CashTransaction`s out of the coins a key owns in the UTXO currency.
+
+use super::script::{self, SecretKey};
+use super::utxo_currency::{CashTransaction, Input, OutPoint, Output, State};
+use super::User;
+
+/// Virtual size of a transaction without inputs or outputs. Fees are charged per unit of size.
+pub const TX_OVERHEAD: u64 = 10;
+/// Virtual size an input adds, its outpoint and signature.
+pub const INPUT_SIZE: u64 = 40;
+/// Virtual size an output adds, its amount and locking script.
+pub const OUTPUT_SIZE: u64 = 30;
+/// Change below this is left to the block author instead of creating a tiny output.
+pub const DUST_LIMIT: u64 = 10;
+/// Branch-and-bound gives up after exploring this many selections.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// What a transaction with that many inputs and outputs pays at `fee_rate`.
+pub fn estimate_fee(inputs: usize, outputs: usize, fee_rate: u64) -> u64 {
+    let size = TX_OVERHEAD + inputs as u64 * INPUT_SIZE + outputs as u64 * OUTPUT_SIZE;
+    size.saturating_mul(fee_rate)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoinSelection {
+    /// Spends the biggest coins first, keeping the number of inputs low.
+    LargestFirst,
+    /// Looks for coins that pay the target without change, falling back to `LargestFirst`.
+    BranchAndBound,
+    /// Spends coins in an order shuffled by `seed`, so that repeated payments do not reveal
+    /// which coins belong together.
+    Random { seed: u64 },
+}
+
+/// The coins chosen to fund a payment and what is left of them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Selection {
+    pub coins: Vec<OutPoint>,
+    pub fee: u64,
+    /// Paid back to the wallet, zero if the selection needs no change output.
+    pub change: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalletError {
+    NoPayments,
+    ZeroAmount,
+    /// The wallet's mature coins can not cover the payments and their fee.
+    InsufficientFunds,
+    Overflow,
+}
+
+/// Tracks the outputs paying to `owner`'s key and spends them with `key`, which has to be
+/// the secret key of `owner`'s public key for the spends to be valid.
+pub struct Wallet {
+    owner: User,
+    key: SecretKey,
+}
+
+impl Wallet {
+    pub fn new(owner: User, key: SecretKey) -> Self {
+        Wallet { owner, key }
+    }
+
+    pub fn owner(&self) -> User {
+        self.owner
+    }
+
+    /// The coins the wallet can spend in the state's current block, ordered by outpoint.
+    pub fn coins(&self, state: &State) -> Vec<(OutPoint, u64)> {
+        let lock = script::pay_to_key(self.owner);
+        let mut coins: Vec<(OutPoint, u64)> = state
+            .utxos()
+            .iter()
+            .filter(|(_, utxo)| utxo.output.lock == lock && utxo.is_mature(state.block_height()))
+            .map(|(outpoint, utxo)| (*outpoint, utxo.output.amount))
+            .collect();
+        coins.sort();
+        coins
+    }
+
+    pub fn balance(&self, state: &State) -> u64 {
+        self.coins(state).iter().map(|(_, amount)| amount).sum()
+    }
+
+    /// Picks coins worth `target` plus the fee of a transaction with `outputs` payment outputs.
+    pub fn select(
+        &self,
+        state: &State,
+        target: u64,
+        outputs: usize,
+        fee_rate: u64,
+        strategy: CoinSelection,
+    ) -> Result<Selection, WalletError> {
+        let mut coins = self.coins(state);
+
+        match strategy {
+            CoinSelection::LargestFirst => {
+                coins.sort_by_key(|(_, amount)| std::cmp::Reverse(*amount));
+                accumulate(&coins, target, outputs, fee_rate)
+            }
+            CoinSelection::BranchAndBound => {
+                coins.sort_by_key(|(_, amount)| std::cmp::Reverse(*amount));
+                branch_and_bound(&coins, target, outputs, fee_rate)
+                    .map_or_else(|| accumulate(&coins, target, outputs, fee_rate), Ok)
+            }
+            CoinSelection::Random { seed } => {
+                shuffle(&mut coins, seed);
+                accumulate(&coins, target, outputs, fee_rate)
+            }
+        }
+    }
+
+    /// A transaction paying every `(receiver, amount)`, with change back to the wallet and all
+    /// inputs signed.
+    pub fn pay(
+        &self,
+        state: &State,
+        payments: &[(User, u64)],
+        fee_rate: u64,
+        strategy: CoinSelection,
+    ) -> Result<CashTransaction, WalletError> {
+        if payments.is_empty() {
+            return Err(WalletError::NoPayments)
+        }
+        if payments.iter().any(|(_, amount)| *amount == 0) {
+            return Err(WalletError::ZeroAmount)
+        }
+        let target = payments
+            .iter()
+            .try_fold(0u64, |sum, (_, amount)| sum.checked_add(*amount))
+            .ok_or(WalletError::Overflow)?;
+        let selection = self.select(state, target, payments.len(), fee_rate, strategy)?;
+
+        let mut outputs: Vec<Output> =
+            payments.iter().map(|(receiver, amount)| Output::owned_by(*receiver, *amount)).collect();
+        if selection.change > 0 {
+            outputs.push(Output::owned_by(self.owner, selection.change));
+        }
+        let mut transaction = CashTransaction::Transfer {
+            inputs: selection.coins.iter().map(|outpoint| Input::unsigned(*outpoint)).collect(),
+            outputs,
+        };
+        for index in 0..selection.coins.len() {
+            transaction.sign(index, &self.key).expect("every selected coin has an input");
+        }
+
+        Ok(transaction)
+    }
+}
+
+// Takes coins in order until they pay for the target, the fee and, if worth it, a change output.
+fn accumulate(coins: &[(OutPoint, u64)], target: u64, outputs: usize, fee_rate: u64) -> Result<Selection, WalletError> {
+    let mut selected = Vec::new();
+    let mut total: u64 = 0;
+
+    for (outpoint, amount) in coins {
+        selected.push(*outpoint);
+        total = total.checked_add(*amount).ok_or(WalletError::Overflow)?;
+
+        let fee_with_change = estimate_fee(selected.len(), outputs + 1, fee_rate);
+        let fee_without_change = estimate_fee(selected.len(), outputs, fee_rate);
+        let available = total.checked_sub(target);
+
+        match available {
+            Some(left) if left >= fee_with_change.saturating_add(DUST_LIMIT) => {
+                return Ok(Selection { coins: selected, fee: fee_with_change, change: left - fee_with_change })
+            }
+            // Change too small to be worth an output goes to the fee.
+            Some(left) if left >= fee_without_change => {
+                return Ok(Selection { coins: selected, fee: left, change: 0 })
+            }
+            _ => {}
+        }
+    }
+    Err(WalletError::InsufficientFunds)
+}
+
+// Searches for coins whose value after their own input fee pays the target without leaving
+// enough for a change output. `coins` have to be sorted largest first.
+fn branch_and_bound(coins: &[(OutPoint, u64)], target: u64, outputs: usize, fee_rate: u64) -> Option<Selection> {
+    let input_fee = estimate_fee(1, 0, fee_rate) - estimate_fee(0, 0, fee_rate);
+    let effective: Vec<u64> = coins.iter().map(|(_, amount)| amount.saturating_sub(input_fee)).collect();
+
+    let low = target.checked_add(estimate_fee(0, outputs, fee_rate))?;
+    let cost_of_change = estimate_fee(0, 1, fee_rate) - estimate_fee(0, 0, fee_rate) + DUST_LIMIT;
+    let high = low.saturating_add(cost_of_change);
+
+    let mut search = Search { effective: &effective, low, high, tries: 0, selected: Vec::new() };
+    let remaining = effective.iter().try_fold(0u64, |sum, value| sum.checked_add(*value))?;
+    if !search.run(0, 0, remaining) {
+        return None
+    }
+
+    let selected = search.selected;
+    let total: u64 = selected.iter().map(|i| coins[*i].1).sum();
+    Some(Selection {
+        coins: selected.iter().map(|i| coins[*i].0).collect(),
+        fee: total - target,
+        change: 0,
+    })
+}
+
+struct Search<'a> {
+    effective: &'a [u64],
+    low: u64,
+    high: u64,
+    tries: usize,
+    selected: Vec<usize>,
+}
+
+impl Search<'_> {
+    // Decides for coin `index` on, given what is selected sums to `sum` and the undecided coins
+    // to `remaining`.
+    fn run(&mut self, index: usize, sum: u64, remaining: u64) -> bool {
+        self.tries += 1;
+        if sum > self.high || sum + remaining < self.low || self.tries > BNB_MAX_TRIES {
+            return false
+        }
+        if sum >= self.low {
+            return true
+        }
+        let Some(value) = self.effective.get(index).copied() else {
+            return false
+        };
+
+        self.selected.push(index);
+        if value > 0 && self.run(index + 1, sum + value, remaining - value) {
+            return true
+        }
+        self.selected.pop();
+        self.run(index + 1, sum, remaining - value)
+    }
+}
+
+// Fisher-Yates with a xorshift generator, enough to hide which coins belong together.
+fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed | 1;
+
+    for i in (1..items.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        items.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::state_machine::utxo_currency::{DigitalCashSystem, GENESIS_TX};
+    use crate::state_machine::{BlockContext, BlockHooks, StateMachine};
+
+    fn genesis(index: u32) -> OutPoint {
+        OutPoint { tx_hash: GENESIS_TX, index }
+    }
+
+    fn wallet_of(owner: User) -> Wallet {
+        Wallet::new(owner, SecretKey::dev(owner))
+    }
+
+    fn alice_coins(amounts: &[u64]) -> State {
+        amounts.iter().map(|amount| Output::owned_by(User::Alice, *amount)).collect()
+    }
+
+    #[test]
+    fn coins_are_own_and_mature() {
+        let mut state = State::from([Output::owned_by(User::Alice, 50), Output::owned_by(User::Bob, 20)]);
+        DigitalCashSystem::on_finalize(&mut state, &BlockContext { height: 0, author: Some(User::Alice) });
+        let wallet = wallet_of(User::Alice);
+
+        assert_eq!(wallet.coins(&state), vec![(genesis(0), 50)]);
+        state.set_block_height(100);
+        assert_eq!(wallet.balance(&state), 100);
+    }
+
+    #[test]
+    fn largest_first_adds_change() {
+        let state = alice_coins(&[1_000, 5_000, 2_000]);
+        let selection = wallet_of(User::Alice).select(&state, 5_500, 1, 1, CoinSelection::LargestFirst).unwrap();
+
+        assert_eq!(selection.coins, vec![genesis(1), genesis(2)]);
+        assert_eq!(selection.fee, estimate_fee(2, 2, 1));
+        assert_eq!(selection.change, 7_000 - 5_500 - selection.fee);
+    }
+
+    #[test]
+    fn dust_change_goes_to_fee() {
+        let state = alice_coins(&[1_000]);
+        let selection = wallet_of(User::Alice).select(&state, 900, 1, 1, CoinSelection::LargestFirst).unwrap();
+
+        assert_eq!(selection, Selection { coins: vec![genesis(0)], fee: 100, change: 0 });
+    }
+
+    #[test]
+    fn branch_and_bound_avoids_change() {
+        let state = alice_coins(&[5_000, 3_000, 2_080, 1_000]);
+        let fee = estimate_fee(2, 1, 1);
+        let wallet = wallet_of(User::Alice);
+
+        let exact = wallet.select(&state, 5_080 - fee, 1, 1, CoinSelection::BranchAndBound).unwrap();
+        assert_eq!(exact, Selection { coins: vec![genesis(1), genesis(2)], fee, change: 0 });
+
+        let largest = wallet.select(&state, 5_080 - fee, 1, 1, CoinSelection::LargestFirst).unwrap();
+        assert!(largest.change > 0);
+    }
+
+    #[test]
+    fn random_selection_depends_on_seed() {
+        let state = alice_coins(&[100, 200, 300, 400, 500, 600, 700, 800]);
+        let wallet = wallet_of(User::Alice);
+        let select = |seed| wallet.select(&state, 50, 1, 0, CoinSelection::Random { seed }).unwrap();
+
+        assert_eq!(select(1), select(1));
+        assert!((0..20).any(|seed| select(seed).coins != select(1).coins));
+    }
+
+    #[test]
+    fn insufficient_funds() {
+        let state = alice_coins(&[100, 200]);
+        let wallet = wallet_of(User::Alice);
+
+        assert_eq!(
+            wallet.select(&state, 300, 1, 1, CoinSelection::LargestFirst),
+            Err(WalletError::InsufficientFunds)
+        );
+        assert_eq!(
+            wallet.pay(&state, &[(User::Bob, 301)], 0, CoinSelection::BranchAndBound),
+            Err(WalletError::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn paid_transaction_is_accepted() {
+        let state = alice_coins(&[1_000, 5_000, 2_000]);
+        let wallet = wallet_of(User::Alice);
+        let payments = [(User::Bob, 4_000), (User::Charlie, 2_500)];
+
+        for strategy in [CoinSelection::LargestFirst, CoinSelection::BranchAndBound, CoinSelection::Random { seed: 7 }] {
+            let transaction = wallet.pay(&state, &payments, 2, strategy).unwrap();
+            let end = DigitalCashSystem::try_next_state(&state, &transaction).unwrap();
+
+            assert_eq!(wallet_of(User::Bob).balance(&end), 4_000);
+            assert_eq!(wallet_of(User::Charlie).balance(&end), 2_500);
+            assert_eq!(wallet.balance(&end) + end.pending_fees(), 8_000 - 6_500);
+        }
+    }
+
+    #[test]
+    fn payments_must_be_valid() {
+        let state = alice_coins(&[1_000]);
+        let wallet = wallet_of(User::Alice);
+
+        assert_eq!(wallet.pay(&state, &[], 1, CoinSelection::LargestFirst), Err(WalletError::NoPayments));
+        assert_eq!(wallet.pay(&state, &[(User::Bob, 0)], 1, CoinSelection::LargestFirst), Err(WalletError::ZeroAmount));
+    }
+}